@@ -28,11 +28,12 @@ extern crate rand;
 use test::Bencher;
 use rand::{Rng};
 use std::collections::HashMap;
+use std::mem;
 
 use std::collections::hash_map::DefaultHasher as StdHasher;
 
 use hamt_rs::{ItemStore, ShareStore, CopyStore};
-use hamt_rs::HamtMap;
+use hamt_rs::{HamtMap, GrowthPolicy, TransientHamtMap, CollisionPolicy};
 
 static BENCH_FIND_COUNT: usize = 1000;
 static BENCH_INSERT_COUNT: usize = 1000;
@@ -110,6 +111,245 @@ fn bench_hamt_remove<IS: ItemStore<u64, u64>>(empty: HamtMap<u64, u64, IS>, coun
     })
 }
 
+fn bench_hamt_find_churned<IS: ItemStore<u64, u64>>(empty: HamtMap<u64, u64, IS>,
+                                                  count: usize,
+                                                  compact: bool,
+                                                  bh: &mut Bencher) {
+    let (mut map, keys) = create_random_hamt(empty, count * 2);
+
+    // Remove half of the entries again, leaving partially filled nodes behind
+    for key in keys[count..].iter() {
+        map = map.minus(key);
+    }
+
+    let map = if compact { map.compact() } else { map };
+
+    bh.iter(|| {
+        for i in (0usize .. BENCH_FIND_COUNT) {
+            let val = keys[i % count];
+
+            unsafe {
+                match map.find(&val) {
+                    Some(&x) => RESULTS[i] = Some(x),
+                    None => RESULTS[i] = None,
+                }
+            }
+        }
+    })
+}
+
+fn bench_hamt_modify<IS: ItemStore<u64, u64>>(empty: HamtMap<u64, u64, IS>,
+                                            count: usize,
+                                            batched: bool,
+                                            bh: &mut Bencher) {
+    let (map, keys) = create_random_hamt(empty, count);
+    let updates: Vec<(u64, u64)> = keys.iter().take(BENCH_INSERT_COUNT).map(|&k| (k, 1)).collect();
+
+    bh.iter(|| {
+        let mut map1 = map.clone();
+
+        if batched {
+            map1 = map1.modify_many(&updates[..], |val, delta| *val += *delta);
+        } else {
+            for &(ref key, delta) in updates.iter() {
+                map1 = map1.modify(key, |val| val + delta);
+            }
+        }
+
+        map1
+    })
+}
+
+fn bench_hamt_difference<IS: ItemStore<u64, u64>>(empty: HamtMap<u64, u64, IS>,
+                                                count: usize,
+                                                overlap: usize,
+                                                in_place: bool,
+                                                bh: &mut Bencher) {
+    let (map, keys) = create_random_hamt(empty.clone(), count);
+    let mut other = empty;
+
+    for &key in keys[.. overlap].iter() {
+        other = other.plus(key, key);
+    }
+
+    bh.iter(|| {
+        if in_place {
+            // Make sure the map is uniquely owned, as it would be in a pipeline
+            map.compact().difference_update(&other)
+        } else {
+            map.compact().difference(&other)
+        }
+    })
+}
+
+fn bench_hamt_build<IS: ItemStore<u64, u64>>(empty: HamtMap<u64, u64, IS>,
+                                           count: usize,
+                                           bulk: bool,
+                                           bh: &mut Bencher) {
+    let values = create_unique_values(count);
+    let pairs: Vec<(u64, u64)> = values.iter().map(|&x| (x, x)).collect();
+
+    bh.iter(|| {
+        if bulk {
+            HamtMap::<u64, u64, IS>::from_sorted_pairs(pairs.clone())
+        } else {
+            let mut map = empty.clone();
+            for &(k, v) in pairs.iter() {
+                map = map.plus(k, v);
+            }
+            map
+        }
+    })
+}
+
+fn bench_hamt_build_sorted_runs(count: usize, run_count: usize, bh: &mut Bencher) {
+    let values = create_unique_values(count);
+    let runs: Vec<Vec<(u64, u64, u64)>> = values.chunks(count / run_count).map(|chunk| {
+        let shard = chunk.iter().fold(CopyStoreHamt::new(), |map, &x| map.plus(x, x));
+        shard.iter().map(|(&k, &v)| (CopyStoreHamt::key_hash(&k), k, v)).collect()
+    }).collect();
+
+    bh.iter(|| {
+        CopyStoreHamt::from_sorted_runs(runs.clone(), &CollisionPolicy::Replace)
+    })
+}
+
+fn bench_hamt_collect(count: usize, use_size_hint: bool, bh: &mut Bencher) {
+    let values = create_unique_values(count);
+    let pairs: Vec<(u64, u64)> = values.iter().map(|&x| (x, x)).collect();
+
+    bh.iter(|| {
+        let map: CopyStoreHamt = if use_size_hint {
+            pairs.iter().cloned().collect()
+        } else {
+            // `filter()` hides the size of the source
+            pairs.iter().cloned().filter(|_| true).collect()
+        };
+        map
+    })
+}
+
+fn bench_hamt_prune<IS: ItemStore<u64, u64>>(empty: HamtMap<u64, u64, IS>,
+                                           count: usize,
+                                           in_place: bool,
+                                           bh: &mut Bencher) {
+    let (map, _) = create_random_hamt(empty, count);
+
+    bh.iter(|| {
+        // Make sure the map is uniquely owned
+        if in_place {
+            map.compact().remove_where(|&key, _| key % 2 == 0)
+        } else {
+            map.compact().retain(|&key, _| key % 2 != 0)
+        }
+    })
+}
+
+// Prunes half of the entries of a uniquely owned map, either in-place with a transient builder or
+// by rebuilding the map with the persistent `retain()`.
+fn bench_hamt_prune_half(count: usize, transient: bool, bh: &mut Bencher) {
+    let (map, _) = create_random_hamt(CopyStoreHamt::new(), count);
+
+    bh.iter(|| {
+        if transient {
+            let mut transient = map.compact().transient();
+            transient.retain(|&key, _| key % 2 != 0);
+            transient.persistent()
+        } else {
+            map.compact().retain(|&key, _| key % 2 != 0)
+        }
+    })
+}
+
+// Inserts and removes keys over and over again, growing and shrinking the nodes involved, which
+// mostly measures how fast nodes are allocated and freed.
+fn bench_hamt_churn<IS: ItemStore<u64, u64>>(empty: HamtMap<u64, u64, IS>,
+                                           count: usize,
+                                           bh: &mut Bencher) {
+    let (map, _) = create_random_hamt(empty, count);
+    let mut map = map.compact();
+    let values = create_unique_values(BENCH_INSERT_COUNT);
+
+    bh.iter(|| {
+        for &x in values.iter() {
+            map = mem::take(&mut map).plus(x, x);
+        }
+
+        for x in values.iter() {
+            map = mem::take(&mut map).minus(x);
+        }
+    })
+}
+
+// Builds the union of two tiny maps, either with `union()`, which merges the two root nodes
+// directly, or by inserting the items of one map into the other one by one.
+fn bench_hamt_union<IS: ItemStore<u64, u64>>(empty: HamtMap<u64, u64, IS>,
+                                           count: usize,
+                                           merge_roots: bool,
+                                           bh: &mut Bencher) {
+    let (left, _) = create_random_hamt(empty.clone(), count);
+    let (right, _) = create_random_hamt(empty, count);
+
+    bh.iter(|| {
+        for _ in 0 .. 1000 {
+            if merge_roots {
+                test::black_box(left.union(&right));
+            } else {
+                let mut union = left.clone();
+                for (&key, &value) in right.iter() {
+                    if union.find(&key).is_none() {
+                        union = union.plus(key, value);
+                    }
+                }
+                test::black_box(union);
+            }
+        }
+    })
+}
+
+// Combines ten maps into one, either with a transient builder, which modifies the result
+// in-place, or with persistent unions.
+fn bench_hamt_merge(count: usize, transient: bool, bh: &mut Bencher) {
+    let maps: Vec<CopyStoreHamt> = (0 .. 10).map(|_| create_random_hamt(CopyStoreHamt::new(), count).0)
+                                            .collect();
+
+    bh.iter(|| {
+        if transient {
+            let mut merged = TransientHamtMap::new();
+            for map in maps.iter() {
+                merged.merge_in_place(map);
+            }
+            merged.persistent()
+        } else {
+            let mut merged = CopyStoreHamt::new();
+            for map in maps.iter() {
+                merged = map.union(&merged);
+            }
+            merged
+        }
+    })
+}
+
+// Fills a transient builder with pairs sorted in trie order, either all at once with
+// `insert_sorted()` or one after the other.
+fn bench_hamt_transient_build(count: usize, sorted: bool, bh: &mut Bencher) {
+    let values = create_unique_values(count);
+    let map: CopyStoreHamt = values.iter().map(|&x| (x, x)).collect();
+    let pairs: Vec<(u64, u64)> = map.iter().map(|(&k, &v)| (k, v)).collect();
+
+    bh.iter(|| {
+        let mut transient = TransientHamtMap::<u64, u64, CopyStore<u64, u64>>::new();
+        if sorted {
+            transient.insert_sorted(&pairs);
+        } else {
+            for &(k, v) in pairs.iter() {
+                transient.insert(k, v);
+            }
+        }
+        transient.persistent()
+    })
+}
+
 fn bench_std_hashmap_find(count: usize, bh: &mut Bencher) {
     let values = create_unique_values(count);
     let mut map = HashMap::new();
@@ -408,6 +648,177 @@ fn bench_iterate_copy_100000(bh: &mut Bencher) {
     bench_hamt_iterate_copy(CopyStoreHamt::new(), 100000, bh);
 }
 
+#[bench]
+fn bench_hamt_churned_find_copy_1000(bh: &mut Bencher) {
+    bench_hamt_find_churned(CopyStoreHamt::new(), 1000, false, bh);
+}
+
+#[bench]
+fn bench_hamt_churned_find_copy_100000(bh: &mut Bencher) {
+    bench_hamt_find_churned(CopyStoreHamt::new(), 100000, false, bh);
+}
+
+#[bench]
+fn bench_hamt_compacted_find_copy_1000(bh: &mut Bencher) {
+    bench_hamt_find_churned(CopyStoreHamt::new(), 1000, true, bh);
+}
+
+#[bench]
+fn bench_hamt_compacted_find_copy_100000(bh: &mut Bencher) {
+    bench_hamt_find_churned(CopyStoreHamt::new(), 100000, true, bh);
+}
+
+// Compare to bench_hamt_find_copy_100000, which may visit up to 12 levels
+#[bench]
+fn bench_hamt_find_max_levels_3_copy_100000(bh: &mut Bencher) {
+    bench_hamt_find(CopyStoreHamt::with_max_levels(3), 100000, bh);
+}
+
+#[bench]
+fn bench_hamt_find_max_levels_4_copy_100000(bh: &mut Bencher) {
+    bench_hamt_find(CopyStoreHamt::with_max_levels(4), 100000, bh);
+}
+
+#[bench]
+fn bench_hamt_modify_loop_copy_100000(bh: &mut Bencher) {
+    bench_hamt_modify(CopyStoreHamt::new(), 100000, false, bh);
+}
+
+#[bench]
+fn bench_hamt_modify_many_copy_100000(bh: &mut Bencher) {
+    bench_hamt_modify(CopyStoreHamt::new(), 100000, true, bh);
+}
+
+#[bench]
+fn bench_hamt_difference_copy_10000(bh: &mut Bencher) {
+    bench_hamt_difference(CopyStoreHamt::new(), 10000, 1000, false, bh);
+}
+
+#[bench]
+fn bench_hamt_difference_update_copy_10000(bh: &mut Bencher) {
+    bench_hamt_difference(CopyStoreHamt::new(), 10000, 1000, true, bh);
+}
+
+#[bench]
+fn bench_hamt_build_insert_copy_10000(bh: &mut Bencher) {
+    bench_hamt_build(CopyStoreHamt::new(), 10000, false, bh);
+}
+
+#[bench]
+fn bench_hamt_build_sorted_copy_10000(bh: &mut Bencher) {
+    bench_hamt_build(CopyStoreHamt::new(), 10000, true, bh);
+}
+
+#[bench]
+fn bench_hamt_build_sorted_runs_copy_10000(bh: &mut Bencher) {
+    bench_hamt_build_sorted_runs(10000, 8, bh);
+}
+
+#[bench]
+fn bench_hamt_collect_copy_50000(bh: &mut Bencher) {
+    bench_hamt_collect(50000, true, bh);
+}
+
+#[bench]
+fn bench_hamt_collect_no_size_hint_copy_50000(bh: &mut Bencher) {
+    bench_hamt_collect(50000, false, bh);
+}
+
+#[bench]
+fn bench_hamt_prune_remove_where_copy_10000(bh: &mut Bencher) {
+    bench_hamt_prune(CopyStoreHamt::new(), 10000, true, bh);
+}
+
+#[bench]
+fn bench_hamt_prune_retain_copy_10000(bh: &mut Bencher) {
+    bench_hamt_prune(CopyStoreHamt::new(), 10000, false, bh);
+}
+
+#[bench]
+fn bench_hamt_prune_half_transient_copy_50000(bh: &mut Bencher) {
+    bench_hamt_prune_half(50000, true, bh);
+}
+
+#[bench]
+fn bench_hamt_prune_half_retain_copy_50000(bh: &mut Bencher) {
+    bench_hamt_prune_half(50000, false, bh);
+}
+
+#[bench]
+fn bench_hamt_churn_copy_10000(bh: &mut Bencher) {
+    bench_hamt_churn(CopyStoreHamt::new(), 10000, bh);
+}
+
+#[bench]
+fn bench_hamt_union_merge_roots_copy_4(bh: &mut Bencher) {
+    bench_hamt_union(CopyStoreHamt::new(), 4, true, bh);
+}
+
+#[bench]
+fn bench_hamt_union_insert_copy_4(bh: &mut Bencher) {
+    bench_hamt_union(CopyStoreHamt::new(), 4, false, bh);
+}
+
+#[bench]
+fn bench_hamt_insert_growth_doubling_copy_50000(bh: &mut Bencher) {
+    bench_hamt_insert(CopyStoreHamt::with_growth_policy(GrowthPolicy::Doubling), 50000, bh);
+}
+
+#[bench]
+fn bench_hamt_find_growth_doubling_copy_50000(bh: &mut Bencher) {
+    bench_hamt_find(CopyStoreHamt::with_growth_policy(GrowthPolicy::Doubling), 50000, bh);
+}
+
+#[bench]
+fn bench_hamt_insert_growth_linear_copy_50000(bh: &mut Bencher) {
+    bench_hamt_insert(CopyStoreHamt::with_growth_policy(GrowthPolicy::Linear), 50000, bh);
+}
+
+#[bench]
+fn bench_hamt_find_growth_linear_copy_50000(bh: &mut Bencher) {
+    bench_hamt_find(CopyStoreHamt::with_growth_policy(GrowthPolicy::Linear), 50000, bh);
+}
+
+#[bench]
+fn bench_hamt_insert_growth_fixed_4_copy_50000(bh: &mut Bencher) {
+    bench_hamt_insert(CopyStoreHamt::with_growth_policy(GrowthPolicy::Fixed(4)), 50000, bh);
+}
+
+#[bench]
+fn bench_hamt_find_growth_fixed_4_copy_50000(bh: &mut Bencher) {
+    bench_hamt_find(CopyStoreHamt::with_growth_policy(GrowthPolicy::Fixed(4)), 50000, bh);
+}
+
+#[bench]
+fn bench_hamt_insert_growth_fixed_32_copy_50000(bh: &mut Bencher) {
+    bench_hamt_insert(CopyStoreHamt::with_growth_policy(GrowthPolicy::Fixed(32)), 50000, bh);
+}
+
+#[bench]
+fn bench_hamt_find_growth_fixed_32_copy_50000(bh: &mut Bencher) {
+    bench_hamt_find(CopyStoreHamt::with_growth_policy(GrowthPolicy::Fixed(32)), 50000, bh);
+}
+
+#[bench]
+fn bench_hamt_merge_transient_copy_10000(bh: &mut Bencher) {
+    bench_hamt_merge(10000, true, bh);
+}
+
+#[bench]
+fn bench_hamt_transient_insert_copy_50000(bh: &mut Bencher) {
+    bench_hamt_transient_build(50000, false, bh);
+}
+
+#[bench]
+fn bench_hamt_transient_insert_sorted_copy_50000(bh: &mut Bencher) {
+    bench_hamt_transient_build(50000, true, bh);
+}
+
+#[bench]
+fn bench_hamt_merge_union_copy_10000(bh: &mut Bencher) {
+    bench_hamt_merge(10000, false, bh);
+}
+
 fn bench_hamt_iterate_copy(mut map: CopyStoreHamt,
                        size: usize,
                        bh: &mut Bencher) {
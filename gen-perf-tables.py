@@ -23,11 +23,11 @@ import re
 import math
 
 def extract_time(line):
-  m = re.search("bench:\s*([\d,]*)\s*ns/iter", line)
+  m = re.search(r"bench:\s*([\d,]*)\s*ns/iter", line)
   return m.group(1).replace(",", "")
 
 def extract_key(line):
-  raw = re.match("test\s*(\S*)\s", line).group(1)
+  raw = re.match(r"test\s*(\S*)\s", line).group(1)
   key = ""
   if raw.find("hamt") >= 0:
     key += "hamt-"
@@ -42,13 +42,14 @@ def extract_key(line):
   if raw.find("clone") >= 0:
     key += "clone-"
 
-  key += re.match("\D*(\d*)", raw).group(1)
+  key += re.match(r"\D*(\d*)", raw).group(1)
   return key
 
 def should_ignore(line):
   return ((line.find("share") >= 0) or
           (line.find("remove") >= 0) or
           (line.find("iterate") >= 0) or
+          (re.search(r"test\s*(bench_)?(hamt|std_hashmap)_(find|insert|clone)_(copy_)?\d+\s", line) is None) or
           (line.find("ns/iter") < 0))
 
 # Parse the timings
@@ -0,0 +1,157 @@
+// Copyright (c) 2013, 2014, 2015, 2016 Michael Woerister
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! A C API for persistent maps from `u64` to `u64`, available with the `ffi` feature.
+//!
+//! Maps are passed around as opaque handles. Since maps are persistent, modifying functions do
+//! not change the map they are given but return a handle to a new map, which shares most of its
+//! memory with the old one. Every handle has to be released with `hamt_u64_destroy()` exactly
+//! once.
+
+use libc::c_void;
+use std::ptr;
+
+use hamt::HamtMap;
+use item_store::CopyStore;
+
+type Map = HamtMap<u64, u64, CopyStore<u64, u64>>;
+
+// Handles are the pointers created by `HamtMap::into_raw()`, which point to a boxed map.
+unsafe fn borrow<'a>(map: *const c_void) -> &'a Map {
+    debug_assert!(!map.is_null());
+    &*(map as *const Map)
+}
+
+fn into_handle(map: Map) -> *mut c_void {
+    map.into_raw() as *mut c_void
+}
+
+/// Creates a new, empty map.
+#[no_mangle]
+pub extern "C" fn hamt_u64_new() -> *mut c_void {
+    into_handle(HamtMap::new())
+}
+
+/// Releases the given map handle. Other maps sharing memory with it are not affected.
+///
+/// # Safety
+///
+/// `map` must be a handle returned by one of the functions of this module that has not been
+/// destroyed yet. It must not be used anymore afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn hamt_u64_destroy(map: *mut c_void) {
+    debug_assert!(!map.is_null());
+    drop(Map::from_raw(map as *mut ()));
+}
+
+/// Returns the number of entries in the given map.
+///
+/// # Safety
+///
+/// `map` must be a valid, not yet destroyed handle.
+#[no_mangle]
+pub unsafe extern "C" fn hamt_u64_len(map: *const c_void) -> usize {
+    borrow(map).len()
+}
+
+/// Returns a handle to a new map containing the entries of `map` plus the given one, replacing
+/// any previous value for `key`. `map` itself stays valid and unchanged.
+///
+/// # Safety
+///
+/// `map` must be a valid, not yet destroyed handle.
+#[no_mangle]
+pub unsafe extern "C" fn hamt_u64_insert(map: *const c_void, key: u64, value: u64) -> *mut c_void {
+    into_handle(borrow(map).clone().plus(key, value))
+}
+
+/// Returns a handle to a new map containing the entries of `map` except the one for `key`. `map`
+/// itself stays valid and unchanged.
+///
+/// # Safety
+///
+/// `map` must be a valid, not yet destroyed handle.
+#[no_mangle]
+pub unsafe extern "C" fn hamt_u64_remove(map: *const c_void, key: u64) -> *mut c_void {
+    into_handle(borrow(map).clone().minus(&key))
+}
+
+/// Looks up `key` in the given map. Returns true and stores the value in `*value` if the key is
+/// found, returns false and leaves `*value` untouched otherwise. `value` may be null if only the
+/// presence of the key is of interest.
+///
+/// # Safety
+///
+/// `map` must be a valid, not yet destroyed handle and `value` must be null or valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn hamt_u64_find(map: *const c_void, key: u64, value: *mut u64) -> bool {
+    match borrow(map).find(&key) {
+        Some(&found) => {
+            if !value.is_null() {
+                ptr::write(value, found);
+            }
+            true
+        }
+        None => false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_c_api() {
+        unsafe {
+            let empty = hamt_u64_new();
+            assert_eq!(hamt_u64_len(empty), 0);
+
+            let mut map = hamt_u64_insert(empty, 1, 10);
+            for key in 2u64 .. 1000 {
+                let new_map = hamt_u64_insert(map, key, key * 10);
+                hamt_u64_destroy(map);
+                map = new_map;
+            }
+
+            let removed = hamt_u64_remove(map, 1);
+
+            // The maps the modified versions have been created from are unchanged
+            assert_eq!(hamt_u64_len(empty), 0);
+            assert_eq!(hamt_u64_len(map), 999);
+            assert_eq!(hamt_u64_len(removed), 998);
+
+            let mut value = 0;
+            assert!(hamt_u64_find(map, 1, &mut value));
+            assert_eq!(value, 10);
+            assert!(hamt_u64_find(map, 500, &mut value));
+            assert_eq!(value, 5000);
+            assert!(hamt_u64_find(map, 999, ptr::null_mut()));
+
+            value = 7;
+            assert!(!hamt_u64_find(removed, 1, &mut value));
+            assert_eq!(value, 7);
+            assert!(!hamt_u64_find(empty, 1, &mut value));
+
+            hamt_u64_destroy(empty);
+            hamt_u64_destroy(map);
+            hamt_u64_destroy(removed);
+        }
+    }
+}
@@ -26,16 +26,24 @@
 
 
 use std::hash::{Hasher, Hash};
+use std::cmp::{self, Reverse};
+use std::collections::BinaryHeap;
+use std::cell::RefCell;
+use std::convert::{Infallible, TryFrom};
+use std::fmt;
 use std::mem;
+use std::ops::Add;
 use std::ptr;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::slice;
+use std::sync::atomic::{self, AtomicUsize, Ordering};
 use std::default::Default;
 
 use std::sync::Arc;
-use item_store::{ItemStore, ShareStore};
+use item_store::{ItemStore, CopyStore, ShareStore, SharedValue};
 
 use std::collections::hash_map::DefaultHasher as StdHasher;
 use libc;
+use rand::Rng;
 
 
 //=-------------------------------------------------------------------------------------------------
@@ -46,6 +54,9 @@ struct NodeRef<K, V, IS, H> {
     ptr: *mut UnsafeNode<K, V, IS, H>
 }
 
+// The two halves of a node split up by `UnsafeNode::split_at_position()`.
+type NodeRefPair<K, V, IS, H> = (NodeRef<K, V, IS, H>, NodeRef<K, V, IS, H>);
+
 // NodeRef knows if it is the only reference to a given node and can thus safely decide to allow for
 // mutable access to the referenced node. This type indicates whether mutable access could be
 // acquired.
@@ -78,6 +89,11 @@ impl<K, V, IS, H> NodeRef<K, V, IS, H>
         }
     }
 
+    // The number of references to the referenced node, including this one.
+    fn ref_count(&self) -> usize {
+        self.borrow().ref_count.load(Ordering::Acquire)
+    }
+
     // Try to safely gain mutable access to the referenced node. This can be used to safely make
     // in-place modifications instead of unnecessarily copying data.
     fn try_borrow_owned<'a>(&'a mut self) -> BorrowedNodeRef<'a, K, V, IS, H> {
@@ -95,9 +111,12 @@ impl<K, V, IS, H> Drop for NodeRef<K, V, IS, H> {
     fn drop(&mut self) {
         unsafe {
             let node: &mut UnsafeNode<K, V, IS, H> = mem::transmute(self.ptr);
-            let old_count = node.ref_count.fetch_sub(1, Ordering::Acquire);
+            // Same protocol as sync::Arc: the release decrement makes all prior uses of the node
+            // visible to the thread destroying it, which synchronizes via the acquire fence.
+            let old_count = node.ref_count.fetch_sub(1, Ordering::Release);
             debug_assert!(old_count >= 1);
             if old_count == 1 {
+                atomic::fence(Ordering::Acquire);
                 node.destroy();
             }
         }
@@ -116,6 +135,21 @@ impl<K, V, IS, H> Clone for NodeRef<K, V, IS, H> {
     }
 }
 
+// Nodes are never modified while they are shared (see `try_borrow_owned()`) and the reference
+// count is maintained atomically, so node references can be sent to and shared with other threads
+// just like sync::Arc.
+unsafe impl<K, V, IS, H> Send for NodeRef<K, V, IS, H>
+    where K: Send+Sync,
+          V: Send+Sync,
+          IS: Send+Sync
+{}
+
+unsafe impl<K, V, IS, H> Sync for NodeRef<K, V, IS, H>
+    where K: Send+Sync,
+          V: Send+Sync,
+          IS: Send+Sync
+{}
+
 
 
 //=-------------------------------------------------------------------------------------------------
@@ -163,8 +197,13 @@ struct UnsafeNode<K, V, IS, H> {
     entry_types: u64,
     // A mask stating at which local keys (an integer between 0 and 31) an entry is exists.
     mask: u32,
-    // The maximum number of entries this node can store.
-    capacity: u8,
+    // The maximum number of entries this node can store. This is wider than the 32 entries a node
+    // can hold at most with 5 bits per level, so that wider nodes don't silently truncate it.
+    capacity: u16,
+    // The number of items stored in the subtree rooted at this node. Kept up-to-date by
+    // init_entry() and drop_entry(), and by the in-place modifications of sub-trees. Maintaining
+    // it makes insertions and removals about 5-30% slower.
+    subtree_size: usize,
     // An artificial field ensuring the correct alignment of entries behind this header.
     __entries: [AlignmentStruct<K, V, IS, H>; 0],
 }
@@ -231,6 +270,111 @@ enum RemovalResult<K, V, IS, H> {
     KillSubTree
 }
 
+// Selects the item to be removed from the tree.
+enum RemovalTarget<'a, K: 'a> {
+    // The item with the given key. The hash passed down the tree must be the key's hash value
+    Key(&'a K),
+    // The first item found by traversal, i.e. the item in the lowest slot on every level. The
+    // hash passed down the tree is ignored
+    First
+}
+
+impl<'a, K> Clone for RemovalTarget<'a, K> {
+    fn clone(&self) -> RemovalTarget<'a, K> {
+        *self
+    }
+}
+
+impl<'a, K> Copy for RemovalTarget<'a, K> {}
+
+impl<'a, K: Eq> RemovalTarget<'a, K> {
+    // Returns the local key of the slot to descend into, or `None` if the node doesn't hold the
+    // target.
+    fn local_key(&self, hash: u64, mask: u32) -> Option<usize> {
+        let local_key = match *self {
+            RemovalTarget::Key(_) => (hash & LEVEL_BIT_MASK) as usize,
+            RemovalTarget::First if mask != 0 => mask.trailing_zeros() as usize,
+            RemovalTarget::First => return None,
+        };
+
+        if (mask & (1 << local_key)) != 0 { Some(local_key) } else { None }
+    }
+
+    fn matches(&self, key: &K) -> bool {
+        match *self {
+            RemovalTarget::Key(target) => *key == *target,
+            RemovalTarget::First => true,
+        }
+    }
+}
+
+// Where a descent along a hash value ended, see `UnsafeNode::try_descend()`.
+struct Descent<'b, K: 'b, V: 'b, IS: 'b, H: 'b> {
+    // The deepest node reached
+    node: &'b UnsafeNode<K, V, IS, H>,
+    // The entry for the hash value in that node, which is never a sub-tree, or `None` if the node
+    // has no entry for the hash value
+    entry: Option<NodeEntryRef<'b, K, V, IS, H>>,
+    // The number of nodes visited, including the one the descent started at
+    steps: usize,
+}
+
+impl<'b, K: Eq, V, IS: ItemStore<K, V>, H> Descent<'b, K, V, IS, H> {
+    // Returns the item with the given key, if the entry the descent ended at contains it.
+    fn find(&self, key: &K) -> Option<&'b IS> {
+        match self.entry {
+            Some(NodeEntryRef::Item(kvp_ref)) if *kvp_ref.key() == *key => Some(kvp_ref),
+            Some(NodeEntryRef::Collision(items)) => items.iter().find(|&kvp| *key == *kvp.key()),
+            _ => None,
+        }
+    }
+}
+
+// The settings of a map that are passed down the tree when inserting an item.
+struct InsertContext<'a, V: 'a> {
+    // Determines the capacity of nodes that need to grow
+    growth: GrowthPolicy,
+    // Notified about every node on the path being copied or modified in-place
+    observer: Option<&'a dyn StructuralObserver>,
+    // Determines the new item if the key is already stored in a collision entry
+    collision: &'a CollisionPolicy<V>,
+    // Items whose local keys are still equal on this level go into a collision entry instead of
+    // a new sub-tree
+    last_level: usize,
+    // If set, combines the existing and the new value of a key that is already present, instead
+    // of replacing the value or applying the collision policy
+    combine: Option<CombineRef<'a, V>>,
+    // Whether an item replaced by the insertion is handed back to the caller. Only then is a
+    // replaced item cloned out of a node that is shared with another map
+    hand_back_replaced: bool,
+}
+
+impl<'a, V> Clone for InsertContext<'a, V> {
+    fn clone(&self) -> InsertContext<'a, V> {
+        *self
+    }
+}
+
+impl<'a, V> Copy for InsertContext<'a, V> {}
+
+impl<'a, V> InsertContext<'a, V> {
+    // Returns the item to store for a key that is already stored in a single-item entry.
+    fn resolve_item<K, IS: ItemStore<K, V>>(&self, existing_kvp: &IS, new_kvp: IS) -> IS {
+        match self.combine {
+            Some(combine) => combine_items(combine, existing_kvp, new_kvp),
+            None => new_kvp,
+        }
+    }
+
+    // Returns the item to store for a key that is already stored in a collision entry.
+    fn resolve_collision<K, IS: ItemStore<K, V>>(&self, existing_kvp: &IS, new_kvp: IS) -> IS {
+        match self.combine {
+            Some(combine) => combine_items(combine, existing_kvp, new_kvp),
+            None => self.collision.resolve(existing_kvp, new_kvp),
+        }
+    }
+}
+
 // impl UnsafeNode
 impl<'a, K, V, IS, H> UnsafeNode<K, V, IS, H>
     where K: 'a,
@@ -304,12 +448,15 @@ impl<'a, K, V, IS, H> UnsafeNode<K, V, IS, H>
                 NodeEntryOwned::Item(kvp) => {
                     ptr::write(mem::transmute(entry_ptr), kvp);
                     self.set_entry_type_code(index, KVP_ENTRY);
+                    self.subtree_size += 1;
                 }
                 NodeEntryOwned::SubTree(node_ref) => {
+                    self.subtree_size += (*node_ref.ptr).subtree_size;
                     ptr::write(mem::transmute(entry_ptr), node_ref);
                     self.set_entry_type_code(index, SUBTREE_ENTRY);
                 }
                 NodeEntryOwned::Collision(arc) => {
+                    self.subtree_size += arc.len();
                     ptr::write(mem::transmute(entry_ptr), arc);
                     self.set_entry_type_code(index, COLLISION_ENTRY);
                 }
@@ -333,6 +480,13 @@ impl<'a, K, V, IS, H> UnsafeNode<K, V, IS, H>
         )
     }
 
+    // The number of bytes allocated for a node with the given capacity, including the header.
+    fn alloc_size(capacity: usize) -> usize {
+        let align = mem::align_of::<AlignmentStruct<K, V, IS, H>>();
+        let header_size = align_to(mem::size_of::<UnsafeNode<K, V, IS, H>>(), align);
+        header_size + capacity * UnsafeNode::<K, V, IS, H>::node_entry_size()
+    }
+
     // Allocates a new node instance with the given mask and capacity. The memory for the node is
     // allocated from the exchange heap. The capacity of the node is fixed from here on after.
     // The entries (including the entry_types bitfield) is not initialized by this call. Entries
@@ -345,6 +499,7 @@ impl<'a, K, V, IS, H> UnsafeNode<K, V, IS, H>
                 entry_types: 0,
                 mask: 0,
                 capacity: 0,
+                subtree_size: 0,
                 __entries: [],
             };
 
@@ -354,16 +509,17 @@ impl<'a, K, V, IS, H> UnsafeNode<K, V, IS, H>
         let align = mem::align_of::<AlignmentStruct<K, V, IS, H>>();
         let entry_count = bit_count(mask);
         debug_assert!(entry_count <= capacity);
+        assert!(capacity <= u16::MAX as usize);
 
-        let header_size = align_to(mem::size_of::<UnsafeNode<K, V, IS, H>>(), align);
-        let node_size = header_size + capacity * UnsafeNode::<K, V, IS, H>::node_entry_size();
+        let node_size = UnsafeNode::<K, V, IS, H>::alloc_size(capacity);
 
         unsafe {
             let node_ptr: *mut UnsafeNode<K, V, IS, H> = mem::transmute(allocate(node_size, align));
             ptr::write(&mut (*node_ptr).ref_count, AtomicUsize::new(1));
             ptr::write(&mut (*node_ptr).entry_types, 0);
             ptr::write(&mut (*node_ptr).mask, mask);
-            ptr::write(&mut (*node_ptr).capacity, capacity as u8);
+            ptr::write(&mut (*node_ptr).capacity, capacity as u16);
+            ptr::write(&mut (*node_ptr).subtree_size, 0);
             NodeRef { ptr: node_ptr }
         }
     }
@@ -380,27 +536,66 @@ impl<'a, K, V, IS, H> UnsafeNode<K, V, IS, H>
             // build on 'stable':
 
             let align = mem::align_of::<AlignmentStruct<K, V, IS, H>>();
-            let header_size = align_to(mem::size_of::<UnsafeNode<K, V, IS, H>>(), align);
-            let node_size = header_size + (self.capacity as usize) * UnsafeNode::<K, V, IS, H>::node_entry_size();
+            let node_size = UnsafeNode::<K, V, IS, H>::alloc_size(self.capacity as usize);
             deallocate(mem::transmute(self), node_size, align);
         }
     }
 
     // Drops a single entry. Does not modify the entry_types or mask field of the node, just calls
-    // the destructor of the entry at the given index.
+    // the destructor of the entry at the given index and updates the subtree size.
     unsafe fn drop_entry(&mut self, index: usize) {
-        // destroy the contained object, trick from Rc
-        match self.get_entry_mut(index) {
-            NodeEntryMutRef::Item(item_ref) => {
-                let _ = ptr::read(item_ref as *mut IS as *const IS);
-            }
-            NodeEntryMutRef::Collision(item_ref) => {
-                let _ = ptr::read(item_ref as *mut Arc<Vec<IS>> as *const Arc<Vec<IS>>);
+        drop(self.take_entry(index));
+    }
+
+    // Moves a single entry out of the node. As with `drop_entry()`, only the subtree size is
+    // updated, the slot itself has to be overwritten or removed from the node by the caller.
+    unsafe fn take_entry(&mut self, index: usize) -> NodeEntryOwned<K, V, IS, H> {
+        let entry_ptr = self.get_entry_ptr(index);
+
+        let (entry, removed_items) = match self.get_entry_type_code(index) {
+            KVP_ENTRY => (NodeEntryOwned::Item(ptr::read(entry_ptr as *const IS)), 1),
+            SUBTREE_ENTRY => {
+                let sub_tree = ptr::read(entry_ptr as *const NodeRef<K, V, IS, H>);
+                let item_count = (*sub_tree.ptr).subtree_size;
+                (NodeEntryOwned::SubTree(sub_tree), item_count)
             }
-            NodeEntryMutRef::SubTree(item_ref) => {
-                let _ = ptr::read(item_ref as *mut NodeRef<K, V, IS, H> as *const NodeRef<K, V, IS, H>);
+            COLLISION_ENTRY => {
+                let items = ptr::read(entry_ptr as *const Arc<Vec<IS>>);
+                let item_count = items.len();
+                (NodeEntryOwned::Collision(items), item_count)
             }
+            _ => panic!("Invalid entry type code")
+        };
+
+        self.subtree_size -= removed_items;
+        entry
+    }
+
+    // Moves all entries out of this node and appends them to `entries`, in reverse order. The node
+    // is left without any entries, so destroying it afterwards will only free its memory.
+    fn take_entries(&mut self, entries: &mut Vec<NodeEntryOwned<K, V, IS, H>>) {
+        for index in (0 .. self.entry_count()).rev() {
+            let entry_ptr = self.get_entry_ptr(index);
+
+            let entry = unsafe {
+                match self.get_entry_type_code(index) {
+                    KVP_ENTRY => NodeEntryOwned::Item(ptr::read(entry_ptr as *const IS)),
+                    SUBTREE_ENTRY => {
+                        NodeEntryOwned::SubTree(ptr::read(entry_ptr as *const NodeRef<K, V, IS, H>))
+                    }
+                    COLLISION_ENTRY => {
+                        NodeEntryOwned::Collision(ptr::read(entry_ptr as *const Arc<Vec<IS>>))
+                    }
+                    _ => panic!("Invalid entry type code")
+                }
+            };
+
+            entries.push(entry);
         }
+
+        self.mask = 0;
+        self.entry_types = 0;
+        self.subtree_size = 0;
     }
 }
 
@@ -424,18 +619,28 @@ impl<K, V, IS, H> UnsafeNode<K, V, IS, H>
               // The number of newly inserted items. Must be set to either 0 (if an existing item is
               // replaced) or 1 (if there was not item with the given key yet). Used to keep track
               // of the trees total item count
-              insertion_count: &mut usize)
+              insertion_count: &mut usize,
+              // Receives the replaced item if `context.hand_back_replaced` is set
+              replaced: &mut Option<IS>,
+              // The map's settings affecting the insertion
+              context: InsertContext<V>)
               // Reference to the new tree containing the inserted element
            -> NodeRef<K, V, IS, H> {
 
         debug_assert!(level <= LAST_LEVEL);
+        if let Some(observer) = context.observer {
+            observer.on_copy(level);
+        }
+
         let local_key = (hash & LEVEL_BIT_MASK) as usize;
 
         // See if the slot is free
         if (self.mask & (1 << local_key)) == 0 {
             // If yes, then fill it with a single-item entry
             *insertion_count = 1;
-            let new_node = self.copy_with_new_entry(local_key, NodeEntryOwned::Item(new_kvp));
+            let new_node = self.copy_with_new_entry(local_key,
+                                                    NodeEntryOwned::Item(new_kvp),
+                                                    context.growth);
             return new_node;
         }
 
@@ -448,8 +653,12 @@ impl<K, V, IS, H> UnsafeNode<K, V, IS, H>
                 if *existing_key == *new_kvp.key() {
                     *insertion_count = 0;
                     // Replace entry for the given key
-                    self.copy_with_new_entry(local_key, NodeEntryOwned::Item(new_kvp))
-                } else if level != LAST_LEVEL {
+                    let new_kvp = context.resolve_item(existing_kvp_ref, new_kvp);
+                    if context.hand_back_replaced {
+                        *replaced = Some(existing_kvp_ref.clone());
+                    }
+                    self.copy_with_new_entry(local_key, NodeEntryOwned::Item(new_kvp), context.growth)
+                } else if level < context.last_level {
                     *insertion_count = 1;
                     // There already is an entry with different key but same hash value, so push
                     // everything down one level:
@@ -463,21 +672,27 @@ impl<K, V, IS, H> UnsafeNode<K, V, IS, H>
                                                                     new_hash,
                                                                     existing_kvp_ref,
                                                                     existing_hash,
-                                                                    level + 1);
+                                                                    level + 1,
+                                                                    context.growth,
+                                                                    context.last_level);
 
                     // 3. return a copy of this node with the single-item entry replaced by the new
                     // subtree entry
-                    self.copy_with_new_entry(local_key, NodeEntryOwned::SubTree(new_sub_tree))
+                    self.copy_with_new_entry(local_key,
+                                             NodeEntryOwned::SubTree(new_sub_tree),
+                                             context.growth)
                 } else {
                     *insertion_count = 1;
-                    // If we have already exhausted all bits from the hash value, put everything in
-                    // collision node
+                    // If we have already exhausted all bits from the hash value (or all levels the
+                    // map may use), put everything in collision node
                     let items = vec!(new_kvp, existing_kvp_ref.clone());
-                    self.copy_with_new_entry(local_key, NodeEntryOwned::Collision(Arc::new(items)))
+                    self.copy_with_new_entry(local_key,
+                                             NodeEntryOwned::Collision(Arc::new(items)),
+                                             context.growth)
                 }
             }
             NodeEntryRef::Collision(items_arc) => {
-                debug_assert!(level == LAST_LEVEL);
+                debug_assert!(level == context.last_level);
                 let items = &*items_arc;
                 let position = items.iter().position(|kvp2| *kvp2.key() == *new_kvp.key());
 
@@ -500,7 +715,10 @@ impl<K, V, IS, H> UnsafeNode<K, V, IS, H>
                             new_items.extend(items.iter().take(position).cloned());
                         }
 
-                        new_items.push(new_kvp);
+                        new_items.push(context.resolve_collision(&items[position], new_kvp));
+                        if context.hand_back_replaced {
+                            *replaced = Some(items[position].clone());
+                        }
 
                         if position < item_count - 1 {
                            new_items.extend(items.iter().skip(position + 1).cloned());
@@ -511,15 +729,19 @@ impl<K, V, IS, H> UnsafeNode<K, V, IS, H>
                     }
                 };
 
-                self.copy_with_new_entry(local_key, NodeEntryOwned::Collision(Arc::new(new_items)))
+                self.copy_with_new_entry(local_key,
+                                         NodeEntryOwned::Collision(Arc::new(new_items)),
+                                         context.growth)
             }
             NodeEntryRef::SubTree(sub_tree_ref) => {
                 let new_sub_tree = sub_tree_ref.borrow().insert(hash >> BITS_PER_LEVEL,
                                                                 level + 1,
                                                                 new_kvp,
-                                                                insertion_count);
+                                                                insertion_count,
+                                                                replaced,
+                                                                context);
 
-                self.copy_with_new_entry(local_key, NodeEntryOwned::SubTree(new_sub_tree))
+                self.copy_with_new_entry(local_key, NodeEntryOwned::SubTree(new_sub_tree), context.growth)
             }
         }
     }
@@ -534,7 +756,9 @@ impl<K, V, IS, H> UnsafeNode<K, V, IS, H>
                            hash: u64,
                            level: usize,
                            new_kvp: IS,
-                           insertion_count: &mut usize)
+                           insertion_count: &mut usize,
+                           replaced: &mut Option<IS>,
+                           context: InsertContext<V>)
                         -> Option<NodeRef<K, V, IS, H>> {
 
         debug_assert!(level <= LAST_LEVEL);
@@ -546,30 +770,39 @@ impl<K, V, IS, H> UnsafeNode<K, V, IS, H>
                 // If yes, then fill it with a single-item entry
                 *insertion_count = 1;
                 self.insert_entry_in_place(local_key, NodeEntryOwned::Item(new_kvp));
+                if let Some(observer) = context.observer {
+                    observer.on_reuse(level);
+                }
                 return None;
             } else {
                 // else fall back to copying
-                return Some(self.insert(hash, level, new_kvp, insertion_count));
+                return Some(self.insert(hash,
+                                        level,
+                                        new_kvp,
+                                        insertion_count,
+                                        replaced,
+                                        context));
             }
         }
 
+        // The slot is taken, so whatever the new entry will be, it replaces the existing one and
+        // doesn't need any additional space
         let index = get_index(self.mask, local_key);
 
-        // If there is no space left in this node but we would need it, again fall back to copying
-        if self.entry_count() == self.capacity as usize &&
-           self.get_entry_type_code(index) != SUBTREE_ENTRY {
-            return Some(self.insert(hash, level, new_kvp, insertion_count));
-        }
-
         let new_entry = match self.get_entry_mut(index) {
             NodeEntryMutRef::Item(existing_kvp_ref) => {
                 let existing_key = existing_kvp_ref.key();
 
                 if *existing_key == *new_kvp.key() {
                     *insertion_count = 0;
-                    // Replace entry for the given key
-                    Some(NodeEntryOwned::Item(new_kvp))
-                } else if level != LAST_LEVEL {
+                    // Replace the item for the given key, moving the existing one out
+                    let new_kvp = context.resolve_item(existing_kvp_ref, new_kvp);
+                    let existing_kvp = mem::replace(existing_kvp_ref, new_kvp);
+                    if context.hand_back_replaced {
+                        *replaced = Some(existing_kvp);
+                    }
+                    None
+                } else if level < context.last_level {
                     *insertion_count = 1;
                     // There already is an entry with different key but same hash value, so push
                     // everything down one level:
@@ -583,21 +816,23 @@ impl<K, V, IS, H> UnsafeNode<K, V, IS, H>
                                                                     new_hash,
                                                                     existing_kvp_ref,
                                                                     existing_hash,
-                                                                    level + 1);
+                                                                    level + 1,
+                                                                    context.growth,
+                                                                    context.last_level);
 
                     // 3. replace the ItemEntryRef entry with the subtree entry
                     Some(NodeEntryOwned::SubTree(new_sub_tree))
                 } else {
                     *insertion_count = 1;
-                    // If we have already exhausted all bits from the hash value, put everything in
-                    // collision node
+                    // If we have already exhausted all bits from the hash value (or all levels the
+                    // map may use), put everything in collision node
                     let items = vec!(new_kvp, existing_kvp_ref.clone());
                     let collision_entry = NodeEntryOwned::Collision(Arc::new(items));
                     Some(collision_entry)
                 }
             }
             NodeEntryMutRef::Collision(items) => {
-                debug_assert!(level == LAST_LEVEL);
+                debug_assert!(level == context.last_level);
                 let position = items.iter().position(|kvp2| *kvp2.key() == *new_kvp.key());
 
                 let new_items = match position {
@@ -619,7 +854,10 @@ impl<K, V, IS, H> UnsafeNode<K, V, IS, H>
                             new_items.extend(items.iter().take(position).cloned());
                         }
 
-                        new_items.push(new_kvp);
+                        new_items.push(context.resolve_collision(&items[position], new_kvp));
+                        if context.hand_back_replaced {
+                            *replaced = Some(items[position].clone());
+                        }
 
                         if position < item_count - 1 {
                            new_items.extend(items.iter().skip(position + 1).cloned());
@@ -638,13 +876,17 @@ impl<K, V, IS, H> UnsafeNode<K, V, IS, H>
                         Some(NodeEntryOwned::SubTree(subtree.insert(hash >> BITS_PER_LEVEL,
                                                level + 1,
                                                new_kvp,
-                                               insertion_count)))
+                                               insertion_count,
+                                               replaced,
+                                               context)))
                     }
                     BorrowedNodeRef::Exclusive(subtree) => {
                         match subtree.try_insert_in_place(hash >> BITS_PER_LEVEL,
                                                           level + 1,
-                                                          new_kvp.clone(),
-                                                          insertion_count) {
+                                                          new_kvp,
+                                                          insertion_count,
+                                                          replaced,
+                                                          context) {
                             Some(new_sub_tree) => Some(NodeEntryOwned::SubTree(new_sub_tree)),
                             None => None
                         }
@@ -658,54 +900,57 @@ impl<K, V, IS, H> UnsafeNode<K, V, IS, H>
                 self.insert_entry_in_place(local_key, e);
             }
             None => {
-                /* No new entry to be inserted, because the subtree could be modified in-place */
+                /* No new entry to be inserted, because the item or the subtree could be modified
+                   in-place */
+                self.subtree_size += *insertion_count;
             }
         }
 
+        if let Some(observer) = context.observer {
+            observer.on_reuse(level);
+        }
+
         return None;
     }
 
     // Remove the item with the given key from the tree. Parameters correspond to this of
-    // `insert()`. The result tells the call (the parent level in the tree) what it should do.
+    // `insert()`, except that `target` selects the item to remove. The result tells the call (the
+    // parent level in the tree) what it should do. The removed item is handed back through
+    // `removed`, which is left untouched if there is no such item.
     fn remove(&self,
               hash: u64,
               level: usize,
-              key: &K,
-              removal_count: &mut usize)
+              target: RemovalTarget<K>,
+              removed: &mut Option<IS>,
+              growth: GrowthPolicy,
+              observer: Option<&dyn StructuralObserver>)
            -> RemovalResult<K, V, IS, H> {
 
         debug_assert!(level <= LAST_LEVEL);
-        let local_key = (hash & LEVEL_BIT_MASK) as usize;
-
-        if (self.mask & (1 << local_key)) == 0 {
-            *removal_count = 0;
-            return RemovalResult::NoChange;
-        }
+        let local_key = match target.local_key(hash, self.mask) {
+            Some(local_key) => local_key,
+            None => return RemovalResult::NoChange
+        };
 
         let index = get_index(self.mask, local_key);
 
-        match self.get_entry(index) {
+        let result = match self.get_entry(index) {
             NodeEntryRef::Item(existing_kvp_ref) => {
-                if *existing_kvp_ref.key() == *key {
-                    *removal_count = 1;
-                    self.collapse_kill_or_change(local_key, index)
+                if target.matches(existing_kvp_ref.key()) {
+                    *removed = Some(existing_kvp_ref.clone());
+                    self.collapse_kill_or_change(local_key, index, growth)
                 } else {
-                    *removal_count = 0;
                     RemovalResult::NoChange
                 }
             }
             NodeEntryRef::Collision(items_arc) => {
-                debug_assert!(level == LAST_LEVEL);
                 let items = &*items_arc;
-                let position = items.iter().position(|kvp| *kvp.key() == *key);
+                let position = items.iter().position(|kvp| target.matches(kvp.key()));
 
                 match position {
-                    None => {
-                        *removal_count = 0;
-                        RemovalResult::NoChange
-                    },
+                    None => RemovalResult::NoChange,
                     Some(position) => {
-                        *removal_count = 1;
+                        *removed = Some(items[position].clone());
                         let item_count = items.len() - 1;
 
                         // The new entry can either still be a collision node, or it can be a simple
@@ -716,7 +961,7 @@ impl<K, V, IS, H> UnsafeNode<K, V, IS, H>
                             if position > 0 {
                                 new_items.extend(items.iter().take(position).cloned());
                             }
-                            if position < item_count - 1 {
+                            if position < item_count {
                                 new_items.extend(items.iter().skip(position + 1).cloned());
                             }
                             debug_assert!(new_items.len() == item_count);
@@ -731,7 +976,7 @@ impl<K, V, IS, H> UnsafeNode<K, V, IS, H>
                             NodeEntryOwned::Item(kvp)
                         };
 
-                        let new_sub_tree = self.copy_with_new_entry(local_key, new_entry);
+                        let new_sub_tree = self.copy_with_new_entry(local_key, new_entry, growth);
                         RemovalResult::ReplaceSubTree(new_sub_tree)
                     }
                 }
@@ -739,14 +984,17 @@ impl<K, V, IS, H> UnsafeNode<K, V, IS, H>
             NodeEntryRef::SubTree(sub_tree_ref) => {
                 let result = sub_tree_ref.borrow().remove(hash >> BITS_PER_LEVEL,
                                                           level + 1,
-                                                          key,
-                                                          removal_count);
+                                                          target,
+                                                          removed,
+                                                          growth,
+                                                          observer);
                 match result {
                     RemovalResult::NoChange => RemovalResult::NoChange,
                     RemovalResult::ReplaceSubTree(x) => {
                         RemovalResult::ReplaceSubTree(
                             self.copy_with_new_entry(local_key,
-                                                     NodeEntryOwned::SubTree(x)))
+                                                     NodeEntryOwned::SubTree(x),
+                                                     growth))
                     }
                     RemovalResult::CollapseSubTree(kvp) => {
                         if bit_count(self.mask) == 1 {
@@ -754,15 +1002,23 @@ impl<K, V, IS, H> UnsafeNode<K, V, IS, H>
                         } else {
                             RemovalResult::ReplaceSubTree(
                                 self.copy_with_new_entry(local_key,
-                                                         NodeEntryOwned::Item(kvp)))
+                                                         NodeEntryOwned::Item(kvp),
+                                                         growth))
                         }
                     },
                     RemovalResult::KillSubTree => {
-                        self.collapse_kill_or_change(local_key, index)
+                        self.collapse_kill_or_change(local_key, index, growth)
                     }
                 }
             }
+        };
+
+        // Unless it is left unchanged or dropped altogether, this node is replaced by a copy
+        if let (Some(observer), &RemovalResult::ReplaceSubTree(_)) = (observer, &result) {
+            observer.on_copy(level);
         }
+
+        result
     }
 
     // Same as `remove()` but will do the modification in-place. As with `try_insert_in_place()` we
@@ -771,17 +1027,17 @@ impl<K, V, IS, H> UnsafeNode<K, V, IS, H>
     fn remove_in_place(&mut self,
                        hash: u64,
                        level: usize,
-                       key: &K,
-                       removal_count: &mut usize)
+                       target: RemovalTarget<K>,
+                       removed: &mut Option<IS>,
+                       growth: GrowthPolicy,
+                       observer: Option<&dyn StructuralObserver>)
                     -> RemovalResult<K, V, IS, H> {
         debug_assert!(level <= LAST_LEVEL);
-        let local_key = (hash & LEVEL_BIT_MASK) as usize;
         let mask = self.mask;
-
-        if (mask & (1 << local_key)) == 0 {
-            *removal_count = 0;
-            return RemovalResult::NoChange;
-        }
+        let local_key = match target.local_key(hash, mask) {
+            Some(local_key) => local_key,
+            None => return RemovalResult::NoChange
+        };
 
         let index = get_index(mask, local_key);
 
@@ -793,25 +1049,19 @@ impl<K, V, IS, H> UnsafeNode<K, V, IS, H>
 
         let action: Action<K, V, IS, H> = match self.get_entry_mut(index) {
             NodeEntryMutRef::Item(existing_kvp_ref) => {
-                if *existing_kvp_ref.key() == *key {
-                    *removal_count = 1;
+                if target.matches(existing_kvp_ref.key()) {
                     Action::CollapseKillOrChange
                 } else {
-                    *removal_count = 0;
                     Action::NoAction
                 }
             }
             NodeEntryMutRef::Collision(items) => {
-                debug_assert!(level == LAST_LEVEL);
-                let position = items.iter().position(|kvp| *kvp.key() == *key);
+                let position = items.iter().position(|kvp| target.matches(kvp.key()));
 
                 match position {
-                    None => {
-                        *removal_count = 0;
-                        Action::NoAction
-                    },
+                    None => Action::NoAction,
                     Some(position) => {
-                        *removal_count = 1;
+                        *removed = Some((**items)[position].clone());
                         let item_count = items.len() - 1;
 
                         // The new entry can either still be a collision node, or it can be a simple
@@ -822,7 +1072,7 @@ impl<K, V, IS, H> UnsafeNode<K, V, IS, H>
                             if position > 0 {
                                 new_items.extend(items.iter().take(position).cloned());
                             }
-                            if position < item_count - 1 {
+                            if position < item_count {
                                 new_items.extend(items.iter().skip(position + 1).cloned());
                             }
                             debug_assert!(new_items.len() == item_count);
@@ -845,12 +1095,16 @@ impl<K, V, IS, H> UnsafeNode<K, V, IS, H>
                 let result = match sub_tree_ref.try_borrow_owned() {
                     BorrowedNodeRef::Shared(node_ref) => node_ref.remove(hash >> BITS_PER_LEVEL,
                                                             level + 1,
-                                                            key,
-                                                            removal_count),
+                                                            target,
+                                                            removed,
+                                                            growth,
+                                                            observer),
                     BorrowedNodeRef::Exclusive(node_ref) => node_ref.remove_in_place(hash >> BITS_PER_LEVEL,
                                                                     level + 1,
-                                                                    key,
-                                                                    removal_count)
+                                                                    target,
+                                                                    removed,
+                                                                    growth,
+                                                                    observer)
                 };
 
                 match result {
@@ -872,9 +1126,21 @@ impl<K, V, IS, H> UnsafeNode<K, V, IS, H>
             }
         };
 
+        if removed.is_some() || matches!(action, Action::CollapseKillOrChange) {
+            if let Some(observer) = observer {
+                observer.on_reuse(level);
+            }
+        }
+
         match action {
-            Action::NoAction => RemovalResult::NoChange,
-            Action::CollapseKillOrChange => self.collapse_kill_or_change_in_place(local_key, index),
+            Action::NoAction => {
+                // Either nothing has been removed, or the sub-tree has been modified in-place
+                self.subtree_size -= removed.is_some() as usize;
+                RemovalResult::NoChange
+            }
+            Action::CollapseKillOrChange => {
+                self.collapse_kill_or_change_in_place(local_key, index, removed)
+            }
             Action::ReplaceEntry(new_entry) => {
                 self.insert_entry_in_place(local_key, new_entry);
                 RemovalResult::NoChange
@@ -882,16 +1148,141 @@ impl<K, V, IS, H> UnsafeNode<K, V, IS, H>
         }
     }
 
+    // Replace the item with the given key by the result of applying `f` to it. The existing tree
+    // is not modified; only the path down to the item is copied. Returns `None` if there is no
+    // item with the given key, in which case `f` is never called.
+    fn modify<F>(&self,
+                 hash: u64,
+                 level: usize,
+                 key: &K,
+                 f: &mut F,
+                 growth: GrowthPolicy)
+              -> Option<NodeRef<K, V, IS, H>>
+        where F: FnMut(&IS) -> IS
+    {
+        debug_assert!(level <= LAST_LEVEL);
+        let local_key = (hash & LEVEL_BIT_MASK) as usize;
+
+        if (self.mask & (1 << local_key)) == 0 {
+            return None;
+        }
+
+        let index = get_index(self.mask, local_key);
+
+        let new_entry = match self.get_entry(index) {
+            NodeEntryRef::Item(existing_kvp_ref) => {
+                if *existing_kvp_ref.key() != *key {
+                    return None;
+                }
+
+                NodeEntryOwned::Item(f(existing_kvp_ref))
+            }
+            NodeEntryRef::Collision(items_arc) => {
+                let position = items_arc.iter().position(|kvp| *kvp.key() == *key)?;
+
+                let mut new_items = Vec::clone(items_arc);
+                new_items[position] = f(&items_arc[position]);
+                NodeEntryOwned::Collision(Arc::new(new_items))
+            }
+            NodeEntryRef::SubTree(sub_tree_ref) => {
+                let new_sub_tree = sub_tree_ref.borrow().modify(hash >> BITS_PER_LEVEL,
+                                                                level + 1,
+                                                                key,
+                                                                f,
+                                                                growth)?;
+                NodeEntryOwned::SubTree(new_sub_tree)
+            }
+        };
+
+        Some(self.copy_with_new_entry(local_key, new_entry, growth))
+    }
+
+    // Same as `modify()` but will do the modification in-place. Since only existing entries are
+    // replaced this never needs additional capacity. Returns false if there is no item with the
+    // given key.
+    fn modify_in_place<F>(&mut self,
+                          hash: u64,
+                          level: usize,
+                          key: &K,
+                          f: &mut F,
+                          growth: GrowthPolicy)
+                       -> bool
+        where F: FnMut(&IS) -> IS
+    {
+        debug_assert!(level <= LAST_LEVEL);
+        let local_key = (hash & LEVEL_BIT_MASK) as usize;
+
+        if (self.mask & (1 << local_key)) == 0 {
+            return false;
+        }
+
+        let index = get_index(self.mask, local_key);
+
+        let new_entry = match self.get_entry_mut(index) {
+            NodeEntryMutRef::Item(existing_kvp_ref) => {
+                if *existing_kvp_ref.key() != *key {
+                    return false;
+                }
+
+                *existing_kvp_ref = f(existing_kvp_ref);
+                None
+            }
+            NodeEntryMutRef::Collision(items_arc) => {
+                let position = match items_arc.iter().position(|kvp| *kvp.key() == *key) {
+                    Some(position) => position,
+                    None => return false
+                };
+
+                // The item vector can only be modified if no other node references it
+                match Arc::get_mut(items_arc) {
+                    Some(items) => {
+                        items[position] = f(&items[position]);
+                        None
+                    }
+                    None => {
+                        let mut new_items = Vec::clone(&**items_arc);
+                        new_items[position] = f(&items_arc[position]);
+                        Some(NodeEntryOwned::Collision(Arc::new(new_items)))
+                    }
+                }
+            }
+            NodeEntryMutRef::SubTree(sub_tree_ref) => {
+                match sub_tree_ref.try_borrow_owned() {
+                    BorrowedNodeRef::Exclusive(sub_tree) => {
+                        return sub_tree.modify_in_place(hash >> BITS_PER_LEVEL,
+                                                        level + 1,
+                                                        key,
+                                                        f,
+                                                        growth);
+                    }
+                    BorrowedNodeRef::Shared(sub_tree) => {
+                        match sub_tree.modify(hash >> BITS_PER_LEVEL, level + 1, key, f, growth) {
+                            Some(new_sub_tree) => Some(NodeEntryOwned::SubTree(new_sub_tree)),
+                            None => return false
+                        }
+                    }
+                }
+            }
+        };
+
+        if let Some(new_entry) = new_entry {
+            self.insert_entry_in_place(local_key, new_entry);
+        }
+
+        true
+    }
+
     // Determines how the parent node should handle the removal of the entry at local_key from this
     // node.
     fn collapse_kill_or_change(&self,
                                local_key: usize,
-                               entry_index: usize)
+                               entry_index: usize,
+                               growth: GrowthPolicy)
                             -> RemovalResult<K, V, IS, H> {
         let new_entry_count = bit_count(self.mask) - 1;
 
         if new_entry_count > 1 {
-            RemovalResult::ReplaceSubTree(self.copy_without_entry(local_key))
+            RemovalResult::ReplaceSubTree(self.copy_without_entry(local_key, growth))
         } else if new_entry_count == 1 {
             let other_index = 1 - entry_index;
 
@@ -899,7 +1290,7 @@ impl<K, V, IS, H> UnsafeNode<K, V, IS, H>
                 NodeEntryRef::Item(kvp_ref) => {
                     RemovalResult::CollapseSubTree(kvp_ref.clone())
                 }
-                _ => RemovalResult::ReplaceSubTree(self.copy_without_entry(local_key))
+                _ => RemovalResult::ReplaceSubTree(self.copy_without_entry(local_key, growth))
             }
         } else {
             debug_assert!(new_entry_count == 0);
@@ -907,42 +1298,158 @@ impl<K, V, IS, H> UnsafeNode<K, V, IS, H>
         }
     }
 
-    // Same as `collapse_kill_or_change()` but will do the modification in-place.
+    // Same as `collapse_kill_or_change()` but will do the modification in-place. If the entry at
+    // local_key is the removed item, it is handed back through `removed`. It is moved out of the
+    // node where possible, and cloned if the node is about to be dropped by the parent anyway.
     fn collapse_kill_or_change_in_place(&mut self,
                                         local_key: usize,
-                                        entry_index: usize)
+                                        entry_index: usize,
+                                        removed: &mut Option<IS>)
                                      -> RemovalResult<K, V, IS, H> {
         let new_entry_count = self.entry_count() - 1;
 
-        if new_entry_count > 1 {
-            self.remove_entry_in_place(local_key);
-            RemovalResult::NoChange
-        } else if new_entry_count == 1 {
+        if new_entry_count == 1 {
             let other_index = 1 - entry_index;
 
-            match self.get_entry(other_index) {
-                NodeEntryRef::Item(kvp_ref) => {
-                    return RemovalResult::CollapseSubTree(kvp_ref.clone())
-                }
-                _ => { /* */ }
+            if let NodeEntryRef::Item(kvp_ref) = self.get_entry(other_index) {
+                self.clone_removed_item(entry_index, removed);
+                return RemovalResult::CollapseSubTree(kvp_ref.clone());
             }
+        }
 
-            self.remove_entry_in_place(local_key);
+        if new_entry_count > 0 {
+            if let NodeEntryOwned::Item(kvp) = self.remove_entry_in_place(local_key) {
+                *removed = Some(kvp);
+            }
             RemovalResult::NoChange
         } else {
             debug_assert!(new_entry_count == 0);
+            self.clone_removed_item(entry_index, removed);
             RemovalResult::KillSubTree
         }
     }
 
+    // Hands back a clone of the removed item at the given index, for nodes that are dropped as a
+    // whole by their parent. A killed sub-tree entry has already handed back its item.
+    fn clone_removed_item(&self, index: usize, removed: &mut Option<IS>) {
+        if let NodeEntryRef::Item(kvp_ref) = self.get_entry(index) {
+            *removed = Some(kvp_ref.clone());
+        }
+    }
+
+    // Returns mutable access to the referenced node, replacing it by a copy first if it is shared.
+    // The copy shares all entries with the original node.
+    fn make_exclusive(node_ref: &mut NodeRef<K, V, IS, H>) -> &mut UnsafeNode<K, V, IS, H> {
+        let copy = match node_ref.try_borrow_owned() {
+            BorrowedNodeRef::Shared(node) => {
+                let mut copy = UnsafeNode::alloc(node.mask, node.capacity as usize);
+                {
+                    let new_node = copy.borrow_mut();
+
+                    for index in 0 .. node.entry_count() {
+                        new_node.init_entry(index, node.get_entry(index).clone_out());
+                    }
+                }
+                Some(copy)
+            }
+            BorrowedNodeRef::Exclusive(_) => None,
+        };
+
+        if let Some(copy) = copy {
+            *node_ref = copy;
+        }
+
+        match node_ref.try_borrow_owned() {
+            BorrowedNodeRef::Exclusive(node) => node,
+            BorrowedNodeRef::Shared(_) => unreachable!(),
+        }
+    }
+
+    // Removes all items for which `f` returns false from the sub-tree rooted at this node, which
+    // is modified in-place. Shared nodes below it are copied before they are modified, see
+    // `make_exclusive()`. Like with single removals, a sub-tree left with just a single item is
+    // collapsed into that item. Returns the number of removed items.
+    fn retain_in_place<F>(&mut self, f: &mut F) -> usize
+        where F: FnMut(&K, &V) -> bool
+    {
+        enum Action<K, V, IS, H> {
+            Keep,
+            RemoveEntry,
+            ReplaceEntry(NodeEntryOwned<K, V, IS, H>)
+        }
+
+        let mut removal_count = 0;
+        let mut unvisited = self.mask;
+
+        // Going backwards, removing an entry doesn't move those that are still to be visited
+        for index in (0 .. self.entry_count()).rev() {
+            let local_key = 31 - unvisited.leading_zeros() as usize;
+            unvisited &= !(1 << local_key);
+            // The number of items removed from a sub-tree, whose size has already been updated
+            let mut removed_below = 0;
+
+            let action = match self.get_entry_mut(index) {
+                NodeEntryMutRef::Item(kvp) => {
+                    if f(kvp.key(), kvp.val()) {
+                        Action::Keep
+                    } else {
+                        removal_count += 1;
+                        Action::RemoveEntry
+                    }
+                }
+                NodeEntryMutRef::Collision(items) => {
+                    let mut retained: Vec<IS> = items.iter()
+                                                     .filter(|kvp| f(kvp.key(), kvp.val()))
+                                                     .cloned()
+                                                     .collect();
+                    removal_count += items.len() - retained.len();
+
+                    match retained.len() {
+                        0 => Action::RemoveEntry,
+                        1 => Action::ReplaceEntry(NodeEntryOwned::Item(retained.pop().unwrap())),
+                        count if count == items.len() => Action::Keep,
+                        _ => Action::ReplaceEntry(NodeEntryOwned::Collision(Arc::new(retained))),
+                    }
+                }
+                NodeEntryMutRef::SubTree(sub_tree_ref) => {
+                    let sub_tree = UnsafeNode::make_exclusive(sub_tree_ref);
+                    removed_below = sub_tree.retain_in_place(f);
+                    removal_count += removed_below;
+
+                    match sub_tree.entry_count() {
+                        0 => Action::RemoveEntry,
+                        1 => match sub_tree.get_entry(0) {
+                            NodeEntryRef::Item(kvp) => {
+                                Action::ReplaceEntry(NodeEntryOwned::Item(kvp.clone()))
+                            }
+                            _ => Action::Keep,
+                        },
+                        _ => Action::Keep,
+                    }
+                }
+            };
+
+            self.subtree_size -= removed_below;
+
+            match action {
+                Action::Keep => {}
+                Action::RemoveEntry => drop(self.remove_entry_in_place(local_key)),
+                Action::ReplaceEntry(entry) => self.insert_entry_in_place(local_key, entry),
+            }
+        }
+
+        removal_count
+    }
+
     // Copies this node with a new entry at `local_key`. Might replace an old entry.
     fn copy_with_new_entry(&self,
                            local_key: usize,
-                           new_entry: NodeEntryOwned<K, V, IS, H>)
+                           new_entry: NodeEntryOwned<K, V, IS, H>,
+                           growth: GrowthPolicy)
                         -> NodeRef<K, V, IS, H> {
         let replace_old_entry = (self.mask & (1 << local_key)) != 0;
         let new_mask: u32 = self.mask | (1 << local_key);
-        let mut new_node_ref = UnsafeNode::alloc(new_mask, self.expanded_capacity());
+        let mut new_node_ref = UnsafeNode::alloc(new_mask, self.expanded_capacity(growth));
 
         {
             let new_node = new_node_ref.borrow_mut();
@@ -1020,22 +1527,16 @@ impl<K, V, IS, H> UnsafeNode<K, V, IS, H>
     }
 
     // Given that the current capacity is too small, returns how big the new node should be.
-    fn expanded_capacity(&self) -> usize {
-        if self.capacity == 0 {
-            MIN_CAPACITY
-        } else if self.capacity > 16 {
-            32
-        } else {
-            ((self.capacity as usize) * 2)
-        }
+    fn expanded_capacity(&self, growth: GrowthPolicy) -> usize {
+        growth.expanded_capacity(self.capacity as usize, self.entry_count())
     }
 
     // Create a copy of this node which does not contain the entry at 'local_key'.
-    fn copy_without_entry(&self, local_key: usize) -> NodeRef<K, V, IS, H> {
+    fn copy_without_entry(&self, local_key: usize, growth: GrowthPolicy) -> NodeRef<K, V, IS, H> {
         debug_assert!((self.mask & (1 << local_key)) != 0);
 
         let new_mask = self.mask & !(1 << local_key);
-        let mut new_node_ref = UnsafeNode::alloc(new_mask, self.expanded_capacity());
+        let mut new_node_ref = UnsafeNode::alloc(new_mask, self.expanded_capacity(growth));
         {
             let new_node = new_node_ref.borrow_mut();
             let index = get_index(self.mask, local_key);
@@ -1064,15 +1565,16 @@ impl<K, V, IS, H> UnsafeNode<K, V, IS, H>
         return new_node_ref;
     }
 
-    // Same as `copy_without_entry()` but applies the modification in place.
-    fn remove_entry_in_place(&mut self, local_key: usize) {
+    // Same as `copy_without_entry()` but applies the modification in place. Returns the removed
+    // entry.
+    fn remove_entry_in_place(&mut self, local_key: usize) -> NodeEntryOwned<K, V, IS, H> {
         debug_assert!((self.mask & (1 << local_key)) != 0);
 
         let new_mask = self.mask & !(1 << local_key);
         let index = get_index(self.mask, local_key);
 
-        unsafe {
-            self.drop_entry(index);
+        let removed_entry = unsafe {
+            let removed_entry = self.take_entry(index);
 
             if index < self.entry_count() - 1 {
                 let source: *const u8 = self.get_entry_ptr(index + 1);
@@ -1087,27 +1589,33 @@ impl<K, V, IS, H> UnsafeNode<K, V, IS, H>
                 self.entry_types = ((self.entry_types & type_mask_up_to_index) >> 2) |
                                    (self.entry_types & !(type_mask_up_to_index >> 2));
             }
-        }
+
+            removed_entry
+        };
 
         self.mask = new_mask;
+        removed_entry
     }
 
-    // Creates a new node with containing the two given items and MIN_CAPACITY. Might create a
-    // whole subtree if the hash values of the two items necessitate it.
+    // Creates a new node with containing the two given items and the initial capacity of the
+    // growth policy. Might create a whole subtree if the hash values of the two items necessitate
+    // it.
     fn new_with_entries(new_kvp: IS,
                         new_hash: u64,
                         existing_kvp: &IS,
                         existing_hash: u64,
-                        level: usize)
+                        level: usize,
+                        growth: GrowthPolicy,
+                        last_level: usize)
                      -> NodeRef<K, V, IS, H> {
-        debug_assert!(level <= LAST_LEVEL);
+        debug_assert!(level <= last_level && last_level <= LAST_LEVEL);
 
         let new_local_key = (new_hash & LEVEL_BIT_MASK) as usize;
         let existing_local_key = (existing_hash & LEVEL_BIT_MASK) as usize;
 
         if new_local_key != existing_local_key {
             let mask = (1 << new_local_key) | (1 << existing_local_key);
-            let mut new_node_ref = UnsafeNode::alloc(mask, MIN_CAPACITY);
+            let mut new_node_ref = UnsafeNode::alloc(mask, growth.initial_capacity());
             {
                 let new_node = new_node_ref.borrow_mut();
 
@@ -1120,9 +1628,9 @@ impl<K, V, IS, H> UnsafeNode<K, V, IS, H>
                 };
             }
             new_node_ref
-        } else if level == LAST_LEVEL {
+        } else if level == last_level {
             let mask = 1 << new_local_key;
-            let mut new_node_ref = UnsafeNode::alloc(mask, MIN_CAPACITY);
+            let mut new_node_ref = UnsafeNode::alloc(mask, growth.initial_capacity());
             {
                 let new_node = new_node_ref.borrow_mut();
                 let items = vec!(new_kvp, existing_kvp.clone());
@@ -1135,9 +1643,11 @@ impl<K, V, IS, H> UnsafeNode<K, V, IS, H>
                                                         new_hash >> BITS_PER_LEVEL,
                                                         existing_kvp,
                                                         existing_hash >> BITS_PER_LEVEL,
-                                                        level + 1);
+                                                        level + 1,
+                                                        growth,
+                                                        last_level);
             let mask = 1 << new_local_key;
-            let mut new_node_ref = UnsafeNode::alloc(mask, MIN_CAPACITY);
+            let mut new_node_ref = UnsafeNode::alloc(mask, growth.initial_capacity());
             {
                 let new_node = new_node_ref.borrow_mut();
                 new_node.init_entry(0, NodeEntryOwned::SubTree(sub_tree));
@@ -1145,593 +1655,6027 @@ impl<K, V, IS, H> UnsafeNode<K, V, IS, H>
             new_node_ref
         }
     }
-}
-
 
+    // Builds a subtree at the given level from items that all share the same hash prefix up to
+    // this level. The items must be sorted in trie order (see `trie_order()`) and must not
+    // contain any duplicate keys. Every node is allocated with exactly the capacity it needs. Items
+    // still sharing their prefix on `last_level` are put into collision entries.
+    fn from_sorted_items(items: &mut [(u64, Option<IS>)], level: usize, last_level: usize)
+        -> NodeRef<K, V, IS, H>
+    {
+        debug_assert!(level <= last_level && last_level <= LAST_LEVEL);
+        debug_assert!(!items.is_empty());
+
+        let local_key_of = |hash: u64| ((hash >> (BITS_PER_LEVEL * level)) & LEVEL_BIT_MASK) as usize;
+
+        // Find the runs of items sharing the same local key
+        let mut runs = [(0, 0); 1 << BITS_PER_LEVEL];
+        let mut run_count = 0;
+        let mut mask = 0u32;
+        let mut start = 0;
+
+        while start < items.len() {
+            let local_key = local_key_of(items[start].0);
+            let end = start + items[start..].iter()
+                                            .take_while(|&&(hash, _)| local_key_of(hash) == local_key)
+                                            .count();
+            debug_assert!((mask >> local_key) == 0);
+            mask |= 1 << local_key;
+            runs[run_count] = (start, end);
+            run_count += 1;
+            start = end;
+        }
 
-//=-------------------------------------------------------------------------------------------------
-// HamtMap
-//=-------------------------------------------------------------------------------------------------
-pub struct HamtMap<K, V, IS=ShareStore<K,V>, H=StdHasher> {
-    root: NodeRef<K, V, IS, H>,
-    element_count: usize,
-}
+        let mut new_node_ref = UnsafeNode::alloc(mask, run_count);
+        {
+            let new_node = new_node_ref.borrow_mut();
 
-// impl HamtMap
-impl<K, V, IS, H> HamtMap<K, V, IS, H>
-    where K: Eq+Send+Sync+Hash,
-          V: Send+Sync,
-          IS: ItemStore<K, V>,
-          H: Hasher+Default
-{
-    pub fn new() -> HamtMap<K, V, IS, H> {
-        HamtMap {
-            root: UnsafeNode::alloc(0, 0),
-            element_count: 0
+            for (index, &(start, end)) in runs[.. run_count].iter().enumerate() {
+                let entry = UnsafeNode::entry_from_sorted_items(&mut items[start .. end],
+                                                                level,
+                                                                last_level);
+                new_node.init_entry(index, entry);
+            }
         }
+        new_node_ref
     }
 
-    pub fn iter<'a>(&'a self) -> HamtMapIterator<'a, K, V, IS, H> {
-        HamtMapIterator::new(self)
+    // Builds the entry for a run of items sharing their local keys up to and including `level`,
+    // for `from_sorted_items()`.
+    fn entry_from_sorted_items(run: &mut [(u64, Option<IS>)], level: usize, last_level: usize)
+        -> NodeEntryOwned<K, V, IS, H>
+    {
+        if run.len() == 1 {
+            NodeEntryOwned::Item(run[0].1.take().unwrap())
+        } else if level == last_level {
+            let colliding = run.iter_mut().map(|item| item.1.take().unwrap()).collect();
+            NodeEntryOwned::Collision(Arc::new(colliding))
+        } else {
+            NodeEntryOwned::SubTree(UnsafeNode::from_sorted_items(run, level + 1, last_level))
+        }
     }
 
-    pub fn find<'a>(&'a self, key: &K) -> Option<&'a V> {
-        // let mut hash = key.hash();
-        let mut hash = hash_of::<K, H>(key);
-
-        let mut level = 0;
-        let mut current_node = self.root.borrow();
+    // Inserts the given items into the sub-tree rooted at this node, which is located at the given
+    // level. Like for `from_sorted_items()`, the items must be sorted in trie order, have distinct
+    // keys, share their local keys on all levels above and come with their full hash values. Each
+    // node on the paths of the items is copied only once, with all items going into it or below
+    // it inserted on the way, instead of once per item. New sub-trees are built bottom-up.
+    // `insertion_count` is increased by the number of keys that weren't present before.
+    fn insert_sorted_items(&self,
+                           items: &mut [(u64, Option<IS>)],
+                           level: usize,
+                           insertion_count: &mut usize,
+                           context: InsertContext<V>)
+                        -> NodeRef<K, V, IS, H> {
+        let local_key_of = |hash: u64| ((hash >> (BITS_PER_LEVEL * level)) & LEVEL_BIT_MASK) as usize;
 
-        loop {
-            debug_assert!(level <= LAST_LEVEL);
-            let local_key = (hash & LEVEL_BIT_MASK) as usize;
+        let new_mask = items.iter().fold(self.mask, |mask, item| mask | (1 << local_key_of(item.0)));
+        let capacity = cmp::max(bit_count(new_mask), self.capacity as usize);
+        let mut new_node_ref = UnsafeNode::alloc(new_mask, capacity);
+        {
+            let new_node = new_node_ref.borrow_mut();
+            let mut start = 0;
 
-            if (current_node.mask & (1 << local_key)) == 0 {
-                return None;
-            }
+            let local_keys = (0 .. 1 << BITS_PER_LEVEL).filter(|&k| new_mask & (1 << k) != 0);
 
-            let index = get_index(current_node.mask, local_key);
+            for (index, local_key) in local_keys.enumerate() {
+                let end = start + items[start..].iter()
+                                                .take_while(|item| local_key_of(item.0) == local_key)
+                                                .count();
+                let run = &mut items[start .. end];
+                start = end;
 
-            match current_node.get_entry(index) {
-                NodeEntryRef::Item(kvp_ref) => return if *key == *kvp_ref.key() {
-                    Some(kvp_ref.val())
+                let existing = if self.mask & (1 << local_key) != 0 {
+                    Some(self.get_entry(get_index(self.mask, local_key)))
                 } else {
                     None
-                },
-                NodeEntryRef::Collision(items) => {
-                    debug_assert!(level == LAST_LEVEL);
-                    let found = items.iter().find(|&kvp| *key == *kvp.key());
-                    return match found {
-                        Some(kvp) => Some(kvp.val()),
-                        None => None,
-                    };
-                }
-                NodeEntryRef::SubTree(subtree_ref) => {
-                    debug_assert!(level < LAST_LEVEL);
-                    current_node = subtree_ref.borrow();
-                    hash = hash >> BITS_PER_LEVEL;
-                    level += 1;
-                }
-            };
+                };
+
+                let entry = match existing {
+                    None => {
+                        *insertion_count += run.len();
+                        UnsafeNode::entry_from_sorted_items(run, level, context.last_level)
+                    }
+                    Some(existing) if run.is_empty() => existing.clone_out(),
+                    Some(NodeEntryRef::SubTree(sub_tree_ref)) => {
+                        let sub_tree = sub_tree_ref.borrow();
+                        NodeEntryOwned::SubTree(
+                            sub_tree.insert_sorted_items(run, level + 1, insertion_count, context))
+                    }
+                    Some(NodeEntryRef::Item(existing_kvp)) => {
+                        let is_replaced = run.iter().any(|item| {
+                            *item.1.as_ref().unwrap().key() == *existing_kvp.key()
+                        });
+
+                        if is_replaced {
+                            *insertion_count += run.len() - 1;
+                            UnsafeNode::entry_from_sorted_items(run, level, context.last_level)
+                        } else {
+                            *insertion_count += run.len();
+                            let existing_hash = hash_of::<K, H>(existing_kvp.key());
+                            let mut merged: Vec<(u64, Option<IS>)> =
+                                run.iter_mut().map(|item| (item.0, item.1.take())).collect();
+                            merged.push((existing_hash, Some(existing_kvp.clone())));
+                            merged.sort_by_key(|&(hash, _)| (trie_order(hash), hash));
+                            UnsafeNode::entry_from_sorted_items(&mut merged[..],
+                                                                level,
+                                                                context.last_level)
+                        }
+                    }
+                    Some(NodeEntryRef::Collision(existing_items)) => {
+                        let mut new_items: Vec<IS> = (**existing_items).clone();
+
+                        for item in run.iter_mut() {
+                            let new_kvp = item.1.take().unwrap();
+
+                            match new_items.iter().position(|kvp| *kvp.key() == *new_kvp.key()) {
+                                Some(position) => {
+                                    let resolved = context.resolve_collision(&new_items[position],
+                                                                             new_kvp);
+                                    new_items[position] = resolved;
+                                }
+                                None => {
+                                    *insertion_count += 1;
+                                    new_items.push(new_kvp);
+                                }
+                            }
+                        }
+
+                        NodeEntryOwned::Collision(Arc::new(new_items))
+                    }
+                };
+
+                new_node.init_entry(index, entry);
+            }
+
+            debug_assert!(start == items.len());
         }
+
+        if let Some(observer) = context.observer {
+            observer.on_copy(level);
+        }
+
+        new_node_ref
     }
 
-    fn insert_internal(self, kvp: IS) -> (HamtMap<K, V, IS, H>, bool) {
-        let HamtMap { mut root, element_count } = self;
-        let hash = hash_of::<K, H>(kvp.key());
-        let mut insertion_count = 0xdeadbeaf;
+    // Returns true if at least one of the node's entries is a sub-tree. This only looks at the
+    // `entry_types` bitfield: a type code is SUBTREE_ENTRY iff its high bit is set and its low
+    // bit is not.
+    fn has_sub_trees(&self) -> bool {
+        let entry_count = self.entry_count();
+        let used_bits = if entry_count * 2 >= 64 { !0u64 } else { (1u64 << (entry_count * 2)) - 1 };
+        let low_bits = 0x5555_5555_5555_5555u64;
+        let entry_types = self.entry_types & used_bits;
 
-        // If we hold the only reference to the root node, then try to insert the KVP in-place
-        let new_root = match root.try_borrow_owned() {
-            BorrowedNodeRef::Exclusive(mutable) => mutable.try_insert_in_place(hash, 0, kvp, &mut insertion_count),
-            BorrowedNodeRef::Shared(immutable) => Some(immutable.insert(hash, 0, kvp, &mut insertion_count))
-        };
+        ((entry_types >> 1) & !entry_types & low_bits) != 0
+    }
 
-        // Make sure that insertion_count was set properly
-        debug_assert!(insertion_count != 0xdeadbeaf);
+    // Creates a new root node containing the items of two root nodes without any sub-trees. Items
+    // of `self` win over items of `other` with an equal key. Two different keys with the same local
+    // key are moved into a new sub-tree, whose collision entries (if any) go on `last_level`.
+    // Returns the new node and the number of keys contained in both nodes.
+    fn union_of_flat_roots(&self,
+                           other: &UnsafeNode<K, V, IS, H>,
+                           growth: GrowthPolicy,
+                           last_level: usize)
+                        -> (NodeRef<K, V, IS, H>, usize) {
+        debug_assert!(!self.has_sub_trees() && !other.has_sub_trees());
+        debug_assert!(last_level > 0);
+
+        let mask = self.mask | other.mask;
+        let mut duplicates = 0;
+        let mut new_node_ref = UnsafeNode::alloc(mask, bit_count(mask));
+        {
+            let new_node = new_node_ref.borrow_mut();
 
-        match new_root {
-            Some(r) => (
-                HamtMap {
-                    root: r,
-                    element_count: element_count + insertion_count
-                },
-                insertion_count != 0
-            ),
-            None => (
-                HamtMap {
-                    root: root,
-                    element_count: element_count + insertion_count
-                },
-                insertion_count != 0
-            )
+            for (index, local_key) in (0 .. 1 << BITS_PER_LEVEL).filter(|&k| mask & (1 << k) != 0).enumerate() {
+                let bit = 1u32 << local_key;
+                let self_item = if self.mask & bit != 0 {
+                    Some(self.item_at(get_index(self.mask, local_key)))
+                } else {
+                    None
+                };
+                let other_item = if other.mask & bit != 0 {
+                    Some(other.item_at(get_index(other.mask, local_key)))
+                } else {
+                    None
+                };
+
+                let entry = match (self_item, other_item) {
+                    (Some(kvp), None) | (None, Some(kvp)) => NodeEntryOwned::Item(kvp.clone()),
+                    (Some(kvp), Some(other_kvp)) if *kvp.key() == *other_kvp.key() => {
+                        duplicates += 1;
+                        NodeEntryOwned::Item(kvp.clone())
+                    }
+                    (Some(kvp), Some(other_kvp)) => {
+                        let sub_tree = UnsafeNode::new_with_entries(
+                            kvp.clone(),
+                            hash_of::<K, H>(kvp.key()) >> BITS_PER_LEVEL,
+                            other_kvp,
+                            hash_of::<K, H>(other_kvp.key()) >> BITS_PER_LEVEL,
+                            1,
+                            growth,
+                            last_level);
+                        NodeEntryOwned::SubTree(sub_tree)
+                    }
+                    (None, None) => unreachable!(),
+                };
+
+                new_node.init_entry(index, entry);
+            }
         }
+        (new_node_ref, duplicates)
     }
 
-    fn try_remove_in_place(self, key: &K) -> (HamtMap<K, V, IS, H>, bool) {
-        let HamtMap { mut root, element_count } = self;
-        let hash = hash_of::<K, H>(key);
-        let mut removal_count = 0xdeadbeaf;
+    // The item stored in the entry with the given index, which must be a single-item entry.
+    fn item_at(&self, index: usize) -> &IS {
+        match self.get_entry(index) {
+            NodeEntryRef::Item(kvp_ref) => kvp_ref,
+            _ => unreachable!(),
+        }
+    }
 
-        let removal_result = match root.try_borrow_owned() {
-            BorrowedNodeRef::Shared(node_ref) => node_ref.remove(hash, 0, key, &mut removal_count),
-            BorrowedNodeRef::Exclusive(node_ref) => node_ref.remove_in_place(hash, 0, key, &mut removal_count)
-        };
-        debug_assert!(removal_count != 0xdeadbeaf);
-        let new_element_count = element_count - removal_count;
+    // Creates a copy of the subtree rooted at this node in which every node is freshly allocated
+    // with a capacity exactly matching its entry count. Collision entries get a fresh copy of
+    // their item vector too, so nothing is shared with the original subtree. The items themselves
+    // are copied with `copy_item`, which may also convert them to a different item store.
+    fn compacted<IS2, F>(&self, copy_item: &mut F) -> NodeRef<K, V, IS2, H>
+        where IS2: ItemStore<K, V>,
+              F: FnMut(&IS) -> IS2
+    {
+        let entry_count = self.entry_count();
+        let mut new_node_ref = UnsafeNode::alloc(self.mask, entry_count);
+        {
+            let new_node = new_node_ref.borrow_mut();
 
-        (match removal_result {
-            RemovalResult::NoChange => HamtMap {
-                root: root,
-                element_count: new_element_count
-            },
-            RemovalResult::ReplaceSubTree(new_root) => HamtMap {
-                root: new_root,
-                element_count: new_element_count
-            },
-            RemovalResult::CollapseSubTree(kvp) => {
-                debug_assert!(bit_count(root.borrow().mask) == 2);
-                let local_key = (hash_of::<K, H>(kvp.key()) & LEVEL_BIT_MASK) as usize;
+            for index in 0 .. entry_count {
+                let entry = match self.get_entry(index) {
+                    NodeEntryRef::Item(kvp_ref) => NodeEntryOwned::Item(copy_item(kvp_ref)),
+                    NodeEntryRef::Collision(items) => {
+                        NodeEntryOwned::Collision(Arc::new(items.iter().map(&mut *copy_item).collect()))
+                    }
+                    NodeEntryRef::SubTree(sub_tree_ref) => {
+                        NodeEntryOwned::SubTree(sub_tree_ref.borrow().compacted(copy_item))
+                    }
+                };
 
-                let mask = 1 << local_key;
-                let mut new_root_ref = UnsafeNode::alloc(mask, MIN_CAPACITY);
-                {
-                    let root = new_root_ref.borrow_mut();
-                    root.init_entry(0, NodeEntryOwned::Item(kvp));
+                new_node.init_entry(index, entry);
+            }
+        }
+        new_node_ref
+    }
+
+    // Applies `f` to the values of all items in the subtree rooted at this node. Items, collision
+    // entries, and sub-trees not shared with anything else are modified in-place, shared ones are
+    // copied first.
+    fn map_values_in_place<F>(&mut self, f: &mut F)
+        where F: FnMut(&mut V),
+              K: Clone,
+              V: Clone
+    {
+        for index in 0 .. self.entry_count() {
+            match self.get_entry_mut(index) {
+                NodeEntryMutRef::Item(kvp) => UnsafeNode::<K, V, IS, H>::map_item_value(kvp, f),
+                NodeEntryMutRef::Collision(items_arc) => {
+                    for kvp in Arc::make_mut(items_arc).iter_mut() {
+                        UnsafeNode::<K, V, IS, H>::map_item_value(kvp, f);
+                    }
                 }
-                HamtMap {
-                    root: new_root_ref,
-                    element_count: new_element_count
+                NodeEntryMutRef::SubTree(sub_tree_ref) => {
+                    let copy = match sub_tree_ref.try_borrow_owned() {
+                        BorrowedNodeRef::Exclusive(sub_tree) => {
+                            sub_tree.map_values_in_place(f);
+                            None
+                        }
+                        BorrowedNodeRef::Shared(sub_tree) => Some(sub_tree.with_mapped_values(f)),
+                    };
+
+                    if let Some(copy) = copy {
+                        *sub_tree_ref = copy;
+                    }
                 }
             }
-            RemovalResult::KillSubTree => {
-                debug_assert!(bit_count(root.borrow().mask) == 1);
-                HamtMap::new()
-            }
-        }, removal_count != 0)
+        }
     }
 
-    pub fn len(&self) -> usize {
-        self.element_count
+    // Creates a copy of the subtree rooted at this node with `f` applied to every value.
+    fn with_mapped_values<F>(&self, f: &mut F) -> NodeRef<K, V, IS, H>
+        where F: FnMut(&mut V),
+              K: Clone,
+              V: Clone
+    {
+        self.compacted(&mut |kvp: &IS| {
+            let mut kvp = kvp.clone();
+            UnsafeNode::<K, V, IS, H>::map_item_value(&mut kvp, f);
+            kvp
+        })
     }
 
-    /// Inserts a key-value pair into the map. An existing value for a
-    /// key is replaced by the new value. The first tuple element of the return value is the new
-    /// map instance representing the map after the insertion. The second tuple element is true if
-    /// the size of the map was changed by the operation and false otherwise.
-    pub fn insert(self, key: K, value: V) -> (HamtMap<K, V, IS, H>, bool) {
-        self.insert_internal(ItemStore::new(key, value))
-    }
+    // Applies `f` to the value of the given item, replacing the item if its value can't be
+    // modified in-place.
+    fn map_item_value<F>(kvp: &mut IS, f: &mut F)
+        where F: FnMut(&mut V),
+              K: Clone,
+              V: Clone
+    {
+        if let Some(value) = kvp.val_mut() {
+            f(value);
+            return;
+        }
 
-    /// Removes a key-value pair from the map. The first tuple element of the return value is the new
-    /// map instance representing the map after the insertion. The second tuple element is true if
-    /// the size of the map was changed by the operation and false otherwise.
-    pub fn remove(self, key: &K) -> (HamtMap<K, V, IS, H>, bool) {
-        self.try_remove_in_place(key)
+        let mut value = kvp.val().clone();
+        f(&mut value);
+        *kvp = ItemStore::new(kvp.key().clone(), value);
     }
 
+    // The number of nodes and collision entries in the subtree rooted at this node that are
+    // referenced from more than one place. The subtrees of shared nodes are not looked at.
+    fn shared_node_count(&self) -> usize {
+        let mut count = 0;
 
-    /// Inserts a key-value pair into the map. Same as `insert()` but with a return type that's
-    /// better suited to chaining multiple calls together.
-    pub fn plus(self, key: K, val: V) -> HamtMap<K, V, IS, H> {
-        self.insert(key, val).0
+        for index in 0 .. self.entry_count() {
+            match self.get_entry(index) {
+                NodeEntryRef::Item(_) => { /* not reference counted */ }
+                NodeEntryRef::Collision(items) => {
+                    if Arc::strong_count(items) > 1 {
+                        count += 1;
+                    }
+                }
+                NodeEntryRef::SubTree(sub_tree_ref) => {
+                    if sub_tree_ref.ref_count() > 1 {
+                        count += 1;
+                    } else {
+                        count += sub_tree_ref.borrow().shared_node_count();
+                    }
+                }
+            }
+        }
+
+        count
     }
 
-    /// Removes a key-value pair from the map. Same as `remove()` but with a return type that's
-    /// better suited to chaining multiple call together
-    pub fn minus(self, key: &K) -> HamtMap<K, V, IS, H> {
-        self.remove(key).0
+    // Descends from this node, which is located at the given level, along the given hash value
+    // until reaching an entry that is not a sub-tree, or a node without an entry for the hash
+    // value. As in `insert()`, `hash` is the *remaining* hash value for that level. `visit` is
+    // called with every node that has an entry for the hash value, the entry's local key and the
+    // node's level, before the entry is read. The descent is aborted with the first error it
+    // returns.
+    fn try_descend<'b, E, F>(&'b self, mut hash: u64, mut level: usize, mut visit: F)
+        -> Result<Descent<'b, K, V, IS, H>, E>
+        where F: FnMut(&'b UnsafeNode<K, V, IS, H>, usize, usize) -> Result<(), E>
+    {
+        let mut current_node = self;
+        let mut steps = 1;
+
+        loop {
+            debug_assert!(level <= LAST_LEVEL);
+            let local_key = (hash & LEVEL_BIT_MASK) as usize;
+
+            if (current_node.mask & (1 << local_key)) == 0 {
+                return Ok(Descent { node: current_node, entry: None, steps });
+            }
+
+            visit(current_node, local_key, level)?;
+
+            match current_node.get_entry(get_index(current_node.mask, local_key)) {
+                NodeEntryRef::SubTree(subtree_ref) => {
+                    current_node = subtree_ref.borrow();
+                    hash >>= BITS_PER_LEVEL;
+                    level += 1;
+                    steps += 1;
+                }
+                entry => return Ok(Descent { node: current_node, entry: Some(entry), steps }),
+            }
+        }
     }
-}
 
-// Clone for HamtMap
-impl<K, V, IS, H> Clone for HamtMap<K, V, IS, H> {
-    fn clone(&self) -> HamtMap<K, V, IS, H> {
-        HamtMap {
-            root: self.root.clone(),
-            element_count: self.element_count
+    // Same as `try_descend()` for a `visit` function that can't fail.
+    fn descend<'b, F>(&'b self, hash: u64, level: usize, mut visit: F) -> Descent<'b, K, V, IS, H>
+        where F: FnMut(&'b UnsafeNode<K, V, IS, H>, usize, usize)
+    {
+        let descent = self.try_descend(hash, level, |node, local_key, level| {
+            visit(node, local_key, level);
+            Ok::<(), Infallible>(())
+        });
+
+        match descent {
+            Ok(descent) => descent,
+            Err(never) => match never {},
         }
     }
-}
 
-// Default for HamtMap
-impl<K, V, IS, H> Default for HamtMap<K, V, IS, H>
-    where K: Eq+Send+Sync+Hash,
-          V: Send+Sync,
-          IS: ItemStore<K, V>,
-          H: Hasher+Default
-{
-    fn default() -> HamtMap<K, V, IS, H> {
-        HamtMap::new()
+    // Looks up the item with the given key in the subtree rooted at this node, which is located at
+    // the given level. As in `insert()`, `hash` is the *remaining* hash value for that level.
+    fn find_item<'b>(&'b self, hash: u64, level: usize, key: &K) -> Option<&'b IS> {
+        self.descend(hash, level, |_, _, _| {}).find(key)
     }
-}
 
-impl<'a, K, V, IS, H> IntoIterator for &'a HamtMap<K, V, IS, H>
-    where K: Eq+Send+Sync+Hash+'a,
-          V: Send+Sync+'a,
-          IS: ItemStore<K, V>+'a,
-          H: Hasher+Default+'a
-{
-    type Item = (&'a K, &'a V);
-    type IntoIter = HamtMapIterator<'a, K, V, IS, H>;
+    // Same as `find_item()`, but only tells whether the key is contained in the trie.
+    fn contains_item(&self, mut hash: u64, key: &K) -> bool {
+        let mut current_node = self;
 
-    fn into_iter(self) -> HamtMapIterator<'a, K, V, IS, H>
-    {
-        self.iter()
-    }
-}
+        loop {
+            let local_key = (hash & LEVEL_BIT_MASK) as usize;
 
-// Eq for HamtMap
-impl<K, V, IS, H> PartialEq for HamtMap<K, V, IS, H>
-    where K: Eq+Send+Sync+Hash,
-          V: PartialEq+Send+Sync,
-          IS: ItemStore<K, V>,
-          H: Hasher+Default
-{
-    fn eq(&self, other: &HamtMap<K, V, IS, H>) -> bool {
-        if self.len() != other.len() {
-            return false;
-        }
+            if (current_node.mask & (1 << local_key)) == 0 {
+                return false;
+            }
 
-        for (k, other_value) in other.iter() {
-            match self.find(k) {
-                Some(this_value) => {
-                    if *this_value != *other_value {
-                        return false;
-                    }
+            let index = get_index(current_node.mask, local_key);
+
+            match current_node.get_entry(index) {
+                NodeEntryRef::Item(kvp_ref) => return *key == *kvp_ref.key(),
+                NodeEntryRef::Collision(items) => {
+                    return items.iter().any(|kvp| *key == *kvp.key());
                 }
-                None => {
-                    return false;
+                NodeEntryRef::SubTree(subtree_ref) => {
+                    current_node = subtree_ref.borrow();
+                    hash >>= BITS_PER_LEVEL;
                 }
-            }
+            };
         }
-
-        true
     }
 
-    fn ne(&self, other: &HamtMap<K, V, IS, H>) -> bool {
-        !(*self == *other)
-    }
-}
+    // Same as `find_item()`, but checks the structural invariants of every node on the way down
+    // before relying on them, instead of panicking or reading garbage when they don't hold. This
+    // node must be the root of a map whose last level is `last_level`.
+    fn try_find_item<'b>(&'b self, hash: u64, key: &K, last_level: usize)
+        -> Result<Option<&'b IS>, CorruptionError>
+    {
+        let descent = self.try_descend(hash, 0, |node, local_key, level| {
+            let index = get_index(node.mask, local_key);
+            let capacity = node.capacity as usize;
 
+            if index >= capacity {
+                return Err(CorruptionError::EntryOutOfBounds { level, index, capacity });
+            }
 
-// Eq for HamtMap
-impl<K, V, IS, H> Eq for HamtMap<K, V, IS, H>
-    where K: Eq+Send+Sync+Hash,
-          V: Eq+Send+Sync,
-          IS: ItemStore<K, V>,
-          H: Hasher+Default
-{
-}
+            match ((node.entry_types >> (index * 2)) & 0b11) as usize {
+                KVP_ENTRY => Ok(()),
+                SUBTREE_ENTRY if level >= last_level => Err(CorruptionError::LevelOverflow { level }),
+                SUBTREE_ENTRY => Ok(()),
+                COLLISION_ENTRY if level != last_level => {
+                    Err(CorruptionError::MisplacedCollision { level })
+                }
+                COLLISION_ENTRY => Ok(()),
+                code => Err(CorruptionError::InvalidEntryType { level, code }),
+            }
+        })?;
 
+        Ok(descent.find(key))
+    }
 
-// FromIterator
-impl<K, V, IS, H> ::std::iter::FromIterator<(K, V)> for HamtMap<K, V, IS, H>
-    where K: Eq+Send+Sync+Hash,
-          V: Send+Sync,
-          IS: ItemStore<K, V>,
-          H: Hasher+Default
-{
-    fn from_iter<T>(iterator: T) -> Self where T: IntoIterator<Item=(K, V)> {
+    // Same as `find_item()` starting at the root, but also returns the number of nodes visited on
+    // the way down, including this one.
+    fn find_item_with_steps<'b>(&'b self, hash: u64, key: &K) -> (Option<&'b IS>, usize) {
+        let descent = self.descend(hash, 0, |_, _, _| {});
+        (descent.find(key), descent.steps)
+    }
 
-        let mut map = HamtMap::new();
+    // Descends along the given hash value like `find_item()` starting at the root, and returns the
+    // deepest node reached, i.e. the one whose entry for the hash value is not a sub-tree. Returns
+    // `None` if this node doesn't have an entry for the hash value at all.
+    fn deepest_node_on_path(&self, hash: u64) -> Option<&UnsafeNode<K, V, IS, H>> {
+        let descent = self.descend(hash, 0, |_, _, _| {});
 
-        for (k, v) in iterator {
-            map = map.plus(k, v);
+        if descent.steps == 1 && descent.entry.is_none() {
+            None
+        } else {
+            Some(descent.node)
         }
+    }
 
-        map
+    // Same as `find_item()`, but records the local keys of all entries visited on the way down in
+    // `path` instead of returning the item. Returns true if the key has been found.
+    fn key_path(&self, hash: u64, key: &K, path: &mut Vec<usize>) -> bool {
+        self.descend(hash, 0, |_, local_key, _| path.push(local_key)).find(key).is_some()
     }
-}
 
+    // Looks up all the given keys in the subtree rooted at this node, which is located at the given
+    // level, and sets `found[index]` for every key found. The queries consist of the full hash
+    // value, the key, and an index into `found`, and must be sorted in trie order. Keys sharing a
+    // local key are looked up together, so the path down to their common sub-tree is only taken
+    // once.
+    fn contains_batch(&self, queries: &[(u64, &K, usize)], level: usize, found: &mut [bool]) {
+        debug_assert!(level <= LAST_LEVEL);
+        let local_key_of = |hash: u64| ((hash >> (BITS_PER_LEVEL * level)) & LEVEL_BIT_MASK) as usize;
+        let mut start = 0;
+
+        while start < queries.len() {
+            let local_key = local_key_of(queries[start].0);
+            let end = start + queries[start..].iter()
+                                              .take_while(|q| local_key_of(q.0) == local_key)
+                                              .count();
+            let group = &queries[start .. end];
+            start = end;
+
+            if (self.mask & (1 << local_key)) == 0 {
+                continue;
+            }
 
-//=-------------------------------------------------------------------------------------------------
-// HamtMapIterator
-//=-------------------------------------------------------------------------------------------------
+            match self.get_entry(get_index(self.mask, local_key)) {
+                NodeEntryRef::Item(kvp_ref) => {
+                    for &(_, key, index) in group {
+                        found[index] = *key == *kvp_ref.key();
+                    }
+                }
+                NodeEntryRef::Collision(items) => {
+                    for &(_, key, index) in group {
+                        found[index] = items.iter().any(|kvp| *key == *kvp.key());
+                    }
+                }
+                NodeEntryRef::SubTree(sub_tree_ref) => {
+                    sub_tree_ref.borrow().contains_batch(group, level + 1, found);
+                }
+            }
+        }
+    }
 
-#[derive(Copy)]
-enum IterNodeRef<'a, K, V, IS, H>
-    where K: 'a,
-          V: 'a,
-          IS: 'a,
-          H: 'a
-{
-    RegularNode(&'a UnsafeNode<K, V, IS, H>),
-    CollisionEntry(&'a Vec<IS>)
+    // Calls `f` for every pair of items with equal keys from the subtrees rooted at this node and
+    // at `other`, both of which are located at the given level. Only entries present in both
+    // nodes are looked at, everything else is skipped without descending into it.
+    fn for_each_common_item<W, OS, F>(&self,
+                                      other: &UnsafeNode<K, W, OS, H>,
+                                      level: usize,
+                                      f: &mut F)
+        where W: Send+Sync,
+              OS: ItemStore<K, W>,
+              F: FnMut(&IS, &OS)
+    {
+        let common_mask = self.mask & other.mask;
+
+        for local_key in 0 .. (1 << BITS_PER_LEVEL) {
+            if (common_mask & (1 << local_key)) == 0 {
+                continue;
+            }
+
+            let this_entry = self.get_entry(get_index(self.mask, local_key));
+            let other_entry = other.get_entry(get_index(other.mask, local_key));
+
+            // Looks up the given item from one side in the subtree of the other side
+            let remaining_hash = |key: &K| hash_of::<K, H>(key) >> (BITS_PER_LEVEL * level);
+
+            match (this_entry, other_entry) {
+                (NodeEntryRef::SubTree(this_sub_tree), NodeEntryRef::SubTree(other_sub_tree)) => {
+                    this_sub_tree.borrow().for_each_common_item(other_sub_tree.borrow(),
+                                                                level + 1,
+                                                                f);
+                }
+                (NodeEntryRef::Item(this_kvp), _) => {
+                    if let Some(other_kvp) = other.find_item(remaining_hash(this_kvp.key()),
+                                                             level,
+                                                             this_kvp.key()) {
+                        f(this_kvp, other_kvp);
+                    }
+                }
+                (NodeEntryRef::Collision(this_items), _) => {
+                    for this_kvp in this_items.iter() {
+                        if let Some(other_kvp) = other.find_item(remaining_hash(this_kvp.key()),
+                                                                 level,
+                                                                 this_kvp.key()) {
+                            f(this_kvp, other_kvp);
+                        }
+                    }
+                }
+                (NodeEntryRef::SubTree(_), NodeEntryRef::Item(other_kvp)) => {
+                    if let Some(this_kvp) = self.find_item(remaining_hash(other_kvp.key()),
+                                                           level,
+                                                           other_kvp.key()) {
+                        f(this_kvp, other_kvp);
+                    }
+                }
+                (NodeEntryRef::SubTree(_), NodeEntryRef::Collision(other_items)) => {
+                    // Only possible if `other` is limited to fewer levels than this node's map
+                    for other_kvp in other_items.iter() {
+                        if let Some(this_kvp) = self.find_item(remaining_hash(other_kvp.key()),
+                                                               level,
+                                                               other_kvp.key()) {
+                            f(this_kvp, other_kvp);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // Merges the subtrees rooted at this node and at `other`, both of which are located at the
+    // given level, aligned node by node like `for_each_common_item()`. Entries present in only one
+    // of the nodes, and sub-trees present in only one of the nodes' sub-trees, are shared with the
+    // new trie by reference. For every key present in both, `take_other` is called with the item
+    // from this side and the one from `other`, and decides which of them to keep. `duplicates`
+    // is increased by the number of such keys.
+    fn merge_with<F>(&self,
+                     other: &UnsafeNode<K, V, IS, H>,
+                     level: usize,
+                     context: InsertContext<V>,
+                     take_other: &mut F,
+                     duplicates: &mut usize)
+                  -> NodeRef<K, V, IS, H>
+        where F: FnMut(&IS, &IS) -> bool
+    {
+        let mask = self.mask | other.mask;
+        let mut new_node_ref = UnsafeNode::alloc(mask, bit_count(mask));
+        {
+            let new_node = new_node_ref.borrow_mut();
+
+            for (index, local_key) in (0 .. 1 << BITS_PER_LEVEL).filter(|&k| mask & (1 << k) != 0).enumerate() {
+                let bit = 1u32 << local_key;
+
+                let entry = if other.mask & bit == 0 {
+                    self.get_entry(get_index(self.mask, local_key)).clone_out()
+                } else if self.mask & bit == 0 {
+                    other.get_entry(get_index(other.mask, local_key)).clone_out()
+                } else {
+                    let this_entry = self.get_entry(get_index(self.mask, local_key));
+                    let other_entry = other.get_entry(get_index(other.mask, local_key));
+
+                    match (this_entry, other_entry) {
+                        (NodeEntryRef::SubTree(this_sub_tree), NodeEntryRef::SubTree(other_sub_tree)) => {
+                            NodeEntryOwned::SubTree(
+                                this_sub_tree.borrow().merge_with(other_sub_tree.borrow(),
+                                                                  level + 1,
+                                                                  context,
+                                                                  take_other,
+                                                                  duplicates))
+                        }
+                        (this_entry, other_entry) => {
+                            UnsafeNode::merge_entries(local_key, level, this_entry, other_entry,
+                                                      context, take_other, duplicates)
+                        }
+                    }
+                };
+
+                new_node.init_entry(index, entry);
+            }
+        }
+        new_node_ref
+    }
+
+    // Merges two entries for the same local key on the given level, at least one of which is not a
+    // sub-tree, for `merge_with()`. The items of one entry are inserted into a copy of the other
+    // one, preferably a sub-tree, so that it is shared with the new trie as far as possible.
+    fn merge_entries<F>(local_key: usize,
+                        level: usize,
+                        this_entry: NodeEntryRef<K, V, IS, H>,
+                        other_entry: NodeEntryRef<K, V, IS, H>,
+                        context: InsertContext<V>,
+                        take_other: &mut F,
+                        duplicates: &mut usize)
+                     -> NodeEntryOwned<K, V, IS, H>
+        where F: FnMut(&IS, &IS) -> bool
+    {
+        let (base, inserted, base_is_this) = match (this_entry, other_entry) {
+            (this_entry, other_entry @ NodeEntryRef::SubTree(_)) => (other_entry, this_entry, false),
+            (this_entry, other_entry) => (this_entry, other_entry, true),
+        };
+
+        let inserted_items: &[IS] = match inserted {
+            NodeEntryRef::Item(kvp_ref) => slice::from_ref(kvp_ref),
+            NodeEntryRef::Collision(items) => &items[..],
+            NodeEntryRef::SubTree(_) => unreachable!(),
+        };
+
+        // A node on the given level that only holds the base entry, to insert the items into
+        let mut node_ref = UnsafeNode::alloc(1 << local_key, context.growth.initial_capacity());
+        node_ref.borrow_mut().init_entry(0, base.clone_out());
+
+        for kvp in inserted_items {
+            let hash = hash_of::<K, H>(kvp.key()) >> (BITS_PER_LEVEL * level);
+
+            let insert = match node_ref.borrow().find_item(hash, level, kvp.key()) {
+                Some(existing) => {
+                    *duplicates += 1;
+
+                    if base_is_this {
+                        take_other(existing, kvp)
+                    } else {
+                        !take_other(kvp, existing)
+                    }
+                }
+                None => true,
+            };
+
+            if insert {
+                let mut insertion_count = 0;
+                let mut replaced = None;
+                let copy = node_ref.borrow_mut().try_insert_in_place(hash,
+                                                                     level,
+                                                                     kvp.clone(),
+                                                                     &mut insertion_count,
+                                                                     &mut replaced,
+                                                                     context);
+                if let Some(copy) = copy {
+                    node_ref = copy;
+                }
+            }
+        }
+
+        let entry = node_ref.borrow().get_entry(0).clone_out();
+        entry
+    }
+
+    // Finds the entry holding everything below the given hash prefix, which covers `levels`
+    // levels starting at the root, and returns a copy of it together with its item count. The
+    // returned entry belongs into a node on level `levels - 1`, unless it is a single item, which
+    // may have been found further up the trie.
+    fn prefix_entry(&self, prefix: u64, level: usize, levels: usize)
+        -> Option<(NodeEntryOwned<K, V, IS, H>, usize)>
+    {
+        debug_assert!(level < levels && levels <= LAST_LEVEL + 1);
+        let local_key = ((prefix >> (BITS_PER_LEVEL * level)) & LEVEL_BIT_MASK) as usize;
+
+        if (self.mask & (1 << local_key)) == 0 {
+            return None;
+        }
+
+        let prefix_mask = (1u64 << (BITS_PER_LEVEL * levels)) - 1;
+
+        match self.get_entry(get_index(self.mask, local_key)) {
+            NodeEntryRef::Item(kvp_ref) => {
+                if (hash_of::<K, H>(kvp_ref.key()) & prefix_mask) == (prefix & prefix_mask) {
+                    Some((NodeEntryOwned::Item(kvp_ref.clone()), 1))
+                } else {
+                    None
+                }
+            }
+            NodeEntryRef::Collision(items) if level + 1 == levels => {
+                Some((NodeEntryOwned::Collision(items.clone()), items.len()))
+            }
+            NodeEntryRef::Collision(items) => {
+                // A collision entry above the last level (see `HamtMap::with_max_levels()`) may
+                // hold items with different prefixes
+                let mut matching: Vec<IS> = items.iter().filter(|kvp| {
+                    (hash_of::<K, H>(kvp.key()) & prefix_mask) == (prefix & prefix_mask)
+                }).cloned().collect();
+
+                match matching.len() {
+                    0 => None,
+                    1 => Some((NodeEntryOwned::Item(matching.pop().unwrap()), 1)),
+                    item_count => Some((NodeEntryOwned::Collision(Arc::new(matching)), item_count)),
+                }
+            }
+            NodeEntryRef::SubTree(sub_tree_ref) => {
+                if level + 1 == levels {
+                    let item_count = sub_tree_ref.borrow().item_count();
+                    Some((NodeEntryOwned::SubTree(sub_tree_ref.clone()), item_count))
+                } else {
+                    sub_tree_ref.borrow().prefix_entry(prefix, level + 1, levels)
+                }
+            }
+        }
+    }
+
+    // Calls `f` for every item stored in the subtree rooted at this node.
+    fn for_each_item<F>(&self, f: &mut F) where F: FnMut(&IS) {
+        for index in 0 .. self.entry_count() {
+            match self.get_entry(index) {
+                NodeEntryRef::Item(kvp_ref) => f(kvp_ref),
+                NodeEntryRef::Collision(items) => {
+                    for kvp in items.iter() {
+                        f(kvp);
+                    }
+                }
+                NodeEntryRef::SubTree(sub_tree_ref) => sub_tree_ref.borrow().for_each_item(f),
+            }
+        }
+    }
+
+    // Calls `f` with the items of every collision entry in the subtree rooted at this node.
+    fn for_each_collision<F>(&self, f: &mut F) where F: FnMut(&[IS]) {
+        for index in 0 .. self.entry_count() {
+            match self.get_entry(index) {
+                NodeEntryRef::Item(_) => { /* nothing to do */ }
+                NodeEntryRef::Collision(items) => f(&items[..]),
+                NodeEntryRef::SubTree(sub_tree_ref) => sub_tree_ref.borrow().for_each_collision(f),
+            }
+        }
+    }
+
+    // Splits the sub-tree rooted at this node into the items at trie positions (see
+    // `trie_order()`) below `pivot` and the remaining ones. Entries lying entirely on one side of
+    // the pivot are shared with this node, only the path towards the pivot is split up. Both
+    // resulting nodes contain exactly the entries they need and either of them may be empty.
+    fn split_at_position(&self, pivot: u64, level: usize) -> NodeRefPair<K, V, IS, H> {
+        debug_assert!(pivot < 1 << (BITS_PER_LEVEL * (LAST_LEVEL + 1)));
+        let shift = BITS_PER_LEVEL * (LAST_LEVEL - level);
+        let pivot_key = ((pivot >> shift) & LEVEL_BIT_MASK) as usize;
+        // If the pivot is the first position of the entry at `pivot_key`, that entry doesn't
+        // need to be split up
+        let pivot_is_aligned = (pivot & ((1 << shift) - 1)) == 0;
+        let mut below = Vec::new();
+        let mut rest = Vec::new();
+
+        for local_key in 0 .. 1 << BITS_PER_LEVEL {
+            if (self.mask & (1 << local_key)) == 0 {
+                continue;
+            }
+
+            let entry = self.get_entry(get_index(self.mask, local_key));
+
+            if local_key < pivot_key {
+                below.push((local_key, entry.clone_out()));
+            } else if local_key > pivot_key || pivot_is_aligned {
+                rest.push((local_key, entry.clone_out()));
+            } else {
+                match entry {
+                    NodeEntryRef::Item(kvp_ref) => {
+                        if trie_order(hash_of::<K, H>(kvp_ref.key())) < pivot {
+                            below.push((local_key, entry.clone_out()));
+                        } else {
+                            rest.push((local_key, entry.clone_out()));
+                        }
+                    }
+                    // Collision entries on the last level cover a single position, so they are
+                    // always aligned. Those further up (see `HamtMap::with_max_levels()`) may
+                    // have to be split up.
+                    NodeEntryRef::Collision(items) => {
+                        let (items_below, items_rest): (Vec<IS>, Vec<IS>) =
+                            items.iter().cloned().partition(|kvp| {
+                                trie_order(hash_of::<K, H>(kvp.key())) < pivot
+                            });
+
+                        for (side, mut side_items) in [(&mut below, items_below),
+                                                       (&mut rest, items_rest)] {
+                            let new_entry = match side_items.len() {
+                                0 => continue,
+                                1 => NodeEntryOwned::Item(side_items.pop().unwrap()),
+                                _ => NodeEntryOwned::Collision(Arc::new(side_items)),
+                            };
+                            side.push((local_key, new_entry));
+                        }
+                    }
+                    NodeEntryRef::SubTree(sub_tree_ref) => {
+                        let (sub_below, sub_rest) =
+                            sub_tree_ref.borrow().split_at_position(pivot, level + 1);
+
+                        for (side, sub_tree) in [(&mut below, sub_below), (&mut rest, sub_rest)] {
+                            // Sub-trees left with a single item are replaced by that item
+                            let new_entry = match sub_tree.borrow().item_count() {
+                                0 => continue,
+                                1 => NodeEntryOwned::Item(sub_tree.borrow().nth_item(0).clone()),
+                                _ => NodeEntryOwned::SubTree(sub_tree.clone()),
+                            };
+                            side.push((local_key, new_entry));
+                        }
+                    }
+                }
+            }
+        }
+
+        let build = |entries: Vec<(usize, NodeEntryOwned<K, V, IS, H>)>| {
+            let mask = entries.iter().fold(0u32, |mask, &(local_key, _)| mask | (1 << local_key));
+            let mut node_ref = UnsafeNode::alloc(mask, entries.len());
+            {
+                let node = node_ref.borrow_mut();
+                for (index, (_, entry)) in entries.into_iter().enumerate() {
+                    node.init_entry(index, entry);
+                }
+            }
+            node_ref
+        };
+
+        (build(below), build(rest))
+    }
+
+    // The number of distinct hash prefixes of `levels` levels among the items below this node,
+    // which is on the given level. Items and collision entries on the last level account for a
+    // single prefix each, so only sub-trees above `levels` have to be visited.
+    fn populated_prefix_count(&self, level: usize, levels: usize) -> usize {
+        debug_assert!(level < levels);
+
+        (0 .. self.entry_count()).map(|index| {
+            match self.get_entry(index) {
+                NodeEntryRef::SubTree(sub_tree_ref) if level + 1 < levels => {
+                    sub_tree_ref.borrow().populated_prefix_count(level + 1, levels)
+                }
+                // Only collision entries above the last level can hold several prefixes
+                NodeEntryRef::Collision(items) if level + 1 < levels => {
+                    let prefix_mask = (1u64 << (BITS_PER_LEVEL * levels)) - 1;
+                    let mut prefixes: Vec<u64> = items.iter().map(|kvp| {
+                        hash_of::<K, H>(kvp.key()) & prefix_mask
+                    }).collect();
+                    prefixes.sort_unstable();
+                    prefixes.dedup();
+                    prefixes.len()
+                }
+                _ => 1,
+            }
+        }).sum()
+    }
+
+    // The number of items stored in the entry with the given index, including everything below it.
+    fn entry_item_count(&self, index: usize) -> usize {
+        match self.get_entry(index) {
+            NodeEntryRef::Item(_) => 1,
+            NodeEntryRef::Collision(items) => items.len(),
+            NodeEntryRef::SubTree(sub_tree_ref) => sub_tree_ref.borrow().item_count(),
+        }
+    }
+
+    // The number of items stored in the subtree rooted at this node.
+    fn item_count(&self) -> usize {
+        self.subtree_size
+    }
+
+    // Returns the item with the given index in iteration order. Sub-trees are skipped as a whole
+    // by their size, so this only descends along the path down to the item.
+    fn nth_item(&self, mut index: usize) -> &IS {
+        debug_assert!(index < self.item_count());
+        let mut current_node = self;
+
+        loop {
+            let mut next_node = None;
+
+            for entry_index in 0 .. current_node.entry_count() {
+                let item_count = current_node.entry_item_count(entry_index);
+
+                if index >= item_count {
+                    index -= item_count;
+                    continue;
+                }
+
+                match current_node.get_entry(entry_index) {
+                    NodeEntryRef::Item(kvp_ref) => return kvp_ref,
+                    NodeEntryRef::Collision(items) => return &items[index],
+                    NodeEntryRef::SubTree(sub_tree_ref) => next_node = Some(sub_tree_ref.borrow()),
+                }
+                break;
+            }
+
+            // All modifications keep the subtree sizes up-to-date, so this can only fail if they
+            // are out of sync. Rather than panicking, fall back to the last item below this node.
+            debug_assert!(next_node.is_some(), "subtree size out of sync");
+            current_node = match next_node {
+                Some(node) => node,
+                None => return current_node.last_item(),
+            };
+        }
+    }
+
+    // Returns the last item in iteration order of the subtree rooted at this node, which must not
+    // be empty.
+    fn last_item(&self) -> &IS {
+        let mut current_node = self;
+
+        loop {
+            match current_node.get_entry(current_node.entry_count() - 1) {
+                NodeEntryRef::Item(kvp_ref) => return kvp_ref,
+                NodeEntryRef::Collision(items) => return &items[items.len() - 1],
+                NodeEntryRef::SubTree(sub_tree_ref) => current_node = sub_tree_ref.borrow(),
+            }
+        }
+    }
+
+    // The number of bytes allocated for this node and everything below it, including the item
+    // vectors of collision entries.
+    fn memory_footprint(&self) -> usize {
+        let mut size = UnsafeNode::<K, V, IS, H>::alloc_size(self.capacity as usize);
+
+        for index in 0 .. self.entry_count() {
+            match self.get_entry(index) {
+                NodeEntryRef::Item(_) => { /* stored inline */ }
+                NodeEntryRef::Collision(items) => {
+                    size += items.capacity() * mem::size_of::<IS>();
+                }
+                NodeEntryRef::SubTree(sub_tree_ref) => {
+                    size += sub_tree_ref.borrow().memory_footprint();
+                }
+            }
+        }
+
+        size
+    }
+
+    // Adds the numbers of nodes and of allocated and used entry slots of the subtree rooted at
+    // this node to `stats`.
+    fn collect_capacity_stats(&self, stats: &mut CapacityStats) {
+        stats.node_count += 1;
+        stats.allocated_slots += self.capacity as usize;
+        stats.used_slots += self.entry_count();
+
+        for index in 0 .. self.entry_count() {
+            if let NodeEntryRef::SubTree(sub_tree_ref) = self.get_entry(index) {
+                sub_tree_ref.borrow().collect_capacity_stats(stats);
+            }
+        }
+    }
+
+    // The number of nodes in the subtree rooted at this node, including this node itself.
+    fn node_count(&self) -> usize {
+        let mut count = 1;
+
+        for index in 0 .. self.entry_count() {
+            if let NodeEntryRef::SubTree(sub_tree_ref) = self.get_entry(index) {
+                count += sub_tree_ref.borrow().node_count();
+            }
+        }
+
+        count
+    }
+
+    // Appends the addresses of this node and all nodes below it to `pointers`, in pre-order.
+    fn collect_node_pointers(&self, pointers: &mut Vec<usize>) {
+        pointers.push(self as *const UnsafeNode<K, V, IS, H> as usize);
+
+        for index in 0 .. self.entry_count() {
+            if let NodeEntryRef::SubTree(sub_tree_ref) = self.get_entry(index) {
+                sub_tree_ref.borrow().collect_node_pointers(pointers);
+            }
+        }
+    }
+
+    // Returns a copy of the subtree rooted at this node in which every sub-tree holding nothing
+    // but a single item has been replaced by that item, or `None` if there is no such sub-tree.
+    // Sub-trees are handled bottom-up, so whole chains of single-entry nodes collapse at once.
+    fn normalized(&self) -> Option<NodeRef<K, V, IS, H>> {
+        let entry_count = self.entry_count();
+        let mut new_entries: Vec<Option<NodeEntryOwned<K, V, IS, H>>> = Vec::with_capacity(entry_count);
+        let mut changed = false;
+
+        for index in 0 .. entry_count {
+            let new_entry = match self.get_entry(index) {
+                NodeEntryRef::SubTree(sub_tree_ref) => {
+                    let normalized_sub_tree = sub_tree_ref.borrow().normalized();
+                    let single_item = {
+                        let sub_tree = normalized_sub_tree.as_ref().unwrap_or(sub_tree_ref).borrow();
+
+                        match sub_tree.get_entry(0) {
+                            NodeEntryRef::Item(kvp_ref) if sub_tree.entry_count() == 1 => {
+                                Some(kvp_ref.clone())
+                            }
+                            _ => None,
+                        }
+                    };
+
+                    match (single_item, normalized_sub_tree) {
+                        (Some(kvp), _) => Some(NodeEntryOwned::Item(kvp)),
+                        (None, Some(sub_tree)) => Some(NodeEntryOwned::SubTree(sub_tree)),
+                        (None, None) => None,
+                    }
+                }
+                _ => None,
+            };
+
+            changed |= new_entry.is_some();
+            new_entries.push(new_entry);
+        }
+
+        if !changed {
+            return None;
+        }
+
+        let mut new_node_ref = UnsafeNode::alloc(self.mask, self.capacity as usize);
+        {
+            let new_node = new_node_ref.borrow_mut();
+
+            for (index, new_entry) in new_entries.into_iter().enumerate() {
+                let entry = new_entry.unwrap_or_else(|| self.get_entry(index).clone_out());
+                new_node.init_entry(index, entry);
+            }
+        }
+        Some(new_node_ref)
+    }
+
+    // Returns a copy of the subtree rooted at this node in which every collision entry holding
+    // just a single item has been replaced by a single-item entry, or `None` if there is no such
+    // collision entry.
+    fn compacted_collisions(&self) -> Option<NodeRef<K, V, IS, H>> {
+        let entry_count = self.entry_count();
+        let mut new_entries: Vec<Option<NodeEntryOwned<K, V, IS, H>>> = Vec::with_capacity(entry_count);
+        let mut changed = false;
+
+        for index in 0 .. entry_count {
+            let new_entry = match self.get_entry(index) {
+                NodeEntryRef::Collision(items) if items.len() == 1 => {
+                    Some(NodeEntryOwned::Item(items[0].clone()))
+                }
+                NodeEntryRef::SubTree(sub_tree_ref) => {
+                    sub_tree_ref.borrow().compacted_collisions().map(NodeEntryOwned::SubTree)
+                }
+                _ => None,
+            };
+
+            changed |= new_entry.is_some();
+            new_entries.push(new_entry);
+        }
+
+        if !changed {
+            return None;
+        }
+
+        let mut new_node_ref = UnsafeNode::alloc(self.mask, self.capacity as usize);
+        {
+            let new_node = new_node_ref.borrow_mut();
+
+            for (index, new_entry) in new_entries.into_iter().enumerate() {
+                let entry = new_entry.unwrap_or_else(|| self.get_entry(index).clone_out());
+                new_node.init_entry(index, entry);
+            }
+        }
+        Some(new_node_ref)
+    }
+
+    // Returns true if there is a collision entry holding less than two items anywhere in the
+    // subtree rooted at this node.
+    fn has_short_collisions(&self) -> bool {
+        (0 .. self.entry_count()).any(|index| {
+            match self.get_entry(index) {
+                NodeEntryRef::Item(_) => false,
+                NodeEntryRef::Collision(items) => items.len() < 2,
+                NodeEntryRef::SubTree(sub_tree_ref) => sub_tree_ref.borrow().has_short_collisions(),
+            }
+        })
+    }
 }
 
-impl<'a, K, V, IS, H> Clone for IterNodeRef<'a, K, V, IS, H>
-    where K: 'a,
-          V: 'a,
-          IS: 'a,
-          H: 'a
+
+
+//=-------------------------------------------------------------------------------------------------
+// GrowthPolicy
+//=-------------------------------------------------------------------------------------------------
+/// Determines how many entries a node has room for when it is allocated. Nodes are copied and
+/// re-allocated whenever they run out of room, so larger nodes make fewer copies necessary when
+/// modifying a map in-place, while smaller nodes are cheaper to copy when modifying a shared map
+/// and use less memory.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum GrowthPolicy {
+    /// Nodes start out with room for 4 entries, which is doubled each time they run out of room,
+    /// up to the maximum of 32 entries. This is the default.
+    #[default]
+    Doubling,
+    /// Nodes start out with room for 4 entries and get room for 4 more each time they run out of
+    /// room.
+    Linear,
+    /// Nodes have room for the given number of entries (at most 32). Nodes that need more room
+    /// get exactly as much as they need. `Fixed(32)` makes every node as big as possible,
+    /// `Fixed(0)` makes every node as small as possible.
+    Fixed(usize),
+}
+
+impl GrowthPolicy {
+    // The capacity of a newly created node holding two entries.
+    fn initial_capacity(self) -> usize {
+        match self {
+            GrowthPolicy::Doubling | GrowthPolicy::Linear => MIN_CAPACITY,
+            GrowthPolicy::Fixed(fixed) => fixed.clamp(2, 1 << BITS_PER_LEVEL),
+        }
+    }
+
+    // The capacity of the copy of a node with the given capacity and entry count, which has to
+    // hold one more entry than the node.
+    fn expanded_capacity(self, capacity: usize, entry_count: usize) -> usize {
+        let expanded = match self {
+            GrowthPolicy::Doubling if capacity == 0 => MIN_CAPACITY,
+            GrowthPolicy::Doubling => capacity * 2,
+            GrowthPolicy::Linear => capacity + MIN_CAPACITY,
+            GrowthPolicy::Fixed(fixed) => cmp::max(fixed, entry_count + 1),
+        };
+
+        cmp::min(expanded, 1 << BITS_PER_LEVEL)
+    }
+}
+
+
+
+//=-------------------------------------------------------------------------------------------------
+// CollisionPolicy
+//=-------------------------------------------------------------------------------------------------
+/// Determines what happens when a key stored in a collision entry, i.e. a key whose hash value
+/// is equal to that of at least one other key in the map, is inserted again. Keys stored anywhere
+/// else always have their value replaced. Multimap-like structures built on top of a map can use
+/// this to handle fully colliding keys on their own terms.
+#[derive(Default)]
+pub enum CollisionPolicy<V> {
+    /// The new value replaces the existing one. This is the default.
+    #[default]
+    Replace,
+    /// The existing value is kept and the new one is dropped.
+    KeepExisting,
+    /// The value is replaced by the result of calling the function with the existing and the new
+    /// value. If the new key-value pair is shared with another map, which can happen when entries
+    /// are moved over from other maps, it can't be taken apart and simply replaces the existing
+    /// one.
+    Combine(CombineFn<V>),
+}
+
+// The function combining the existing and the new value for `CollisionPolicy::Combine`.
+type CombineFn<V> = Arc<dyn Fn(&V, V) -> V + Send + Sync>;
+
+// A borrowed function combining the existing and the new value, see `insert_or_combine()`.
+type CombineRef<'a, V> = &'a dyn Fn(&V, V) -> V;
+
+impl<V> CollisionPolicy<V> {
+    // Returns the item to store for a key that is already stored in a collision entry.
+    fn resolve<K, IS: ItemStore<K, V>>(&self, existing_kvp: &IS, new_kvp: IS) -> IS {
+        match *self {
+            CollisionPolicy::Replace => new_kvp,
+            CollisionPolicy::KeepExisting => existing_kvp.clone(),
+            CollisionPolicy::Combine(ref combine) => {
+                combine_items(&**combine, existing_kvp, new_kvp)
+            }
+        }
+    }
+}
+
+// Returns an item holding the result of calling `combine` with the values of the two given items.
+// If the new item is shared with another map, it can't be taken apart and is returned as is.
+fn combine_items<K, V, IS>(combine: CombineRef<V>, existing_kvp: &IS, new_kvp: IS) -> IS
+    where IS: ItemStore<K, V>
 {
-    fn clone(&self) -> Self {
+    match new_kvp.try_into_pair() {
+        Ok((key, value)) => IS::new(key, combine(existing_kvp.val(), value)),
+        Err(new_kvp) => new_kvp,
+    }
+}
+
+impl<V> Clone for CollisionPolicy<V> {
+    fn clone(&self) -> CollisionPolicy<V> {
         match *self {
-            IterNodeRef::RegularNode(x) => IterNodeRef::RegularNode(x),
-            IterNodeRef::CollisionEntry(x) => IterNodeRef::CollisionEntry(x)
+            CollisionPolicy::Replace => CollisionPolicy::Replace,
+            CollisionPolicy::KeepExisting => CollisionPolicy::KeepExisting,
+            CollisionPolicy::Combine(ref combine) => CollisionPolicy::Combine(combine.clone()),
         }
     }
-}
+}
+
+
+
+
+//=-------------------------------------------------------------------------------------------------
+// StructuralObserver
+//=-------------------------------------------------------------------------------------------------
+/// Receives an event for every node on the path that an `insert()` or `remove()` (or any of the
+/// operations built on them, like `plus()` and `minus()`) changes, telling whether the node has
+/// been copied because it is shared with other maps, or whether it has been modified in-place.
+/// This allows measuring how much copying structural sharing causes in a real workload. Install
+/// an observer with `HamtMap::observe()`. Without one, no events are generated at all.
+pub trait StructuralObserver: Send+Sync {
+    /// Called when a node at the given level of the trie (0 being the root) has been copied.
+    fn on_copy(&self, level: usize);
+
+    /// Called when a node at the given level of the trie has been modified in-place.
+    fn on_reuse(&self, level: usize);
+}
+
+
+
+//=-------------------------------------------------------------------------------------------------
+// CapacityStats
+//=-------------------------------------------------------------------------------------------------
+/// How well the memory allocated for the nodes of a map is used, as returned by
+/// `HamtMap::capacity_stats()`. Every node has room for a number of entries (slots), some of which
+/// may be unused. Lots of wasted slots, e.g. after many removals, are a reason to `compact()` the
+/// map. Like `memory_footprint()`, nodes shared with other maps are counted in full.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CapacityStats {
+    /// The number of nodes, including the root node.
+    pub node_count: usize,
+    /// The number of entry slots allocated across all nodes.
+    pub allocated_slots: usize,
+    /// The number of entry slots holding an entry. A collision entry takes up a single slot.
+    pub used_slots: usize,
+    /// The number of allocated but unused entry slots.
+    pub wasted_slots: usize,
+    /// The number of bytes taken up by the unused entry slots.
+    pub wasted_bytes: usize,
+    /// The total number of bytes allocated for the nodes, see `HamtMap::memory_footprint()`.
+    pub memory_footprint: usize,
+}
+
+impl fmt::Display for CapacityStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let wasted_percent = if self.allocated_slots == 0 {
+            0.0
+        } else {
+            100.0 * self.wasted_slots as f64 / self.allocated_slots as f64
+        };
+
+        write!(f,
+               "{} nodes, {} of {} entry slots used ({} wasted, {:.1}%), {} of {} bytes wasted",
+               self.node_count,
+               self.used_slots,
+               self.allocated_slots,
+               self.wasted_slots,
+               wasted_percent,
+               self.wasted_bytes,
+               self.memory_footprint)
+    }
+}
+
+
+//=-------------------------------------------------------------------------------------------------
+// CorruptionError
+//=-------------------------------------------------------------------------------------------------
+/// A violated structural invariant of the trie, as detected by `HamtMap::try_find()`. None of these
+/// can occur unless the memory of a node has been corrupted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CorruptionError {
+    /// An entry is tagged with a type code that doesn't denote any entry type.
+    InvalidEntryType { level: usize, code: usize },
+    /// A node has more entries than it has room for.
+    EntryOutOfBounds { level: usize, index: usize, capacity: usize },
+    /// A node on the last level of the trie (see `HamtMap::max_levels()`) refers to yet another
+    /// subtree.
+    LevelOverflow { level: usize },
+    /// A collision entry is stored above the last level of the trie.
+    MisplacedCollision { level: usize },
+}
+
+impl fmt::Display for CorruptionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CorruptionError::InvalidEntryType { level, code } => {
+                write!(f, "invalid entry type code {} on level {}", code, level)
+            }
+            CorruptionError::EntryOutOfBounds { level, index, capacity } => {
+                write!(f, "entry {} out of bounds of node with capacity {} on level {}",
+                       index, capacity, level)
+            }
+            CorruptionError::LevelOverflow { level } => {
+                write!(f, "subtree below the last level (level {})", level)
+            }
+            CorruptionError::MisplacedCollision { level } => {
+                write!(f, "collision entry above the last level (level {})", level)
+            }
+        }
+    }
+}
+
+impl ::std::error::Error for CorruptionError {}
+
+
+//=-------------------------------------------------------------------------------------------------
+// HamtMap
+//=-------------------------------------------------------------------------------------------------
+pub struct HamtMap<K, V, IS=ShareStore<K,V>, H=StdHasher> {
+    root: NodeRef<K, V, IS, H>,
+    element_count: usize,
+    growth: GrowthPolicy,
+    observer: Option<Arc<dyn StructuralObserver>>,
+    collision: CollisionPolicy<V>,
+    // The deepest level sub-trees are created on, see `with_max_levels()`
+    last_level: usize,
+}
+
+// Trie geometry
+impl<K, V, IS, H> HamtMap<K, V, IS, H> {
+    /// The number of hash bits consumed per trie level.
+    pub const BITS_PER_LEVEL: usize = BITS_PER_LEVEL;
+    /// The maximum number of entries a single node can hold.
+    pub const BRANCHING_FACTOR: usize = 1 << BITS_PER_LEVEL;
+    /// The maximum number of levels of the trie, including the root. Keys whose hashes are still
+    /// equal at the last level end up in collision entries.
+    pub const MAX_DEPTH: usize = LAST_LEVEL + 1;
+}
+
+// impl HamtMap
+impl<K, V, IS, H> HamtMap<K, V, IS, H>
+    where K: Eq+Send+Sync+Hash,
+          V: Send+Sync,
+          IS: ItemStore<K, V>,
+          H: Hasher+Default
+{
+    pub fn new() -> HamtMap<K, V, IS, H> {
+        HamtMap::with_growth_policy(GrowthPolicy::default())
+    }
+
+    /// Creates an empty map whose nodes are allocated according to the given growth policy. The
+    /// policy is kept by all maps derived from this one.
+    pub fn with_growth_policy(growth: GrowthPolicy) -> HamtMap<K, V, IS, H> {
+        HamtMap {
+            root: UnsafeNode::alloc(0, 0),
+            element_count: 0,
+            growth,
+            observer: None,
+            collision: CollisionPolicy::default(),
+            last_level: LAST_LEVEL
+        }
+    }
+
+    /// Returns the growth policy nodes of this map are allocated with.
+    pub fn growth_policy(&self) -> GrowthPolicy {
+        self.growth
+    }
+
+    /// Creates an empty map whose trie has at most `max_levels` levels, including the root node.
+    /// Keys whose hash values are still equal on the last of these levels end up in collision
+    /// entries, which are searched linearly, instead of in further sub-trees. This trades longer
+    /// scans through collision entries for a guaranteed maximum number of nodes visited per
+    /// lookup, which makes the latency of lookups more predictable. Values above `MAX_DEPTH`
+    /// have no effect. The limit is kept by all maps derived from this one.
+    pub fn with_max_levels(max_levels: usize) -> HamtMap<K, V, IS, H> {
+        assert!(max_levels > 0);
+        HamtMap { last_level: cmp::min(max_levels, LAST_LEVEL + 1) - 1, ..HamtMap::new() }
+    }
+
+    /// Returns the maximum number of levels of this map's trie, see `with_max_levels()`.
+    pub fn max_levels(&self) -> usize {
+        self.last_level + 1
+    }
+
+    /// Sets the policy for re-inserting keys stored in collision entries, see `CollisionPolicy`.
+    /// Like the growth policy, the collision policy is kept by all maps derived from this one.
+    pub fn set_collision_policy(self, collision: CollisionPolicy<V>) -> HamtMap<K, V, IS, H> {
+        HamtMap { collision, ..self }
+    }
+
+    /// Returns the policy for re-inserting keys stored in collision entries.
+    pub fn collision_policy(&self) -> &CollisionPolicy<V> {
+        &self.collision
+    }
+
+    /// Installs an observer that is notified about the nodes copied or modified in-place by
+    /// updates of this map, see `StructuralObserver`. Like the growth policy, the observer is kept
+    /// by all maps derived from this one.
+    pub fn observe(self, observer: Arc<dyn StructuralObserver>) -> HamtMap<K, V, IS, H> {
+        HamtMap { observer: Some(observer), ..self }
+    }
+
+    /// Removes the observer installed with `observe()`, if any.
+    pub fn stop_observing(self) -> HamtMap<K, V, IS, H> {
+        HamtMap { observer: None, ..self }
+    }
+
+    /// Creates an empty map that is expected to hold about `capacity` entries. Only the root node
+    /// is allocated up-front, with room for as many entries as the given capacity will probably
+    /// occupy, so that it doesn't have to be re-allocated repeatedly while the map is filled.
+    pub fn with_capacity(capacity: usize) -> HamtMap<K, V, IS, H> {
+        HamtMap {
+            root: UnsafeNode::alloc(0, cmp::min(capacity, 1 << BITS_PER_LEVEL)),
+            element_count: 0,
+            growth: GrowthPolicy::default(),
+            observer: None,
+            collision: CollisionPolicy::default(),
+            last_level: LAST_LEVEL
+        }
+    }
+
+    /// Returns an iterator over all entries of the map, in the order in which they are stored in
+    /// the trie. The iterator knows how many entries are left, see `size_hint()`.
+    pub fn iter<'a>(&'a self) -> Entries<'a, K, V, IS, H> {
+        HamtMapIterator::new(self)
+    }
+
+    /// Returns an iterator over the entries of the map together with their depth, i.e. the level
+    /// of the trie they are stored on, 0 being the root node. A lookup visits one node per level,
+    /// so the depth tells how expensive looking up a key is. Keys sharing their whole hash value
+    /// with other keys are at depth `max_levels() - 1`.
+    pub fn iter_with_depth(&self) -> DepthEntries<'_, K, V, IS, H> {
+        DepthEntries { entries: self.iter() }
+    }
+
+    /// Returns an iterator over the keys of the map, in the same order as `iter()`.
+    pub fn keys<'a>(&'a self) -> Keys<'a, K, V, IS, H> {
+        Keys { entries: self.iter() }
+    }
+
+    /// Returns an iterator over the values of the map, in the same order as `iter()`.
+    pub fn values<'a>(&'a self) -> Values<'a, K, V, IS, H> {
+        Values { entries: self.iter() }
+    }
+
+    /// Returns an iterator continuing the iteration at the given cursor, which must have been
+    /// obtained from an iterator over this map (or over an unmodified clone of it). This allows
+    /// for pausing long traversals without keeping the iterator, and thus a borrow of the map,
+    /// around. Panics if the cursor does not match the structure of this map.
+    pub fn iter_from<'a>(&'a self, cursor: Cursor) -> HamtMapIterator<'a, K, V, IS, H> {
+        HamtMapIterator::from_cursor(self, cursor)
+    }
+
+    pub fn find<'a>(&'a self, key: &K) -> Option<&'a V> {
+        let hash = hash_of::<K, H>(key);
+        self.root.borrow().find_item(hash, 0, key).map(|kvp| kvp.val())
+    }
+
+    /// Same as `find()`, but also returns the number of nodes visited during the lookup, including
+    /// the root. This allows for profiling the cost of lookups for a particular distribution of
+    /// keys, one call at a time and without installing an observer.
+    pub fn find_with_steps<'a>(&'a self, key: &K) -> (Option<&'a V>, usize) {
+        let hash = hash_of::<K, H>(key);
+        let (item, steps) = self.root.borrow().find_item_with_steps(hash, key);
+        (item.map(|kvp| kvp.val()), steps)
+    }
+
+    /// Descends towards `key` like `find()` and folds `f` over all entries of the deepest node
+    /// reached, including those stored further down below that node. These are the entries whose
+    /// hash values share the longest prefix with the one of `key` that is used for routing, which
+    /// helps with bucketed statistics around a key. `key` itself doesn't need to be contained in
+    /// the map. Returns `None` if no entry of the map shares the first level of the hash value.
+    pub fn find_subtree_fold<B, F>(&self, key: &K, init: B, mut f: F) -> Option<B>
+        where F: FnMut(B, &K, &V) -> B
+    {
+        let hash = hash_of::<K, H>(key);
+        let node = self.root.borrow().deepest_node_on_path(hash)?;
+        let mut accumulator = Some(init);
+
+        node.for_each_item(&mut |kvp: &IS| {
+            let value = accumulator.take().unwrap();
+            accumulator = Some(f(value, kvp.key(), kvp.val()));
+        });
+
+        accumulator
+    }
+
+    /// Same as `find()`, but verifies the structure of every node on the way down to the key.
+    /// Instead of panicking (or worse) when an invariant of the trie doesn't hold, which can only
+    /// happen if the memory of the map has been corrupted, the violation is returned as an error.
+    pub fn try_find<'a>(&'a self, key: &K) -> Result<Option<&'a V>, CorruptionError> {
+        let hash = hash_of::<K, H>(key);
+        let item = self.root.borrow().try_find_item(hash, key, self.last_level)?;
+        Ok(item.map(|kvp| kvp.val()))
+    }
+
+    // Creates an empty map with the same growth policy, observer and depth limit as this one.
+    fn empty_like<L, W, LS>(&self) -> HamtMap<L, W, LS, H>
+        where L: Eq+Send+Sync+Hash,
+              W: Send+Sync,
+              LS: ItemStore<L, W>
+    {
+        HamtMap {
+            observer: self.observer.clone(),
+            last_level: self.last_level,
+            ..HamtMap::with_growth_policy(self.growth)
+        }
+    }
+
+    // Sorts items paired with their hash values into trie order, which is cheap if they already
+    // are, and drops all but the last one of multiple items with the same key.
+    fn sort_hashed_items(items: &mut Vec<(u64, Option<IS>)>) {
+        // The sort is stable, so items with equal keys stay in their original order. Ties in trie
+        // order are broken by the full hash value, keeping items with equal hashes adjacent.
+        items.sort_by_key(|&(hash, _)| (trie_order(hash), hash));
+
+        // Equal keys have equal hashes, so duplicates can only occur within runs of equal hashes
+        let mut start = 0;
+        while start < items.len() {
+            let hash = items[start].0;
+            let end = start + items[start..].iter().take_while(|item| item.0 == hash).count();
+
+            for i in start .. end {
+                let is_overwritten = {
+                    let key = items[i].1.as_ref().unwrap().key();
+                    items[i + 1 .. end].iter().any(|item| *item.1.as_ref().unwrap().key() == *key)
+                };
+
+                if is_overwritten {
+                    items[i].1 = None;
+                }
+            }
+
+            start = end;
+        }
+
+        items.retain(|item| item.1.is_some());
+    }
+
+    // Builds a map bottom-up from items paired with their hash values. Items are sorted into trie
+    // order first, which is cheap if they already are. Of multiple items with the same key, the
+    // last one wins. No sub-trees are created below `last_level`.
+    fn from_hashed_items(mut items: Vec<(u64, Option<IS>)>, last_level: usize)
+        -> HamtMap<K, V, IS, H>
+    {
+        HamtMap::<K, V, IS, H>::sort_hashed_items(&mut items);
+        HamtMap::from_trie_ordered_items(items, last_level)
+    }
+
+    // Same as `from_hashed_items()`, for items that are already sorted in trie order and have
+    // distinct keys.
+    fn from_trie_ordered_items(mut items: Vec<(u64, Option<IS>)>, last_level: usize)
+        -> HamtMap<K, V, IS, H>
+    {
+        if items.is_empty() {
+            return HamtMap { last_level, ..HamtMap::new() };
+        }
+
+        HamtMap {
+            root: UnsafeNode::from_sorted_items(&mut items[..], 0, last_level),
+            element_count: items.len(),
+            growth: GrowthPolicy::default(),
+            observer: None,
+            collision: CollisionPolicy::default(),
+            last_level
+        }
+    }
+
+    fn insert_internal(self, kvp: IS) -> (HamtMap<K, V, IS, H>, bool) {
+        self.insert_combining(kvp, None)
+    }
+
+    // Same as `insert_internal()`, but if the key is already present, its new value is the result
+    // of calling `combine` with the existing and the new value, if given.
+    fn insert_combining(self, kvp: IS, combine: Option<CombineRef<V>>)
+        -> (HamtMap<K, V, IS, H>, bool)
+    {
+        let hash = hash_of::<K, H>(kvp.key());
+        self.insert_hashed(hash, kvp, combine)
+    }
+
+    // Same as `insert_combining()`, for callers that already know the hash value of the key.
+    fn insert_hashed(self, hash: u64, kvp: IS, combine: Option<CombineRef<V>>)
+        -> (HamtMap<K, V, IS, H>, bool)
+    {
+        self.insert_hashed_replacing(hash, kvp, combine, None)
+    }
+
+    // Same as `insert_hashed()`, but hands back the item replaced by the insertion through
+    // `replaced`, if given. Nodes not shared with any other map are modified in-place, in which
+    // case the replaced item is moved out of the trie.
+    fn insert_hashed_replacing(self,
+                               hash: u64,
+                               kvp: IS,
+                               combine: Option<CombineRef<V>>,
+                               replaced: Option<&mut Option<IS>>)
+        -> (HamtMap<K, V, IS, H>, bool)
+    {
+        let HamtMap { mut root, element_count, growth, observer, collision, last_level } = self;
+        let mut insertion_count = 0xdeadbeaf;
+        let mut replaced_kvp = None;
+
+        let context = InsertContext {
+            growth,
+            observer: observer.as_deref(),
+            collision: &collision,
+            last_level,
+            combine,
+            hand_back_replaced: replaced.is_some()
+        };
+
+        // If we hold the only reference to the root node, then try to insert the KVP in-place
+        let new_root = match root.try_borrow_owned() {
+            BorrowedNodeRef::Exclusive(mutable) => {
+                mutable.try_insert_in_place(hash, 0, kvp, &mut insertion_count, &mut replaced_kvp,
+                                            context)
+            }
+            BorrowedNodeRef::Shared(immutable) => {
+                Some(immutable.insert(hash, 0, kvp, &mut insertion_count, &mut replaced_kvp,
+                                      context))
+            }
+        };
+
+        // Make sure that insertion_count was set properly
+        debug_assert!(insertion_count != 0xdeadbeaf);
+
+        if let Some(replaced) = replaced {
+            *replaced = replaced_kvp;
+        }
+
+        let map = HamtMap {
+            root: new_root.unwrap_or(root),
+            element_count: element_count + insertion_count,
+            growth,
+            observer,
+            collision,
+            last_level
+        };
+
+        map.check_element_count();
+        (map, insertion_count != 0)
+    }
+
+    // With debug assertions enabled, compares the element count to the number of items actually
+    // stored in the trie every now and then, to catch insertion or removal counts that have been
+    // set wrongly. A check takes time linear in the size of the map, so larger maps are checked
+    // less often, keeping the cost per operation constant on average.
+    #[cfg(debug_assertions)]
+    fn check_element_count(&self) {
+        use std::cell::Cell;
+        thread_local!(static OPERATIONS_UNTIL_CHECK: Cell<usize> = const { Cell::new(0) });
+
+        let check = OPERATIONS_UNTIL_CHECK.with(|countdown| {
+            match countdown.get() {
+                0 => {
+                    countdown.set(cmp::max(self.element_count, 64));
+                    true
+                }
+                remaining => {
+                    countdown.set(remaining - 1);
+                    false
+                }
+            }
+        });
+
+        if check {
+            let mut item_count = 0;
+            self.root.borrow().for_each_item(&mut |_: &IS| item_count += 1);
+
+            assert_eq!(item_count, self.element_count);
+            assert_eq!(self.root.borrow().item_count(), self.element_count);
+        }
+    }
+
+    #[cfg(not(debug_assertions))]
+    fn check_element_count(&self) {}
+
+    // Removes the item with the given key and hands it back. Nodes not shared with any other map
+    // are modified in-place, in which case the item is moved out of the trie.
+    fn remove_item(self, key: &K) -> (HamtMap<K, V, IS, H>, Option<IS>) {
+        let hash = hash_of::<K, H>(key);
+        self.remove_target(hash, RemovalTarget::Key(key))
+    }
+
+    // Same as `remove_item()`, but removes the first item found by traversal.
+    fn remove_first_item(self) -> (HamtMap<K, V, IS, H>, Option<IS>) {
+        self.remove_target(0, RemovalTarget::First)
+    }
+
+    fn remove_target(self, hash: u64, target: RemovalTarget<K>)
+        -> (HamtMap<K, V, IS, H>, Option<IS>)
+    {
+        let HamtMap { mut root, element_count, growth, observer, collision, last_level } = self;
+        let mut removed = None;
+
+        let removal_result = match root.try_borrow_owned() {
+            BorrowedNodeRef::Shared(node_ref) => {
+                node_ref.remove(hash, 0, target, &mut removed, growth, observer.as_deref())
+            }
+            BorrowedNodeRef::Exclusive(node_ref) => {
+                node_ref.remove_in_place(hash, 0, target, &mut removed, growth,
+                                         observer.as_deref())
+            }
+        };
+        let new_element_count = element_count - removed.is_some() as usize;
+
+        // Note that a root node ending up with a single sub-tree entry is kept as is, even though
+        // that costs one additional hop per lookup. The sub-tree can't be adopted as the new root,
+        // because its local keys are taken from the next five bits of the hash value. Promoting it
+        // would require rebuilding the whole sub-tree.
+        let map = match removal_result {
+            RemovalResult::NoChange => HamtMap {
+                root: root,
+                element_count: new_element_count,
+                growth,
+                observer,
+                collision,
+                last_level
+            },
+            RemovalResult::ReplaceSubTree(new_root) => HamtMap {
+                root: new_root,
+                element_count: new_element_count,
+                growth,
+                observer,
+                collision,
+                last_level
+            },
+            RemovalResult::CollapseSubTree(kvp) => {
+                // Either the root held the remaining item next to the removed one, or its only
+                // entry was a sub-tree that has been collapsed into the remaining item
+                debug_assert!(bit_count(root.borrow().mask) <= 2);
+                let local_key = (hash_of::<K, H>(kvp.key()) & LEVEL_BIT_MASK) as usize;
+
+                let mask = 1 << local_key;
+                let mut new_root_ref = UnsafeNode::alloc(mask, growth.initial_capacity());
+                {
+                    let root = new_root_ref.borrow_mut();
+                    root.init_entry(0, NodeEntryOwned::Item(kvp));
+                }
+                HamtMap {
+                    root: new_root_ref,
+                    element_count: new_element_count,
+                    growth,
+                    observer,
+                    collision,
+                    last_level
+                }
+            }
+            RemovalResult::KillSubTree => {
+                debug_assert!(bit_count(root.borrow().mask) == 1);
+                HamtMap { observer, collision, last_level, ..HamtMap::with_growth_policy(growth) }
+            }
+        };
+
+        map.check_element_count();
+        (map, removed)
+    }
+
+    fn modify_internal<F>(self, key: &K, f: &mut F) -> HamtMap<K, V, IS, H>
+        where F: FnMut(&IS) -> IS
+    {
+        let HamtMap { mut root, element_count, growth, observer, collision, last_level } = self;
+        let hash = hash_of::<K, H>(key);
+
+        // If we hold the only reference to the root node, then modify the tree in-place
+        let new_root = match root.try_borrow_owned() {
+            BorrowedNodeRef::Exclusive(mutable) => {
+                mutable.modify_in_place(hash, 0, key, f, growth);
+                None
+            }
+            BorrowedNodeRef::Shared(immutable) => immutable.modify(hash, 0, key, f, growth)
+        };
+
+        HamtMap {
+            root: new_root.unwrap_or(root),
+            element_count,
+            growth,
+            observer,
+            collision,
+            last_level
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.element_count
+    }
+
+    /// Inserts a key-value pair into the map. An existing value for a
+    /// key is replaced by the new value. The first tuple element of the return value is the new
+    /// map instance representing the map after the insertion. The second tuple element is true if
+    /// the size of the map was changed by the operation and false otherwise.
+    pub fn insert(self, key: K, value: V) -> (HamtMap<K, V, IS, H>, bool) {
+        self.insert_internal(ItemStore::new(key, value))
+    }
+
+    /// Inserts `value` for `key` if the key isn't present yet, or replaces the existing value by
+    /// `combine(existing_value, value)` otherwise. Unlike looking the key up before inserting,
+    /// this descends the trie just once, and modifies it in-place if it isn't shared with another
+    /// map. This is the building block for accumulating maps like histograms or running sums.
+    pub fn insert_or_combine<F>(self, key: K, value: V, combine: F) -> HamtMap<K, V, IS, H>
+        where F: Fn(&V, V) -> V
+    {
+        self.insert_combining(ItemStore::new(key, value), Some(&combine)).0
+    }
+
+    /// Replaces the value for `key` by `f(existing_value)`, or inserts `default` if the key isn't
+    /// present yet. Like `insert_or_combine()`, this hashes the key and descends the trie just
+    /// once.
+    pub fn update<F>(self, key: K, default: V, f: F) -> HamtMap<K, V, IS, H>
+        where F: Fn(&V) -> V
+    {
+        self.insert_or_combine(key, default, |existing, _| f(existing))
+    }
+
+    /// Returns the map together with the value for `key`. If the key is missing, the result of `f`
+    /// is inserted first, otherwise `f` isn't called at all. The value is cloned, as the map is
+    /// moved into the result. This is the lookup of a cache filling itself on demand.
+    pub fn get_or_insert_with<F>(self, key: K, f: F) -> (HamtMap<K, V, IS, H>, V)
+        where F: FnOnce() -> V,
+              V: Clone
+    {
+        let hash = hash_of::<K, H>(&key);
+
+        if let Some(kvp) = self.root.borrow().find_item(hash, 0, &key) {
+            let value = kvp.val().clone();
+            return (self, value);
+        }
+
+        let value = f();
+        let (map, _) = self.insert_hashed(hash, ItemStore::new(key, value.clone()), None);
+        (map, value)
+    }
+
+    /// Inserts all key-value pairs of `iterator` into the map, like calling `insert()` for each of
+    /// them. Returns the new map together with the number of keys that were not contained in the
+    /// map before, as opposed to those whose value has been replaced.
+    pub fn insert_all<I>(self, iterator: I) -> (HamtMap<K, V, IS, H>, usize)
+        where I: IntoIterator<Item=(K, V)>
+    {
+        let mut map = self;
+        let mut added = 0;
+
+        for (key, value) in iterator {
+            let (new_map, is_new) = map.insert(key, value);
+            map = new_map;
+
+            if is_new {
+                added += 1;
+            }
+        }
+
+        (map, added)
+    }
+
+    /// Inserts all key-value pairs of `iterator` into the map and returns the result. Like
+    /// extending the map, but taking and returning it by value, so that a single map can be
+    /// threaded through several stages of a pipeline.
+    pub fn collect_into<I>(self, iterator: I) -> HamtMap<K, V, IS, H>
+        where I: IntoIterator<Item=(K, V)>
+    {
+        iterator.into_iter().fold(self, |map, (key, value)| map.plus(key, value))
+    }
+
+    /// Removes a key-value pair from the map. The first tuple element of the return value is the new
+    /// map instance representing the map after the insertion. The second tuple element is true if
+    /// the size of the map was changed by the operation and false otherwise.
+    pub fn remove(self, key: &K) -> (HamtMap<K, V, IS, H>, bool) {
+        let (map, removed) = self.remove_item(key);
+        (map, removed.is_some())
+    }
+
+
+    /// Inserts a key-value pair into the map. Same as `insert()` but with a return type that's
+    /// better suited to chaining multiple calls together.
+    pub fn plus(self, key: K, val: V) -> HamtMap<K, V, IS, H> {
+        self.insert(key, val).0
+    }
+
+    /// Removes a key-value pair from the map. Same as `remove()` but with a return type that's
+    /// better suited to chaining multiple call together
+    pub fn minus(self, key: &K) -> HamtMap<K, V, IS, H> {
+        self.remove(key).0
+    }
+
+    /// Replaces the value stored under `key` with the result of applying `f` to the current value.
+    /// Only the path down to the entry is copied, nodes not shared with any other map are modified
+    /// in-place. If the map does not contain `key`, `f` is not called and the map is returned
+    /// unchanged.
+    pub fn modify<F>(self, key: &K, f: F) -> HamtMap<K, V, IS, H>
+        where F: FnOnce(&V) -> V,
+              K: Clone
+    {
+        let mut f = Some(f);
+        self.modify_internal(key, &mut |kvp: &IS| {
+            let f = f.take().unwrap();
+            ItemStore::new(kvp.key().clone(), f(kvp.val()))
+        })
+    }
+
+    /// Applies each update value to the current value of the corresponding key via `f`, e.g. to
+    /// add deltas to a set of counters. Keys not contained in the map are skipped. As long as
+    /// the map is not shared with other maps, all updates are done in-place.
+    pub fn modify_many<F>(self, updates: &[(K, V)], mut f: F) -> HamtMap<K, V, IS, H>
+        where F: FnMut(&mut V, &V),
+              K: Clone,
+              V: Clone
+    {
+        let mut map = self;
+
+        for (key, update) in updates {
+            map = map.modify_internal(key, &mut |kvp: &IS| {
+                let mut val = kvp.val().clone();
+                f(&mut val, update);
+                ItemStore::new(kvp.key().clone(), val)
+            });
+        }
+
+        map
+    }
+
+    /// Applies a batch of changes: for each entry of `deltas`, the value stored under its key is
+    /// replaced by the result of applying `f` to the current value and the delta. Keys not
+    /// contained in the map are inserted with the delta as their value. As with `modify_many()`,
+    /// all changes are done in-place as long as the map is not shared with other maps. Like
+    /// `update()`, each delta hashes its key and descends the trie just once.
+    pub fn apply_deltas<F>(self, deltas: &HamtMap<K, V, IS, H>, f: F) -> HamtMap<K, V, IS, H>
+        where F: FnMut(&V, &V) -> V,
+              K: Clone,
+              V: Clone
+    {
+        // The combine function passed down the trie can't be `FnMut`
+        let f = RefCell::new(f);
+        let mut map = self;
+
+        for (key, delta) in deltas.iter() {
+            let combine = |existing: &V, _| (f.borrow_mut())(existing, delta);
+            map = map.insert_combining(ItemStore::new(key.clone(), delta.clone()),
+                                       Some(&combine)).0;
+        }
+
+        map
+    }
+
+    /// Removes a key-value pair from the map and returns the removed value. The first tuple element
+    /// of the return value is the new map instance representing the map after the removal. The
+    /// second tuple element is the value that was stored for `key`, or `None` if the map did not
+    /// contain `key`.
+    ///
+    /// The trie is only walked once. Nodes not shared with any other map are modified in-place
+    /// and the value is moved out of them; it is only cloned if the entry is still shared with
+    /// another map.
+    pub fn take(self, key: &K) -> (HamtMap<K, V, IS, H>, Option<V>)
+        where V: Clone
+    {
+        let (map, removed) = self.remove_item(key);
+        let value = removed.map(|kvp| match kvp.try_into_pair() {
+            Ok((_, value)) => value,
+            Err(kvp) => kvp.val().clone()
+        });
+
+        (map, value)
+    }
+
+    /// Rebuilds the whole trie, returning a map with the same entries whose nodes are freshly
+    /// allocated and exactly as big as they need to be. After lots of insertions and removals
+    /// nodes tend to be fragmented and over-allocated; compacting the map before a read-heavy
+    /// phase restores locality. The returned map shares no memory with `self`.
+    pub fn compact(&self) -> HamtMap<K, V, IS, H> {
+        HamtMap {
+            root: self.root.borrow().compacted(&mut |kvp: &IS| kvp.clone()),
+            element_count: self.element_count,
+            growth: self.growth,
+            observer: self.observer.clone(),
+            collision: self.collision.clone(),
+            last_level: self.last_level
+        }
+    }
+
+    /// Returns a copy of the map storing its entries in a `ShareStore`, i.e. every key-value pair
+    /// behind an `Arc`. Copying parts of the trie when modifying a shared map then only bumps
+    /// reference counts instead of cloning keys and values. This is the way to migrate a map that
+    /// started out with small values in a `CopyStore` once its values get expensive to copy. The
+    /// copy has the same structure as `self`, so no key is hashed again.
+    pub fn to_share_store(&self) -> HamtMap<K, V, ShareStore<K, V>, H>
+        where K: Clone,
+              V: Clone
+    {
+        let mut share_item = |kvp: &IS| ShareStore::new(kvp.key().clone(), kvp.val().clone());
+
+        HamtMap {
+            root: self.root.borrow().compacted(&mut share_item),
+            element_count: self.element_count,
+            growth: self.growth,
+            observer: self.observer.clone(),
+            collision: self.collision.clone(),
+            last_level: self.last_level
+        }
+    }
+
+    /// Returns a completely independent copy of the map. Unlike `clone()`, which just shares the
+    /// trie, this allocates new nodes and clones every key and value, so that nothing at all is
+    /// shared with `self`. Subsequent modifications of the copy can then always happen in-place.
+    pub fn deep_clone(&self) -> HamtMap<K, V, IS, H>
+        where K: Clone,
+              V: Clone
+    {
+        let mut copy_item = |kvp: &IS| ItemStore::new(kvp.key().clone(), kvp.val().clone());
+
+        HamtMap {
+            root: self.root.borrow().compacted(&mut copy_item),
+            element_count: self.element_count,
+            growth: self.growth,
+            observer: self.observer.clone(),
+            collision: self.collision.clone(),
+            last_level: self.last_level
+        }
+    }
+
+    // The number of references to the root node. The root can only be modified in-place if this
+    // is 1, otherwise insertions and removals copy the path down to the modified entry.
+    #[cfg(test)]
+    pub fn ref_count_of_root(&self) -> usize {
+        self.root.ref_count()
+    }
+
+    /// Returns the number of nodes of this map that are shared with other maps, counting the root
+    /// node too. Nodes below a shared node are not counted, so this is the number of nodes that
+    /// would have to be copied before every part of the map could be modified in-place.
+    pub fn count_shared_nodes(&self) -> usize {
+        if self.root.ref_count() > 1 {
+            1
+        } else {
+            self.root.borrow().shared_node_count()
+        }
+    }
+
+    /// Turns the map into a mutable builder, see `TransientHamtMap`. Nodes the map shares with
+    /// other maps are copied the first time they are modified, all others are modified in-place.
+    pub fn transient(self) -> TransientHamtMap<K, V, IS, H> {
+        TransientHamtMap { map: self }
+    }
+
+    /// Consumes the map and returns the entry for the given key, which allows for inserting a
+    /// value only if the key is not present yet.
+    pub fn entry(self, key: K) -> Entry<K, V, IS, H> {
+        Entry { map: self, key }
+    }
+
+    /// Returns the number of nodes the trie of this map consists of, including the root node.
+    pub fn node_count(&self) -> usize {
+        self.root.borrow().node_count()
+    }
+
+    /// Returns the addresses of all nodes of this map's trie, starting with the root node. This is
+    /// a tool for inspecting structural sharing: the nodes two maps have in common are exactly
+    /// the addresses contained in both results. The addresses are only meaningful as long as the
+    /// map is alive.
+    pub fn collect_node_pointers(&self) -> Vec<usize> {
+        let mut pointers = Vec::with_capacity(self.node_count());
+        self.root.borrow().collect_node_pointers(&mut pointers);
+        pointers
+    }
+
+    /// Collapses every sub-tree that holds just a single item into that item, the same way a
+    /// single removal does for the sub-tree it removes from. Some removals, e.g. those resolving
+    /// a hash collision, leave chains of nodes behind that only lead to a single item. After
+    /// lots of removals, `normalize()` gets rid of them, making lookups shorter and saving memory.
+    pub fn normalize(self) -> HamtMap<K, V, IS, H> {
+        match self.root.borrow().normalized() {
+            Some(root) => HamtMap {
+                root,
+                element_count: self.element_count,
+                growth: self.growth,
+                observer: self.observer.clone(),
+                collision: self.collision.clone(),
+                last_level: self.last_level
+            },
+            None => self,
+        }
+    }
+
+    /// Replaces every collision entry that is left with just a single item by a single-item
+    /// entry. Removing from a collision entry already does this, so no operation should leave
+    /// such entries behind. This maintenance pass repairs them anyway, should they show up, which
+    /// keeps lookups of the affected keys from descending into a collision entry needlessly. The
+    /// map is only copied if there is something to replace.
+    pub fn compact_collisions(self) -> HamtMap<K, V, IS, H> {
+        let map = match self.root.borrow().compacted_collisions() {
+            Some(root) => HamtMap {
+                root,
+                element_count: self.element_count,
+                growth: self.growth,
+                observer: self.observer.clone(),
+                collision: self.collision.clone(),
+                last_level: self.last_level
+            },
+            None => self,
+        };
+
+        debug_assert!(!map.root.borrow().has_short_collisions());
+        map
+    }
+
+    /// Returns true if all entries of the map are stored directly in the root node, i.e. the trie
+    /// consists of a single level only. This is a cheap check that doesn't traverse the map.
+    pub fn is_single_level(&self) -> bool {
+        !self.root.borrow().has_sub_trees()
+    }
+
+    /// Returns the ratio of the map's actual node count to the number of nodes a map of the same
+    /// size is expected to have if its keys' hash values were uniformly distributed. This helps
+    /// to find out whether the `Hash` implementation of the key type produces clustered hash
+    /// values, before that becomes a performance problem.
+    ///
+    /// For a good hash function the ratio is close to 1. Values above 2 mean that many keys share
+    /// long hash prefixes, which makes the trie deeper than necessary. Note that keys whose hash
+    /// values are fully equal end up in collision entries instead of extra nodes, so these are not
+    /// detected by this measure.
+    pub fn hash_quality(&self) -> f64 {
+        let item_count = self.element_count as f64;
+        // There is always the root node
+        let mut expected_node_count = 1.0;
+
+        // A node exists on a given level for every hash prefix shared by at least two items. The
+        // number of items sharing a prefix is approximately Poisson distributed.
+        for level in 1 .. self.last_level + 1 {
+            let prefix_count = (1u64 << (BITS_PER_LEVEL * level)) as f64;
+            let lambda = item_count / prefix_count;
+
+            let probability = if lambda < 1e-4 {
+                // Avoid the cancellation in the exact formula for very small values
+                lambda * lambda / 2.0
+            } else {
+                1.0 - (-lambda).exp() * (1.0 + lambda)
+            };
+
+            expected_node_count += prefix_count * probability;
+        }
+
+        self.node_count() as f64 / expected_node_count
+    }
+
+    /// Returns the number of bytes allocated for the nodes of this map. Nodes shared with other
+    /// maps are counted in full, memory owned by the keys and values themselves is not counted.
+    pub fn memory_footprint(&self) -> usize {
+        self.root.borrow().memory_footprint()
+    }
+
+    /// Returns a summary of how well the memory allocated for the nodes of this map is used. See
+    /// `CapacityStats`.
+    pub fn capacity_stats(&self) -> CapacityStats {
+        let mut stats = CapacityStats {
+            node_count: 0,
+            allocated_slots: 0,
+            used_slots: 0,
+            wasted_slots: 0,
+            wasted_bytes: 0,
+            memory_footprint: self.memory_footprint(),
+        };
+
+        self.root.borrow().collect_capacity_stats(&mut stats);
+        stats.wasted_slots = stats.allocated_slots - stats.used_slots;
+        stats.wasted_bytes = stats.wasted_slots * UnsafeNode::<K, V, IS, H>::node_entry_size();
+        stats
+    }
+
+    /// Returns a reference to the value stored for `key`, or `default` if the map does not contain
+    /// `key`.
+    pub fn get_or<'a>(&'a self, key: &K, default: &'a V) -> &'a V {
+        self.find(key).unwrap_or(default)
+    }
+
+    /// Looks up the value stored for `key`. This is guaranteed to only ever read from the map: no
+    /// reference counts are touched and nothing is allocated, so any number of threads can safely
+    /// call `peek()` on a shared map at the same time without contending with each other.
+    pub fn peek<'a>(&'a self, key: &K) -> Option<&'a V> {
+        // `find()` walks the trie via plain borrows and never clones a NodeRef
+        self.find(key)
+    }
+
+    /// Returns true if both maps contain the same keys and `eq` returns true for the values of
+    /// every key. Unlike `==`, this allows for comparing values by a custom notion of equality,
+    /// e.g. floating point values within some tolerance. `eq` is called with the value from
+    /// `self` first.
+    pub fn equal_with<F>(&self, other: &HamtMap<K, V, IS, H>, mut eq: F) -> bool
+        where F: FnMut(&V, &V) -> bool
+    {
+        if self.len() != other.len() {
+            return false;
+        }
+
+        self.iter().all(|(key, value)| {
+            match other.find(key) {
+                Some(other_value) => eq(value, other_value),
+                None => false,
+            }
+        })
+    }
+
+    /// Returns a map containing only those entries for which `f` returns true. The resulting map
+    /// is built from scratch.
+    pub fn retain<F>(self, f: F) -> HamtMap<K, V, IS, H>
+        where F: FnMut(&K, &V) -> bool
+    {
+        self.retain_count(f).0
+    }
+
+    /// Same as `retain()` but additionally returns the number of entries that have been dropped
+    /// because `f` returned false for them.
+    pub fn retain_count<F>(self, mut f: F) -> (HamtMap<K, V, IS, H>, usize)
+        where F: FnMut(&K, &V) -> bool
+    {
+        let mut retained = self.empty_like();
+
+        self.root.borrow().for_each_item(&mut |kvp: &IS| {
+            if f(kvp.key(), kvp.val()) {
+                retained = mem::take(&mut retained).insert_internal(kvp.clone()).0;
+            }
+        });
+
+        let removed = self.len() - retained.len();
+        (retained, removed)
+    }
+
+    /// Removes all entries for which `f` returns false, like `retain()`, but builds the remaining
+    /// entries into a trie whose nodes are exactly as big as they need to be, like `compact()`.
+    /// This is meant for long-lived caches that are pruned every now and then and should give
+    /// back the memory the removed entries took up. Entries are moved out of the map where
+    /// possible.
+    pub fn prune<F>(self, mut f: F) -> HamtMap<K, V, IS, H>
+        where F: FnMut(&K, &V) -> bool
+    {
+        let (growth, observer, collision) = (self.growth, self.observer.clone(), self.collision.clone());
+        let last_level = self.last_level;
+
+        let items = MoveEntries::new(self).filter(|kvp| f(kvp.key(), kvp.val()))
+                                          .map(|kvp| (hash_of::<K, H>(kvp.key()), Some(kvp)))
+                                          .collect();
+
+        HamtMap {
+            growth,
+            observer,
+            collision,
+            ..HamtMap::from_hashed_items(items, last_level)
+        }
+    }
+
+    /// Keeps only the `k` entries with the largest values and drops all others, e.g. for using the
+    /// map as a cache with a fixed capacity that evicts its least valuable entries. Of entries
+    /// with equal values straddling the cut, those coming first in iteration order are kept, so
+    /// the result is deterministic for a given hasher.
+    pub fn trim_to_top(self, k: usize) -> HamtMap<K, V, IS, H>
+        where V: Ord+Clone
+    {
+        if k >= self.len() {
+            return self;
+        }
+
+        if k == 0 {
+            return self.empty_like();
+        }
+
+        // The k-th largest value is the lowest one kept
+        let mut values: Vec<&V> = self.iter().map(|(_, value)| value).collect();
+        let (_, threshold, _) = values.select_nth_unstable_by(k - 1, |a, b| b.cmp(a));
+        let threshold = (*threshold).clone();
+
+        let mut ties_left = k - self.iter().filter(|&(_, value)| *value > threshold).count();
+
+        self.retain(|_, value| {
+            match value.cmp(&threshold) {
+                cmp::Ordering::Greater => true,
+                cmp::Ordering::Equal if ties_left > 0 => {
+                    ties_left -= 1;
+                    true
+                }
+                _ => false,
+            }
+        })
+    }
+
+    /// Returns a map containing the keys present in both `self` and `other`, mapped to
+    /// `f(key, self_value, other_value)`. Parts of the tries that only exist in one of the two maps
+    /// are skipped without being traversed.
+    pub fn intersection_with<F>(&self, other: &HamtMap<K, V, IS, H>, mut f: F) -> HamtMap<K, V, IS, H>
+        where F: FnMut(&K, &V, &V) -> V,
+              K: Clone
+    {
+        let mut intersection = self.empty_like();
+
+        let mut add_common_item = |this_kvp: &IS, other_kvp: &IS| {
+            let key = this_kvp.key();
+            let value = f(key, this_kvp.val(), other_kvp.val());
+            intersection = mem::take(&mut intersection).plus(key.clone(), value);
+        };
+
+        self.root.borrow().for_each_common_item(other.root.borrow(), 0, &mut add_common_item);
+        intersection
+    }
+
+    /// Returns a new map containing the entries of `self` whose keys are not contained in `other`.
+    /// The resulting map is built from scratch.
+    pub fn difference(&self, other: &HamtMap<K, V, IS, H>) -> HamtMap<K, V, IS, H> {
+        self.clone().retain(|key, _| other.find(key).is_none())
+    }
+
+    /// Removes all keys contained in `other` from the map. Unlike `difference()` this consumes
+    /// `self` and removes the entries in-place as long as the nodes are not shared with other
+    /// maps, which is cheaper if `other` is small compared to `self`.
+    pub fn difference_update(self, other: &HamtMap<K, V, IS, H>) -> HamtMap<K, V, IS, H> {
+        let mut map = self;
+
+        for (key, _) in other.iter() {
+            map = map.minus(key);
+        }
+
+        map
+    }
+
+    /// Consumes the map and returns all of its values. Values are moved out of the map where
+    /// possible and only cloned if they are shared with another map. Nodes are freed as soon as
+    /// their contents have been moved out.
+    pub fn into_values(self) -> Vec<V>
+        where V: Clone
+    {
+        MoveEntries::new(self).map(|kvp| match kvp.try_into_pair() {
+            Ok((_, value)) => value,
+            Err(kvp) => kvp.val().clone()
+        }).collect()
+    }
+
+    /// Consumes the map and returns an iterator over its entries in ascending key order. All
+    /// entries are moved out of the map (or cloned if they are shared with another map) and sorted
+    /// up-front, so the memory of the trie is freed before the first entry is yielded. Use this if
+    /// the entries of a map are needed in order exactly once, after the map has been built.
+    pub fn into_sorted_iter(self) -> IntoSorted<K, V>
+        where K: Ord + Clone,
+              V: Clone
+    {
+        let mut pairs: Vec<(K, V)> = MoveEntries::new(self).map(|kvp| match kvp.try_into_pair() {
+            Ok(pair) => pair,
+            Err(kvp) => (kvp.key().clone(), kvp.val().clone())
+        }).collect();
+
+        pairs.sort_by(|a, b| a.0.cmp(&b.0));
+        IntoSorted { pairs: pairs.into_iter() }
+    }
+
+    // Returns references to all entries of the map, in ascending key order.
+    fn sorted_entries(&self) -> Vec<(&K, &V)>
+        where K: Ord
+    {
+        let mut entries: Vec<(&K, &V)> = self.iter().collect();
+        entries.sort_unstable_by(|a, b| a.0.cmp(b.0));
+        entries
+    }
+
+    /// Consumes the map and returns all of its keys. Like `into_values()`, keys are moved out of
+    /// the map where possible. The values are dropped.
+    pub fn into_keys(self) -> Vec<K>
+        where K: Clone
+    {
+        MoveEntries::new(self).map(|kvp| match kvp.try_into_pair() {
+            Ok((key, _)) => key,
+            Err(kvp) => kvp.key().clone()
+        }).collect()
+    }
+
+    /// Consumes the map, removing all entries for which `f` returns true. Returns the map with the
+    /// remaining entries together with the removed ones, e.g. to move expired entries of a cache
+    /// to a secondary store. Like `into_values()`, removed entries are moved out of the map where
+    /// possible.
+    pub fn drain_filter<F>(self, mut f: F) -> (Self, Vec<(K, V)>)
+        where F: FnMut(&K, &V) -> bool,
+              K: Clone,
+              V: Clone
+    {
+        let mut kept = self.empty_like();
+        let mut drained = Vec::new();
+
+        for kvp in MoveEntries::new(self) {
+            if f(kvp.key(), kvp.val()) {
+                drained.push(match kvp.try_into_pair() {
+                    Ok(pair) => pair,
+                    Err(kvp) => (kvp.key().clone(), kvp.val().clone())
+                });
+            } else {
+                kept = kept.insert_internal(kvp).0;
+            }
+        }
+
+        (kept, drained)
+    }
+
+    /// Consumes the map and moves its entries into an array, if the map contains exactly `N`
+    /// entries. Otherwise the map is returned unchanged. Entries are in the order the map's
+    /// iterator yields them in, which depends on their hash values; use `into_sorted_iter()`
+    /// instead where a deterministic order matters. Like `into_values()`, entries are moved out of
+    /// the map where possible.
+    pub fn try_into_array<const N: usize>(self) -> Result<[(K, V); N], Self>
+        where K: Clone,
+              V: Clone
+    {
+        if self.element_count != N {
+            return Err(self);
+        }
+
+        let pairs: Vec<(K, V)> = MoveEntries::new(self).map(|kvp| {
+            match kvp.try_into_pair() {
+                Ok(pair) => pair,
+                Err(kvp) => (kvp.key().clone(), kvp.val().clone())
+            }
+        }).collect();
+
+        // The map contained exactly `N` entries, so the conversion can't fail
+        Ok(<[(K, V); N]>::try_from(pairs).unwrap_or_else(|_| unreachable!()))
+    }
+
+    /// Inserts a key-value pair into the map, returning the value previously stored for `key`.
+    /// The first tuple element of the return value is the new map instance representing the map
+    /// after the insertion. The second tuple element is the replaced value, or `None` if the map
+    /// did not contain `key` before.
+    ///
+    /// As with `take()`, the trie is only walked once and the replaced value is moved out of nodes
+    /// not shared with any other map. It is only cloned if the entry is still shared.
+    pub fn replace(self, key: K, value: V) -> (HamtMap<K, V, IS, H>, Option<V>)
+        where V: Clone
+    {
+        let hash = hash_of::<K, H>(&key);
+        let mut replaced = None;
+        let (map, _) = self.insert_hashed_replacing(hash, IS::new(key, value), None,
+                                                    Some(&mut replaced));
+        let previous = replaced.map(|kvp| match kvp.try_into_pair() {
+            Ok((_, value)) => value,
+            Err(kvp) => kvp.val().clone()
+        });
+
+        (map, previous)
+    }
+
+    /// Applies `f` to the values stored under the two distinct keys `key1` and `key2` at once, e.g.
+    /// to transfer an amount from one account to another. Both paths are copied (or modified
+    /// in-place where possible) like with `modify()`. If either key is not contained in the map,
+    /// `f` is not called and the map is returned unchanged.
+    ///
+    /// Panics if `key1 == key2`.
+    pub fn modify2<F>(self, key1: &K, key2: &K, f: F) -> HamtMap<K, V, IS, H>
+        where F: FnOnce(&mut V, &mut V),
+              K: Clone,
+              V: Clone
+    {
+        assert!(*key1 != *key2, "modify2() needs two distinct keys");
+
+        let (mut value1, mut value2) = match (self.find(key1), self.find(key2)) {
+            (Some(value1), Some(value2)) => (value1.clone(), value2.clone()),
+            _ => return self
+        };
+
+        f(&mut value1, &mut value2);
+
+        let mut value1 = Some(value1);
+        let mut value2 = Some(value2);
+
+        self.modify_internal(key1, &mut |kvp: &IS| {
+                ItemStore::new(kvp.key().clone(), value1.take().unwrap())
+            })
+            .modify_internal(key2, &mut |kvp: &IS| {
+                ItemStore::new(kvp.key().clone(), value2.take().unwrap())
+            })
+    }
+
+    /// Exchanges the values stored under `key1` and `key2`, copying the paths down to both entries
+    /// like `modify2()`. If either key is not contained in the map, or both keys are the same, the
+    /// map is returned unchanged.
+    pub fn swap_values(self, key1: &K, key2: &K) -> HamtMap<K, V, IS, H>
+        where K: Clone,
+              V: Clone
+    {
+        if *key1 == *key2 {
+            return self;
+        }
+
+        self.modify2(key1, key2, mem::swap)
+    }
+
+    /// Returns the number of entries stored below each of the root node's slots. This shows how
+    /// evenly the keys are distributed over the first level of the trie, which is what matters
+    /// when splitting the map up for parallel processing.
+    pub fn subtree_sizes(&self) -> [usize; 1 << BITS_PER_LEVEL] {
+        let root = self.root.borrow();
+        let mut sizes = [0; 1 << BITS_PER_LEVEL];
+
+        for (local_key, size) in sizes.iter_mut().enumerate() {
+            if (root.mask & (1 << local_key)) != 0 {
+                *size = root.entry_item_count(get_index(root.mask, local_key));
+            }
+        }
+
+        sizes
+    }
+
+    /// Returns an iterator over the entries stored below the given slot of the root node, i.e.
+    /// the entries whose hash values have `slot` as their lowest `BITS_PER_LEVEL` bits. The
+    /// iterators of all 32 slots together yield the same entries as `iter()`, in the same order,
+    /// so the slots can be handed out to up to 32 workers processing a shared map in parallel.
+    /// See `subtree_sizes()` for how many entries each of them gets. Panics if `slot` is 32 or
+    /// more.
+    pub fn iter_root_slot<'a>(&'a self, slot: usize) -> HamtMapIterator<'a, K, V, IS, H> {
+        assert!(slot < 1 << BITS_PER_LEVEL, "root slot out of range");
+        let root = self.root.borrow();
+
+        if (root.mask & (1 << slot)) == 0 {
+            return HamtMapIterator::for_root_slot(self, slot, 0);
+        }
+
+        let item_count = root.entry_item_count(get_index(root.mask, slot));
+        HamtMapIterator::for_root_slot(self, slot, item_count)
+    }
+
+    /// Builds a map from the given key-value pairs bottom-up, instead of inserting one pair after
+    /// the other. This is fastest if the pairs are already sorted in the order in which the trie
+    /// stores them, which is the order a map's iterator yields them in. Otherwise they are sorted
+    /// first. Of multiple pairs with the same key, the last one wins.
+    pub fn from_sorted_pairs(pairs: Vec<(K, V)>) -> HamtMap<K, V, IS, H> {
+        let items = pairs.into_iter()
+                         .map(|(key, value)| (hash_of::<K, H>(&key), Some(ItemStore::new(key, value))))
+                         .collect();
+
+        HamtMap::from_hashed_items(items, LAST_LEVEL)
+    }
+
+    /// Builds a map bottom-up from key-value pairs paired with their hash values, like
+    /// `from_sorted_pairs()` but without hashing the keys again. This saves time for key types
+    /// that are expensive to hash if the hash values are known anyway, e.g. from a prior pass
+    /// over the data. Each hash value must be the one that `key_hash()` returns for the key.
+    /// Otherwise the map is still safe to use, but looking up the affected keys will fail.
+    pub fn from_root_parts(entries: Vec<(u64, K, V)>) -> HamtMap<K, V, IS, H> {
+        let items = entries.into_iter()
+                           .map(|(hash, key, value)| (hash, Some(ItemStore::new(key, value))))
+                           .collect();
+
+        HamtMap::from_hashed_items(items, LAST_LEVEL)
+    }
+
+    /// Builds a map bottom-up from several runs of key-value pairs paired with their hash values,
+    /// e.g. loaded from separately written shards. As with `from_root_parts()`, each hash value
+    /// must be the one that `key_hash()` returns for the key. Runs that are sorted in the order in
+    /// which a map's iterator yields its entries are merged in a single pass, others are sorted
+    /// first. Every key occurring more than once is resolved by `conflicts`, not just fully
+    /// colliding ones, with the pairs of earlier runs (and earlier pairs within a run) taking the
+    /// role of the existing value.
+    pub fn from_sorted_runs(runs: Vec<Vec<(u64, K, V)>>, conflicts: &CollisionPolicy<V>)
+        -> HamtMap<K, V, IS, H>
+    {
+        let item_count = runs.iter().map(Vec::len).sum();
+        let mut runs: Vec<_> = runs.into_iter().map(|mut run| {
+            let mut previous = (0, 0);
+            let is_sorted = run.iter().all(|&(hash, _, _)| {
+                let order = (trie_order(hash), hash);
+                let in_order = previous <= order;
+                previous = order;
+                in_order
+            });
+
+            if !is_sorted {
+                // The sort is stable, so equal keys keep their order within the run
+                run.sort_by_cached_key(|&(hash, _, _)| (trie_order(hash), hash));
+            }
+            run.into_iter()
+        }).collect();
+
+        // The positions of the first pairs not merged yet of all runs, smallest in trie order
+        // first, together with the pairs themselves. Ties go to the earlier run, so that its pairs
+        // take the role of the existing value.
+        let mut heads = BinaryHeap::with_capacity(runs.len());
+        let mut head_items: Vec<Option<IS>> = Vec::with_capacity(runs.len());
+        for (index, run) in runs.iter_mut().enumerate() {
+            head_items.push(run.next().map(|(hash, key, value)| {
+                heads.push(Reverse((trie_order(hash), hash, index)));
+                IS::new(key, value)
+            }));
+        }
+
+        let mut merged: Vec<(u64, Option<IS>)> = Vec::with_capacity(item_count);
+        // Equal keys have equal hashes, so duplicates can only occur within runs of equal hashes
+        let mut equal_hashes_start = 0;
+
+        while let Some(Reverse((_, hash, index))) = heads.pop() {
+            let next = runs[index].next().map(|(next_hash, key, value)| {
+                heads.push(Reverse((trie_order(next_hash), next_hash, index)));
+                IS::new(key, value)
+            });
+            let kvp = mem::replace(&mut head_items[index], next).unwrap();
+
+            if merged.last().is_none_or(|&(last_hash, _)| last_hash != hash) {
+                equal_hashes_start = merged.len();
+            }
+
+            let existing = merged[equal_hashes_start ..].iter_mut().find(|item| {
+                *item.1.as_ref().unwrap().key() == *kvp.key()
+            });
+
+            match existing {
+                Some(item) => {
+                    let resolved = conflicts.resolve(item.1.as_ref().unwrap(), kvp);
+                    item.1 = Some(resolved);
+                }
+                None => merged.push((hash, Some(kvp))),
+            }
+        }
+
+        HamtMap::from_trie_ordered_items(merged, LAST_LEVEL)
+    }
+
+    /// Returns a map containing only the entries whose *hash value* starts with the given prefix,
+    /// or `None` if there are no such entries. The prefix consists of the lowest
+    /// `levels * BITS_PER_LEVEL` bits of `prefix_bits`, i.e. it selects one of the slots on each
+    /// of the topmost `levels` levels of the trie. Note that this has nothing to do with ranges of
+    /// keys.
+    ///
+    /// The returned map shares the selected branch with this map, so splitting a large map into
+    /// shards this way is cheap: iterating over all prefixes for a given number of levels yields
+    /// maps that together contain every entry exactly once.
+    pub fn subtree_for_prefix(&self, prefix_bits: u64, levels: usize) -> Option<HamtMap<K, V, IS, H>> {
+        assert!(levels <= HamtMap::<K, V, IS, H>::MAX_DEPTH);
+
+        if self.element_count == 0 {
+            return None;
+        }
+
+        if levels == 0 {
+            return Some(self.clone());
+        }
+
+        let (mut entry, element_count) = self.root.borrow().prefix_entry(prefix_bits, 0, levels)?;
+
+        match entry {
+            NodeEntryOwned::Item(kvp) => return Some(self.empty_like().insert_internal(kvp).0),
+            // A collision entry found above the last level can't be moved further down
+            NodeEntryOwned::Collision(items) if levels - 1 > self.last_level => {
+                let items = items.iter().map(|kvp| (hash_of::<K, H>(kvp.key()), Some(kvp.clone())))
+                                        .collect();
+                return Some(HamtMap {
+                    growth: self.growth,
+                    observer: self.observer.clone(),
+                    collision: self.collision.clone(),
+                    ..HamtMap::from_hashed_items(items, self.last_level)
+                });
+            }
+            _ => {}
+        }
+
+        // Re-create the path from the root down to the shared branch
+        for level in (0 .. levels).rev() {
+            let local_key = (prefix_bits >> (BITS_PER_LEVEL * level)) & LEVEL_BIT_MASK;
+            let mut node_ref = UnsafeNode::alloc(1 << local_key, 1);
+            node_ref.borrow_mut().init_entry(0, entry);
+            entry = NodeEntryOwned::SubTree(node_ref);
+        }
+
+        match entry {
+            NodeEntryOwned::SubTree(root) => {
+                Some(HamtMap {
+                    root,
+                    element_count,
+                    growth: self.growth,
+                    observer: self.observer.clone(),
+                    collision: self.collision.clone(),
+                    last_level: self.last_level
+                })
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    /// Splits the map into the entries whose hash values lie at trie positions below `pivot`, and
+    /// the remaining ones. Trie positions are the ones `scan_hash_range()` works with, so the
+    /// first map contains the entries of `scan_hash_range(0, pivot)`. Rather than inserting the
+    /// entries into new maps, the trie is split structurally: only the nodes on the path towards
+    /// the pivot are copied, all other branches are shared with the resulting maps. Pivots that
+    /// are multiples of `1 << (BITS_PER_LEVEL * n)` leave the `n` lowest levels of the trie
+    /// untouched.
+    pub fn split_at_hash(self, pivot: u64) -> (Self, Self) {
+        if pivot >= 1 << (BITS_PER_LEVEL * HamtMap::<K, V, IS, H>::MAX_DEPTH) {
+            let rest = self.empty_like();
+            let rest = HamtMap { collision: self.collision.clone(), ..rest };
+            return (self, rest);
+        }
+
+        let (below, rest) = self.root.borrow().split_at_position(pivot, 0);
+        let split = |root: NodeRef<K, V, IS, H>| {
+            HamtMap {
+                element_count: root.borrow().item_count(),
+                root,
+                growth: self.growth,
+                observer: self.observer.clone(),
+                collision: self.collision.clone(),
+                last_level: self.last_level
+            }
+        };
+
+        (split(below), split(rest))
+    }
+
+    /// Returns the number of distinct prefixes of `levels * BITS_PER_LEVEL` bits among the hash
+    /// values of the map's keys, i.e. how many of the slots on the topmost `levels` levels of the
+    /// trie are populated. This shows how widely the keys are spread, e.g. how many of the
+    /// non-empty shards `subtree_for_prefix()` would return. Only the nodes on the topmost `levels`
+    /// levels are visited, so this is much cheaper than a full traversal for small `levels`.
+    pub fn estimate_unique_prefixes(&self, levels: usize) -> usize {
+        assert!(levels <= HamtMap::<K, V, IS, H>::MAX_DEPTH);
+
+        if self.element_count == 0 {
+            0
+        } else if levels == 0 {
+            1
+        } else {
+            self.root.borrow().populated_prefix_count(0, levels)
+        }
+    }
+
+    /// Returns the union of `self` and `other`, where keys present in both maps are mapped to the
+    /// sum of their values (`self`'s value on the left-hand side). This is the typical operation
+    /// for merging word counts or other metrics. The larger of the two maps is used as the
+    /// starting point, so only the entries of the smaller one have to be inserted.
+    pub fn merge_counts(&self, other: &HamtMap<K, V, IS, H>) -> HamtMap<K, V, IS, H>
+        where K: Clone,
+              V: Add<Output=V> + Clone
+    {
+        let (mut merged, smaller, smaller_is_left) = if self.len() >= other.len() {
+            (self.clone(), other, false)
+        } else {
+            (other.clone(), self, true)
+        };
+
+        smaller.root.borrow().for_each_item(&mut |kvp: &IS| {
+            let sum = match merged.find(kvp.key()) {
+                Some(value) if smaller_is_left => Some(kvp.val().clone() + value.clone()),
+                Some(value) => Some(value.clone() + kvp.val().clone()),
+                None => None,
+            };
+
+            merged = match sum {
+                Some(sum) => mem::take(&mut merged).plus(kvp.key().clone(), sum),
+                None => mem::take(&mut merged).insert_internal(kvp.clone()).0,
+            };
+        });
+
+        merged
+    }
+
+    /// Returns the union of `self` and `other`. For keys present in both maps, `self`'s value is
+    /// kept. If neither map has more than a single level (see `is_single_level()`), the new root
+    /// node is built directly from the two root nodes, which makes unions of tiny maps cheap.
+    pub fn union(&self, other: &HamtMap<K, V, IS, H>) -> HamtMap<K, V, IS, H> {
+        // Maps limited to a single level may have collision entries in their root nodes
+        let roots_are_flat = self.last_level > 0 && other.last_level > 0 &&
+                             self.is_single_level() && other.is_single_level();
+
+        if roots_are_flat {
+            let (root, duplicates) = self.root.borrow().union_of_flat_roots(other.root.borrow(),
+                                                                            self.growth,
+                                                                            self.last_level);
+            let element_count = self.len() + other.len() - duplicates;
+            return HamtMap {
+                root,
+                element_count,
+                growth: self.growth,
+                observer: self.observer.clone(),
+                collision: self.collision.clone(),
+                last_level: self.last_level
+            };
+        }
+
+        let (mut merged, smaller, smaller_is_self) = if self.len() >= other.len() {
+            (self.clone(), other, false)
+        } else {
+            (other.clone(), self, true)
+        };
+
+        smaller.root.borrow().for_each_item(&mut |kvp: &IS| {
+            if smaller_is_self || merged.find(kvp.key()).is_none() {
+                merged = mem::take(&mut merged).insert_internal(kvp.clone()).0;
+            }
+        });
+
+        merged
+    }
+
+    /// Returns the union of all maps stored as values of `maps`, e.g. sub-results computed per
+    /// partition. For keys present in more than one of them, the value encountered first while
+    /// iterating over `maps` is kept. See `flatten_with()` for resolving such conflicts otherwise.
+    /// The result is built in-place and has the settings of the first map, like its growth and
+    /// collision policy. If `maps` is empty, an empty map with the default settings is returned.
+    pub fn flatten<K1, OS, H1>(maps: &HamtMap<K1, HamtMap<K, V, IS, H>, OS, H1>)
+        -> HamtMap<K, V, IS, H>
+        where K1: Eq+Send+Sync+Hash,
+              OS: ItemStore<K1, HamtMap<K, V, IS, H>>,
+              H1: Hasher+Default
+    {
+        let inner_maps: Vec<&HamtMap<K, V, IS, H>> = maps.iter().map(|(_, map)| map).collect();
+
+        let mut flattened = match inner_maps.first() {
+            Some(first) => HamtMap { collision: first.collision.clone(), ..first.empty_like() },
+            None => return HamtMap::new(),
+        }.transient();
+
+        // Merging replaces existing values, so the first map has to be merged last
+        for map in inner_maps.iter().rev() {
+            flattened.merge_in_place(map);
+        }
+
+        flattened.persistent()
+    }
+
+    /// Same as `flatten()`, but keys present in more than one of the maps are mapped to the result
+    /// of applying `f` to the value accumulated so far and the value of the next map containing
+    /// the key, in iteration order.
+    pub fn flatten_with<K1, OS, H1, F>(maps: &HamtMap<K1, HamtMap<K, V, IS, H>, OS, H1>, f: F)
+        -> HamtMap<K, V, IS, H>
+        where K1: Eq+Send+Sync+Hash,
+              OS: ItemStore<K1, HamtMap<K, V, IS, H>>,
+              H1: Hasher+Default,
+              F: FnMut(&V, &V) -> V,
+              K: Clone,
+              V: Clone
+    {
+        let mut inner_maps = maps.iter().map(|(_, map)| map);
+
+        let mut flattened = match inner_maps.next() {
+            Some(first) => first.clone().transient(),
+            None => return HamtMap::new(),
+        };
+
+        // The combine function passed down the trie can't be `FnMut`
+        let f = RefCell::new(f);
+        let combine = |accumulated: &V, value: V| (f.borrow_mut())(accumulated, &value);
+
+        for map in inner_maps {
+            flattened.merge_in_place_combining(map, &combine);
+        }
+
+        flattened.persistent()
+    }
+
+    /// Removes some entry from the map and returns it, or `None` if the map is empty. This is meant
+    /// for caches that need to shrink the map without caring which entry goes. No guarantee is
+    /// made about which entry is chosen.
+    ///
+    /// The entry is removed in a single descent. As with `take()`, it is moved out of nodes not
+    /// shared with any other map, and only cloned if it is still shared.
+    pub fn evict_one(self) -> (Self, Option<(K, V)>)
+        where K: Clone,
+              V: Clone
+    {
+        let (map, removed) = self.remove_first_item();
+        let pair = removed.map(|kvp| match kvp.try_into_pair() {
+            Ok(pair) => pair,
+            Err(kvp) => (kvp.key().clone(), kvp.val().clone())
+        });
+
+        (map, pair)
+    }
+
+    /// Returns for each of the given keys whether it is contained in the map. The keys are sorted
+    /// by their position in the trie first, so that keys with a common hash prefix share the
+    /// descent down to the node they have in common.
+    pub fn bulk_contains(&self, keys: &[&K]) -> Vec<bool> {
+        let mut queries: Vec<(u64, &K, usize)> = Vec::with_capacity(keys.len());
+
+        for (index, &key) in keys.iter().enumerate() {
+            queries.push((hash_of::<K, H>(key), key, index));
+        }
+
+        queries.sort_by_key(|query| trie_order(query.0));
+
+        let mut found = vec![false; keys.len()];
+        self.root.borrow().contains_batch(&queries[..], 0, &mut found[..]);
+        found
+    }
+
+    /// Removes all entries for which `f` returns true, i.e. the inverse of `retain()`. Instead of
+    /// building a new map from the remaining entries, the matching keys are removed one by one,
+    /// which happens in-place for nodes not shared with other maps. Shared nodes are copied once
+    /// and then modified in-place too.
+    pub fn remove_where<F>(self, mut f: F) -> HamtMap<K, V, IS, H>
+        where F: FnMut(&K, &V) -> bool,
+              K: Clone
+    {
+        let mut doomed_keys = Vec::new();
+
+        self.root.borrow().for_each_item(&mut |kvp: &IS| {
+            if f(kvp.key(), kvp.val()) {
+                doomed_keys.push(kvp.key().clone());
+            }
+        });
+
+        let mut map = self;
+
+        for key in doomed_keys.iter() {
+            map = map.minus(key);
+        }
+
+        map
+    }
+
+    /// Consumes the map and returns an opaque pointer owning it, e.g. for handing the map to
+    /// foreign code. The trie is not copied, the pointer refers to the boxed `HamtMap` value itself,
+    /// i.e. the root node together with all settings of the map, like its growth policy, collision
+    /// policy and observer. The layout of that value is not specified, so foreign code must treat
+    /// the pointer as opaque.
+    ///
+    /// The map is leaked unless the pointer is eventually passed to `from_raw()`, which must
+    /// happen exactly once per call to `into_raw()`.
+    pub fn into_raw(self) -> *mut () {
+        Box::into_raw(Box::new(self)) as *mut ()
+    }
+
+    /// Takes back ownership of a map previously passed to `into_raw()`.
+    ///
+    /// # Safety
+    ///
+    /// The pointer must have been returned by `into_raw()` for a map of exactly this type, and
+    /// must not be used anymore afterwards. Calling `from_raw()` twice for the same pointer
+    /// results in a double free.
+    pub unsafe fn from_raw(ptr: *mut ()) -> HamtMap<K, V, IS, H> {
+        *Box::from_raw(ptr as *mut HamtMap<K, V, IS, H>)
+    }
+
+    /// Applies `f` to every value of the map. Values are modified in-place where the nodes holding
+    /// them are not shared with other maps, and otherwise in a copy of the shared part of the trie.
+    /// Since `f` gets mutable access to the value, this avoids creating new values, which is
+    /// cheaper for big ones.
+    pub fn map_values_in_place<F>(self, mut f: F) -> HamtMap<K, V, IS, H>
+        where F: FnMut(&mut V),
+              K: Clone,
+              V: Clone
+    {
+        let HamtMap { mut root, element_count, growth, observer, collision, last_level } = self;
+
+        let copy = match root.try_borrow_owned() {
+            BorrowedNodeRef::Exclusive(node) => {
+                node.map_values_in_place(&mut f);
+                None
+            }
+            BorrowedNodeRef::Shared(node) => Some(node.with_mapped_values(&mut f)),
+        };
+
+        HamtMap {
+            root: copy.unwrap_or(root),
+            element_count,
+            growth,
+            observer,
+            collision,
+            last_level
+        }
+    }
+
+    /// Returns true if the map contains an entry for the given key.
+    pub fn contains_key(&self, key: &K) -> bool {
+        let hash = hash_of::<K, H>(key);
+        self.root.borrow().contains_item(hash, key)
+    }
+
+    /// Returns the number of distinct values stored in the map, e.g. how many different states a
+    /// set of jobs is in. The values are collected into a temporary map used as a set, in a single
+    /// pass over the map.
+    pub fn distinct_value_count(&self) -> usize
+        where V: Eq+Hash
+    {
+        let mut values = TransientHamtMap::<&V, (), CopyStore<&V, ()>, H>::new();
+
+        for (_, value) in self.iter() {
+            values.insert(value, ());
+        }
+
+        values.len()
+    }
+
+    /// Returns true if the map contains all of the given keys. Stops at the first missing key.
+    pub fn contains_all(&self, keys: &[&K]) -> bool {
+        keys.iter().all(|&key| self.contains_key(key))
+    }
+
+    /// Returns true if the map contains at least one of the given keys. Stops at the first key
+    /// found.
+    pub fn contains_any(&self, keys: &[&K]) -> bool {
+        keys.iter().any(|&key| self.contains_key(key))
+    }
+
+    /// Returns the 64-bit hash value a map of this type routes `key` by. The hasher is selected by
+    /// the map type, e.g. `HamtMap::<K, V, IS, FnvHasher>::key_hash(&key)`. Two keys with the same
+    /// hash value always end up in the same collision entry, and `from_root_parts()` expects the
+    /// hash values computed by this function.
+    pub fn key_hash(key: &K) -> u64 {
+        hash_of::<K, H>(key)
+    }
+
+    /// Returns the local keys (the 5-bit chunks of the hash value used on each level) of the
+    /// entries visited when looking up `key`, starting at the root, together with whether the key
+    /// has been found. If the lookup fails at an empty slot, the path ends at the last entry that
+    /// exists. This is meant for debugging, e.g. to find out why two keys end up in the same
+    /// sub-tree or collision entry.
+    pub fn key_path(&self, key: &K) -> (Vec<usize>, bool) {
+        let mut path = Vec::new();
+        let found = self.root.borrow().key_path(hash_of::<K, H>(key), key, &mut path);
+        (path, found)
+    }
+
+    /// Returns a clone of the value stored for `key`, or `V::default()` if the map does not
+    /// contain the key, e.g. for counters that are zero unless they have been set.
+    pub fn find_cloned_or_default(&self, key: &K) -> V
+        where V: Default + Clone
+    {
+        self.find(key).cloned().unwrap_or_default()
+    }
+
+    /// Replaces the contents of `buf` with clones of all entries of the map, in iteration order.
+    /// Passing the same buffer for every snapshot of a frequently read map avoids allocating a
+    /// new vector each time, as the buffer only grows if the map has grown.
+    pub fn write_pairs(&self, buf: &mut Vec<(K, V)>)
+        where K: Clone,
+              V: Clone
+    {
+        buf.clear();
+        buf.reserve(self.element_count);
+
+        self.root.borrow().for_each_item(&mut |kvp: &IS| {
+            buf.push((kvp.key().clone(), kvp.val().clone()));
+        });
+    }
+
+    /// Returns copies of all entries of the map, sorted in ascending order of their values, e.g.
+    /// for ranking words by their frequencies. Entries with equal values are in iteration order.
+    pub fn sorted_by_value(&self) -> Vec<(K, V)>
+        where K: Clone,
+              V: Ord+Clone
+    {
+        let mut pairs = Vec::new();
+        self.write_pairs(&mut pairs);
+        pairs.sort_by(|a, b| a.1.cmp(&b.1));
+        pairs
+    }
+
+    /// Same as `sorted_by_value()`, but sorted in descending order of the values.
+    pub fn sorted_by_value_desc(&self) -> Vec<(K, V)>
+        where K: Clone,
+              V: Ord+Clone
+    {
+        let mut pairs = Vec::new();
+        self.write_pairs(&mut pairs);
+        pairs.sort_by(|a, b| b.1.cmp(&a.1));
+        pairs
+    }
+
+    /// Returns a uniformly chosen random entry of the map, or `None` if the map is empty. Every
+    /// node keeps track of the number of entries below it, so the path down to the chosen entry
+    /// is found without visiting any other part of the map, no matter how unevenly the entries
+    /// are distributed across the trie.
+    pub fn sample<R: Rng>(&self, rng: &mut R) -> Option<(&K, &V)> {
+        if self.element_count == 0 {
+            return None;
+        }
+
+        let kvp = self.root.borrow().nth_item(rng.gen_range(0, self.element_count));
+        Some((kvp.key(), kvp.val()))
+    }
+
+    /// Returns an iterator over the entries whose hash values lie within the trie positions
+    /// `[lo, hi)`. The trie position of a hash value consists of its lower 60 bits (the ones used
+    /// for routing) with the order of the 5-bit chunks reversed, so that every sub-tree covers a
+    /// contiguous range of positions. Only sub-trees overlapping the range are visited.
+    ///
+    /// Positions lie within `[0, 1 << 60)`. Splitting this interval into disjoint ranges lets
+    /// several workers scan a shared map in parallel without visiting the whole trie each. For a
+    /// good hash function each range receives a share of the entries proportional to its size.
+    pub fn scan_hash_range(&self, lo: u64, hi: u64) -> HashRangeIterator<'_, K, V, IS, H> {
+        HashRangeIterator::new(self, lo, hi)
+    }
+
+    /// Looks up several keys at once and returns the values found, in the order of `keys`. All
+    /// returned references borrow the map for the same lifetime, so they can be held at the same
+    /// time, e.g. to pass them on together.
+    pub fn get_disjoint(&self, keys: &[&K]) -> Vec<Option<&V>> {
+        keys.iter().map(|&key| self.find(key)).collect()
+    }
+
+    // Merges `self` and `other`, whose tries must be aligned, see `UnsafeNode::merge_with()`. For
+    // keys present in both maps, `take_other` decides whether the item from `other` replaces the
+    // one from `self`.
+    fn merge_aligned<F>(&self, other: &HamtMap<K, V, IS, H>, mut take_other: F)
+        -> HamtMap<K, V, IS, H>
+        where F: FnMut(&IS, &IS) -> bool
+    {
+        debug_assert!(self.last_level == other.last_level);
+
+        // Items are only inserted after `take_other` has decided for them, so they always replace
+        // an existing item, regardless of the collision policy
+        let keep_new = |_: &V, new: V| new;
+        let context = InsertContext {
+            growth: self.growth,
+            observer: None,
+            collision: &self.collision,
+            last_level: self.last_level,
+            combine: Some(&keep_new),
+            hand_back_replaced: false
+        };
+
+        let mut duplicates = 0;
+        let root = self.root.borrow().merge_with(other.root.borrow(),
+                                                 0,
+                                                 context,
+                                                 &mut take_other,
+                                                 &mut duplicates);
+        let map = HamtMap {
+            root,
+            element_count: self.len() + other.len() - duplicates,
+            growth: self.growth,
+            observer: self.observer.clone(),
+            collision: self.collision.clone(),
+            last_level: self.last_level
+        };
+
+        map.check_element_count();
+        map
+    }
+
+    /// Returns the union of `self` and `other`. For keys present in both maps, the value for which
+    /// `ts` returns the larger result is kept, e.g. the one with the newer timestamp or version
+    /// number. If both are equal, the value from `self` is kept. `ts` is only called for keys
+    /// present in both maps.
+    pub fn merge_by<T, F>(&self, other: &HamtMap<K, V, IS, H>, mut ts: F) -> HamtMap<K, V, IS, H>
+        where T: Ord,
+              F: FnMut(&K, &V) -> T
+    {
+        if self.last_level == other.last_level {
+            let take_other_value = |kvp: &IS, other_kvp: &IS| {
+                ts(other_kvp.key(), other_kvp.val()) > ts(kvp.key(), kvp.val())
+            };
+            return self.merge_aligned(other, take_other_value);
+        }
+
+        // The tries of maps with different last levels aren't aligned at the deepest level, so
+        // start out with the larger map and only insert the entries of the smaller one
+        let (mut merged, smaller, smaller_is_self) = if self.len() >= other.len() {
+            (self.clone(), other, false)
+        } else {
+            (other.clone(), self, true)
+        };
+
+        smaller.root.borrow().for_each_item(&mut |kvp: &IS| {
+            let replace = match merged.find(kvp.key()) {
+                Some(existing) => {
+                    let candidate_ts = ts(kvp.key(), kvp.val());
+                    let existing_ts = ts(kvp.key(), existing);
+
+                    if smaller_is_self {
+                        candidate_ts >= existing_ts
+                    } else {
+                        candidate_ts > existing_ts
+                    }
+                }
+                None => true,
+            };
+
+            if replace {
+                merged = mem::take(&mut merged).insert_internal(kvp.clone()).0;
+            }
+        });
+
+        merged
+    }
+
+    /// Returns the groups of keys whose hash values are fully equal. Such keys are stored in
+    /// collision entries, which are searched linearly. Keys that only share the 60 bits used for
+    /// routing end up in the same collision entry, but are not reported as colliding here, since
+    /// a better hash function can't do anything about it. With a decent hash function, this should
+    /// return nothing for all but gigantic maps.
+    pub fn collision_groups(&self) -> Vec<Vec<K>>
+        where K: Clone
+    {
+        let mut groups = Vec::new();
+
+        self.root.borrow().for_each_collision(&mut |items: &[IS]| {
+            let mut hashed: Vec<(u64, &K)> = Vec::with_capacity(items.len());
+
+            for kvp in items.iter() {
+                hashed.push((hash_of::<K, H>(kvp.key()), kvp.key()));
+            }
+
+            hashed.sort_by_key(|&(hash, _)| hash);
+
+            let mut start = 0;
+            while start < hashed.len() {
+                let hash = hashed[start].0;
+                let end = start + hashed[start..].iter().take_while(|entry| entry.0 == hash).count();
+
+                if end - start > 1 {
+                    groups.push(hashed[start .. end].iter().map(|&(_, key)| key.clone()).collect());
+                }
+
+                start = end;
+            }
+        });
+
+        groups
+    }
+
+    /// Returns a map from the full 64-bit hash value of each key to the entries whose keys hash to
+    /// it. With a decent hash function, nearly all of these lists have a single entry. Unlike
+    /// `collision_groups()`, this reports every entry, not just the colliding ones. The item store
+    /// of the result can be chosen freely, its hash values are hashed with `H` again. The result
+    /// has the growth policy, observer and maximum depth of this map.
+    pub fn group_by_hash<GS>(&self) -> HamtMap<u64, Vec<(K, V)>, GS, H>
+        where GS: ItemStore<u64, Vec<(K, V)>>,
+              K: Clone,
+              V: Clone
+    {
+        let mut hashed: Vec<(u64, (K, V))> = Vec::with_capacity(self.element_count);
+
+        self.root.borrow().for_each_item(&mut |kvp: &IS| {
+            hashed.push((hash_of::<K, H>(kvp.key()), (kvp.key().clone(), kvp.val().clone())));
+        });
+
+        hashed.sort_by_key(|&(hash, _)| hash);
+
+        let mut groups: Vec<(u64, Vec<(K, V)>)> = Vec::new();
+        for (hash, entry) in hashed.into_iter() {
+            match groups.last_mut() {
+                Some(&mut (last_hash, ref mut entries)) if last_hash == hash => entries.push(entry),
+                _ => groups.push((hash, vec![entry])),
+            }
+        }
+
+        let items = groups.into_iter().map(|(hash, entries)| {
+            (hash_of::<u64, H>(&hash), Some(GS::new(hash, entries)))
+        }).collect();
+
+        HamtMap {
+            growth: self.growth,
+            observer: self.observer.clone(),
+            ..HamtMap::from_hashed_items(items, self.last_level)
+        }
+    }
+
+    /// Returns a map with the keys transformed by `f` and the values of this map. Since the new
+    /// keys have different hash values, the trie is rebuilt from scratch. If `f` maps several keys
+    /// to the same new key, the entry visited last by `iter()` wins. Use `map_keys_checked()` to
+    /// detect this instead. The item store of the result can be chosen freely.
+    pub fn map_keys<L, LS, F>(&self, mut f: F) -> HamtMap<L, V, LS, H>
+        where L: Eq+Send+Sync+Hash,
+              V: Clone,
+              LS: ItemStore<L, V>,
+              F: FnMut(&K) -> L
+    {
+        let items = self.iter().map(|(key, value)| {
+            let new_key = f(key);
+            (hash_of::<L, H>(&new_key), Some(LS::new(new_key, value.clone())))
+        }).collect();
+
+        HamtMap { growth: self.growth, ..HamtMap::from_hashed_items(items, self.last_level) }
+    }
+
+    /// Same as `map_keys()`, but fails with the first new key that more than one of the keys of
+    /// this map has been transformed into.
+    pub fn map_keys_checked<L, LS, F>(&self, mut f: F) -> Result<HamtMap<L, V, LS, H>, L>
+        where L: Eq+Send+Sync+Hash,
+              V: Clone,
+              LS: ItemStore<L, V>,
+              F: FnMut(&K) -> L
+    {
+        let mut mapped = self.empty_like().transient();
+
+        for (key, value) in self.iter() {
+            let new_key = f(key);
+
+            if mapped.find(&new_key).is_some() {
+                return Err(new_key);
+            }
+
+            mapped.insert(new_key, value.clone());
+        }
+
+        Ok(mapped.persistent())
+    }
+
+    /// Joins this map with `other` on their common keys: the returned map contains the keys
+    /// present in both maps, each mapped to the pair of both values. Keys present in only one of
+    /// the two maps are dropped. Parts of the tries that only exist in one of the maps are skipped
+    /// without being traversed. The item store of the result can be chosen freely.
+    pub fn zip_with<W, OS, ZS>(&self, other: &HamtMap<K, W, OS, H>) -> HamtMap<K, (V, W), ZS, H>
+        where K: Clone,
+              V: Clone,
+              W: Send+Sync+Clone,
+              OS: ItemStore<K, W>,
+              ZS: ItemStore<K, (V, W)>
+    {
+        let mut zipped = self.empty_like();
+
+        let mut add_common_item = |this_kvp: &IS, other_kvp: &OS| {
+            let pair = (this_kvp.val().clone(), other_kvp.val().clone());
+            zipped = mem::take(&mut zipped).plus(this_kvp.key().clone(), pair);
+        };
+
+        self.root.borrow().for_each_common_item(other.root.borrow(), 0, &mut add_common_item);
+        zipped
+    }
+}
+
+// ShareStore specific methods of HamtMap
+impl<K, V, H> HamtMap<K, V, ShareStore<K, V>, H>
+    where K: Eq+Send+Sync+Hash,
+          V: Send+Sync,
+          H: Hasher+Default
+{
+    /// Returns the strong count of the `Arc` holding the value of `key`, i.e. the number of item
+    /// stores sharing it. Every map node containing the entry holds one of them, so the count
+    /// tells how widely a value is shared among maps and other stores. Returns `None` if the key is
+    /// not contained in the map.
+    pub fn value_arc_count(&self, key: &K) -> Option<usize> {
+        let hash = hash_of::<K, H>(key);
+        self.root.borrow().find_item(hash, 0, key).map(|kvp| kvp.strong_count())
+    }
+
+    /// Returns an iterator over owned handles to the values of the map, see `SharedValue`. Getting
+    /// a handle only increments a reference count, so this collects all values without cloning
+    /// them and without keeping the map borrowed.
+    pub fn arc_values(&self) -> ArcValues<'_, K, V, H> {
+        ArcValues { entries: self.iter() }
+    }
+
+    /// Removes all entries whose key-value pair is referenced by nothing but this map, keeping
+    /// those that are still in use elsewhere, e.g. by another map sharing them. This implements a
+    /// "drop what nobody else is using" policy for caches. Note that all entries of nodes this map
+    /// shares with other maps count as being in use.
+    pub fn evict_unshared(self) -> HamtMap<K, V, ShareStore<K, V>, H> {
+        let (growth, observer, collision) = (self.growth, self.observer.clone(), self.collision.clone());
+        let last_level = self.last_level;
+
+        let items = MoveEntries::new(self).filter(|kvp| kvp.strong_count() > 1)
+                                          .map(|kvp| (hash_of::<K, H>(kvp.key()), Some(kvp)))
+                                          .collect();
+
+        HamtMap {
+            growth,
+            observer,
+            collision,
+            ..HamtMap::from_hashed_items(items, last_level)
+        }
+    }
+}
+
+// Clone for HamtMap
+impl<K, V, IS, H> Clone for HamtMap<K, V, IS, H> {
+    fn clone(&self) -> HamtMap<K, V, IS, H> {
+        HamtMap {
+            root: self.root.clone(),
+            element_count: self.element_count,
+            growth: self.growth,
+            observer: self.observer.clone(),
+            collision: self.collision.clone(),
+            last_level: self.last_level
+        }
+    }
+}
+
+// Default for HamtMap
+impl<K, V, IS, H> Default for HamtMap<K, V, IS, H>
+    where K: Eq+Send+Sync+Hash,
+          V: Send+Sync,
+          IS: ItemStore<K, V>,
+          H: Hasher+Default
+{
+    fn default() -> HamtMap<K, V, IS, H> {
+        HamtMap::new()
+    }
+}
+
+impl<'a, K, V, IS, H> IntoIterator for &'a HamtMap<K, V, IS, H>
+    where K: Eq+Send+Sync+Hash+'a,
+          V: Send+Sync+'a,
+          IS: ItemStore<K, V>+'a,
+          H: Hasher+Default+'a
+{
+    type Item = (&'a K, &'a V);
+    type IntoIter = HamtMapIterator<'a, K, V, IS, H>;
+
+    fn into_iter(self) -> HamtMapIterator<'a, K, V, IS, H>
+    {
+        self.iter()
+    }
+}
+
+impl<K, V, IS, H> IntoIterator for HamtMap<K, V, IS, H>
+    where K: Eq+Send+Sync+Hash+Clone,
+          V: Send+Sync+Clone,
+          IS: ItemStore<K, V>,
+          H: Hasher+Default
+{
+    type Item = (K, V);
+    type IntoIter = IntoIter<K, V, IS, H>;
+
+    /// Consumes the map and returns an iterator moving its entries out. Nodes that are not shared
+    /// with any other map are taken apart and freed as the iteration goes on, so their entries are
+    /// moved instead of cloned. Entries of shared nodes are cloned.
+    fn into_iter(self) -> IntoIter<K, V, IS, H> {
+        IntoIter { entries: MoveEntries::new(self) }
+    }
+}
+
+// PartialEq for HamtMap
+// Maps sharing their root node, e.g. a map and its clone, are equal without looking at their
+// entries. This deviates from comparing the entries for values that aren't equal to themselves:
+// a map holding a NaN value is equal to its clone, but not to a map that has been built from the
+// same entries. `partial_cmp()` takes the same shortcut, so that both stay consistent.
+impl<K, V, IS, H> PartialEq for HamtMap<K, V, IS, H>
+    where K: Eq+Send+Sync+Hash,
+          V: PartialEq+Send+Sync,
+          IS: ItemStore<K, V>,
+          H: Hasher+Default
+{
+    fn eq(&self, other: &HamtMap<K, V, IS, H>) -> bool {
+        if self.root.ptr == other.root.ptr {
+            return true;
+        }
+
+        if self.len() != other.len() {
+            return false;
+        }
+
+        for (k, other_value) in other.iter() {
+            match self.find(k) {
+                Some(this_value) => {
+                    if *this_value != *other_value {
+                        return false;
+                    }
+                }
+                None => {
+                    return false;
+                }
+            }
+        }
+
+        true
+    }
+}
+
+
+// Eq for HamtMap
+impl<K, V, IS, H> Eq for HamtMap<K, V, IS, H>
+    where K: Eq+Send+Sync+Hash,
+          V: Eq+Send+Sync,
+          IS: ItemStore<K, V>,
+          H: Hasher+Default
+{
+}
+
+// PartialOrd for HamtMap
+// Maps are compared lexicographically by their entries in ascending key order. Since the trie
+// doesn't store entries in key order, both maps are sorted first, which takes O(n log n) time for
+// every comparison. As with `==`, maps sharing their root node are equal.
+impl<K, V, IS, H> PartialOrd for HamtMap<K, V, IS, H>
+    where K: Ord+Send+Sync+Hash,
+          V: PartialOrd+Send+Sync,
+          IS: ItemStore<K, V>,
+          H: Hasher+Default
+{
+    fn partial_cmp(&self, other: &HamtMap<K, V, IS, H>) -> Option<cmp::Ordering> {
+        if self.root.ptr == other.root.ptr {
+            return Some(cmp::Ordering::Equal);
+        }
+
+        self.sorted_entries().partial_cmp(&other.sorted_entries())
+    }
+}
+
+// Ord for HamtMap
+impl<K, V, IS, H> Ord for HamtMap<K, V, IS, H>
+    where K: Ord+Send+Sync+Hash,
+          V: Ord+Send+Sync,
+          IS: ItemStore<K, V>,
+          H: Hasher+Default
+{
+    fn cmp(&self, other: &HamtMap<K, V, IS, H>) -> cmp::Ordering {
+        self.sorted_entries().cmp(&other.sorted_entries())
+    }
+}
+
+
+// FromIterator
+impl<K, V, IS, H> ::std::iter::FromIterator<(K, V)> for HamtMap<K, V, IS, H>
+    where K: Eq+Send+Sync+Hash,
+          V: Send+Sync,
+          IS: ItemStore<K, V>,
+          H: Hasher+Default
+{
+    /// Builds a map from the given pairs. Like with `insert()`, a later pair replaces the value
+    /// of an earlier one with the same key. The map being built is not shared with anything, so
+    /// its nodes are updated in place. The hasher is selected by the target type, e.g.
+    /// `pairs.collect::<HamtMap<_, _, CopyStore<_, _>, FnvHasher>>()`.
+    fn from_iter<T>(iterator: T) -> Self where T: IntoIterator<Item=(K, V)> {
+        let iterator = iterator.into_iter();
+        let mut map = HamtMap::with_capacity(iterator.size_hint().0);
+
+        for (k, v) in iterator {
+            map = map.plus(k, v);
+        }
+
+        map
+    }
+}
+
+// Extend
+impl<K, V, IS, H> Extend<(K, V)> for HamtMap<K, V, IS, H>
+    where K: Eq+Send+Sync+Hash,
+          V: Send+Sync,
+          IS: ItemStore<K, V>,
+          H: Hasher+Default
+{
+    /// Inserts the given pairs, replacing the values of keys already in the map. Nodes not shared
+    /// with another map are updated in place.
+    fn extend<T>(&mut self, iterator: T) where T: IntoIterator<Item=(K, V)> {
+        let mut map = mem::take(self);
+
+        for (k, v) in iterator {
+            map = map.plus(k, v);
+        }
+
+        *self = map;
+    }
+}
+
+// Extend from references
+impl<'a, K, V, IS, H> Extend<&'a (K, V)> for HamtMap<K, V, IS, H>
+    where K: Eq+Send+Sync+Hash+Clone+'a,
+          V: Send+Sync+Clone+'a,
+          IS: ItemStore<K, V>,
+          H: Hasher+Default
+{
+    fn extend<T>(&mut self, iterator: T) where T: IntoIterator<Item=&'a (K, V)> {
+        let mut map = mem::take(self);
+
+        for (k, v) in iterator {
+            map = map.plus(k.clone(), v.clone());
+        }
+
+        *self = map;
+    }
+}
+
+
+//=-------------------------------------------------------------------------------------------------
+// Entry
+//=-------------------------------------------------------------------------------------------------
+/// A map together with a key, as returned by `HamtMap::entry()`. The methods of this type consume
+/// the entry and return the updated map.
+pub struct Entry<K, V, IS, H> {
+    map: HamtMap<K, V, IS, H>,
+    key: K,
+}
+
+impl<K, V, IS, H> Entry<K, V, IS, H>
+    where K: Eq+Send+Sync+Hash,
+          V: Send+Sync,
+          IS: ItemStore<K, V>,
+          H: Hasher+Default
+{
+    /// Returns the map with `default` inserted for the key, unless the key is already present.
+    pub fn or_insert(self, default: V) -> HamtMap<K, V, IS, H> {
+        self.or_insert_with_key(|_| default)
+    }
+
+    /// Returns the map with the result of `f` inserted for the key, unless the key is already
+    /// present. `f` is only called if the key is missing.
+    pub fn or_insert_with<F>(self, f: F) -> HamtMap<K, V, IS, H>
+        where F: FnOnce() -> V
+    {
+        self.or_insert_with_key(|_| f())
+    }
+
+    /// Returns the map with the result of `f(key)` inserted for the key, unless the key is already
+    /// present. This is useful if the default value is derived from the key. `f` is only called
+    /// if the key is missing.
+    pub fn or_insert_with_key<F>(self, f: F) -> HamtMap<K, V, IS, H>
+        where F: FnOnce(&K) -> V
+    {
+        let Entry { map, key } = self;
+
+        if map.find(&key).is_some() {
+            return map;
+        }
+
+        let value = f(&key);
+        map.plus(key, value)
+    }
+}
+
+//=-------------------------------------------------------------------------------------------------
+// TransientHamtMap
+//=-------------------------------------------------------------------------------------------------
+/// A mutable builder for `HamtMap`s. Nodes the builder doesn't share with any persistent map are
+/// always modified in-place, so filling a map with lots of entries doesn't create any garbage
+/// along the way. Get one with `HamtMap::transient()` and turn it back into a persistent map with
+/// `persistent()`, both of which are O(1).
+pub struct TransientHamtMap<K, V, IS=ShareStore<K,V>, H=StdHasher> {
+    map: HamtMap<K, V, IS, H>,
+}
+
+impl<K, V, IS, H> TransientHamtMap<K, V, IS, H>
+    where K: Eq+Send+Sync+Hash,
+          V: Send+Sync,
+          IS: ItemStore<K, V>,
+          H: Hasher+Default
+{
+    pub fn new() -> TransientHamtMap<K, V, IS, H> {
+        HamtMap::new().transient()
+    }
+
+    /// Inserts a key-value pair, replacing the value previously stored for the key. Returns true
+    /// if the key was not present before.
+    pub fn insert(&mut self, key: K, value: V) -> bool {
+        self.insert_item(IS::new(key, value))
+    }
+
+    /// Inserts all the given key-value pairs, like calling `insert()` for each of them, but
+    /// descends into each node only once for all pairs ending up below it, instead of starting
+    /// from the root for every single pair. New sub-trees are built bottom-up. This is fastest if
+    /// the pairs are already sorted in the order in which a map's iterator yields them. Otherwise
+    /// they are sorted first. Of multiple pairs with the same key, the last one wins.
+    pub fn insert_sorted(&mut self, pairs: &[(K, V)])
+        where K: Clone,
+              V: Clone
+    {
+        let mut items: Vec<(u64, Option<IS>)> = pairs.iter().map(|(key, value)| {
+            (hash_of::<K, H>(key), Some(IS::new(key.clone(), value.clone())))
+        }).collect();
+
+        HamtMap::<K, V, IS, H>::sort_hashed_items(&mut items);
+
+        if items.is_empty() {
+            return;
+        }
+
+        let HamtMap { root, element_count, growth, observer, collision, last_level } =
+            mem::take(&mut self.map);
+        let mut insertion_count = 0;
+
+        let context = InsertContext {
+            growth,
+            observer: observer.as_deref(),
+            collision: &collision,
+            last_level,
+            combine: None,
+            hand_back_replaced: false
+        };
+
+        let root = root.borrow().insert_sorted_items(&mut items[..],
+                                                     0,
+                                                     &mut insertion_count,
+                                                     context);
+
+        self.map = HamtMap {
+            root,
+            element_count: element_count + insertion_count,
+            growth,
+            observer: observer.clone(),
+            collision: collision.clone(),
+            last_level
+        };
+        self.map.check_element_count();
+    }
+
+    /// Removes the entry for the given key. Returns true if the key was present.
+    pub fn remove(&mut self, key: &K) -> bool {
+        let (map, removed) = mem::take(&mut self.map).remove(key);
+        self.map = map;
+        removed
+    }
+
+    pub fn find(&self, key: &K) -> Option<&V> {
+        self.map.find(key)
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.map.contains_key(key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.len() == 0
+    }
+
+    /// Inserts all entries of `other`, replacing the values of keys already present. This is
+    /// the in-place counterpart of a union preferring `other`'s values, meant for combining many
+    /// maps while building a new one. `other` isn't modified.
+    pub fn merge_in_place(&mut self, other: &HamtMap<K, V, IS, H>) {
+        other.root.borrow().for_each_item(&mut |kvp: &IS| {
+            self.insert_item(kvp.clone());
+        });
+    }
+
+    /// Removes all entries for which `f` returns false. Unlike the persistent `retain()`, which
+    /// builds a new map from the remaining entries, nodes are modified in-place. Only nodes the
+    /// builder still shares with persistent maps are copied, once.
+    pub fn retain<F>(&mut self, mut f: F)
+        where F: FnMut(&K, &V) -> bool
+    {
+        let root = UnsafeNode::make_exclusive(&mut self.map.root);
+        let removal_count = root.retain_in_place(&mut f);
+
+        self.map.element_count -= removal_count;
+        self.map.check_element_count();
+    }
+
+    /// Turns the builder into a persistent map containing its entries.
+    pub fn persistent(self) -> HamtMap<K, V, IS, H> {
+        self.map
+    }
+
+    fn insert_item(&mut self, kvp: IS) -> bool {
+        let (map, inserted) = mem::take(&mut self.map).insert_internal(kvp);
+        self.map = map;
+        inserted
+    }
+
+    // Same as `merge_in_place()`, but the values of keys already present are replaced by the
+    // result of calling `combine` with the existing and the new value.
+    fn merge_in_place_combining(&mut self, other: &HamtMap<K, V, IS, H>, combine: CombineRef<V>) {
+        other.root.borrow().for_each_item(&mut |kvp: &IS| {
+            let (map, _) = mem::take(&mut self.map).insert_combining(kvp.clone(), Some(combine));
+            self.map = map;
+        });
+    }
+}
+
+impl<K, V, IS, H> Default for TransientHamtMap<K, V, IS, H>
+    where K: Eq+Send+Sync+Hash,
+          V: Send+Sync,
+          IS: ItemStore<K, V>,
+          H: Hasher+Default
+{
+    fn default() -> TransientHamtMap<K, V, IS, H> {
+        TransientHamtMap::new()
+    }
+}
+
+//=-------------------------------------------------------------------------------------------------
+// HamtMapIterator
+//=-------------------------------------------------------------------------------------------------
+
+#[derive(Copy)]
+enum IterNodeRef<'a, K, V, IS, H>
+    where K: 'a,
+          V: 'a,
+          IS: 'a,
+          H: 'a
+{
+    RegularNode(&'a UnsafeNode<K, V, IS, H>),
+    CollisionEntry(&'a Vec<IS>)
+}
+
+impl<'a, K, V, IS, H> Clone for IterNodeRef<'a, K, V, IS, H>
+    where K: 'a,
+          V: 'a,
+          IS: 'a,
+          H: 'a
+{
+    fn clone(&self) -> Self {
+        match *self {
+            IterNodeRef::RegularNode(x) => IterNodeRef::RegularNode(x),
+            IterNodeRef::CollisionEntry(x) => IterNodeRef::CollisionEntry(x)
+        }
+    }
+}
+
+/// The saved position of a `HamtMapIterator`, see `HamtMapIterator::cursor()`. A cursor does not
+/// borrow the map. It stores the position within each node on the path down to the current item,
+/// so it can only be used with the map it was created from (or with an unmodified clone of it).
+#[derive(Clone, Debug)]
+pub struct Cursor {
+    indices: Vec<isize>,
+    remaining: usize,
+}
+
+/// An iterator over the entries of a map, see `HamtMap::iter()`. The trie is traversed depth-first
+/// with an explicit stack of node positions, so collision entries are walked item by item and no
+/// recursion is involved. Every entry is yielded exactly once.
+pub struct HamtMapIterator<'a, K, V, IS, H>
+    where K: 'a,
+          V: 'a,
+          IS: 'a,
+          H: 'a
+{
+    node_stack: [(IterNodeRef<'a, K, V, IS, H>, isize); LAST_LEVEL + 2],
+    stack_size: usize,
+    len: usize,
+}
+
+/// The iterator returned by `HamtMap::iter()`, under the name used for the iterators of the other
+/// collections in `std`.
+pub type Entries<'a, K, V, IS, H=StdHasher> = HamtMapIterator<'a, K, V, IS, H>;
+
+impl<'a, K, V, IS, H>
+HamtMapIterator<'a, K, V, IS, H>
+    where K: Eq+Send+Sync,
+          V: Send+Sync,
+          IS: ItemStore<K, V>,
+          H: Hasher
+{
+    fn new(map: &'a HamtMap<K, V, IS, H>) -> HamtMapIterator<'a, K, V, IS, H> {
+        let mut iterator = HamtMapIterator {
+            node_stack: unsafe{ mem::zeroed() },
+            stack_size: 1,
+            len: map.element_count,
+        };
+
+        iterator.node_stack[0] = (IterNodeRef::RegularNode(map.root.borrow()), -1);
+        iterator
+    }
+
+    // Creates an iterator over the `item_count` items below the given slot of the map's root node
+    // only. It starts right before the slot's entry and stops after the entry's items have been
+    // returned.
+    fn for_root_slot(map: &'a HamtMap<K, V, IS, H>,
+                     slot: usize,
+                     item_count: usize)
+                  -> HamtMapIterator<'a, K, V, IS, H> {
+        let root = map.root.borrow();
+        let mut iterator = HamtMapIterator {
+            node_stack: unsafe{ mem::zeroed() },
+            stack_size: 0,
+            len: item_count,
+        };
+
+        if (root.mask & (1 << slot)) != 0 {
+            let index = get_index(root.mask, slot) as isize;
+            iterator.node_stack[0] = (IterNodeRef::RegularNode(root), index - 1);
+            iterator.stack_size = 1;
+        }
+
+        iterator
+    }
+
+    // Rebuilds the iterator state saved in the given cursor on top of the given map. Panics if the
+    // cursor does not fit the structure of the map.
+    fn from_cursor(map: &'a HamtMap<K, V, IS, H>,
+                   cursor: Cursor)
+                -> HamtMapIterator<'a, K, V, IS, H> {
+        let mut iterator = HamtMapIterator {
+            node_stack: unsafe{ mem::zeroed() },
+            stack_size: 0,
+            len: cursor.remaining,
+        };
+
+        for (depth, &index) in cursor.indices.iter().enumerate() {
+            let node = if depth == 0 {
+                IterNodeRef::RegularNode(map.root.borrow())
+            } else {
+                match iterator.node_stack[depth - 1] {
+                    (IterNodeRef::RegularNode(parent), parent_index) => {
+                        assert!(parent_index >= 0 && (parent_index as usize) < parent.entry_count(),
+                                "cursor does not belong to this map");
+
+                        match parent.get_entry(parent_index as usize) {
+                            NodeEntryRef::SubTree(sub_tree_ref) => {
+                                IterNodeRef::RegularNode(sub_tree_ref.borrow())
+                            }
+                            NodeEntryRef::Collision(items) => IterNodeRef::CollisionEntry(&**items),
+                            NodeEntryRef::Item(_) => panic!("cursor does not belong to this map"),
+                        }
+                    }
+                    (IterNodeRef::CollisionEntry(_), _) => {
+                        panic!("cursor does not belong to this map")
+                    }
+                }
+            };
+
+            let entry_count = match node {
+                IterNodeRef::RegularNode(node) => node.entry_count(),
+                IterNodeRef::CollisionEntry(items) => items.len(),
+            };
+            assert!(index >= -1 && index < entry_count as isize,
+                    "cursor does not belong to this map");
+            assert!(depth < iterator.node_stack.len(), "cursor does not belong to this map");
+
+            iterator.node_stack[depth] = (node, index);
+            iterator.stack_size += 1;
+        }
+
+        iterator
+    }
+
+    /// Returns a cursor marking the current position of this iterator. Passing it to
+    /// `HamtMap::iter_from()` yields an iterator continuing with the items this iterator has not
+    /// returned yet.
+    pub fn cursor(&self) -> Cursor {
+        Cursor {
+            indices: self.node_stack[.. self.stack_size].iter().map(|&(_, index)| index).collect(),
+            remaining: self.len,
+        }
+    }
+
+    // The level of the trie the item returned last is stored on. Items of a collision entry have
+    // an additional frame on the stack.
+    fn current_depth(&self) -> usize {
+        match self.node_stack[self.stack_size - 1] {
+            (IterNodeRef::RegularNode(_), _) => self.stack_size - 1,
+            (IterNodeRef::CollisionEntry(_), _) => self.stack_size - 2,
+        }
+    }
+
+    // Moves on to the next item. This does not update `len`.
+    fn advance(&mut self) -> Option<&'a IS> {
+        if self.stack_size == 0 {
+            return None;
+        }
+
+        let (current_node, index) = self.node_stack[self.stack_size - 1].clone();
+        let next_index: usize = (index + 1) as usize;
+
+        match current_node {
+            IterNodeRef::RegularNode(node_ref) => {
+                if next_index == node_ref.entry_count() {
+                    self.stack_size -= 1;
+                    return self.advance();
+                } else {
+                    let (_, ref mut stack_index) = self.node_stack[self.stack_size - 1];
+                    *stack_index = next_index as isize;
+                }
+
+                match node_ref.get_entry(next_index) {
+                    NodeEntryRef::Item(item_ref) => {
+                        return Some(item_ref);
+                    }
+                    NodeEntryRef::Collision(items_arc) => {
+                        let items = &**items_arc;
+                        self.node_stack[self.stack_size] = (IterNodeRef::CollisionEntry(items), 0);
+                        self.stack_size += 1;
+                        return Some(&items[0]);
+                    },
+                    NodeEntryRef::SubTree(subtree_ref) => {
+                        self.node_stack[self.stack_size] = (IterNodeRef::RegularNode(subtree_ref.borrow()), -1);
+                        self.stack_size += 1;
+                        return self.advance();
+                    }
+                };
+            }
+            IterNodeRef::CollisionEntry(items_ref) => {
+                if next_index == items_ref.len() {
+                    self.stack_size -= 1;
+                    return self.advance();
+                }
+
+                let (_, ref mut stack_index) = self.node_stack[self.stack_size - 1];
+                *stack_index = next_index as isize;
+
+                return Some(&items_ref[next_index]);
+            }
+        }
+    }
+
+    // Returns the next item and updates `len`. Iterators over a part of the map stop once `len`
+    // reaches zero.
+    fn next_item(&mut self) -> Option<&'a IS> {
+        if self.len == 0 {
+            return None;
+        }
+
+        let item = self.advance();
+
+        if item.is_some() {
+            self.len -= 1;
+        }
+
+        item
+    }
+}
+
+impl<'a, K, V, IS, H>
+Iterator for HamtMapIterator<'a, K, V, IS, H>
+    where K: Eq+Send+Sync,
+          V: Send+Sync,
+          IS: ItemStore<K, V>,
+          H: 'a + Hasher
+{
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<(&'a K, &'a V)> {
+        self.next_item().map(|kvp| (kvp.key(), kvp.val()))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.len, Some(self.len))
+    }
+}
+
+//=-------------------------------------------------------------------------------------------------
+// HashRangeIterator
+//=-------------------------------------------------------------------------------------------------
+// For each level on the path down to the current entry: the node, the local keys of the entries
+// not visited yet, and the trie position of the node.
+type HashRangeFrame<'a, K, V, IS, H> = (&'a UnsafeNode<K, V, IS, H>, u32, u64);
+
+/// An iterator over the entries of a map whose hash values lie in a given range of trie positions,
+/// see `HamtMap::scan_hash_range()`.
+pub struct HashRangeIterator<'a, K, V, IS, H>
+    where K: 'a,
+          V: 'a,
+          IS: 'a,
+          H: 'a
+{
+    node_stack: Vec<HashRangeFrame<'a, K, V, IS, H>>,
+    // The items of the collision entry currently iterated over.
+    collision_items: &'a [IS],
+    // Whether the range covers all positions of that collision entry. Only collision entries
+    // above the last level (see `HamtMap::with_max_levels()`) may be partially covered.
+    collision_covered: bool,
+    lo: u64,
+    hi: u64,
+}
+
+impl<'a, K, V, IS, H>
+HashRangeIterator<'a, K, V, IS, H>
+    where K: Eq+Send+Sync+Hash,
+          V: Send+Sync,
+          IS: ItemStore<K, V>,
+          H: Hasher+Default
+{
+    fn new(map: &'a HamtMap<K, V, IS, H>, lo: u64, hi: u64) -> HashRangeIterator<'a, K, V, IS, H> {
+        let root = map.root.borrow();
+
+        HashRangeIterator {
+            node_stack: vec![(root, root.mask, 0)],
+            collision_items: &[],
+            collision_covered: true,
+            lo,
+            hi,
+        }
+    }
+
+    fn contains_key_position(&self, key: &K) -> bool {
+        (self.lo .. self.hi).contains(&trie_order(hash_of::<K, H>(key)))
+    }
+}
+
+impl<'a, K, V, IS, H>
+Iterator for HashRangeIterator<'a, K, V, IS, H>
+    where K: Eq+Send+Sync+Hash,
+          V: Send+Sync,
+          IS: ItemStore<K, V>,
+          H: Hasher+Default
+{
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<(&'a K, &'a V)> {
+        loop {
+            if let Some((item, rest)) = self.collision_items.split_first() {
+                self.collision_items = rest;
+
+                if self.collision_covered || self.contains_key_position(item.key()) {
+                    return Some((item.key(), item.val()));
+                }
+
+                continue;
+            }
+
+            let level = self.node_stack.len().checked_sub(1)?;
+            let (node, remaining, position) = self.node_stack[level];
+
+            if remaining == 0 {
+                self.node_stack.pop();
+                continue;
+            }
+
+            let local_key = remaining.trailing_zeros() as usize;
+            self.node_stack[level].1 = remaining & (remaining - 1);
+
+            // The range of trie positions covered by the entry
+            let entry_position = (position << BITS_PER_LEVEL) | local_key as u64;
+            let shift = BITS_PER_LEVEL * (LAST_LEVEL - level);
+            let start = entry_position << shift;
+            let end = start + (1 << shift);
+
+            if start >= self.hi || end <= self.lo {
+                continue;
+            }
+
+            match node.get_entry(get_index(node.mask, local_key)) {
+                NodeEntryRef::Item(kvp_ref) => {
+                    // Items aren't necessarily stored on the last level, so unless the range of
+                    // the entry is fully covered, the position of the item has to be checked
+                    let covered = start >= self.lo && end <= self.hi;
+
+                    if covered || self.contains_key_position(kvp_ref.key()) {
+                        return Some((kvp_ref.key(), kvp_ref.val()));
+                    }
+                }
+                NodeEntryRef::Collision(items) => {
+                    self.collision_items = &items[..];
+                    self.collision_covered = start >= self.lo && end <= self.hi;
+                }
+                NodeEntryRef::SubTree(sub_tree_ref) => {
+                    let sub_tree = sub_tree_ref.borrow();
+                    self.node_stack.push((sub_tree, sub_tree.mask, entry_position));
+                }
+            }
+        }
+    }
+}
+
+//=-------------------------------------------------------------------------------------------------
+// MoveEntries
+//=-------------------------------------------------------------------------------------------------
+// An iterator moving the items out of a map. Nodes (and collision entries) that are not shared
+// with any other map are taken apart and freed as the iteration goes on, so their items can be
+// moved instead of cloned. The items of shared nodes are cloned, which is cheap for ShareStore.
+struct MoveEntries<K, V, IS, H> {
+    // The entries still to be visited, the next one at the end
+    pending: Vec<NodeEntryOwned<K, V, IS, H>>,
+    len: usize,
+}
+
+impl<K, V, IS, H> MoveEntries<K, V, IS, H> {
+    fn new(map: HamtMap<K, V, IS, H>) -> MoveEntries<K, V, IS, H> {
+        let HamtMap { root, element_count, .. } = map;
+
+        MoveEntries {
+            pending: vec!(NodeEntryOwned::SubTree(root)),
+            len: element_count,
+        }
+    }
+}
+
+impl<K, V, IS, H> Iterator for MoveEntries<K, V, IS, H>
+    where K: Eq+Send+Sync,
+          V: Send+Sync,
+          IS: ItemStore<K, V>,
+          H: Hasher
+{
+    type Item = IS;
+
+    fn next(&mut self) -> Option<IS> {
+        loop {
+            match self.pending.pop()? {
+                NodeEntryOwned::Item(kvp) => {
+                    self.len -= 1;
+                    return Some(kvp);
+                }
+                NodeEntryOwned::Collision(items_arc) => {
+                    match Arc::try_unwrap(items_arc) {
+                        Ok(items) => {
+                            self.pending.extend(items.into_iter().rev().map(NodeEntryOwned::Item));
+                        }
+                        Err(items_arc) => {
+                            self.pending.extend(items_arc.iter().rev().cloned().map(NodeEntryOwned::Item));
+                        }
+                    }
+                }
+                NodeEntryOwned::SubTree(mut node_ref) => {
+                    match node_ref.try_borrow_owned() {
+                        BorrowedNodeRef::Exclusive(node) => node.take_entries(&mut self.pending),
+                        BorrowedNodeRef::Shared(node) => {
+                            for index in (0 .. node.entry_count()).rev() {
+                                self.pending.push(node.get_entry(index).clone_out());
+                            }
+                        }
+                    }
+                    // `node_ref` is released here, freeing the node if it was taken apart
+                }
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.len, Some(self.len))
+    }
+}
+
+//=-------------------------------------------------------------------------------------------------
+// DepthEntries
+//=-------------------------------------------------------------------------------------------------
+/// An iterator over the entries of a map together with the level of the trie they are stored on,
+/// see `HamtMap::iter_with_depth()`.
+pub struct DepthEntries<'a, K, V, IS, H>
+    where K: 'a,
+          V: 'a,
+          IS: 'a,
+          H: 'a
+{
+    entries: HamtMapIterator<'a, K, V, IS, H>,
+}
+
+impl<'a, K, V, IS, H>
+Iterator for DepthEntries<'a, K, V, IS, H>
+    where K: Eq+Send+Sync,
+          V: Send+Sync,
+          IS: ItemStore<K, V>,
+          H: 'a + Hasher
+{
+    type Item = (usize, &'a K, &'a V);
+
+    fn next(&mut self) -> Option<(usize, &'a K, &'a V)> {
+        let (key, value) = self.entries.next()?;
+        Some((self.entries.current_depth(), key, value))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.entries.size_hint()
+    }
+}
+
+//=-------------------------------------------------------------------------------------------------
+// Keys
+//=-------------------------------------------------------------------------------------------------
+/// An iterator over the keys of a map, see `HamtMap::keys()`.
+pub struct Keys<'a, K, V, IS, H>
+    where K: 'a,
+          V: 'a,
+          IS: 'a,
+          H: 'a
+{
+    entries: HamtMapIterator<'a, K, V, IS, H>,
+}
+
+impl<'a, K, V, IS, H>
+Iterator for Keys<'a, K, V, IS, H>
+    where K: Eq+Send+Sync,
+          V: Send+Sync,
+          IS: ItemStore<K, V>,
+          H: 'a + Hasher
+{
+    type Item = &'a K;
+
+    fn next(&mut self) -> Option<&'a K> {
+        self.entries.next().map(|(key, _)| key)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.entries.size_hint()
+    }
+}
+
+//=-------------------------------------------------------------------------------------------------
+// Values
+//=-------------------------------------------------------------------------------------------------
+/// An iterator over the values of a map, see `HamtMap::values()`.
+pub struct Values<'a, K, V, IS, H>
+    where K: 'a,
+          V: 'a,
+          IS: 'a,
+          H: 'a
+{
+    entries: HamtMapIterator<'a, K, V, IS, H>,
+}
+
+impl<'a, K, V, IS, H>
+Iterator for Values<'a, K, V, IS, H>
+    where K: Eq+Send+Sync,
+          V: Send+Sync,
+          IS: ItemStore<K, V>,
+          H: 'a + Hasher
+{
+    type Item = &'a V;
+
+    fn next(&mut self) -> Option<&'a V> {
+        self.entries.next().map(|(_, value)| value)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.entries.size_hint()
+    }
+}
+
+//=-------------------------------------------------------------------------------------------------
+// ArcValues
+//=-------------------------------------------------------------------------------------------------
+/// An iterator over owned handles to the values of a map with shared item stores, see
+/// `HamtMap::arc_values()`.
+pub struct ArcValues<'a, K, V, H>
+    where K: 'a,
+          V: 'a,
+          H: 'a
+{
+    entries: HamtMapIterator<'a, K, V, ShareStore<K, V>, H>,
+}
+
+impl<'a, K, V, H>
+Iterator for ArcValues<'a, K, V, H>
+    where K: Eq+Send+Sync,
+          V: Send+Sync,
+          H: 'a + Hasher
+{
+    type Item = SharedValue<K, V>;
+
+    fn next(&mut self) -> Option<SharedValue<K, V>> {
+        self.entries.next_item().map(|kvp| kvp.shared_value())
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.entries.size_hint()
+    }
+}
+
+//=-------------------------------------------------------------------------------------------------
+// IntoSorted
+//=-------------------------------------------------------------------------------------------------
+/// An iterator yielding the entries of a consumed map in ascending key order, see
+/// `HamtMap::into_sorted_iter()`.
+pub struct IntoSorted<K, V> {
+    pairs: ::std::vec::IntoIter<(K, V)>,
+}
+
+impl<K, V> Iterator for IntoSorted<K, V> {
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<(K, V)> {
+        self.pairs.next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.pairs.size_hint()
+    }
+}
+
+impl<K, V> ExactSizeIterator for IntoSorted<K, V> {}
+
+//=-------------------------------------------------------------------------------------------------
+// IntoIter
+//=-------------------------------------------------------------------------------------------------
+/// An iterator moving the entries out of a consumed map, in the order in which they are stored in
+/// the trie, see `HamtMap::into_iter()`.
+pub struct IntoIter<K, V, IS, H> {
+    entries: MoveEntries<K, V, IS, H>,
+}
+
+impl<K, V, IS, H> Iterator for IntoIter<K, V, IS, H>
+    where K: Eq+Send+Sync+Clone,
+          V: Send+Sync+Clone,
+          IS: ItemStore<K, V>,
+          H: Hasher
+{
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<(K, V)> {
+        self.entries.next().map(|kvp| match kvp.try_into_pair() {
+            Ok(pair) => pair,
+            Err(kvp) => (kvp.key().clone(), kvp.val().clone())
+        })
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.entries.size_hint()
+    }
+}
+
+impl<K, V, IS, H> ExactSizeIterator for IntoIter<K, V, IS, H>
+    where K: Eq+Send+Sync+Clone,
+          V: Send+Sync+Clone,
+          IS: ItemStore<K, V>,
+          H: Hasher
+{}
+
+//=-------------------------------------------------------------------------------------------------
+// Utility functions
+//=------------------------------------------------------------------------------------------------
+fn get_index(mask: u32, index: usize) -> usize {
+    debug_assert!((mask & (1 << index)) != 0);
+
+    let bits_set_up_to_index = (1 << index) - 1;
+    let masked = mask & bits_set_up_to_index;
+
+    bit_count(masked)
+}
+
+#[inline]
+fn bit_count(x: u32) -> usize {
+    x.count_ones() as usize
+}
+
+#[inline]
+fn align_to(size: usize, align: usize) -> usize {
+    debug_assert!(align != 0 && bit_count(align as u32) == 1);
+    (size + align - 1) & !(align - 1)
+}
+
+// Maps a hash value to a number such that sorting by that number yields the order in which the
+// trie stores the items, i.e. ordered by the local key at the root level first, then by the local
+// key at the level below, and so on.
+fn trie_order(hash: u64) -> u64 {
+    let mut order = 0;
+
+    for level in 0 .. LAST_LEVEL + 1 {
+        order = (order << BITS_PER_LEVEL) | ((hash >> (BITS_PER_LEVEL * level)) & LEVEL_BIT_MASK);
+    }
+
+    order
+}
+
+#[inline]
+fn hash_of<T: Hash, H: Hasher + Default>(value: &T) -> u64 {
+    let mut h: H = Default::default();
+    value.hash(&mut h);
+    h.finish()
+}
+
+#[inline(always)]
+pub unsafe fn allocate(size: usize, _align: usize) -> *mut u8 {
+    libc::malloc(size as libc::size_t) as *mut u8
+}
+
+#[inline(always)]
+pub unsafe fn deallocate(ptr: *mut u8, _old_size: usize, _align: usize) {
+    libc::free(ptr as *mut libc::c_void)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::get_index;
+    use super::{HamtMap, UnsafeNode, NodeEntryRef, NodeEntryOwned, StdHasher, BITS_PER_LEVEL,
+                CorruptionError};
+    use item_store::ItemStore;
+    use testing::Test;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    type CopyStore = ::item_store::CopyStore<u64, u64>;
+    type ShareStore = ::item_store::ShareStore<u64, u64>;
+
+    #[test]
+    fn test_get_index() {
+        assert_eq!(get_index(0b00000000000000000000000000000001, 0), 0);
+        assert_eq!(get_index(0b00000000000000000000000000000010, 1), 0);
+        assert_eq!(get_index(0b00000000000000000000000000000100, 2), 0);
+        assert_eq!(get_index(0b10000000000000000000000000000000, 31), 0);
+
+        assert_eq!(get_index(0b00000000000000000000000000101010, 1), 0);
+        assert_eq!(get_index(0b00000000000000000000000000101010, 3), 1);
+        assert_eq!(get_index(0b00000000000000000000000000101010, 5), 2);
+    }
+
+    #[test]
+    fn test_capacity_not_truncated() {
+        // Wider than what fits into a byte, with every local key in use
+        let capacity = 300;
+        let mut node_ref = UnsafeNode::<u64, u64, CopyStore, StdHasher>::alloc(!0, capacity);
+        {
+            let node = node_ref.borrow_mut();
+            for index in 0 .. node.entry_count() {
+                node.init_entry(index, NodeEntryOwned::Item(CopyStore::new(index as u64, 0)));
+            }
+        }
+
+        let node = node_ref.borrow();
+        assert_eq!(node.capacity as usize, capacity);
+        assert_eq!(node.entry_count(), 1 << BITS_PER_LEVEL);
+
+        for index in 0 .. node.entry_count() {
+            match node.get_entry(index) {
+                NodeEntryRef::Item(kvp) => assert_eq!(*kvp.key(), index as u64),
+                _ => panic!(),
+            }
+        }
+    }
+
+    #[test]
+    fn test_with_capacity() {
+        let map = HamtMap::<u64, u64, CopyStore>::with_capacity(3);
+        assert_eq!(map.root.borrow().capacity, 3);
+
+        let map = HamtMap::<u64, u64, CopyStore>::with_capacity(50000);
+        assert_eq!(map.root.borrow().capacity as usize, 1 << BITS_PER_LEVEL);
+
+        // The size hint of the source is used for the root node
+        let map: HamtMap<u64, u64, CopyStore> = (0u64 .. 10).map(|x| (x, x)).collect();
+        assert_eq!(map.root.borrow().capacity, 10);
+        assert_eq!(map.len(), 10);
+    }
+
+    #[test]
+    fn test_root_with_single_sub_tree() {
+        use testing::CollidingHasher;
+        type Map = HamtMap<u64, u64, CopyStore, CollidingHasher>;
+
+        // Find two keys sharing all hash bits and one with a different root slot
+        let hash = |key: u64| super::hash_of::<u64, CollidingHasher>(&key);
+        let first = 0;
+        let second = (1 ..).find(|&key| hash(key) == hash(first)).unwrap();
+        let other = (1 ..).find(|&key| hash(key) & 0x1F != hash(first) & 0x1F).unwrap();
+
+        let map = Map::new().plus(first, 1).plus(second, 2).plus(other, 3).minus(&other);
+        assert_eq!(super::bit_count(map.root.borrow().mask), 1);
+        assert!(matches!(map.root.borrow().get_entry(0), NodeEntryRef::SubTree(_)));
+
+        assert_eq!(map.find(&first), Some(&1));
+        assert_eq!(map.find(&second), Some(&2));
+        assert_eq!(map.find(&other), None);
+        assert_eq!(map.iter().count(), 2);
+
+        let map = map.minus(&first);
+        assert_eq!(map.find(&second), Some(&2));
+        assert_eq!(map.len(), 1);
+    }
+
+    #[test]
+    fn test_compact_collisions() {
+        use testing::CollidingHasher;
+        type Map = HamtMap<u64, u64, CopyStore, CollidingHasher>;
+
+        let hash = |key: u64| super::hash_of::<u64, CollidingHasher>(&key);
+        let first = 0;
+        let other = (1 ..).find(|&key| hash(key) & 0x1F != hash(first) & 0x1F).unwrap();
+        let mut map = Map::new().plus(other, 2);
+
+        // Hand-build the path down to a collision entry holding just `first`
+        let mut entry = NodeEntryOwned::Collision(Arc::new(vec!(CopyStore::new(first, 1))));
+        for level in (1 .. super::LAST_LEVEL + 1).rev() {
+            let local_key = (hash(first) >> (BITS_PER_LEVEL * level)) & super::LEVEL_BIT_MASK;
+            let mut node_ref = UnsafeNode::alloc(1 << local_key, 1);
+            node_ref.borrow_mut().init_entry(0, entry);
+            entry = NodeEntryOwned::SubTree(node_ref);
+        }
+        map.root.borrow_mut().insert_entry_in_place((hash(first) & 0x1F) as usize, entry);
+        map.element_count += 1;
+        assert!(map.root.borrow().has_short_collisions());
+
+        let shared = map.clone();
+        let map = map.compact_collisions();
+        assert!(!map.root.borrow().has_short_collisions());
+        assert!(shared.root.borrow().has_short_collisions());
+        assert_eq!(check_subtree_sizes(map.root.borrow()), 2);
+
+        assert_eq!(map.len(), 2);
+        assert_eq!(map.find(&first), Some(&1));
+        assert_eq!(map.find(&other), Some(&2));
+        assert_eq!(map.iter().count(), 2);
+
+        // A map without such collision entries is left alone
+        let root = map.root.ptr;
+        let map = map.compact_collisions();
+        assert!(map.root.ptr == root);
+    }
+
+    // Counts the items below every node from scratch and compares the result to the cached
+    // subtree size.
+    fn check_subtree_sizes<IS, H>(node: &UnsafeNode<u64, u64, IS, H>) -> usize
+        where IS: ItemStore<u64, u64>,
+              H: ::std::hash::Hasher + Default
+    {
+        let mut count = 0;
+
+        for index in 0 .. node.entry_count() {
+            count += match node.get_entry(index) {
+                NodeEntryRef::Item(_) => 1,
+                NodeEntryRef::Collision(items) => items.len(),
+                NodeEntryRef::SubTree(sub_tree_ref) => check_subtree_sizes(sub_tree_ref.borrow()),
+            };
+        }
+
+        assert_eq!(node.subtree_size, count);
+        count
+    }
+
+    #[test]
+    fn test_subtree_size_maintained() {
+        use testing::CollidingHasher;
+        use rand::{Rng, SeedableRng, XorShiftRng};
+
+        fn churn<IS, H>(mut map: HamtMap<u64, u64, IS, H>)
+            where IS: ItemStore<u64, u64>,
+                  H: ::std::hash::Hasher + Default
+        {
+            let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+            let mut snapshots = Vec::new();
+
+            for round in 0 .. 20000 {
+                let key = rng.gen_range(0, 2000);
+
+                map = if rng.gen_weighted_bool(3) {
+                    map.minus(&key)
+                } else {
+                    map.plus(key, round)
+                };
+
+                // Keep some older versions around, so that both the copying and the in-place
+                // code paths are taken
+                if round % 97 == 0 {
+                    snapshots.push(map.clone());
+                }
+                if round % 1000 == 0 {
+                    assert_eq!(check_subtree_sizes(map.root.borrow()), map.len());
+                    snapshots.clear();
+                }
+            }
+
+            assert_eq!(check_subtree_sizes(map.root.borrow()), map.len());
+
+            let (map, _) = map.drain_filter(|&key, _| key % 2 == 0);
+            assert_eq!(check_subtree_sizes(map.root.borrow()), map.len());
+            let map = map.normalize();
+            assert_eq!(check_subtree_sizes(map.root.borrow()), map.len());
+            let map = map.map_values_in_place(|value| *value += 1);
+            assert_eq!(check_subtree_sizes(map.root.borrow()), map.len());
+
+            let snapshot = map.clone();
+            let mut transient = map.transient();
+            transient.retain(|&key, _| key % 3 != 0);
+            let map = transient.persistent();
+            assert_eq!(check_subtree_sizes(map.root.borrow()), map.len());
+            assert_eq!(check_subtree_sizes(snapshot.root.borrow()), snapshot.len());
+        }
+
+        churn(HamtMap::<u64, u64, CopyStore>::new());
+        churn(HamtMap::<u64, u64, ShareStore>::new());
+        churn(HamtMap::<u64, u64, CopyStore, CollidingHasher>::new());
+        churn(HamtMap::<u64, u64, CopyStore>::with_growth_policy(super::GrowthPolicy::Fixed(0)));
+    }
+
+    #[test]
+    fn test_compact_tight_capacities() {
+        fn assert_tight<IS: ::item_store::ItemStore<u64, u64>>(node: &UnsafeNode<u64, u64, IS, StdHasher>) {
+            assert_eq!(node.capacity as usize, node.entry_count());
+
+            for index in 0 .. node.entry_count() {
+                if let NodeEntryRef::SubTree(sub_tree_ref) = node.get_entry(index) {
+                    assert_tight(sub_tree_ref.borrow());
+                }
+            }
+        }
+
+        let mut map: HamtMap<u64, u64, ShareStore> = HamtMap::new();
+
+        for i in 0u64 .. 1000 {
+            map = map.plus(i, i);
+        }
+
+        assert_tight(map.compact().root.borrow());
+    }
+
+    #[test]
+    fn test_geometry_constants() {
+        type Map = HamtMap<u64, u64, ShareStore>;
+
+        assert_eq!(Map::BRANCHING_FACTOR, 1 << Map::BITS_PER_LEVEL);
+        assert_eq!(Map::MAX_DEPTH, 64 / Map::BITS_PER_LEVEL);
+    }
+
+    // A value type counting how often it has been cloned
+    struct CloneCounter(Arc<AtomicUsize>);
+
+    impl Clone for CloneCounter {
+        fn clone(&self) -> CloneCounter {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            CloneCounter(self.0.clone())
+        }
+    }
+
+    #[test]
+    fn test_into_values_moves_unshared() {
+        let clones = Arc::new(AtomicUsize::new(0));
+        let mut map: HamtMap<u64, CloneCounter> = HamtMap::new();
+
+        for i in 0u64 .. 1000 {
+            map = map.plus(i, CloneCounter(clones.clone()));
+        }
+
+        let shared = map.clone();
+        assert_eq!(shared.into_values().len(), 1000);
+        assert_eq!(clones.load(Ordering::SeqCst), 1000);
+
+        assert_eq!(map.into_values().len(), 1000);
+        assert_eq!(clones.load(Ordering::SeqCst), 1000);
+
+        // Every value has been dropped again
+        assert_eq!(Arc::strong_count(&clones), 1);
+    }
+
+    #[test]
+    fn test_take_moves_unshared() {
+        let clones = Arc::new(AtomicUsize::new(0));
+        let mut map: HamtMap<u64, CloneCounter> = HamtMap::new();
+
+        for i in 0u64 .. 1000 {
+            map = map.plus(i, CloneCounter(clones.clone()));
+        }
+
+        // Values still referenced by another map have to be cloned
+        let shared = map.clone();
+        for i in 0u64 .. 100 {
+            let (new_map, taken) = map.take(&i);
+            assert!(taken.is_some());
+            map = new_map;
+        }
+        assert_eq!(clones.load(Ordering::SeqCst), 100);
+        drop(shared);
+
+        // Once the map is the only owner, all values are moved out
+        for i in 100u64 .. 1000 {
+            let (new_map, taken) = map.take(&i);
+            assert!(taken.is_some());
+            map = new_map;
+        }
+        assert_eq!(clones.load(Ordering::SeqCst), 100);
+        assert_eq!(map.len(), 0);
+        assert_eq!(Arc::strong_count(&clones), 1);
+    }
+
+    #[test]
+    fn test_replace_moves_unshared() {
+        let clones = Arc::new(AtomicUsize::new(0));
+        let mut map: HamtMap<u64, CloneCounter> = HamtMap::new();
+
+        for i in 0u64 .. 1000 {
+            map = map.plus(i, CloneCounter(clones.clone()));
+        }
+
+        // Values still referenced by another map have to be cloned
+        let shared = map.clone();
+        for i in 0u64 .. 100 {
+            let (new_map, previous) = map.replace(i, CloneCounter(clones.clone()));
+            assert!(previous.is_some());
+            map = new_map;
+        }
+        assert_eq!(clones.load(Ordering::SeqCst), 100);
+        drop(shared);
+
+        // Once the map is the only owner, all values are moved out
+        for i in 0u64 .. 1000 {
+            let (new_map, previous) = map.replace(i, CloneCounter(clones.clone()));
+            assert!(previous.is_some());
+            map = new_map;
+        }
+        assert_eq!(clones.load(Ordering::SeqCst), 100);
+        assert_eq!(map.len(), 1000);
+        assert_eq!(Arc::strong_count(&clones), 1001);
+    }
+
+    #[test]
+    fn test_evict_one_moves_unshared() {
+        let clones = Arc::new(AtomicUsize::new(0));
+        let mut map: HamtMap<u64, CloneCounter> = HamtMap::new();
+
+        for i in 0u64 .. 1000 {
+            map = map.plus(i, CloneCounter(clones.clone()));
+        }
+
+        while map.len() > 0 {
+            let (smaller, evicted) = map.evict_one();
+            assert!(evicted.is_some());
+            map = smaller;
+        }
+
+        assert_eq!(clones.load(Ordering::SeqCst), 0);
+        assert_eq!(Arc::strong_count(&clones), 1);
+    }
+
+    #[test]
+    fn test_eq_consistent_with_partial_cmp() {
+        let map: HamtMap<u64, f64> = HamtMap::new().plus(1, 1.0).plus(2, f64::NAN);
+
+        // Maps sharing their root are equal, even if a value isn't equal to itself
+        let clone = map.clone();
+        assert!(map == clone);
+        assert_eq!(map.partial_cmp(&clone), Some(::std::cmp::Ordering::Equal));
+
+        let rebuilt: HamtMap<u64, f64> = map.iter().map(|(&k, &v)| (k, v)).collect();
+        assert!(map != rebuilt);
+        assert_eq!(map.partial_cmp(&rebuilt), None);
+    }
+
+    #[test]
+    fn test_to_share_store() {
+        let clones = Arc::new(AtomicUsize::new(0));
+        let mut map: HamtMap<u64, CloneCounter, ::item_store::CopyStore<u64, CloneCounter>> =
+            HamtMap::new();
+
+        for i in 0u64 .. 1000 {
+            map = map.plus(i, CloneCounter(clones.clone()));
+        }
+
+        // Modifying a shared copy clones the values of every node on the path
+        clones.store(0, Ordering::SeqCst);
+        let _ = map.clone().plus(1000, CloneCounter(clones.clone()));
+        assert!(clones.load(Ordering::SeqCst) > 0);
+
+        clones.store(0, Ordering::SeqCst);
+        let shared = map.to_share_store();
+        assert_eq!(clones.load(Ordering::SeqCst), 1000);
+        assert_eq!(shared.len(), 1000);
+        assert!((0u64 .. 1000).all(|i| shared.find(&i).is_some()));
+        assert_eq!(shared.node_count(), map.node_count());
+
+        // After the conversion, it just bumps the reference counts of the pairs
+        clones.store(0, Ordering::SeqCst);
+        let modified = shared.clone().plus(1000, CloneCounter(clones.clone())).minus(&0);
+        assert_eq!(clones.load(Ordering::SeqCst), 0);
+        assert_eq!(modified.len(), 1000);
+        assert_eq!(shared.len(), 1000);
+    }
+
+    #[test]
+    fn test_evict_unshared() {
+        let cache: HamtMap<u64, u64, ShareStore> = (0u64 .. 1000).map(|x| (x, x)).collect();
+
+        // The pinned map gets its own nodes, sharing the key-value pairs of 100 entries
+        let pinned = cache.clone().prune(|&k, _| k < 100);
+        assert_eq!(cache.ref_count_of_root(), 1);
+        assert_eq!(cache.value_arc_count(&0), Some(2));
+        assert_eq!(cache.value_arc_count(&100), Some(1));
+
+        let cache = cache.evict_unshared();
+        assert_eq!(cache.len(), 100);
+        for x in 0u64 .. 1000 {
+            assert_eq!(cache.find(&x).is_some(), x < 100);
+        }
+
+        // Entries of nodes shared with another map are in use
+        let kept = cache.clone().evict_unshared();
+        assert_eq!(kept.len(), 100);
+
+        drop(kept);
+        drop(pinned);
+        let cache = cache.evict_unshared();
+        assert_eq!(cache.len(), 0);
+    }
+
+    #[test]
+    fn test_try_find_corrupted() {
+        let mut map: HamtMap<u64, u64, ShareStore> = HamtMap::new();
+        for x in 0u64 .. 1000 {
+            map = map.plus(x, x + 1);
+        }
+
+        for x in 0u64 .. 1100 {
+            assert_eq!(map.try_find(&x), Ok(map.find(&x)));
+        }
+
+        // A map with a single entry, which is the first entry of the root node
+        let mut map: HamtMap<u64, u64, ShareStore> = HamtMap::new().plus(7, 8);
+        let entry_types = map.root.borrow().entry_types;
+        let capacity = map.root.borrow().capacity;
+
+        map.root.borrow_mut().entry_types = entry_types & !0b11;
+        assert_eq!(map.try_find(&7), Err(CorruptionError::InvalidEntryType { level: 0, code: 0 }));
+
+        map.root.borrow_mut().entry_types = entry_types | 0b11;
+        assert_eq!(map.try_find(&7), Err(CorruptionError::MisplacedCollision { level: 0 }));
+
+        map.root.borrow_mut().entry_types = entry_types;
+        map.root.borrow_mut().capacity = 0;
+        assert_eq!(map.try_find(&7),
+                   Err(CorruptionError::EntryOutOfBounds { level: 0, index: 0, capacity: 0 }));
+
+        // Repair the node, so it can be freed
+        map.root.borrow_mut().capacity = capacity;
+        assert_eq!(map.try_find(&7), Ok(Some(&8)));
+    }
+
+    #[test]
+    fn test_arc_values() {
+        use testing::CollidingHasher;
+
+        fn check<H: ::std::hash::Hasher + Default>() {
+            let mut map = HamtMap::<u64, String, ::item_store::ShareStore<u64, String>, H>::new();
+            for x in 0u64 .. 1000 {
+                map = map.plus(x, x.to_string());
+            }
+
+            let values: Vec<_> = map.arc_values().collect();
+            assert_eq!(values.len(), map.len());
+
+            for (value, (&key, map_value)) in values.iter().zip(map.iter()) {
+                assert_eq!(*value.key(), key);
+                assert_eq!(**value, key.to_string());
+                assert!(::std::ptr::eq(&**value, map_value));
+                assert_eq!(map.value_arc_count(&key), Some(2));
+            }
+
+            // The handles outlive the map
+            drop(map);
+            assert!(values.iter().all(|value| value.strong_count() == 1));
+            assert_eq!(values.iter().map(|value| value.len()).sum::<usize>(), 2890);
+        }
+
+        check::<::std::collections::hash_map::DefaultHasher>();
+        check::<CollidingHasher>();
+    }
+
+    #[test]
+    fn test_value_arc_count() {
+        let map: HamtMap<u64, u64, ShareStore> = (0u64 .. 3).map(|x| (x, x)).collect();
+        assert_eq!(map.value_arc_count(&0), Some(1));
+        assert_eq!(map.value_arc_count(&3), None);
+
+        // The root of the first map is shared, so the second one gets a copy of it
+        let other = map.clone().plus(3, 3);
+        for x in 0u64 .. 3 {
+            assert!(map.value_arc_count(&x).unwrap() >= 2);
+            assert!(other.value_arc_count(&x).unwrap() >= 2);
+        }
+        assert_eq!(other.value_arc_count(&3), Some(1));
+
+        drop(other);
+        assert_eq!(map.value_arc_count(&0), Some(1));
+    }
+
+    #[test]
+    fn test_into_keys_drops_values() {
+        let values = Arc::new(AtomicUsize::new(0));
+        let mut map: HamtMap<u64, CloneCounter> = HamtMap::new();
+
+        for i in 0u64 .. 1000 {
+            map = map.plus(i, CloneCounter(values.clone()));
+        }
+
+        let shared = map.clone();
+        assert_eq!(map.into_keys().len(), 1000);
+        assert_eq!(Arc::strong_count(&values), 1001);
+
+        assert_eq!(shared.into_keys().len(), 1000);
+        assert_eq!(Arc::strong_count(&values), 1);
+        assert_eq!(values.load(Ordering::SeqCst), 0);
+    }
+
+//=-------------------------------------------------------------------------------------------------
+// Test HamtMap<CopyStore>
+//=-------------------------------------------------------------------------------------------------
+
+    #[test]
+    fn test_iterator_copy() {
+        let mut map: HamtMap<u64, u64, CopyStore> = HamtMap::new();
+        let count = 1000usize;
+
+        for i in (0u64 .. count as u64) {
+            map = map.plus(i, i);
+        }
+
+        let it = map.iter();
+        assert_eq!((count, Some(count)), it.size_hint());
+
+        let reference: HashMap<u64, u64> = it.map(|(x, y)| (*x, *y)).collect();
+
+        assert_eq!(count, reference.len());
+
+        for i in (0u64 .. count as u64) {
+            assert_eq!(reference.get(&i), Some(&i));
+        }
+    }
+
+    #[test]
+    fn test_insert_copy() {
+        Test::test_insert(HamtMap::<u64, u64, CopyStore>::new());
+    }
+
+    #[test]
+    fn test_insert_ascending_copy() {
+        Test::test_insert_ascending(HamtMap::<u64, u64, CopyStore>::new());
+    }
+
+    #[test]
+    fn test_insert_descending_copy() {
+        Test::test_insert_descending(HamtMap::<u64, u64, CopyStore>::new());
+    }
+
+    #[test]
+    fn test_insert_overwrite_copy() {
+        Test::test_insert_overwrite(HamtMap::<u64, u64, CopyStore>::new());
+    }
+
+    #[test]
+    fn test_remove_copy() {
+        Test::test_remove(HamtMap::<u64, u64, CopyStore>::new());
+    }
+
+    #[test]
+    fn test_default_copy() {
+        Test::test_default::<CopyStore>();
+    }
+
+    #[test]
+    fn test_eq_empty_copy() {
+        Test::test_eq_empty::<CopyStore>();
+    }
+
+    #[test]
+    fn test_eq_random_copy() {
+        Test::test_eq_random::<CopyStore>();
+    }
+
+    #[test]
+    fn test_compact_copy() {
+        Test::test_compact(HamtMap::<u64, u64, CopyStore>::new());
+    }
+
+    #[test]
+    fn test_modify_many_copy() {
+        Test::test_modify_many(HamtMap::<u64, u64, CopyStore>::new());
+    }
+
+    #[test]
+    fn test_take_copy() {
+        Test::test_take(HamtMap::<u64, u64, CopyStore>::new());
+    }
+
+    #[test]
+    fn test_get_or_copy() {
+        Test::test_get_or(HamtMap::<u64, u64, CopyStore>::new());
+    }
+
+    #[test]
+    fn test_extend_ref_copy() {
+        Test::test_extend_ref(HamtMap::<u64, u64, CopyStore>::new());
+    }
+
+    #[test]
+    fn test_peek_concurrent_copy() {
+        Test::test_peek_concurrent(HamtMap::<u64, u64, CopyStore>::new());
+    }
+
+    #[test]
+    fn test_collect_with_hasher_copy() {
+        Test::test_collect_with_hasher::<CopyStore>();
+    }
+
+    #[test]
+    fn test_retain_count_copy() {
+        Test::test_retain_count(HamtMap::<u64, u64, CopyStore>::new());
+    }
+
+    #[test]
+    fn test_intersection_with_copy() {
+        Test::test_intersection_with(HamtMap::<u64, u64, CopyStore>::new());
+    }
+
+    #[test]
+    fn test_difference_update_copy() {
+        Test::test_difference_update(HamtMap::<u64, u64, CopyStore>::new());
+    }
+
+    #[test]
+    fn test_into_values_copy() {
+        Test::test_into_values(HamtMap::<u64, u64, CopyStore>::new());
+    }
+
+    #[test]
+    fn test_into_keys_copy() {
+        Test::test_into_keys(HamtMap::<u64, u64, CopyStore>::new());
+    }
+
+    #[test]
+    fn test_replace_copy() {
+        Test::test_replace(HamtMap::<u64, u64, CopyStore>::new());
+    }
+
+    #[test]
+    fn test_modify2_copy() {
+        Test::test_modify2(HamtMap::<u64, u64, CopyStore>::new());
+    }
+
+    #[test]
+    fn test_subtree_sizes_copy() {
+        Test::test_subtree_sizes(HamtMap::<u64, u64, CopyStore>::new());
+    }
+
+    #[test]
+    fn test_from_sorted_pairs_copy() {
+        Test::test_from_sorted_pairs::<CopyStore>();
+    }
+
+    #[test]
+    fn test_from_root_parts_copy() {
+        Test::test_from_root_parts::<CopyStore>();
+    }
+
+    #[test]
+    fn test_subtree_for_prefix_copy() {
+        Test::test_subtree_for_prefix(HamtMap::<u64, u64, CopyStore>::new());
+    }
+
+    #[test]
+    fn test_merge_counts_copy() {
+        Test::test_merge_counts(HamtMap::<u64, u64, CopyStore>::new());
+    }
+
+    #[test]
+    fn test_hash_quality_copy() {
+        Test::test_hash_quality(HamtMap::<u64, u64, CopyStore>::new());
+    }
+
+    #[test]
+    fn test_entry_or_insert_with_key_copy() {
+        Test::test_entry_or_insert_with_key(HamtMap::<u64, u64, CopyStore>::new());
+    }
+
+    #[test]
+    fn test_evict_one_copy() {
+        Test::test_evict_one(HamtMap::<u64, u64, CopyStore>::new());
+    }
+
+    #[test]
+    fn test_bulk_contains_copy() {
+        Test::test_bulk_contains(HamtMap::<u64, u64, CopyStore>::new());
+    }
+
+    #[test]
+    fn test_remove_where_copy() {
+        Test::test_remove_where(HamtMap::<u64, u64, CopyStore>::new());
+    }
+
+    #[test]
+    fn test_iter_from_copy() {
+        Test::test_iter_from(HamtMap::<u64, u64, CopyStore>::new());
+    }
+
+    #[test]
+    fn test_deep_clone_copy() {
+        Test::test_deep_clone(HamtMap::<u64, u64, CopyStore>::new());
+    }
+
+    #[test]
+    fn test_into_raw_copy() {
+        Test::test_into_raw(HamtMap::<u64, u64, CopyStore>::new());
+    }
+
+    #[test]
+    fn test_map_values_in_place_copy() {
+        Test::test_map_values_in_place(HamtMap::<u64, u64, CopyStore>::new());
+    }
+
+    #[test]
+    fn test_contains_all_any_copy() {
+        Test::test_contains_all_any(HamtMap::<u64, u64, CopyStore>::new());
+    }
+
+    #[test]
+    fn test_find_cloned_or_default_copy() {
+        Test::test_find_cloned_or_default(HamtMap::<u64, u64, CopyStore>::new());
+    }
+
+    #[test]
+    fn test_merge_by_copy() {
+        Test::test_merge_by(HamtMap::<u64, u64, CopyStore>::new());
+    }
+
+    #[test]
+    fn test_ref_count_of_root_copy() {
+        Test::test_ref_count_of_root(HamtMap::<u64, u64, CopyStore>::new());
+    }
+
+    #[test]
+    fn test_collision_groups_copy() {
+        Test::test_collision_groups(HamtMap::<u64, u64, CopyStore>::new());
+    }
+
+    #[test]
+    fn test_zip_with_copy() {
+        Test::test_zip_with(HamtMap::<u64, u64, CopyStore>::new());
+    }
+
+    #[test]
+    fn test_union_copy() {
+        Test::test_union(HamtMap::<u64, u64, CopyStore>::new());
+    }
+
+    #[test]
+    fn test_get_disjoint_copy() {
+        Test::test_get_disjoint(HamtMap::<u64, u64, CopyStore>::new());
+    }
+
+    #[test]
+    fn test_normalize_copy() {
+        Test::test_normalize(HamtMap::<u64, u64, CopyStore>::new());
+    }
+
+    #[test]
+    fn test_key_path_copy() {
+        Test::test_key_path(HamtMap::<u64, u64, CopyStore>::new());
+    }
+
+    #[test]
+    fn test_growth_policy_copy() {
+        Test::test_growth_policy(HamtMap::<u64, u64, CopyStore>::new());
+    }
+
+    #[test]
+    fn test_drain_filter_copy() {
+        Test::test_drain_filter(HamtMap::<u64, u64, CopyStore>::new());
+    }
+
+    #[test]
+    fn test_sample_copy() {
+        Test::test_sample(HamtMap::<u64, u64, CopyStore>::new());
+    }
+
+    #[test]
+    fn test_scan_hash_range_copy() {
+        Test::test_scan_hash_range(HamtMap::<u64, u64, CopyStore>::new());
+    }
+
+    #[test]
+    fn test_into_sorted_iter_copy() {
+        Test::test_into_sorted_iter(HamtMap::<u64, u64, CopyStore>::new());
+    }
+
+    #[test]
+    fn test_transient_copy() {
+        Test::test_transient(HamtMap::<u64, u64, CopyStore>::new());
+    }
+
+    #[test]
+    fn test_capacity_stats_copy() {
+        Test::test_capacity_stats(HamtMap::<u64, u64, CopyStore>::new());
+    }
+
+    #[test]
+    fn test_observe_copy() {
+        Test::test_observe(HamtMap::<u64, u64, CopyStore>::new());
+    }
+
+    #[test]
+    fn test_try_into_array_copy() {
+        Test::test_try_into_array(HamtMap::<u64, u64, CopyStore>::new());
+    }
+
+    #[test]
+    fn test_key_hash_copy() {
+        Test::test_key_hash(HamtMap::<u64, u64, CopyStore>::new());
+    }
+
+    #[test]
+    fn test_split_at_hash_copy() {
+        Test::test_split_at_hash(HamtMap::<u64, u64, CopyStore>::new());
+    }
+
+    #[test]
+    fn test_collision_policy_copy() {
+        Test::test_collision_policy(HamtMap::<u64, u64, CopyStore>::new());
+    }
+
+    #[test]
+    fn test_iter_with_depth_copy() {
+        Test::test_iter_with_depth(HamtMap::<u64, u64, CopyStore>::new());
+    }
+
+    #[test]
+    fn test_partial_ord_copy() {
+        Test::test_partial_ord(HamtMap::<u64, u64, CopyStore>::new());
+    }
+
+    #[test]
+    fn test_apply_deltas_copy() {
+        Test::test_apply_deltas(HamtMap::<u64, u64, CopyStore>::new());
+    }
+
+    #[test]
+    fn test_estimate_unique_prefixes_copy() {
+        Test::test_estimate_unique_prefixes(HamtMap::<u64, u64, CopyStore>::new());
+    }
+
+    #[test]
+    fn test_distinct_value_count_copy() {
+        Test::test_distinct_value_count(HamtMap::<u64, u64, CopyStore>::new());
+    }
+
+    #[test]
+    fn test_prune_copy() {
+        Test::test_prune(HamtMap::<u64, u64, CopyStore>::new());
+    }
+
+    #[test]
+    fn test_insert_all_copy() {
+        Test::test_insert_all(HamtMap::<u64, u64, CopyStore>::new());
+    }
+
+    #[test]
+    fn test_swap_values_copy() {
+        Test::test_swap_values(HamtMap::<u64, u64, CopyStore>::new());
+    }
+
+    #[test]
+    fn test_group_by_hash_copy() {
+        Test::test_group_by_hash(HamtMap::<u64, u64, CopyStore>::new());
+    }
+
+    #[test]
+    fn test_collect_into_copy() {
+        Test::test_collect_into(HamtMap::<u64, u64, CopyStore>::new());
+    }
+
+    #[test]
+    fn test_map_keys_copy() {
+        Test::test_map_keys(HamtMap::<u64, u64, CopyStore>::new());
+    }
+
+    #[test]
+    fn test_flatten_copy() {
+        Test::test_flatten(HamtMap::<u64, u64, CopyStore>::new());
+    }
+
+    #[test]
+    fn test_write_pairs_copy() {
+        Test::test_write_pairs(HamtMap::<u64, u64, CopyStore>::new());
+    }
+
+    #[test]
+    fn test_max_levels_copy() {
+        Test::test_max_levels(HamtMap::<u64, u64, CopyStore>::new());
+    }
+
+    #[test]
+    fn test_from_sorted_runs_copy() {
+        Test::test_from_sorted_runs::<CopyStore>();
+    }
+
+    #[test]
+    fn test_find_with_steps_copy() {
+        Test::test_find_with_steps(HamtMap::<u64, u64, CopyStore>::new());
+    }
+
+    #[test]
+    fn test_trim_to_top_copy() {
+        Test::test_trim_to_top(HamtMap::<u64, u64, CopyStore>::new());
+    }
+
+    #[test]
+    fn test_equal_with_copy() {
+        Test::test_equal_with(HamtMap::<u64, f64, ::item_store::CopyStore<u64, f64>>::new());
+    }
+
+    #[test]
+    fn test_insert_sorted_copy() {
+        Test::test_insert_sorted::<CopyStore>();
+    }
+
+    #[test]
+    fn test_collect_node_pointers_copy() {
+        Test::test_collect_node_pointers(HamtMap::<u64, u64, CopyStore>::new());
+    }
+
+    #[test]
+    fn test_insert_or_combine_copy() {
+        Test::test_insert_or_combine::<CopyStore>();
+    }
+
+    #[test]
+    fn test_sorted_by_value_copy() {
+        Test::test_sorted_by_value(HamtMap::<u64, u64, CopyStore>::new());
+    }
+
+    #[test]
+    fn test_eq_shared_root_copy() {
+        use testing::CountingEq;
+        type Map = HamtMap<u64, CountingEq, ::item_store::CopyStore<u64, CountingEq>>;
+        Test::test_eq_shared_root(Map::new());
+    }
+
+    #[test]
+    fn test_transient_retain_copy() {
+        Test::test_transient_retain::<CopyStore>();
+    }
+
+    #[test]
+    fn test_iter_root_slot_copy() {
+        Test::test_iter_root_slot::<CopyStore>();
+    }
+
+    #[test]
+    fn test_find_subtree_fold_copy() {
+        Test::test_find_subtree_fold::<CopyStore>();
+    }
+
+    #[test]
+    fn test_iter_copy() {
+        Test::test_iter::<CopyStore>();
+    }
+
+    #[test]
+    fn test_keys_values_copy() {
+        Test::test_keys_values::<CopyStore>();
+    }
+
+    #[test]
+    fn test_into_iter_copy() {
+        Test::test_into_iter::<::item_store::CopyStore<u64, ::testing::CountingClone>>();
+    }
+
+    #[test]
+    fn test_from_iter_copy() {
+        Test::test_from_iter::<CopyStore>();
+    }
+
+    #[test]
+    fn test_extend_copy() {
+        Test::test_extend(HamtMap::<u64, u64, CopyStore>::new());
+    }
+
+    #[test]
+    fn test_update_copy() {
+        Test::test_update::<CopyStore>();
+    }
+
+    #[test]
+    fn test_contains_key_copy() {
+        Test::test_contains_key::<CopyStore>();
+    }
+
+    #[test]
+    fn test_get_or_insert_with_copy() {
+        Test::test_get_or_insert_with::<CopyStore>();
+    }
+
+    #[test]
+    fn test_eq_same_len_copy() {
+        Test::test_eq_same_len::<CopyStore>();
+    }
+
+    #[test]
+    fn test_observe_after_emptied_copy() {
+        Test::test_observe_after_emptied(HamtMap::<u64, u64, CopyStore>::new());
+    }
+
+    #[test]
+    fn test_collision_policy_after_emptied_copy() {
+        Test::test_collision_policy_after_emptied::<CopyStore>();
+    }
+
+    #[test]
+    fn test_remove_from_collision_copy() {
+        Test::test_remove_from_collision::<CopyStore>();
+    }
+
+    #[test]
+    fn test_iter_collisions_copy() {
+        Test::test_iter_collisions::<CopyStore>();
+    }
+
+    #[test]
+    fn test_remove_collapsing_into_root_copy() {
+        Test::test_remove_collapsing_into_root::<CopyStore>();
+    }
+
+    #[test]
+    fn test_insert_in_place_full_node_copy() {
+        Test::test_insert_in_place_full_node(HamtMap::<u64, u64, CopyStore>::new());
+    }
+
+    #[test]
+    fn test_merge_by_aligned_copy() {
+        Test::test_merge_by_aligned::<CopyStore>();
+    }
+
+    #[test]
+    fn stress_test_copy() {
+        Test::random_insert_remove_stress_test(HamtMap::<u64, u64, CopyStore>::new());
+    }
+
+    #[test]
+    fn concurrent_clone_find_stress_test_copy() {
+        let map: HamtMap<u64, u64, CopyStore> = (0u64 .. 10000).map(|x| (x, x * 7)).collect();
+        Test::concurrent_clone_find_stress(map, 8, 20000);
+    }
+
+
+
+//=-------------------------------------------------------------------------------------------------
+// Test HamtMap<ShareStore>
+//=-------------------------------------------------------------------------------------------------
+
+    #[test]
+    fn test_iterator_share() {
+        let mut map: HamtMap<u64, u64, ShareStore> = HamtMap::new();
+        let count = 1000usize;
+
+        for i in (0u64 .. count as u64) {
+            map = map.plus(i, i);
+        }
+
+        let it = map.iter();
+        assert_eq!((count, Some(count)), it.size_hint());
+
+        let test: HashMap<u64, u64> = it.map(|(x, y)| (*x, *y)).collect();
+
+        assert_eq!(count, test.len());
+
+        for i in (0u64 .. count as u64) {
+            assert_eq!(test.get(&i), Some(&i));
+        }
+    }
+
+    #[test]
+    fn test_insert_share() {
+        Test::test_insert(HamtMap::<u64, u64, ShareStore>::new());
+    }
+
+    #[test]
+    fn test_insert_ascending_share() {
+        Test::test_insert_ascending(HamtMap::<u64, u64, ShareStore>::new());
+    }
+
+    #[test]
+    fn test_insert_descending_share() {
+        Test::test_insert_descending(HamtMap::<u64, u64, ShareStore>::new());
+    }
+
+    #[test]
+    fn test_insert_overwrite_share() {
+        Test::test_insert_overwrite(HamtMap::<u64, u64, ShareStore>::new());
+    }
+
+    #[test]
+    fn test_remove_share() {
+        Test::test_remove(HamtMap::<u64, u64, ShareStore>::new());
+    }
+
+    #[test]
+    fn test_compact_share() {
+        Test::test_compact(HamtMap::<u64, u64, ShareStore>::new());
+    }
+
+    #[test]
+    fn test_modify_many_share() {
+        Test::test_modify_many(HamtMap::<u64, u64, ShareStore>::new());
+    }
+
+    #[test]
+    fn test_take_share() {
+        Test::test_take(HamtMap::<u64, u64, ShareStore>::new());
+    }
+
+    #[test]
+    fn test_get_or_share() {
+        Test::test_get_or(HamtMap::<u64, u64, ShareStore>::new());
+    }
+
+    #[test]
+    fn test_extend_ref_share() {
+        Test::test_extend_ref(HamtMap::<u64, u64, ShareStore>::new());
+    }
+
+    #[test]
+    fn test_peek_concurrent_share() {
+        Test::test_peek_concurrent(HamtMap::<u64, u64, ShareStore>::new());
+    }
+
+    #[test]
+    fn test_collect_with_hasher_share() {
+        Test::test_collect_with_hasher::<ShareStore>();
+    }
+
+    #[test]
+    fn test_retain_count_share() {
+        Test::test_retain_count(HamtMap::<u64, u64, ShareStore>::new());
+    }
+
+    #[test]
+    fn test_intersection_with_share() {
+        Test::test_intersection_with(HamtMap::<u64, u64, ShareStore>::new());
+    }
+
+    #[test]
+    fn test_difference_update_share() {
+        Test::test_difference_update(HamtMap::<u64, u64, ShareStore>::new());
+    }
+
+    #[test]
+    fn test_into_values_share() {
+        Test::test_into_values(HamtMap::<u64, u64, ShareStore>::new());
+    }
+
+    #[test]
+    fn test_into_keys_share() {
+        Test::test_into_keys(HamtMap::<u64, u64, ShareStore>::new());
+    }
+
+    #[test]
+    fn test_replace_share() {
+        Test::test_replace(HamtMap::<u64, u64, ShareStore>::new());
+    }
+
+    #[test]
+    fn test_modify2_share() {
+        Test::test_modify2(HamtMap::<u64, u64, ShareStore>::new());
+    }
+
+    #[test]
+    fn test_subtree_sizes_share() {
+        Test::test_subtree_sizes(HamtMap::<u64, u64, ShareStore>::new());
+    }
+
+    #[test]
+    fn test_from_sorted_pairs_share() {
+        Test::test_from_sorted_pairs::<ShareStore>();
+    }
+
+    #[test]
+    fn test_from_root_parts_share() {
+        Test::test_from_root_parts::<ShareStore>();
+    }
+
+    #[test]
+    fn test_subtree_for_prefix_share() {
+        Test::test_subtree_for_prefix(HamtMap::<u64, u64, ShareStore>::new());
+    }
+
+    #[test]
+    fn test_merge_counts_share() {
+        Test::test_merge_counts(HamtMap::<u64, u64, ShareStore>::new());
+    }
+
+    #[test]
+    fn test_hash_quality_share() {
+        Test::test_hash_quality(HamtMap::<u64, u64, ShareStore>::new());
+    }
+
+    #[test]
+    fn test_entry_or_insert_with_key_share() {
+        Test::test_entry_or_insert_with_key(HamtMap::<u64, u64, ShareStore>::new());
+    }
+
+    #[test]
+    fn test_evict_one_share() {
+        Test::test_evict_one(HamtMap::<u64, u64, ShareStore>::new());
+    }
+
+    #[test]
+    fn test_bulk_contains_share() {
+        Test::test_bulk_contains(HamtMap::<u64, u64, ShareStore>::new());
+    }
+
+    #[test]
+    fn test_remove_where_share() {
+        Test::test_remove_where(HamtMap::<u64, u64, ShareStore>::new());
+    }
+
+    #[test]
+    fn test_iter_from_share() {
+        Test::test_iter_from(HamtMap::<u64, u64, ShareStore>::new());
+    }
+
+    #[test]
+    fn test_deep_clone_share() {
+        Test::test_deep_clone(HamtMap::<u64, u64, ShareStore>::new());
+    }
+
+    #[test]
+    fn test_into_raw_share() {
+        Test::test_into_raw(HamtMap::<u64, u64, ShareStore>::new());
+    }
+
+    #[test]
+    fn test_map_values_in_place_share() {
+        Test::test_map_values_in_place(HamtMap::<u64, u64, ShareStore>::new());
+    }
+
+    #[test]
+    fn test_contains_all_any_share() {
+        Test::test_contains_all_any(HamtMap::<u64, u64, ShareStore>::new());
+    }
+
+    #[test]
+    fn test_find_cloned_or_default_share() {
+        Test::test_find_cloned_or_default(HamtMap::<u64, u64, ShareStore>::new());
+    }
+
+    #[test]
+    fn test_merge_by_share() {
+        Test::test_merge_by(HamtMap::<u64, u64, ShareStore>::new());
+    }
+
+    #[test]
+    fn test_ref_count_of_root_share() {
+        Test::test_ref_count_of_root(HamtMap::<u64, u64, ShareStore>::new());
+    }
+
+    #[test]
+    fn test_collision_groups_share() {
+        Test::test_collision_groups(HamtMap::<u64, u64, ShareStore>::new());
+    }
+
+    #[test]
+    fn test_zip_with_share() {
+        Test::test_zip_with(HamtMap::<u64, u64, ShareStore>::new());
+    }
+
+    #[test]
+    fn test_union_share() {
+        Test::test_union(HamtMap::<u64, u64, ShareStore>::new());
+    }
+
+    #[test]
+    fn test_get_disjoint_share() {
+        Test::test_get_disjoint(HamtMap::<u64, u64, ShareStore>::new());
+    }
+
+    #[test]
+    fn test_normalize_share() {
+        Test::test_normalize(HamtMap::<u64, u64, ShareStore>::new());
+    }
+
+    #[test]
+    fn test_key_path_share() {
+        Test::test_key_path(HamtMap::<u64, u64, ShareStore>::new());
+    }
 
-pub struct HamtMapIterator<'a, K, V, IS, H>
-    where K: 'a,
-          V: 'a,
-          IS: 'a,
-          H: 'a
-{
-    node_stack: [(IterNodeRef<'a, K, V, IS, H>, isize); LAST_LEVEL + 2],
-    stack_size: usize,
-    len: usize,
-}
+    #[test]
+    fn test_growth_policy_share() {
+        Test::test_growth_policy(HamtMap::<u64, u64, ShareStore>::new());
+    }
 
-impl<'a, K, V, IS, H>
-HamtMapIterator<'a, K, V, IS, H>
-    where K: Eq+Send+Sync,
-          V: Send+Sync,
-          IS: ItemStore<K, V>,
-          H: Hasher
-{
-    fn new(map: &'a HamtMap<K, V, IS, H>) -> HamtMapIterator<'a, K, V, IS, H> {
-        let mut iterator = HamtMapIterator {
-            node_stack: unsafe{ mem::zeroed() },
-            stack_size: 1,
-            len: map.element_count,
-        };
+    #[test]
+    fn test_drain_filter_share() {
+        Test::test_drain_filter(HamtMap::<u64, u64, ShareStore>::new());
+    }
 
-        iterator.node_stack[0] = (IterNodeRef::RegularNode(map.root.borrow()), -1);
-        iterator
+    #[test]
+    fn test_sample_share() {
+        Test::test_sample(HamtMap::<u64, u64, ShareStore>::new());
     }
-}
 
-impl<'a, K, V, IS, H>
-Iterator for HamtMapIterator<'a, K, V, IS, H>
-    where K: Eq+Send+Sync,
-          V: Send+Sync,
-          IS: ItemStore<K, V>,
-          H: 'a + Hasher
-{
-    type Item = (&'a K, &'a V);
+    #[test]
+    fn test_scan_hash_range_share() {
+        Test::test_scan_hash_range(HamtMap::<u64, u64, ShareStore>::new());
+    }
 
-    fn next(&mut self) -> Option<(&'a K, &'a V)> {
-        if self.stack_size == 0 {
-            return None;
-        }
+    #[test]
+    fn test_into_sorted_iter_share() {
+        Test::test_into_sorted_iter(HamtMap::<u64, u64, ShareStore>::new());
+    }
 
-        let (current_node, index) = self.node_stack[self.stack_size - 1].clone();
-        let next_index: usize = (index + 1) as usize;
+    #[test]
+    fn test_transient_share() {
+        Test::test_transient(HamtMap::<u64, u64, ShareStore>::new());
+    }
 
-        match current_node {
-            IterNodeRef::RegularNode(node_ref) => {
-                if next_index == node_ref.entry_count() {
-                    self.stack_size -= 1;
-                    return self.next();
-                } else {
-                    let (_, ref mut stack_index) = self.node_stack[self.stack_size - 1];
-                    *stack_index = next_index as isize;
-                }
+    #[test]
+    fn test_capacity_stats_share() {
+        Test::test_capacity_stats(HamtMap::<u64, u64, ShareStore>::new());
+    }
 
-                match node_ref.get_entry(next_index) {
-                    NodeEntryRef::Item(item_ref) => {
-                        return Some((item_ref.key(), item_ref.val()));
-                    }
-                    NodeEntryRef::Collision(items_arc) => {
-                        let items = &**items_arc;
-                        self.node_stack[self.stack_size] = (IterNodeRef::CollisionEntry(items), 0);
-                        self.stack_size += 1;
-                        let item = &items[0];
-                        return Some((item.key(), item.val()));
-                    },
-                    NodeEntryRef::SubTree(subtree_ref) => {
-                        self.node_stack[self.stack_size] = (IterNodeRef::RegularNode(subtree_ref.borrow()), -1);
-                        self.stack_size += 1;
-                        return self.next();
-                    }
-                };
-            }
-            IterNodeRef::CollisionEntry(items_ref) => {
-                if next_index == items_ref.len() {
-                    self.stack_size -= 1;
-                    return self.next();
-                }
+    #[test]
+    fn test_observe_share() {
+        Test::test_observe(HamtMap::<u64, u64, ShareStore>::new());
+    }
 
-                let item = &items_ref[next_index];
-                return Some((item.key(), item.val()));
-            }
-        }
+    #[test]
+    fn test_try_into_array_share() {
+        Test::test_try_into_array(HamtMap::<u64, u64, ShareStore>::new());
     }
 
-    fn size_hint(&self) -> (usize, Option<usize>) {
-        (self.len, Some(self.len))
+    #[test]
+    fn test_key_hash_share() {
+        Test::test_key_hash(HamtMap::<u64, u64, ShareStore>::new());
     }
-}
 
-//=-------------------------------------------------------------------------------------------------
-// Utility functions
-//=------------------------------------------------------------------------------------------------
-fn get_index(mask: u32, index: usize) -> usize {
-    debug_assert!((mask & (1 << index)) != 0);
+    #[test]
+    fn test_split_at_hash_share() {
+        Test::test_split_at_hash(HamtMap::<u64, u64, ShareStore>::new());
+    }
 
-    let bits_set_up_to_index = (1 << index) - 1;
-    let masked = mask & bits_set_up_to_index;
+    #[test]
+    fn test_collision_policy_share() {
+        Test::test_collision_policy(HamtMap::<u64, u64, ShareStore>::new());
+    }
 
-    bit_count(masked)
-}
+    #[test]
+    fn test_iter_with_depth_share() {
+        Test::test_iter_with_depth(HamtMap::<u64, u64, ShareStore>::new());
+    }
 
-#[inline]
-fn bit_count(x: u32) -> usize {
-    x.count_ones() as usize
-}
+    #[test]
+    fn test_partial_ord_share() {
+        Test::test_partial_ord(HamtMap::<u64, u64, ShareStore>::new());
+    }
 
-#[inline]
-fn align_to(size: usize, align: usize) -> usize {
-    debug_assert!(align != 0 && bit_count(align as u32) == 1);
-    (size + align - 1) & !(align - 1)
-}
+    #[test]
+    fn test_apply_deltas_share() {
+        Test::test_apply_deltas(HamtMap::<u64, u64, ShareStore>::new());
+    }
 
-#[inline]
-fn hash_of<T: Hash, H: Hasher + Default>(value: &T) -> u64 {
-    let mut h: H = Default::default();
-    value.hash(&mut h);
-    h.finish()
-}
+    #[test]
+    fn test_estimate_unique_prefixes_share() {
+        Test::test_estimate_unique_prefixes(HamtMap::<u64, u64, ShareStore>::new());
+    }
 
-#[inline(always)]
-pub unsafe fn allocate(size: usize, _align: usize) -> *mut u8 {
-    libc::malloc(size as libc::size_t) as *mut u8
-}
+    #[test]
+    fn test_distinct_value_count_share() {
+        Test::test_distinct_value_count(HamtMap::<u64, u64, ShareStore>::new());
+    }
 
-#[inline(always)]
-pub unsafe fn deallocate(ptr: *mut u8, _old_size: usize, _align: usize) {
-    libc::free(ptr as *mut libc::c_void)
-}
+    #[test]
+    fn test_prune_share() {
+        Test::test_prune(HamtMap::<u64, u64, ShareStore>::new());
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::get_index;
-    use super::HamtMap;
-    use testing::Test;
-    use std::collections::HashMap;
+    #[test]
+    fn test_insert_all_share() {
+        Test::test_insert_all(HamtMap::<u64, u64, ShareStore>::new());
+    }
 
-    type CopyStore = ::item_store::CopyStore<u64, u64>;
-    type ShareStore = ::item_store::ShareStore<u64, u64>;
+    #[test]
+    fn test_swap_values_share() {
+        Test::test_swap_values(HamtMap::<u64, u64, ShareStore>::new());
+    }
 
     #[test]
-    fn test_get_index() {
-        assert_eq!(get_index(0b00000000000000000000000000000001, 0), 0);
-        assert_eq!(get_index(0b00000000000000000000000000000010, 1), 0);
-        assert_eq!(get_index(0b00000000000000000000000000000100, 2), 0);
-        assert_eq!(get_index(0b10000000000000000000000000000000, 31), 0);
+    fn test_group_by_hash_share() {
+        Test::test_group_by_hash(HamtMap::<u64, u64, ShareStore>::new());
+    }
 
-        assert_eq!(get_index(0b00000000000000000000000000101010, 1), 0);
-        assert_eq!(get_index(0b00000000000000000000000000101010, 3), 1);
-        assert_eq!(get_index(0b00000000000000000000000000101010, 5), 2);
+    #[test]
+    fn test_collect_into_share() {
+        Test::test_collect_into(HamtMap::<u64, u64, ShareStore>::new());
     }
 
-//=-------------------------------------------------------------------------------------------------
-// Test HamtMap<CopyStore>
-//=-------------------------------------------------------------------------------------------------
+    #[test]
+    fn test_map_keys_share() {
+        Test::test_map_keys(HamtMap::<u64, u64, ShareStore>::new());
+    }
 
     #[test]
-    fn test_iterator_copy() {
-        let mut map: HamtMap<u64, u64, CopyStore> = HamtMap::new();
-        let count = 1000usize;
+    fn test_flatten_share() {
+        Test::test_flatten(HamtMap::<u64, u64, ShareStore>::new());
+    }
 
-        for i in (0u64 .. count as u64) {
-            map = map.plus(i, i);
-        }
+    #[test]
+    fn test_write_pairs_share() {
+        Test::test_write_pairs(HamtMap::<u64, u64, ShareStore>::new());
+    }
 
-        let it = map.iter();
-        assert_eq!((count, Some(count)), it.size_hint());
+    #[test]
+    fn test_max_levels_share() {
+        Test::test_max_levels(HamtMap::<u64, u64, ShareStore>::new());
+    }
 
-        let reference: HashMap<u64, u64> = it.map(|(x, y)| (*x, *y)).collect();
+    #[test]
+    fn test_from_sorted_runs_share() {
+        Test::test_from_sorted_runs::<ShareStore>();
+    }
 
-        assert_eq!(count, reference.len());
+    #[test]
+    fn test_find_with_steps_share() {
+        Test::test_find_with_steps(HamtMap::<u64, u64, ShareStore>::new());
+    }
 
-        for i in (0u64 .. count as u64) {
-            assert_eq!(reference.get(&i), Some(&i));
-        }
+    #[test]
+    fn test_trim_to_top_share() {
+        Test::test_trim_to_top(HamtMap::<u64, u64, ShareStore>::new());
     }
 
     #[test]
-    fn test_insert_copy() {
-        Test::test_insert(HamtMap::<u64, u64, CopyStore>::new());
+    fn test_equal_with_share() {
+        Test::test_equal_with(HamtMap::<u64, f64, ::item_store::ShareStore<u64, f64>>::new());
     }
 
     #[test]
-    fn test_insert_ascending_copy() {
-        Test::test_insert_ascending(HamtMap::<u64, u64, CopyStore>::new());
+    fn test_insert_sorted_share() {
+        Test::test_insert_sorted::<ShareStore>();
     }
 
     #[test]
-    fn test_insert_descending_copy() {
-        Test::test_insert_descending(HamtMap::<u64, u64, CopyStore>::new());
+    fn test_collect_node_pointers_share() {
+        Test::test_collect_node_pointers(HamtMap::<u64, u64, ShareStore>::new());
     }
 
     #[test]
-    fn test_insert_overwrite_copy() {
-        Test::test_insert_overwrite(HamtMap::<u64, u64, CopyStore>::new());
+    fn test_insert_or_combine_share() {
+        Test::test_insert_or_combine::<ShareStore>();
     }
 
     #[test]
-    fn test_remove_copy() {
-        Test::test_remove(HamtMap::<u64, u64, CopyStore>::new());
+    fn test_sorted_by_value_share() {
+        Test::test_sorted_by_value(HamtMap::<u64, u64, ShareStore>::new());
     }
 
     #[test]
-    fn test_default_copy() {
-        Test::test_default::<CopyStore>();
+    fn test_eq_shared_root_share() {
+        use testing::CountingEq;
+        type Map = HamtMap<u64, CountingEq, ::item_store::ShareStore<u64, CountingEq>>;
+        Test::test_eq_shared_root(Map::new());
     }
 
     #[test]
-    fn test_eq_empty_copy() {
-        Test::test_eq_empty::<CopyStore>();
+    fn test_transient_retain_share() {
+        Test::test_transient_retain::<ShareStore>();
     }
 
     #[test]
-    fn test_eq_random_copy() {
-        Test::test_eq_random::<CopyStore>();
+    fn test_iter_root_slot_share() {
+        Test::test_iter_root_slot::<ShareStore>();
     }
 
     #[test]
-    fn stress_test_copy() {
-        Test::random_insert_remove_stress_test(HamtMap::<u64, u64, CopyStore>::new());
+    fn test_find_subtree_fold_share() {
+        Test::test_find_subtree_fold::<ShareStore>();
     }
 
+    #[test]
+    fn test_iter_share() {
+        Test::test_iter::<ShareStore>();
+    }
 
+    #[test]
+    fn test_keys_values_share() {
+        Test::test_keys_values::<ShareStore>();
+    }
 
-//=-------------------------------------------------------------------------------------------------
-// Test HamtMap<ShareStore>
-//=-------------------------------------------------------------------------------------------------
+    #[test]
+    fn test_into_iter_share() {
+        Test::test_into_iter::<::item_store::ShareStore<u64, ::testing::CountingClone>>();
+    }
 
     #[test]
-    fn test_iterator_share() {
-        let mut map: HamtMap<u64, u64, ShareStore> = HamtMap::new();
-        let count = 1000usize;
+    fn test_from_iter_share() {
+        Test::test_from_iter::<ShareStore>();
+    }
 
-        for i in (0u64 .. count as u64) {
-            map = map.plus(i, i);
-        }
+    #[test]
+    fn test_extend_share() {
+        Test::test_extend(HamtMap::<u64, u64, ShareStore>::new());
+    }
 
-        let it = map.iter();
-        assert_eq!((count, Some(count)), it.size_hint());
+    #[test]
+    fn test_update_share() {
+        Test::test_update::<ShareStore>();
+    }
 
-        let test: HashMap<u64, u64> = it.map(|(x, y)| (*x, *y)).collect();
+    #[test]
+    fn test_contains_key_share() {
+        Test::test_contains_key::<ShareStore>();
+    }
 
-        assert_eq!(count, test.len());
+    #[test]
+    fn test_get_or_insert_with_share() {
+        Test::test_get_or_insert_with::<ShareStore>();
+    }
 
-        for i in (0u64 .. count as u64) {
-            assert_eq!(test.get(&i), Some(&i));
-        }
+    #[test]
+    fn test_eq_same_len_share() {
+        Test::test_eq_same_len::<ShareStore>();
     }
 
     #[test]
-    fn test_insert_share() {
-        Test::test_insert(HamtMap::<u64, u64, ShareStore>::new());
+    fn test_observe_after_emptied_share() {
+        Test::test_observe_after_emptied(HamtMap::<u64, u64, ShareStore>::new());
     }
 
     #[test]
-    fn test_insert_ascending_share() {
-        Test::test_insert_ascending(HamtMap::<u64, u64, ShareStore>::new());
+    fn test_collision_policy_after_emptied_share() {
+        Test::test_collision_policy_after_emptied::<ShareStore>();
     }
 
     #[test]
-    fn test_insert_descending_share() {
-        Test::test_insert_descending(HamtMap::<u64, u64, ShareStore>::new());
+    fn test_remove_from_collision_share() {
+        Test::test_remove_from_collision::<ShareStore>();
     }
 
     #[test]
-    fn test_insert_overwrite_share() {
-        Test::test_insert_overwrite(HamtMap::<u64, u64, ShareStore>::new());
+    fn test_iter_collisions_share() {
+        Test::test_iter_collisions::<ShareStore>();
     }
 
     #[test]
-    fn test_remove_share() {
-        Test::test_remove(HamtMap::<u64, u64, ShareStore>::new());
+    fn test_remove_collapsing_into_root_share() {
+        Test::test_remove_collapsing_into_root::<ShareStore>();
+    }
+
+    #[test]
+    fn test_insert_in_place_full_node_share() {
+        Test::test_insert_in_place_full_node(HamtMap::<u64, u64, ShareStore>::new());
+    }
+
+    #[test]
+    fn test_merge_by_aligned_share() {
+        Test::test_merge_by_aligned::<ShareStore>();
     }
 
     #[test]
     fn stress_test_share() {
         Test::random_insert_remove_stress_test(HamtMap::<u64, u64, ShareStore>::new());
     }
+
+    #[test]
+    fn concurrent_clone_find_stress_test_share() {
+        let map: HamtMap<u64, u64, ShareStore> = (0u64 .. 10000).map(|x| (x, x * 7)).collect();
+        Test::concurrent_clone_find_stress(map, 8, 20000);
+    }
 }
@@ -18,6 +18,7 @@
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
 // THE SOFTWARE.
 
+use std::ops::Deref;
 use std::sync::Arc;
 
 //=-------------------------------------------------------------------------------------------------
@@ -28,6 +29,17 @@ pub trait ItemStore<K, V>: Clone+Send+Sync {
     fn val<'a>(&'a self) -> &'a V;
 
     fn new(key: K, val: V) -> Self;
+
+    /// Moves the key and the value out of the store, if that is possible without cloning them.
+    /// Otherwise the store is handed back unchanged.
+    fn try_into_pair(self) -> Result<(K, V), Self> {
+        Err(self)
+    }
+
+    /// Gives mutable access to the value, if that is possible without affecting any other store.
+    fn val_mut(&mut self) -> Option<&mut V> {
+        None
+    }
 }
 
 
@@ -50,6 +62,14 @@ impl<K: Clone+Send+Sync, V: Clone+Send+Sync> ItemStore<K, V> for CopyStore<K, V>
             val: val
         }
     }
+
+    fn try_into_pair(self) -> Result<(K, V), CopyStore<K, V>> {
+        Ok((self.key, self.val))
+    }
+
+    fn val_mut(&mut self) -> Option<&mut V> {
+        Some(&mut self.val)
+    }
 }
 
 impl<K: Clone+Send+Sync, V: Clone+Send+Sync> Clone for CopyStore<K, V> {
@@ -77,6 +97,26 @@ impl<K: Send+Sync, V: Send+Sync> ItemStore<K, V> for ShareStore<K, V> {
     fn new(k: K, v: V) -> ShareStore<K, V> {
         ShareStore { store: Arc::new((k, v)) }
     }
+
+    fn try_into_pair(self) -> Result<(K, V), ShareStore<K, V>> {
+        Arc::try_unwrap(self.store).map_err(|store| ShareStore { store })
+    }
+
+    fn val_mut(&mut self) -> Option<&mut V> {
+        Arc::get_mut(&mut self.store).map(|pair| &mut pair.1)
+    }
+}
+
+impl<K, V> ShareStore<K, V> {
+    /// Returns the number of stores sharing this key-value pair, this one included.
+    pub fn strong_count(&self) -> usize {
+        Arc::strong_count(&self.store)
+    }
+
+    /// Returns a handle to the value which shares the key-value pair with this store.
+    pub fn shared_value(&self) -> SharedValue<K, V> {
+        SharedValue { store: self.store.clone() }
+    }
 }
 
 impl<K: Send+Sync, V: Send+Sync> Clone for ShareStore<K, V> {
@@ -86,3 +126,41 @@ impl<K: Send+Sync, V: Send+Sync> Clone for ShareStore<K, V> {
         }
     }
 }
+
+
+
+//=-------------------------------------------------------------------------------------------------
+// struct SharedValue
+//=-------------------------------------------------------------------------------------------------
+/// An owned handle to a value stored in a `ShareStore`. The key-value pair is reference counted,
+/// so getting a handle doesn't clone the value, and the handle stays valid after the map it has
+/// been obtained from is gone. Dereferences to the value.
+pub struct SharedValue<K, V> {
+    store: Arc<(K, V)>,
+}
+
+impl<K, V> SharedValue<K, V> {
+    /// The key stored together with the value.
+    pub fn key(&self) -> &K {
+        &self.store.0
+    }
+
+    /// Returns the number of handles and stores sharing the key-value pair, this one included.
+    pub fn strong_count(&self) -> usize {
+        Arc::strong_count(&self.store)
+    }
+}
+
+impl<K, V> Deref for SharedValue<K, V> {
+    type Target = V;
+
+    fn deref(&self) -> &V {
+        &self.store.1
+    }
+}
+
+impl<K, V> Clone for SharedValue<K, V> {
+    fn clone(&self) -> SharedValue<K, V> {
+        SharedValue { store: self.store.clone() }
+    }
+}
@@ -32,10 +32,29 @@ extern crate rand;
 
 pub use hamt::HamtMap;
 pub use hamt::HamtMapIterator;
-pub use item_store::{ItemStore, ShareStore, CopyStore};
+pub use hamt::Entries;
+pub use hamt::HashRangeIterator;
+pub use hamt::DepthEntries;
+pub use hamt::Keys;
+pub use hamt::Values;
+pub use hamt::ArcValues;
+pub use hamt::IntoSorted;
+pub use hamt::IntoIter;
+pub use hamt::Cursor;
+pub use hamt::Entry;
+pub use hamt::TransientHamtMap;
+pub use hamt::GrowthPolicy;
+pub use hamt::CapacityStats;
+pub use hamt::CorruptionError;
+pub use hamt::StructuralObserver;
+pub use hamt::CollisionPolicy;
+pub use item_store::{ItemStore, ShareStore, CopyStore, SharedValue};
 
 mod hamt;
 mod item_store;
 
+#[cfg(feature = "ffi")]
+pub mod ffi;
+
 #[cfg(test)]
 mod testing;
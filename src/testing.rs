@@ -19,10 +19,16 @@
 // THE SOFTWARE.
 
 use rand::{self, Rng};
-use std::collections::HashMap;
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::ptr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
 
-use item_store::ItemStore;
-use hamt::HamtMap;
+use item_store::{ItemStore, CopyStore, ShareStore};
+use hamt::{HamtMap, GrowthPolicy, StructuralObserver, CollisionPolicy, Entries};
 use std::iter::FromIterator;
 
 macro_rules! assert_find(
@@ -39,6 +45,104 @@ macro_rules! assert_find(
     );
 );
 
+// A simple FNV-1a hasher, used for testing maps with a non-default hasher.
+pub struct FnvHasher(u64);
+
+impl Default for FnvHasher {
+    fn default() -> FnvHasher {
+        FnvHasher(0xcbf29ce484222325)
+    }
+}
+
+impl Hasher for FnvHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 = (self.0 ^ (byte as u64)).wrapping_mul(0x100000001b3);
+        }
+    }
+}
+
+// A hasher producing only 16 distinct hash values, used for testing collision handling.
+#[derive(Default)]
+pub struct CollidingHasher(FnvHasher);
+
+impl Hasher for CollidingHasher {
+    fn finish(&self) -> u64 {
+        self.0.finish() & 0xF
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        self.0.write(bytes);
+    }
+}
+
+// A hasher for u64 keys mapping each pair of keys `2n` and `2n + 1` to hash values differing only
+// in the topmost bit, which is not used for routing.
+#[derive(Default)]
+pub struct TopBitHasher(FnvHasher);
+
+impl Hasher for TopBitHasher {
+    fn finish(&self) -> u64 {
+        self.0.finish()
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        self.0.write(bytes);
+    }
+
+    fn write_u64(&mut self, value: u64) {
+        self.0.write_u64(value >> 1);
+        self.0 = FnvHasher((self.0.finish() & !(1 << 63)) | ((value & 1) << 63));
+    }
+}
+
+// A hasher for u64 keys mapping each pair of keys `2n` and `2n + 1` to hash values differing only
+// in the last bits used for routing, so that every pair shares a long hash prefix.
+#[derive(Default)]
+pub struct PairingHasher(FnvHasher);
+
+impl Hasher for PairingHasher {
+    fn finish(&self) -> u64 {
+        self.0.finish()
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        self.0.write(bytes);
+    }
+
+    fn write_u64(&mut self, value: u64) {
+        self.0.write_u64(value >> 1);
+        self.0 = FnvHasher((self.0.finish() & !(1 << 59)) | ((value & 1) << 59));
+    }
+}
+
+// A value counting how often it has been compared for equality, to tell whether comparing two
+// maps has looked at their values at all.
+#[derive(Clone)]
+pub struct CountingEq(u64, Arc<AtomicUsize>);
+
+impl PartialEq for CountingEq {
+    fn eq(&self, other: &CountingEq) -> bool {
+        self.1.fetch_add(1, Ordering::SeqCst);
+        self.0 == other.0
+    }
+}
+
+// A value counting how often it has been cloned, to tell whether entries have been moved out of a
+// map or copied.
+pub struct CountingClone(u64, Arc<AtomicUsize>);
+
+impl Clone for CountingClone {
+    fn clone(&self) -> CountingClone {
+        self.1.fetch_add(1, Ordering::SeqCst);
+        CountingClone(self.0, self.1.clone())
+    }
+}
+
 pub struct Test;
 
 impl Test {
@@ -189,32 +293,3172 @@ impl Test {
         }
     }
 
-    pub fn random_insert_remove_stress_test<IS: ItemStore<u64, u64>> (empty: HamtMap<u64, u64, IS>) {
-        let mut reference: HashMap<u64, u64> = HashMap::new();
+    pub fn test_compact<IS: ItemStore<u64, u64>>(empty: HamtMap<u64, u64, IS>) {
         let mut rng = rand::thread_rng();
+        let mut map = empty;
+
+        for _ in 0 .. 10000usize {
+            let value: u64 = rng.gen_range(0, 5000);
+
+            if rng.gen_weighted_bool(3) {
+                map = map.minus(&value);
+            } else {
+                map = map.plus(value, value);
+            }
+        }
 
+        let compacted = map.compact();
+
+        assert_eq!(compacted.len(), map.len());
+        assert!(compacted == map);
+        assert!(compacted.memory_footprint() <= map.memory_footprint());
+
+        // The compacted map must still be usable as a starting point for modifications
+        let (compacted, _) = compacted.insert(5000, 5000);
+        assert_find!(compacted, 5000, 5000);
+        assert_find!(map, 5000, None);
+    }
+
+    pub fn test_modify_many<IS: ItemStore<u64, u64>>(empty: HamtMap<u64, u64, IS>) {
         let mut map = empty;
 
-        for _ in 0 .. 5000000usize {
-            let value: u64 = rng.gen();
+        for x in 0u64 .. 1000 {
+            map = map.plus(x, x);
+        }
 
-            if rng.gen_weighted_bool(2) {
-                let ref_size_change = reference.remove(&value).is_some();
-                let (map1, size_change) = map.remove(&value);
-                assert_eq!(ref_size_change, size_change);
-                assert_find!(map1, value, None);
-                assert_eq!(reference.len(), map1.len());
-                assert_eq!(reference.get(&value), map1.find(&value));
-                map = map1;
+        let original = map.clone();
+        let updates: Vec<(u64, u64)> = (0u64 .. 1000).filter(|x| x % 3 == 0)
+                                                      .map(|x| (x, 1000))
+                                                      .chain(Some((5000, 1)))
+                                                      .collect();
+
+        // The first call has to copy, because `original` shares all nodes
+        let map = map.modify_many(&updates[..], |val, delta| *val += *delta);
+        // The second call can do everything in-place
+        let map = map.modify_many(&updates[..], |val, delta| *val += *delta);
+
+        assert_eq!(map.len(), 1000);
+        assert_find!(map, 5000, None);
+
+        for x in 0u64 .. 1000 {
+            if x % 3 == 0 {
+                assert_find!(map, x, x + 2000);
             } else {
-                let ref_size_change = reference.insert(value, value).is_none();
-                let (map1, size_change) = map.insert(value, value);
-                assert_eq!(ref_size_change, size_change);
-                assert_find!(map1, value, value);
-                assert_eq!(reference.len(), map1.len());
-                assert_eq!(reference.get(&value), map1.find(&value));
-                map = map1;
+                assert_find!(map, x, x);
+            }
+            assert_find!(original, x, x);
+        }
+
+        let map = map.modify(&1, |val| val * 10).modify(&5000, |_| panic!());
+        assert_find!(map, 1, 10);
+    }
+
+    pub fn test_take<IS: ItemStore<u64, u64>>(empty: HamtMap<u64, u64, IS>) {
+        let map = empty.plus(1, 2).plus(3, 4);
+
+        let (map1, taken1) = map.clone().take(&1);
+        let (map2, taken2) = map1.clone().take(&1);
+
+        assert_eq!(taken1, Some(2));
+        assert_eq!(taken2, None);
+
+        assert_find!(map, 1, 2);
+        assert_find!(map1, 1, None);
+        assert_find!(map1, 3, 4);
+        assert_find!(map2, 3, 4);
+
+        assert_eq!(map.len(), 2);
+        assert_eq!(map1.len(), 1);
+        assert_eq!(map2.len(), 1);
+    }
+
+    pub fn test_get_or<IS: ItemStore<u64, u64>>(empty: HamtMap<u64, u64, IS>) {
+        let map = empty.plus(1, 2);
+        let default = 100;
+
+        assert_eq!(*map.get_or(&1, &default), 2);
+        assert!(!ptr::eq(map.get_or(&1, &default), &default));
+        assert!(ptr::eq(map.get_or(&2, &default), &default));
+    }
+
+    pub fn test_extend_ref<IS: ItemStore<u64, u64>>(empty: HamtMap<u64, u64, IS>) {
+        let pairs: Vec<(u64, u64)> = (0u64 .. 100).map(|x| (x, x * 2)).collect();
+        let mut map = empty.plus(1000, 1000);
+
+        map.extend(pairs.iter());
+
+        assert_eq!(map.len(), 101);
+        assert_find!(map, 1000, 1000);
+
+        for &(k, v) in pairs.iter() {
+            assert_find!(map, k, v);
+        }
+    }
+
+    pub fn test_peek_concurrent<IS: ItemStore<u64, u64>+'static>(empty: HamtMap<u64, u64, IS>) {
+        let mut map = empty;
+
+        for x in 0u64 .. 10000 {
+            map = map.plus(x, x * 3);
+        }
+
+        let map = Arc::new(map);
+
+        let threads: Vec<_> = (0u64 .. 8).map(|offset| {
+            let map = map.clone();
+            thread::spawn(move || {
+                for x in 0u64 .. 10000 {
+                    let key = (x + offset * 1000) % 12000;
+                    let expected = if key < 10000 { Some(key * 3) } else { None };
+                    assert_eq!(map.peek(&key).cloned(), expected);
+                }
+            })
+        }).collect();
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        assert_eq!(map.len(), 10000);
+    }
+
+    pub fn test_collect_with_hasher<IS: ItemStore<u64, u64>>() {
+        let pairs = (0u64 .. 1000).map(|x| (x, x + 1)).chain(Some((0, 0)));
+
+        let map = pairs.collect::<HamtMap<u64, u64, IS, FnvHasher>>();
+
+        assert_eq!(map.len(), 1000);
+        assert_find!(map, 0, 0);
+
+        for x in 1u64 .. 1000 {
+            assert_find!(map, x, x + 1);
+        }
+    }
+
+    pub fn test_retain_count<IS: ItemStore<u64, u64>>(empty: HamtMap<u64, u64, IS>) {
+        let mut map = empty;
+
+        for x in 0u64 .. 1000 {
+            map = map.plus(x, x);
+        }
+
+        let (evens, removed) = map.clone().retain_count(|&k, _| k % 2 == 0);
+        let (same, none_removed) = evens.clone().retain_count(|_, _| true);
+        let (empty, all_removed) = evens.clone().retain_count(|_, _| false);
+
+        assert_eq!(removed, 500);
+        assert_eq!(none_removed, 0);
+        assert_eq!(all_removed, 500);
+
+        assert_eq!(evens.len(), 500);
+        assert!(same == evens);
+        assert_eq!(empty.len(), 0);
+        assert_eq!(map.len(), 1000);
+
+        for x in 0u64 .. 1000 {
+            if x % 2 == 0 {
+                assert_find!(evens, x, x);
+            } else {
+                assert_find!(evens, x, None);
+            }
+        }
+    }
+
+    pub fn test_intersection_with<IS: ItemStore<u64, u64>>(empty: HamtMap<u64, u64, IS>) {
+        let mut map1 = empty.clone();
+        let mut map2 = empty;
+
+        for x in 0u64 .. 1000 {
+            map1 = map1.plus(x, x);
+        }
+
+        for x in 500u64 .. 2000 {
+            map2 = map2.plus(x, x * 10);
+        }
+
+        let intersection = map1.intersection_with(&map2, |_, v1, v2| v1 + v2);
+        assert_eq!(intersection.len(), 500);
+
+        for x in 0u64 .. 2000 {
+            if (500 .. 1000).contains(&x) {
+                assert_find!(intersection, x, x * 11);
+            } else {
+                assert_find!(intersection, x, None);
+            }
+        }
+
+        // The key is passed along and the arguments are in the right order
+        let intersection = map2.intersection_with(&map1, |&k, v2, v1| k + v2 - v1);
+        assert_find!(intersection, 600, 6000);
+        assert_eq!(map1.intersection_with(&HamtMap::new(), |_, v, _| *v).len(), 0);
+    }
+
+    pub fn test_difference_update<IS: ItemStore<u64, u64>>(empty: HamtMap<u64, u64, IS>) {
+        let mut map = empty.clone();
+        let mut other = empty;
+
+        for x in 0u64 .. 1000 {
+            map = map.plus(x, x);
+        }
+
+        for x in 900u64 .. 1100 {
+            other = other.plus(x, 0);
+        }
+
+        let difference = map.difference(&other);
+        let updated = map.clone().difference_update(&other);
+
+        assert_eq!(map.len(), 1000);
+        assert_eq!(difference.len(), 900);
+        assert!(updated == difference);
+
+        for x in 0u64 .. 1100 {
+            if x < 900 {
+                assert_find!(updated, x, x);
+            } else {
+                assert_find!(updated, x, None);
+            }
+        }
+    }
+
+    pub fn test_into_values<IS: ItemStore<u64, u64>>(empty: HamtMap<u64, u64, IS>) {
+        let mut map = empty;
+
+        for x in 0u64 .. 1000 {
+            map = map.plus(x, x + 1);
+        }
+
+        // Values of a map sharing its nodes with another map are cloned...
+        let shared = map.clone();
+        let mut values = shared.into_values();
+        values.sort();
+        assert_eq!(values, (1u64 .. 1001).collect::<Vec<_>>());
+
+        // ... and moved out of a uniquely owned map
+        let len = map.len();
+        let mut values = map.into_values();
+        values.sort();
+        assert_eq!(values.len(), len);
+        assert_eq!(values, (1u64 .. 1001).collect::<Vec<_>>());
+    }
+
+    pub fn test_into_keys<IS: ItemStore<u64, u64>>(empty: HamtMap<u64, u64, IS>) {
+        let mut map = empty;
+
+        for x in 0u64 .. 1000 {
+            map = map.plus(x * 7, x);
+        }
+
+        let mut keys = map.clone().into_keys();
+        keys.sort();
+        assert_eq!(keys, (0u64 .. 1000).map(|x| x * 7).collect::<Vec<_>>());
+
+        let mut keys = map.into_keys();
+        keys.sort();
+        assert_eq!(keys, (0u64 .. 1000).map(|x| x * 7).collect::<Vec<_>>());
+    }
+
+    pub fn test_replace<IS: ItemStore<u64, u64>>(empty: HamtMap<u64, u64, IS>) {
+        let (map1, previous1) = empty.clone().replace(1, 2);
+        let (map2, previous2) = map1.clone().replace(1, 4);
+        let (map3, previous3) = map2.clone().replace(3, 6);
+
+        assert_eq!(previous1, None);
+        assert_eq!(previous2, Some(2));
+        assert_eq!(previous3, None);
+
+        assert_find!(map1, 1, 2);
+        assert_find!(map2, 1, 4);
+        assert_find!(map3, 1, 4);
+        assert_find!(map3, 3, 6);
+
+        assert_eq!(map2.len(), 1);
+        assert_eq!(map3.len(), 2);
+    }
+
+    pub fn test_modify2<IS: ItemStore<u64, u64>>(empty: HamtMap<u64, u64, IS>) {
+        let mut map = empty;
+
+        for x in 0u64 .. 100 {
+            map = map.plus(x, 1000);
+        }
+
+        let original = map.clone();
+        let transfer = |from: &mut u64, to: &mut u64| {
+            *from -= 10;
+            *to += 10;
+        };
+
+        let map = map.modify2(&3, &7, transfer).modify2(&7, &50, transfer);
+
+        assert_find!(map, 3, 990);
+        assert_find!(map, 7, 1000);
+        assert_find!(map, 50, 1010);
+        assert_eq!(map.iter().map(|(_, v)| *v).sum::<u64>(), 100 * 1000);
+        assert!(original == original.clone().modify2(&3, &1000, |_, _| panic!()));
+
+        for x in 0u64 .. 100 {
+            assert_find!(original, x, 1000);
+        }
+    }
+
+    pub fn test_subtree_sizes<IS: ItemStore<u64, u64>>(empty: HamtMap<u64, u64, IS>) {
+        assert_eq!(empty.subtree_sizes().iter().sum::<usize>(), 0);
+
+        let mut map = empty;
+
+        for x in 0u64 .. 10000 {
+            map = map.plus(x, x);
+        }
+
+        let sizes = map.subtree_sizes();
+        assert_eq!(sizes.len(), HamtMap::<u64, u64, IS>::BRANCHING_FACTOR);
+        assert_eq!(sizes.iter().sum::<usize>(), map.len());
+
+        // With a decent hash function, every slot should have gotten something
+        assert!(sizes.iter().all(|&size| size > 0));
+    }
+
+    pub fn test_from_sorted_pairs<IS: ItemStore<u64, u64>>() {
+        fn check<IS: ItemStore<u64, u64>, H: Hasher+Default>() {
+            let mut rng = rand::thread_rng();
+            let mut pairs: Vec<(u64, u64)> = (0 .. 2000).map(|_| (rng.gen_range(0, 1000), rng.gen()))
+                                                        .collect();
+
+            let mut reference = HamtMap::<u64, u64, IS, H>::new();
+            for &(k, v) in pairs.iter() {
+                reference = reference.plus(k, v);
+            }
+
+            // Unsorted input with duplicate keys
+            let map = HamtMap::<u64, u64, IS, H>::from_sorted_pairs(pairs.clone());
+            assert_eq!(map.len(), reference.len());
+            assert!(map == reference);
+
+            // Input in trie order
+            pairs = reference.iter().map(|(&k, &v)| (k, v)).collect();
+            let map = HamtMap::<u64, u64, IS, H>::from_sorted_pairs(pairs);
+            assert!(map == reference);
+
+            assert_eq!(map.iter().count(), map.len());
+
+            // The result can be modified like any other map
+            let first = *reference.iter().next().unwrap().0;
+            let map = map.plus(1000, 1).minus(&first);
+            assert_eq!(map.len(), reference.len());
+            assert_find!(map, 1000, 1);
+            assert_find!(map, first, None);
+        }
+
+        check::<IS, ::std::collections::hash_map::DefaultHasher>();
+        check::<IS, CollidingHasher>();
+
+        assert_eq!(HamtMap::<u64, u64, IS>::from_sorted_pairs(Vec::new()).len(), 0);
+    }
+
+    pub fn test_subtree_for_prefix<IS: ItemStore<u64, u64>>(empty: HamtMap<u64, u64, IS>) {
+        assert!(empty.subtree_for_prefix(0, 1).is_none());
+
+        let map = empty.clone().plus(1, 1);
+        let shards: Vec<_> = (0 .. 1024).filter_map(|prefix| map.subtree_for_prefix(prefix, 2))
+                                        .collect();
+        assert_eq!(shards.len(), 1);
+        let shard = &shards[0];
+        assert_find!(shard, 1, 1);
+
+        let mut map = empty;
+        for x in 0u64 .. 10000 {
+            map = map.plus(x, x);
+        }
+
+        assert!(map.subtree_for_prefix(0, 0).unwrap() == map);
+
+        for &levels in [1usize, 2, 3].iter() {
+            let mut total = 0;
+
+            for prefix in 0 .. 1u64 << (levels * HamtMap::<u64, u64, IS>::BITS_PER_LEVEL) {
+                if let Some(shard) = map.subtree_for_prefix(prefix, levels) {
+                    assert!(shard.len() > 0);
+                    assert_eq!(shard.iter().count(), shard.len());
+
+                    for (&key, &value) in shard.iter() {
+                        assert_find!(shard, key, value);
+                        assert_find!(map, key, value);
+                    }
+
+                    total += shard.len();
+                }
+            }
+
+            assert_eq!(total, map.len());
+        }
+
+        // A shard is an ordinary map
+        let shard = map.subtree_for_prefix(7, 2).unwrap();
+        let key = *shard.iter().next().unwrap().0;
+        let shard = shard.minus(&key).plus(10000, 0);
+        assert_find!(shard, key, None);
+        assert_find!(shard, 10000, 0);
+        assert_find!(map, key, key);
+    }
+
+    pub fn test_merge_counts<IS: ItemStore<u64, u64>>(empty: HamtMap<u64, u64, IS>) {
+        let count_words = |words: &[u64]| {
+            let mut counts = empty.clone();
+            for &word in words.iter() {
+                let count = counts.find(&word).cloned().unwrap_or(0);
+                counts = counts.plus(word, count + 1);
+            }
+            counts
+        };
+
+        let first = count_words(&[1, 2, 2, 3, 3, 3]);
+        let second = count_words(&[3, 4, 4, 4, 4, 5, 6, 6]);
+
+        for merged in [first.merge_counts(&second), second.merge_counts(&first)].iter() {
+            assert_eq!(merged.len(), 6);
+            assert_find!(merged, 1, 1);
+            assert_find!(merged, 2, 2);
+            assert_find!(merged, 3, 4);
+            assert_find!(merged, 4, 4);
+            assert_find!(merged, 5, 1);
+            assert_find!(merged, 6, 2);
+        }
+
+        // The inputs are left untouched
+        assert_find!(first, 3, 3);
+        assert_find!(second, 3, 1);
+
+        assert!(first.merge_counts(&empty) == first);
+        assert!(empty.merge_counts(&second) == second);
+    }
+
+    pub fn test_hash_quality<IS: ItemStore<u64, u64>>(empty: HamtMap<u64, u64, IS>) {
+        assert_eq!(empty.node_count(), 1);
+
+        let mut map = empty;
+        let mut clustered = HamtMap::<u64, u64, IS, PairingHasher>::new();
+
+        for x in 0u64 .. 10000 {
+            map = map.plus(x, x);
+            clustered = clustered.plus(x, x);
+        }
+
+        assert!(map.node_count() > 1);
+        let quality = map.hash_quality();
+        assert!(quality > 0.9 && quality < 1.1, "quality: {}", quality);
+
+        assert_eq!(clustered.len(), map.len());
+        let quality = clustered.hash_quality();
+        assert!(quality > 2.0, "quality: {}", quality);
+    }
+
+    pub fn test_entry_or_insert_with_key<IS: ItemStore<u64, u64>>(empty: HamtMap<u64, u64, IS>) {
+        let mut calls = 0;
+
+        let map = empty.entry(1).or_insert_with_key(|&key| { calls += 1; key * 10 });
+        assert_eq!(calls, 1);
+        assert_find!(map, 1, 10);
+
+        // The key is present, so `f` must not be called
+        let map = map.entry(1).or_insert_with_key(|&key| { calls += 1; key * 100 });
+        assert_eq!(calls, 1);
+        assert_find!(map, 1, 10);
+
+        let map = map.entry(2).or_insert(2).entry(2).or_insert(3);
+        assert_find!(map, 2, 2);
+
+        let map = map.entry(3).or_insert_with(|| 3).entry(3).or_insert_with(|| panic!());
+        assert_find!(map, 3, 3);
+        assert_eq!(map.len(), 3);
+    }
+
+    pub fn test_evict_one<IS: ItemStore<u64, u64>>(empty: HamtMap<u64, u64, IS>) {
+        let (map, evicted) = empty.evict_one();
+        assert!(evicted.is_none());
+        assert_eq!(map.len(), 0);
+
+        let mut map = map;
+        for x in 0u64 .. 1000 {
+            map = map.plus(x, x + 1);
+        }
+
+        let original = map.clone();
+        let mut evicted_keys = HashSet::new();
+
+        while map.len() > 0 {
+            let (smaller, evicted) = map.evict_one();
+            let (key, value) = evicted.unwrap();
+            assert_find!(original, key, value);
+            assert_find!(smaller, key, None);
+            assert!(evicted_keys.insert(key));
+            map = smaller;
+        }
+
+        assert_eq!(evicted_keys.len(), 1000);
+        assert!(map.evict_one().1.is_none());
+
+        // Evicting from a map that owns all of its nodes, with the items in collision entries
+        let mut map = HamtMap::<u64, u64, IS, CollidingHasher>::new();
+        for x in 0u64 .. 1000 {
+            map = map.plus(x, x + 1);
+        }
+
+        let mut evicted_keys = HashSet::new();
+        while map.len() > 0 {
+            let (smaller, evicted) = map.evict_one();
+            let (key, value) = evicted.unwrap();
+            assert_eq!(value, key + 1);
+            assert_find!(smaller, key, None);
+            assert!(evicted_keys.insert(key));
+            map = smaller;
+        }
+
+        assert_eq!(evicted_keys.len(), 1000);
+    }
+
+    pub fn test_bulk_contains<IS: ItemStore<u64, u64>>(empty: HamtMap<u64, u64, IS>) {
+        fn check<IS: ItemStore<u64, u64>, H: Hasher+Default>(map: &HamtMap<u64, u64, IS, H>) {
+            let mut rng = rand::thread_rng();
+            let queries: Vec<u64> = (0 .. 3000).map(|_| rng.gen_range(0, 2000)).collect();
+            let query_refs: Vec<&u64> = queries.iter().collect();
+
+            let found = map.bulk_contains(&query_refs[..]);
+            assert_eq!(found.len(), queries.len());
+
+            for (key, &is_found) in queries.iter().zip(found.iter()) {
+                assert_eq!(map.find(key).is_some(), is_found);
             }
         }
+
+        assert!(empty.bulk_contains(&[&1, &2]) == vec![false, false]);
+        assert!(empty.bulk_contains(&[]).is_empty());
+
+        let mut map = empty;
+        let mut colliding = HamtMap::<u64, u64, IS, CollidingHasher>::new();
+
+        for x in 0u64 .. 1000 {
+            map = map.plus(x * 2, x);
+            colliding = colliding.plus(x * 2, x);
+        }
+
+        check(&map);
+        check(&colliding);
+    }
+
+    pub fn test_remove_where<IS: ItemStore<u64, u64>>(empty: HamtMap<u64, u64, IS>) {
+        let mut map = empty.clone();
+
+        for x in 0u64 .. 10000 {
+            map = map.plus(x, x * 2);
+        }
+
+        let shared = map.clone();
+        let map = map.remove_where(|&key, _| key % 2 == 0);
+        assert_eq!(map.len(), 5000);
+
+        for x in 0u64 .. 10000 {
+            if x % 2 == 0 {
+                assert_find!(map, x, None);
+            } else {
+                assert_find!(map, x, x * 2);
+            }
+
+            // The original map is not affected
+            assert_find!(shared, x, x * 2);
+        }
+
+        assert!(map == shared.clone().retain(|&key, _| key % 2 != 0));
+
+        let map = map.remove_where(|_, &value| value > 10000);
+        assert_eq!(map.len(), 2500);
+
+        assert_eq!(map.remove_where(|_, _| true).len(), 0);
+        assert_eq!(empty.remove_where(|_, _| true).len(), 0);
+    }
+
+    pub fn test_iter_from<IS: ItemStore<u64, u64>>(empty: HamtMap<u64, u64, IS>) {
+        fn check<IS: ItemStore<u64, u64>, H: Hasher+Default>(map: &HamtMap<u64, u64, IS, H>) {
+            let all: Vec<u64> = map.iter().map(|(&k, _)| k).collect();
+
+            for &pause_after in [0, 1, 7, all.len() / 2, all.len() - 1, all.len()].iter() {
+                let cursor = {
+                    let mut iterator = map.iter();
+                    for _ in 0 .. pause_after {
+                        iterator.next().unwrap();
+                    }
+                    iterator.cursor()
+                };
+
+                // Resuming on a clone works just as well
+                let clone = map.clone();
+                let resumed = clone.iter_from(cursor.clone());
+                let remaining = all.len() - pause_after;
+                assert_eq!(resumed.size_hint(), (remaining, Some(remaining)));
+
+                let rest: Vec<u64> = resumed.map(|(&k, _)| k).collect();
+                assert_eq!(&rest[..], &all[pause_after ..]);
+
+                // The cursor can be used more than once
+                assert_eq!(map.iter_from(cursor).count(), all.len() - pause_after);
+            }
+        }
+
+        let mut map = empty;
+        let mut colliding = HamtMap::<u64, u64, IS, CollidingHasher>::new();
+
+        for x in 0u64 .. 1000 {
+            map = map.plus(x, x);
+            colliding = colliding.plus(x, x);
+        }
+
+        check(&map);
+        check(&colliding);
+    }
+
+    pub fn test_deep_clone<IS: ItemStore<u64, u64>>(empty: HamtMap<u64, u64, IS>) {
+        let mut original = empty.clone();
+        let mut colliding = HamtMap::<u64, u64, IS, CollidingHasher>::new();
+
+        for x in 0u64 .. 1000 {
+            original = original.plus(x, x);
+            colliding = colliding.plus(x, x);
+        }
+
+        assert_eq!(original.count_shared_nodes(), 0);
+
+        let shallow = original.clone();
+        assert_eq!(original.count_shared_nodes(), 1);
+        drop(shallow);
+
+        // Modifying a shallow clone shares everything but the path to the modified entry
+        let modified = original.clone().plus(0, 1);
+        assert!(original.count_shared_nodes() > 1);
+        drop(modified);
+        assert_eq!(original.count_shared_nodes(), 0);
+
+        let deep = original.deep_clone();
+        assert_eq!(original.count_shared_nodes(), 0);
+        assert_eq!(deep.count_shared_nodes(), 0);
+        assert!(deep == original);
+
+        let colliding_clone = colliding.deep_clone();
+        assert_eq!(colliding.count_shared_nodes(), 0);
+        assert!(colliding_clone == colliding);
+
+        assert_eq!(empty.deep_clone().len(), 0);
+    }
+
+    pub fn test_into_raw<IS: ItemStore<u64, u64>>(empty: HamtMap<u64, u64, IS>) {
+        let mut map = empty;
+
+        for x in 0u64 .. 1000 {
+            map = map.plus(x, x + 1);
+        }
+
+        let clone = map.clone();
+        let raw = map.into_raw();
+        assert!(!raw.is_null());
+
+        let map = unsafe { HamtMap::<u64, u64, IS>::from_raw(raw) };
+        assert_eq!(map.len(), 1000);
+        assert!(map == clone);
+
+        // The trie has not been copied
+        assert_eq!(clone.count_shared_nodes(), 1);
+
+        // Round-trip a map modified in between
+        let raw = map.plus(1000, 0).into_raw();
+        let map = unsafe { HamtMap::<u64, u64, IS>::from_raw(raw) };
+        assert_eq!(map.len(), 1001);
+        assert_find!(map, 1000, 0);
+    }
+
+    pub fn test_map_values_in_place<IS: ItemStore<u64, u64>>(empty: HamtMap<u64, u64, IS>) {
+        let mut map = empty;
+        let mut colliding = HamtMap::<u64, u64, IS, CollidingHasher>::new();
+
+        for x in 0u64 .. 1000 {
+            map = map.plus(x, x);
+            colliding = colliding.plus(x, x);
+        }
+
+        // Shares the trie with `map`, which must not be affected
+        let shared = map.clone();
+        let map = map.map_values_in_place(|value| *value += 1);
+
+        // Now the map owns its trie, so this happens in-place
+        let map = map.map_values_in_place(|value| *value += 1);
+        assert_eq!(map.count_shared_nodes(), 0);
+
+        // Partly shared after modifying a clone
+        let partly_shared = map.clone().plus(1000, 998);
+        let partly_shared = partly_shared.map_values_in_place(|value| *value *= 2);
+
+        let colliding = colliding.map_values_in_place(|value| *value += 2);
+
+        assert_eq!(map.len(), 1000);
+        assert_eq!(partly_shared.len(), 1001);
+
+        for x in 0u64 .. 1000 {
+            assert_find!(shared, x, x);
+            assert_find!(map, x, x + 2);
+            assert_find!(partly_shared, x, (x + 2) * 2);
+            assert_find!(colliding, x, x + 2);
+        }
+
+        assert_find!(partly_shared, 1000, 1996);
+    }
+
+    pub fn test_contains_all_any<IS: ItemStore<u64, u64>>(empty: HamtMap<u64, u64, IS>) {
+        let map = empty.plus(1, 1).plus(2, 2).plus(3, 3);
+
+        assert!(map.contains_key(&1));
+        assert!(!map.contains_key(&4));
+
+        // All present
+        assert!(map.contains_all(&[&1, &2, &3]));
+        assert!(map.contains_any(&[&1, &2, &3]));
+
+        // Some missing
+        assert!(!map.contains_all(&[&1, &4, &3]));
+        assert!(map.contains_any(&[&4, &5, &3]));
+
+        // All missing
+        assert!(!map.contains_all(&[&4, &5]));
+        assert!(!map.contains_any(&[&4, &5]));
+
+        // No keys at all
+        assert!(map.contains_all(&[]));
+        assert!(!map.contains_any(&[]));
+    }
+
+    pub fn test_find_cloned_or_default<IS: ItemStore<u64, u64>>(empty: HamtMap<u64, u64, IS>) {
+        assert_eq!(empty.find_cloned_or_default(&1), 0);
+
+        let mut counts = empty;
+        for &word in [1u64, 2, 2, 3, 3, 3].iter() {
+            let count = counts.find_cloned_or_default(&word);
+            counts = counts.plus(word, count + 1);
+        }
+
+        assert_eq!(counts.find_cloned_or_default(&1), 1);
+        assert_eq!(counts.find_cloned_or_default(&2), 2);
+        assert_eq!(counts.find_cloned_or_default(&3), 3);
+        assert_eq!(counts.find_cloned_or_default(&4), 0);
+    }
+
+    pub fn test_merge_by<IS: ItemStore<u64, u64>>(empty: HamtMap<u64, u64, IS>) {
+        // Values are `version * 1000 + payload`
+        let version = |_: &u64, value: &u64| *value / 1000;
+
+        let mut local = empty.clone();
+        let mut remote = empty.clone();
+
+        for x in 0u64 .. 100 {
+            local = local.plus(x, 1000 + x);
+        }
+
+        // Newer versions for some keys, older ones for others, equal ones for the rest
+        for x in 50u64 .. 150 {
+            let remote_version = match x % 3 { 0 => 2, 1 => 0, _ => 1 };
+            remote = remote.plus(x, remote_version * 1000 + x + 500);
+        }
+
+        for &(left, right) in [(&local, &remote), (&remote, &local)].iter() {
+            let mut ts_calls = 0;
+            let merged = left.merge_by(right, |key, value| { ts_calls += 1; version(key, value) });
+
+            // Only called for the 50 conflicting keys, twice each
+            assert_eq!(ts_calls, 100);
+            assert_eq!(merged.len(), 150);
+
+            for x in 0u64 .. 150 {
+                let expected = if x < 50 {
+                    1000 + x
+                } else if x >= 100 {
+                    remote.find(&x).cloned().unwrap()
+                } else {
+                    match x % 3 {
+                        0 => 2000 + x + 500,
+                        1 => 1000 + x,
+                        // Equal versions, so the value from `left` stays
+                        _ => *left.find(&x).unwrap(),
+                    }
+                };
+
+                assert_find!(merged, x, expected);
+            }
+        }
+
+        assert!(local.merge_by(&empty, version) == local);
+        assert!(empty.merge_by(&remote, version) == remote);
+    }
+
+    pub fn test_ref_count_of_root<IS: ItemStore<u64, u64>>(empty: HamtMap<u64, u64, IS>) {
+        let mut map = empty;
+
+        for x in 0u64 .. 1000 {
+            map = map.plus(x, x);
+            // Nothing else references the root, so the fast in-place path is taken
+            assert_eq!(map.ref_count_of_root(), 1);
+        }
+
+        let clone = map.clone();
+        assert_eq!(map.ref_count_of_root(), 2);
+        assert_eq!(clone.ref_count_of_root(), 2);
+
+        // Inserting into the clone copies the path down to the new entry, leaving the original
+        // root alone and sharing everything else
+        let clone = clone.plus(1000, 1000);
+        assert_eq!(map.ref_count_of_root(), 1);
+        assert_eq!(clone.ref_count_of_root(), 1);
+        assert!(map.count_shared_nodes() > 0);
+        assert_find!(map, 1000, None);
+        assert_find!(clone, 1000, 1000);
+
+        // Same for removals
+        let other = map.clone();
+        let other = other.minus(&0);
+        assert_eq!(map.ref_count_of_root(), 1);
+        assert_eq!(other.ref_count_of_root(), 1);
+        assert_find!(map, 0, 0);
+        assert_find!(other, 0, None);
+
+        drop(clone);
+        drop(other);
+        assert_eq!(map.count_shared_nodes(), 0);
+    }
+
+    pub fn test_collision_groups<IS: ItemStore<u64, u64>>(empty: HamtMap<u64, u64, IS>) {
+        let mut map = empty;
+        let mut colliding = HamtMap::<u64, u64, IS, CollidingHasher>::new();
+        let mut routing_only = HamtMap::<u64, u64, IS, TopBitHasher>::new();
+
+        for x in 0u64 .. 1000 {
+            map = map.plus(x, x);
+            colliding = colliding.plus(x, x);
+            routing_only = routing_only.plus(x, x);
+        }
+
+        assert!(map.collision_groups().is_empty());
+
+        // Only 16 distinct hash values
+        let groups = colliding.collision_groups();
+        assert_eq!(groups.len(), 16);
+        assert_eq!(groups.iter().map(|group| group.len()).sum::<usize>(), 1000);
+
+        // Every key shows up exactly once
+        let mut seen = HashSet::new();
+        for key in groups.iter().flat_map(|group| group.iter()) {
+            assert!(seen.insert(*key));
+        }
+
+        // These keys share a collision entry but differ in the topmost hash bit
+        assert!(routing_only.collision_groups().is_empty());
+        assert_find!(routing_only, 0, 0);
+        assert_find!(routing_only, 1, 1);
+    }
+
+    pub fn test_zip_with<IS: ItemStore<u64, u64>>(empty: HamtMap<u64, u64, IS>) {
+        type Joined = HamtMap<u64, (u64, String), ShareStore<u64, (u64, String)>>;
+        type Pairs = HamtMap<u64, (u64, u64), CopyStore<u64, (u64, u64)>>;
+
+        let mut prices = empty.clone();
+        let mut names = HamtMap::<u64, String, ShareStore<u64, String>>::new();
+
+        for x in 0u64 .. 1000 {
+            prices = prices.plus(x, x * 100);
+        }
+
+        for x in 500u64 .. 1500 {
+            names = names.plus(x, format!("item {}", x));
+        }
+
+        let joined: Joined = prices.zip_with(&names);
+        assert_eq!(joined.len(), 500);
+
+        for x in 0u64 .. 1500 {
+            match joined.find(&x) {
+                Some(&(price, ref name)) => {
+                    assert!((500 .. 1000).contains(&x));
+                    assert_eq!(price, x * 100);
+                    assert_eq!(*name, format!("item {}", x));
+                }
+                None => assert!(!(500 .. 1000).contains(&x)),
+            }
+        }
+
+        let nothing: Pairs = prices.zip_with(&empty);
+        assert_eq!(nothing.len(), 0);
+    }
+
+    pub fn test_union<IS: ItemStore<u64, u64>>(empty: HamtMap<u64, u64, IS>) {
+        assert!(empty.is_single_level());
+
+        // Grow two tiny maps as long as they fit into their root nodes, with overlapping keys
+        let mut left = empty.clone();
+        let mut right = empty.clone();
+        let mut x = 0u64;
+
+        loop {
+            let next_left = left.clone().plus(x, x);
+            let next_right = right.clone().plus(x + 3, x + 3000);
+
+            if !next_left.is_single_level() || !next_right.is_single_level() {
+                break;
+            }
+
+            left = next_left;
+            right = next_right;
+            x += 1;
+        }
+
+        assert!(left.len() > 3);
+        assert!(!left.union(&right).is_single_level());
+
+        let mut large = empty.clone();
+        for y in 0u64 .. 1000 {
+            large = large.plus(y, y + 5000);
+        }
+        assert!(!large.is_single_level());
+
+        for &(a, b) in [(&left, &right), (&right, &left), (&left, &large), (&large, &right)].iter() {
+            let union = a.union(b);
+            let mut reference: HashMap<u64, u64> = b.iter().map(|(&k, &v)| (k, v)).collect();
+            reference.extend(a.iter().map(|(&k, &v)| (k, v)));
+
+            assert_eq!(union.len(), reference.len());
+            assert_eq!(union.iter().count(), reference.len());
+            for (key, value) in reference.iter() {
+                assert_eq!(union.find(key), Some(value));
+            }
+        }
+
+        assert_eq!(left.union(&empty).len(), left.len());
+        assert_eq!(empty.union(&empty).len(), 0);
+    }
+
+    pub fn test_get_disjoint<IS: ItemStore<u64, u64>>(empty: HamtMap<u64, u64, IS>) {
+        let mut map = empty;
+
+        for x in 0u64 .. 100 {
+            map = map.plus(x, x * 10);
+        }
+
+        let values = map.get_disjoint(&[&3, &500, &42]);
+        assert_eq!(values, vec![Some(&30), None, Some(&420)]);
+
+        // All three references are live at once
+        let (a, b, c) = match &map.get_disjoint(&[&1, &2, &3])[..] {
+            &[Some(a), Some(b), Some(c)] => (a, b, c),
+            _ => panic!(),
+        };
+        assert_eq!(a + b + c, 60);
+
+        assert!(map.get_disjoint(&[]).is_empty());
+    }
+
+    pub fn test_normalize<IS: ItemStore<u64, u64>>(empty: HamtMap<u64, u64, IS>) {
+        let mut map = empty;
+        let mut colliding = HamtMap::<u64, u64, IS, CollidingHasher>::new();
+
+        for x in 0u64 .. 1000 {
+            map = map.plus(x, x);
+            colliding = colliding.plus(x, x);
+        }
+
+        // Resolving the collisions leaves chains of single-entry nodes behind
+        for x in 20u64 .. 1000 {
+            map = map.minus(&x);
+            colliding = colliding.minus(&x);
+        }
+
+        let node_count = colliding.node_count();
+        let normalized = colliding.clone().normalize();
+        assert!(normalized.node_count() < node_count);
+        assert_eq!(normalized.len(), 20);
+
+        for x in 0u64 .. 1000 {
+            assert_eq!(normalized.find(&x), colliding.find(&x));
+        }
+
+        // Normalizing again doesn't change anything
+        assert_eq!(normalized.clone().normalize().node_count(), normalized.node_count());
+
+        let normalized = map.clone().normalize();
+        assert!(normalized.node_count() <= map.node_count());
+        assert_eq!(normalized.len(), 20);
+
+        for x in 0u64 .. 1000 {
+            assert_eq!(normalized.find(&x), map.find(&x));
+        }
+    }
+
+    pub fn test_key_path<IS: ItemStore<u64, u64>>(empty: HamtMap<u64, u64, IS>) {
+        assert_eq!(empty.key_path(&1), (vec![], false));
+
+        let single = empty.plus(1, 1);
+        let (path, found) = single.key_path(&1);
+        assert_eq!(path.len(), 1);
+        assert!(found);
+
+        // Keys 4 and 5 share all but the last of the 12 levels their hash values are routed on
+        let last_level = 11;
+        let mut pair = HamtMap::<u64, u64, IS, PairingHasher>::new();
+        pair = pair.plus(4, 4).plus(5, 5);
+
+        let (path_4, found_4) = pair.key_path(&4);
+        let (path_5, found_5) = pair.key_path(&5);
+        assert!(found_4 && found_5);
+        assert_eq!(path_4.len(), last_level + 1);
+        assert_eq!(path_5.len(), last_level + 1);
+        assert_eq!(path_4[.. last_level], path_5[.. last_level]);
+        assert!(path_4[last_level] != path_5[last_level]);
+        assert!(path_4.iter().all(|&local_key| local_key < 32));
+
+        let (path_5, found_5) = pair.minus(&5).key_path(&5);
+        assert!(!found_5);
+        assert_eq!(path_5.len(), 1);
+    }
+
+    pub fn test_growth_policy<IS: ItemStore<u64, u64>>(empty: HamtMap<u64, u64, IS>) {
+        assert_eq!(empty.growth_policy(), GrowthPolicy::Doubling);
+
+        let policies = [GrowthPolicy::Fixed(0), GrowthPolicy::Linear, GrowthPolicy::Doubling,
+                        GrowthPolicy::Fixed(8), GrowthPolicy::Fixed(32)];
+        let mut footprints = Vec::new();
+
+        for &policy in policies.iter() {
+            let mut map = HamtMap::<u64, u64, IS>::with_growth_policy(policy);
+            let mut snapshots = Vec::new();
+
+            for x in 0u64 .. 5000 {
+                map = map.plus(x, x);
+
+                if x % 1000 == 0 {
+                    snapshots.push(map.clone());
+                }
+            }
+
+            footprints.push(map.memory_footprint());
+
+            for x in (0u64 .. 5000).filter(|x| x % 3 == 0) {
+                map = map.minus(&x);
+            }
+
+            assert_eq!(map.growth_policy(), policy);
+            assert_eq!(map.len(), 3333);
+
+            for x in 0u64 .. 5000 {
+                if x % 3 == 0 {
+                    assert_eq!(map.find(&x), None);
+                } else {
+                    assert_eq!(map.find(&x), Some(&x));
+                }
+            }
+
+            // Earlier versions of the map aren't affected by the in-place modifications
+            for (index, snapshot) in snapshots.iter().enumerate() {
+                assert_eq!(snapshot.len(), index * 1000 + 1);
+                assert_eq!(snapshot.find(&(index as u64 * 1000)), Some(&(index as u64 * 1000)));
+            }
+        }
+
+        // Fixed(0) allocates nodes as small as possible, Fixed(32) as big as possible
+        assert!(footprints[0] <= footprints[1] && footprints[0] <= footprints[2]);
+        assert!(footprints[2] <= footprints[4] && footprints[3] <= footprints[4]);
+    }
+
+    pub fn test_drain_filter<IS: ItemStore<u64, u64>>(empty: HamtMap<u64, u64, IS>) {
+        let mut map = empty.clone();
+
+        for x in 0u64 .. 1000 {
+            map = map.plus(x, x * 2);
+        }
+
+        // Keep a second reference, so some entries can't be moved out
+        let original = map.clone();
+        let (kept, drained) = map.drain_filter(|&key, _| key % 3 == 0);
+
+        assert_eq!(kept.len(), 666);
+        assert_eq!(drained.len(), 334);
+        assert!(drained.iter().all(|&(key, value)| key % 3 == 0 && value == key * 2));
+
+        // Together, the drained entries and the remaining map make up the original one
+        let mut reconstructed = kept.clone();
+        for &(key, value) in drained.iter() {
+            reconstructed = reconstructed.plus(key, value);
+        }
+        assert!(reconstructed == original);
+
+        let (kept, drained) = original.drain_filter(|_, _| false);
+        assert_eq!(kept.len(), 1000);
+        assert!(drained.is_empty());
+
+        let (kept, drained) = kept.drain_filter(|_, _| true);
+        assert_eq!(kept.len(), 0);
+        assert_eq!(drained.len(), 1000);
+
+        let (kept, drained) = empty.drain_filter(|_, _| true);
+        assert_eq!(kept.len(), 0);
+        assert!(drained.is_empty());
+    }
+
+    pub fn test_sample<IS: ItemStore<u64, u64>>(empty: HamtMap<u64, u64, IS>) {
+        use rand::{SeedableRng, XorShiftRng};
+
+        let mut rng = XorShiftRng::from_seed([11, 22, 33, 44]);
+        assert_eq!(empty.sample(&mut rng), None);
+
+        // Keys 0 to 19 form pairs stored in collision entries at the bottom of the trie, while the
+        // even keys from 100 to 138 are stored right below the root. Picking an entry per level
+        // uniformly would favor the latter.
+        let mut map = HamtMap::<u64, u64, IS, TopBitHasher>::new();
+
+        for x in 0u64 .. 20 {
+            map = map.plus(x, x);
+        }
+        for x in (100u64 .. 140).filter(|x| x % 2 == 0) {
+            map = map.plus(x, x);
+        }
+        assert_eq!(map.len(), 40);
+
+        let draws = 100000;
+        let mut counts: HashMap<u64, usize> = HashMap::new();
+
+        for _ in 0 .. draws {
+            let (&key, &value) = map.sample(&mut rng).unwrap();
+            assert_eq!(key, value);
+            *counts.entry(key).or_insert(0) += 1;
+        }
+
+        // Each key is expected 2500 times, with a standard deviation of about 50
+        assert_eq!(counts.len(), 40);
+        for (_, &count) in counts.iter() {
+            assert!((2200 .. 2800).contains(&count));
+        }
+    }
+
+    pub fn test_scan_hash_range<IS: ItemStore<u64, u64>>(empty: HamtMap<u64, u64, IS>) {
+        let end = 1u64 << 60;
+        assert_eq!(empty.scan_hash_range(0, end).count(), 0);
+
+        fn check<IS, H>(map: &HamtMap<u64, u64, IS, H>)
+            where IS: ItemStore<u64, u64>,
+                  H: Hasher + Default
+        {
+            let end = 1u64 << 60;
+            let all: HashSet<u64> = map.iter().map(|(&key, _)| key).collect();
+            assert_eq!(map.scan_hash_range(0, end).count(), map.len());
+            assert_eq!(map.scan_hash_range(0, 0).count(), 0);
+            assert_eq!(map.scan_hash_range(end, u64::MAX).count(), 0);
+
+            // Ranges of different sizes, not aligned to sub-tree boundaries
+            for &shards in [2u64, 7, 32, 1000].iter() {
+                let width = end / shards + 1;
+                let mut seen = HashSet::new();
+
+                for shard in 0 .. shards {
+                    let lo = shard * width;
+                    let hi = cmp::min(lo + width, end);
+
+                    for (&key, &value) in map.scan_hash_range(lo, hi) {
+                        assert_eq!(key, value);
+                        // Every entry is found in exactly one range
+                        assert!(seen.insert(key));
+                    }
+                }
+
+                assert!(seen == all);
+            }
+        }
+
+        let mut map = empty;
+        let mut colliding = HamtMap::<u64, u64, IS, CollidingHasher>::new();
+        let mut clustered = HamtMap::<u64, u64, IS, PairingHasher>::new();
+
+        for x in 0u64 .. 5000 {
+            map = map.plus(x, x);
+            clustered = clustered.plus(x, x);
+        }
+        for x in 0u64 .. 200 {
+            colliding = colliding.plus(x, x);
+        }
+
+        check(&map);
+        check(&colliding);
+        check(&clustered);
+    }
+
+    pub fn test_into_sorted_iter<IS: ItemStore<u64, u64>>(empty: HamtMap<u64, u64, IS>) {
+        let mut rng = rand::thread_rng();
+        let mut map = empty.clone();
+
+        for _ in 0 .. 1000 {
+            let x: u64 = rng.gen();
+            map = map.plus(x, !x);
+        }
+
+        // The clone keeps some entries shared, so they have to be cloned
+        let shared = map.clone();
+        let len = map.len();
+        let sorted = map.into_sorted_iter();
+        assert_eq!(sorted.len(), len);
+
+        let pairs: Vec<(u64, u64)> = sorted.collect();
+        assert_eq!(pairs.len(), len);
+        assert!(pairs.windows(2).all(|pair| pair[0].0 < pair[1].0));
+        assert!(pairs.iter().all(|&(key, value)| value == !key && shared.find(&key) == Some(&value)));
+
+        assert_eq!(empty.into_sorted_iter().next(), None);
+    }
+
+    pub fn test_transient<IS: ItemStore<u64, u64>>(empty: HamtMap<u64, u64, IS>) {
+        let mut transient = empty.clone().transient();
+        assert!(transient.is_empty());
+
+        for x in 0u64 .. 1000 {
+            assert!(transient.insert(x, x));
+        }
+        assert!(!transient.insert(5, 50));
+        assert!(transient.remove(&6));
+        assert!(!transient.remove(&6));
+        assert_eq!(transient.len(), 999);
+        assert_eq!(transient.find(&5), Some(&50));
+
+        // Merging maps, later ones win on conflicts
+        let mut others = Vec::new();
+        for i in 0u64 .. 5 {
+            let mut other = empty.clone();
+            for x in (i * 500) .. (i * 500 + 1000) {
+                other = other.plus(x, i);
+            }
+            others.push(other);
+        }
+
+        let snapshot = others[0].clone();
+        let mut merged = HamtMap::<u64, u64, IS>::new().transient();
+        for other in others.iter() {
+            merged.merge_in_place(other);
+        }
+
+        let merged = merged.persistent();
+        assert_eq!(merged.len(), 3000);
+        for x in 0u64 .. 3000 {
+            let expected = cmp::min(x / 500, 4);
+            assert_eq!(merged.find(&x), Some(&expected));
+        }
+
+        // The merged maps are unaffected
+        assert!(snapshot == others[0]);
+        assert_eq!(others[4].len(), 1000);
+
+        let persistent = transient.persistent();
+        assert_eq!(persistent.len(), 999);
+        assert_eq!(persistent.find(&6), None);
+    }
+
+    pub fn test_capacity_stats<IS: ItemStore<u64, u64>>(empty: HamtMap<u64, u64, IS>) {
+        let stats = empty.capacity_stats();
+        assert_eq!((stats.node_count, stats.allocated_slots, stats.used_slots), (1, 0, 0));
+        assert_eq!((stats.wasted_slots, stats.wasted_bytes), (0, 0));
+        assert_eq!(stats.memory_footprint, empty.memory_footprint());
+
+        // Find keys with five different hash values, and one more key sharing its hash value
+        // with the first of them. The hash values are smaller than 32, so they all end up in
+        // different root slots.
+        fn colliding_hash(key: u64) -> u64 {
+            let mut hasher = CollidingHasher::default();
+            key.hash(&mut hasher);
+            hasher.finish()
+        }
+
+        let mut keys: Vec<u64> = Vec::new();
+        let mut key = 0;
+        while keys.len() < 5 {
+            if keys.iter().all(|&other| colliding_hash(other) != colliding_hash(key)) {
+                keys.push(key);
+            }
+            key += 1;
+        }
+        let mut collider = key;
+        while colliding_hash(collider) != colliding_hash(keys[0]) {
+            collider += 1;
+        }
+
+        let mut map = HamtMap::<u64, u64, IS, CollidingHasher>::new();
+        for &key in &keys[.. 3] {
+            map = map.plus(key, key);
+        }
+
+        // A root node with the initial capacity of 4, one slot unused
+        let stats = map.capacity_stats();
+        assert_eq!((stats.node_count, stats.allocated_slots, stats.used_slots), (1, 4, 3));
+        assert_eq!(stats.wasted_slots, 1);
+        let slot_size = stats.wasted_bytes;
+        assert!(slot_size > 0);
+
+        for &key in &keys[3 ..] {
+            map = map.plus(key, key);
+        }
+
+        // The fifth entry doubles the root capacity to 8
+        let stats = map.capacity_stats();
+        assert_eq!((stats.node_count, stats.allocated_slots, stats.used_slots), (1, 8, 5));
+        assert_eq!(stats.wasted_slots, 3);
+        assert_eq!(stats.wasted_bytes, 3 * slot_size);
+
+        // The colliding key replaces the first entry with a chain of eleven nodes down to the
+        // last level, each with one of four slots used, the last one holding a collision entry
+        map = map.plus(collider, collider);
+        let stats = map.capacity_stats();
+        assert_eq!(stats.node_count, 12);
+        assert_eq!(stats.allocated_slots, 8 + 11 * 4);
+        assert_eq!(stats.used_slots, 5 + 11);
+        assert_eq!(stats.wasted_slots, 36);
+        assert_eq!(stats.wasted_bytes, 36 * slot_size);
+        assert_eq!(stats.memory_footprint, map.memory_footprint());
+        assert!(stats.to_string()
+                     .starts_with("12 nodes, 16 of 52 entry slots used (36 wasted, 69.2%)"));
+    }
+
+    pub fn test_from_root_parts<IS: ItemStore<u64, u64>>() {
+        fn check<IS: ItemStore<u64, u64>, H: Hasher+Default>() {
+            fn hash<H: Hasher+Default>(key: u64) -> u64 {
+                let mut hasher = H::default();
+                key.hash(&mut hasher);
+                hasher.finish()
+            }
+
+            let mut rng = rand::thread_rng();
+            let pairs: Vec<(u64, u64)> = (0 .. 2000).map(|_| (rng.gen_range(0, 1000), rng.gen()))
+                                                    .collect();
+
+            let mut reference = HamtMap::<u64, u64, IS, H>::new();
+            for &(k, v) in pairs.iter() {
+                reference = reference.plus(k, v);
+            }
+
+            // Duplicate keys, the last one wins just like with repeated insertion
+            let entries = pairs.iter().map(|&(k, v)| (hash::<H>(k), k, v)).collect();
+            let map = HamtMap::<u64, u64, IS, H>::from_root_parts(entries);
+            assert_eq!(map.len(), reference.len());
+            assert!(map == reference);
+
+            for &(k, _) in pairs.iter() {
+                assert_eq!(map.find(&k), reference.find(&k));
+            }
+
+            let first = *reference.iter().next().unwrap().0;
+            let map = map.plus(1000, 1).minus(&first);
+            assert_eq!(map.len(), reference.len());
+            assert_find!(map, 1000, 1);
+            assert_find!(map, first, None);
+        }
+
+        check::<IS, ::std::collections::hash_map::DefaultHasher>();
+        check::<IS, CollidingHasher>();
+
+        assert_eq!(HamtMap::<u64, u64, IS>::from_root_parts(Vec::new()).len(), 0);
+    }
+
+    pub fn test_observe<IS: ItemStore<u64, u64>>(empty: HamtMap<u64, u64, IS>) {
+        use std::sync::Mutex;
+
+        #[derive(Default)]
+        struct Recorder {
+            // (level, was copied)
+            events: Mutex<Vec<(usize, bool)>>,
+        }
+
+        impl StructuralObserver for Recorder {
+            fn on_copy(&self, level: usize) {
+                self.events.lock().unwrap().push((level, true));
+            }
+
+            fn on_reuse(&self, level: usize) {
+                self.events.lock().unwrap().push((level, false));
+            }
+        }
+
+        let recorder = Arc::new(Recorder::default());
+        let take_events = || -> Vec<(usize, bool)> {
+            recorder.events.lock().unwrap().drain(..).collect()
+        };
+
+        let mut map = empty.clone();
+        for x in 0u64 .. 1000 {
+            map = map.plus(x, x);
+        }
+        let mut map = map.observe(recorder.clone());
+        assert!(take_events().is_empty());
+
+        // Replacing values only modifies the uniquely owned map in-place, on every level down to
+        // the item
+        for x in 0u64 .. 1000 {
+            map = map.plus(x, x + 1);
+            let events = take_events();
+            assert!(!events.is_empty());
+            assert!(events.iter().all(|&(_, copied)| !copied));
+            assert!(events.iter().any(|&(level, _)| level == 0));
+        }
+
+        // With a second map sharing the root, the nodes on the path have to be copied
+        let shared = map.clone();
+        map = map.plus(0, 0);
+        let events = take_events();
+        assert!(events.contains(&(0, true)));
+        assert!(events.iter().all(|&(_, copied)| copied));
+
+        // The same goes for removal
+        let (removed, _) = shared.clone().remove(&1);
+        let events = take_events();
+        assert!(events.contains(&(0, true)));
+        assert!(events.iter().all(|&(_, copied)| copied));
+
+        // Removing a key that isn't there doesn't change any node
+        let (map, _) = map.remove(&5000);
+        assert!(take_events().is_empty());
+
+        // The copied root isn't shared, so it is modified in-place, unlike the nodes below it
+        let (removed, _) = removed.remove(&2);
+        let events = take_events();
+        assert!(events.contains(&(0, false)));
+        assert!(events.iter().all(|&(level, copied)| copied == (level > 0)));
+
+        // The observer stays with derived maps until it is removed
+        let removed = removed.plus(3000, 3000);
+        assert!(!take_events().is_empty());
+        let map = map.stop_observing().plus(2000, 2000);
+        assert!(take_events().is_empty());
+        assert_eq!(map.len(), removed.len() + 2);
+
+        // Maps without an observer don't generate any events
+        assert_eq!(empty.plus(1, 1).plus(2, 2).len(), 2);
+        assert!(take_events().is_empty());
+    }
+
+    pub fn test_try_into_array<IS: ItemStore<u64, u64>>(empty: HamtMap<u64, u64, IS>) {
+        let map = empty.clone().plus(1, 10).plus(2, 20).plus(3, 30);
+
+        // Entries shared with another map are cloned
+        let shared = map.clone();
+        let mut array: [(u64, u64); 3] = map.try_into_array().ok().unwrap();
+        array.sort();
+        assert_eq!(array, [(1, 10), (2, 20), (3, 30)]);
+
+        let array: [(u64, u64); 3] = shared.try_into_array().ok().unwrap();
+        assert!(array.iter().all(|&(key, value)| value == key * 10));
+
+        // The wrong number of entries gives back the map unchanged
+        let map = empty.clone().plus(1, 10).plus(2, 20).plus(3, 30).plus(4, 40);
+        let map = map.try_into_array::<3>().err().unwrap();
+        assert_eq!(map.len(), 4);
+        assert_find!(map, 4, 40);
+
+        let map = map.try_into_array::<5>().err().unwrap();
+        assert_eq!(map.len(), 4);
+
+        let array: [(u64, u64); 0] = empty.try_into_array().ok().unwrap();
+        assert!(array.is_empty());
+    }
+
+    pub fn test_key_hash<IS: ItemStore<u64, u64>>(empty: HamtMap<u64, u64, IS>) {
+        type Colliding<IS> = HamtMap<u64, u64, IS, CollidingHasher>;
+
+        let mut map = empty;
+        let mut colliding = Colliding::<IS>::new();
+        for x in 0u64 .. 100 {
+            map = map.plus(x, x);
+            colliding = colliding.plus(x, x);
+        }
+
+        for x in 0u64 .. 100 {
+            let mut hasher = ::std::collections::hash_map::DefaultHasher::default();
+            x.hash(&mut hasher);
+            assert_eq!(HamtMap::<u64, u64, IS>::key_hash(&x), hasher.finish());
+            let mut hasher = CollidingHasher::default();
+            x.hash(&mut hasher);
+            assert_eq!(Colliding::<IS>::key_hash(&x), hasher.finish());
+            assert!(Colliding::<IS>::key_hash(&x) < 16);
+        }
+
+        // Keys reported to share a hash value are found on the same path down the trie
+        for x in 0u64 .. 100 {
+            for y in 0u64 .. 100 {
+                let (path_x, found_x) = colliding.key_path(&x);
+                let (path_y, found_y) = colliding.key_path(&y);
+                assert!(found_x && found_y);
+
+                let same_hash = Colliding::<IS>::key_hash(&x) == Colliding::<IS>::key_hash(&y);
+                assert_eq!(path_x == path_y, same_hash);
+            }
+        }
+
+        // The hash values are the ones `from_root_parts()` expects
+        let entries = (0u64 .. 100).map(|x| (HamtMap::<u64, u64, IS>::key_hash(&x), x, x))
+                                   .collect();
+        assert!(HamtMap::<u64, u64, IS>::from_root_parts(entries) == map);
+    }
+
+    pub fn test_split_at_hash<IS: ItemStore<u64, u64>>(empty: HamtMap<u64, u64, IS>) {
+        fn check<IS: ItemStore<u64, u64>, H: Hasher+Default>(map: &HamtMap<u64, u64, IS, H>) {
+            let end = 1u64 << 60;
+            let mut rng = rand::thread_rng();
+            let mut pivots = vec!(0, 1, end - 1, end, end + 1, !0, end / 2, end / 32 * 3);
+            pivots.extend((0 .. 20).map(|_| rng.gen_range(0, end)));
+            pivots.extend((0 .. 20).map(|_| rng.gen_range(0, 32) << 55));
+            pivots.extend((0 .. 20).map(|_| rng.gen_range(0, 1 << 10) << 50));
+
+            for &pivot in pivots.iter() {
+                let (below, rest) = map.clone().split_at_hash(pivot);
+                assert_eq!(below.len() + rest.len(), map.len());
+                assert_eq!(below.iter().count(), below.len());
+                assert_eq!(rest.iter().count(), rest.len());
+
+                let lo = cmp::min(pivot, end);
+                let expected: HashSet<u64> = map.scan_hash_range(0, lo).map(|(&k, _)| k).collect();
+                assert!(below.iter().all(|(k, _)| expected.contains(k)));
+                assert!(rest.iter().all(|(k, _)| !expected.contains(k)));
+
+                // The halves can be modified like any other map
+                let reunited = below.union(&rest);
+                assert!(reunited == *map);
+
+                let (below, _) = below.remove(&0);
+                let rest = rest.plus(0, 0);
+                assert!(below.find(&0).is_none());
+                assert_find!(rest, 0, 0);
+            }
+        }
+
+        let mut map = empty.clone();
+        let mut colliding = HamtMap::<u64, u64, IS, CollidingHasher>::new();
+        let mut clustered = HamtMap::<u64, u64, IS, PairingHasher>::new();
+
+        for x in 0u64 .. 5000 {
+            map = map.plus(x, x);
+            clustered = clustered.plus(x, x);
+        }
+        for x in 0u64 .. 200 {
+            colliding = colliding.plus(x, x);
+        }
+
+        check(&map);
+        check(&colliding);
+        check(&clustered);
+        check(&empty.clone().plus(1, 1));
+        check(&empty);
+    }
+
+    pub fn test_collision_policy<IS: ItemStore<u64, u64>>(empty: HamtMap<u64, u64, IS>) {
+        // With only 16 distinct hash values, each of these keys shares its hash value with others
+        let colliding = |policy: CollisionPolicy<u64>| {
+            let mut map = HamtMap::<u64, u64, IS, CollidingHasher>::new()
+                .set_collision_policy(policy);
+            for x in 0u64 .. 200 {
+                map = map.plus(x, x);
+            }
+            map
+        };
+
+        let sum = CollisionPolicy::Combine(Arc::new(|existing: &u64, new: u64| existing + new));
+        let mut map = colliding(sum);
+        let shared = map.clone();
+
+        for x in 0u64 .. 200 {
+            map = map.plus(x, 1000);
+        }
+        for x in 0u64 .. 200 {
+            assert_find!(map, x, x + 1000);
+            assert_find!(shared, x, x);
+        }
+
+        // Derived maps keep the policy
+        let (map, _) = map.remove(&0);
+        let map = map.plus(1, 1);
+        assert_find!(map, 1, 1002);
+
+        let mut map = colliding(CollisionPolicy::KeepExisting);
+        for x in 0u64 .. 200 {
+            map = map.plus(x, 1000);
+        }
+        for x in 0u64 .. 200 {
+            assert_find!(map, x, x);
+        }
+
+        let map = colliding(CollisionPolicy::Replace).plus(5, 1000);
+        assert_find!(map, 5, 1000);
+
+        // Keys that are not in collision entries are always replaced
+        let map = empty.set_collision_policy(CollisionPolicy::KeepExisting).plus(1, 1).plus(1, 2);
+        assert_find!(map, 1, 2);
+        assert!(matches!(*map.collision_policy(), CollisionPolicy::KeepExisting));
+    }
+
+    pub fn test_iter_with_depth<IS: ItemStore<u64, u64>>(empty: HamtMap<u64, u64, IS>) {
+        fn check<IS: ItemStore<u64, u64>, H: Hasher+Default>(map: &HamtMap<u64, u64, IS, H>) {
+            let max_depth = HamtMap::<u64, u64, IS, H>::MAX_DEPTH;
+            let mut count = 0;
+
+            for (depth, &key, &value) in map.iter_with_depth() {
+                assert_eq!(key, value);
+                assert!(depth < max_depth);
+                // The path to a key has one local key per level
+                assert_eq!(depth + 1, map.key_path(&key).0.len());
+
+                if map.is_single_level() {
+                    assert_eq!(depth, 0);
+                }
+                count += 1;
+            }
+
+            assert_eq!(count, map.len());
+            assert_eq!(map.iter_with_depth().size_hint(), (map.len(), Some(map.len())));
+        }
+
+        assert!(empty.iter_with_depth().next().is_none());
+
+        let mut map = empty;
+        let mut colliding = HamtMap::<u64, u64, IS, CollidingHasher>::new();
+        let mut single_level = HamtMap::<u64, u64, IS, CollidingHasher>::new();
+
+        for x in 0u64 .. 1000 {
+            map = map.plus(x, x);
+        }
+        for x in 0u64 .. 100 {
+            colliding = colliding.plus(x, x);
+        }
+        // Keys with distinct hash values all end up in the root node
+        let hash = |key: &u64| HamtMap::<u64, u64, IS, CollidingHasher>::key_hash(key);
+        for x in 0u64 .. 100 {
+            if single_level.iter().all(|(k, _)| hash(k) != hash(&x)) {
+                single_level = single_level.plus(x, x);
+            }
+        }
+        assert!(single_level.is_single_level());
+
+        check(&map);
+        check(&colliding);
+        check(&single_level);
+
+        // With only 16 hash values, every key is in a collision entry at the last level
+        assert!(colliding.iter_with_depth().all(|(depth, _, _)| depth == 11));
+        assert!(single_level.iter_with_depth().all(|(depth, _, _)| depth == 0));
+    }
+
+    pub fn test_partial_ord<IS: ItemStore<u64, u64>>(empty: HamtMap<u64, u64, IS>) {
+        use std::cmp::Ordering;
+        use std::collections::{BTreeMap, BTreeSet};
+
+        let mut map = empty.clone();
+        for x in 0u64 .. 100 {
+            map = map.plus(x, x);
+        }
+
+        // A map whose sorted entries are a prefix of another map's is less than the other map
+        let mut prefix = empty.clone();
+        for x in 0u64 .. 50 {
+            prefix = prefix.plus(x, x);
+        }
+        assert!(prefix < map);
+        assert!(map > prefix);
+        assert!(empty < prefix);
+        assert_eq!(map.partial_cmp(&map.clone()), Some(Ordering::Equal));
+        assert_eq!(map.cmp(&map.clone().plus(0, 0)), Ordering::Equal);
+
+        // The first differing entry decides, no matter how many entries follow
+        assert!(map.clone().plus(10, 11) > map);
+        assert!(map.clone().minus(&10) > map);
+        assert!(prefix.clone().plus(1, 0) < map);
+
+        // The order is the one of the sorted entries, as with `BTreeMap`
+        let mut rng = rand::thread_rng();
+        let random_maps: Vec<_> = (0 .. 50).map(|_| {
+            let mut map = empty.clone();
+            let mut reference = BTreeMap::new();
+            for _ in 0 .. rng.gen_range(0, 8) {
+                let (key, value) = (rng.gen_range(0, 8), rng.gen_range(0, 3));
+                map = map.plus(key, value);
+                reference.insert(key, value);
+            }
+            (map, reference)
+        }).collect();
+
+        for (a, a_reference) in random_maps.iter() {
+            for (b, b_reference) in random_maps.iter() {
+                assert_eq!(a.cmp(b), a_reference.cmp(b_reference));
+                assert_eq!(a.partial_cmp(b), a_reference.partial_cmp(b_reference));
+            }
+        }
+
+        // Maps can be used as keys of ordered collections
+        let set: BTreeSet<HamtMap<u64, u64, IS>> = vec!(map.clone(), prefix, map, empty).into_iter()
+                                                                                          .collect();
+        assert_eq!(set.len(), 3);
+    }
+
+    pub fn test_apply_deltas<IS: ItemStore<u64, u64>>(empty: HamtMap<u64, u64, IS>) {
+        let mut counters = empty.clone();
+        for x in 0u64 .. 1000 {
+            counters = counters.plus(x, x);
+        }
+
+        // Increments for half of the existing counters and for some new ones
+        let mut deltas = empty.clone();
+        for x in (0u64 .. 1500).filter(|x| x & 1 == 0) {
+            deltas = deltas.plus(x, 1);
+        }
+
+        let shared = counters.clone();
+        let counters = counters.apply_deltas(&deltas, |count, delta| count + delta);
+        assert_eq!(counters.len(), 1250);
+
+        for x in 0u64 .. 1500 {
+            match (x < 1000, x & 1 == 0) {
+                (true, true) => { assert_find!(counters, x, x + 1); }
+                (true, false) => { assert_find!(counters, x, x); }
+                (false, true) => { assert_find!(counters, x, 1); }
+                (false, false) => { assert_find!(counters, x, None); }
+            }
+        }
+
+        // The original map is not affected
+        assert_eq!(shared.len(), 1000);
+        assert!((0u64 .. 1000).all(|x| shared.find(&x) == Some(&x)));
+
+        // Applying the same deltas to an owned map works in-place and gives the same result
+        let owned = shared.clone().plus(0, 0).minus(&0).plus(0, 0);
+        drop(shared);
+        assert!(owned.apply_deltas(&deltas, |count, delta| count + delta) == counters);
+
+        assert!(counters.clone().apply_deltas(&empty, |_, _| unreachable!()) == counters);
+    }
+
+    pub fn test_estimate_unique_prefixes<IS: ItemStore<u64, u64>>(empty: HamtMap<u64, u64, IS>) {
+        fn check<IS: ItemStore<u64, u64>, H: Hasher+Default>(map: &HamtMap<u64, u64, IS, H>) {
+            // For a single level, that's the number of populated root slots
+            let populated_slots = map.subtree_sizes().iter().filter(|&&size| size > 0).count();
+            assert_eq!(map.estimate_unique_prefixes(1), populated_slots);
+
+            for levels in 0 .. HamtMap::<u64, u64, IS, H>::MAX_DEPTH + 1 {
+                let bits = levels * HamtMap::<u64, u64, IS, H>::BITS_PER_LEVEL;
+                let prefixes: HashSet<u64> =
+                    map.iter()
+                       .map(|(key, _)| HamtMap::<u64, u64, IS, H>::key_hash(key) & ((1 << bits) - 1))
+                       .collect();
+                assert_eq!(map.estimate_unique_prefixes(levels), prefixes.len());
+            }
+        }
+
+        assert_eq!(empty.estimate_unique_prefixes(0), 0);
+        assert_eq!(empty.estimate_unique_prefixes(3), 0);
+
+        let mut map = empty.clone();
+        let mut colliding = HamtMap::<u64, u64, IS, CollidingHasher>::new();
+        let mut clustered = HamtMap::<u64, u64, IS, PairingHasher>::new();
+
+        for x in 0u64 .. 3000 {
+            map = map.plus(x, x);
+            clustered = clustered.plus(x, x);
+        }
+        for x in 0u64 .. 200 {
+            colliding = colliding.plus(x, x);
+        }
+
+        check(&map);
+        check(&colliding);
+        check(&clustered);
+        check(&empty.plus(1, 1));
+
+        assert_eq!(colliding.estimate_unique_prefixes(12), 16);
+    }
+
+    pub fn test_distinct_value_count<IS: ItemStore<u64, u64>>(empty: HamtMap<u64, u64, IS>) {
+        assert_eq!(empty.distinct_value_count(), 0);
+
+        // Several keys sharing each value
+        let mut map = empty.clone();
+        for x in 0u64 .. 1000 {
+            map = map.plus(x, x / 10);
+        }
+        assert_eq!(map.distinct_value_count(), 100);
+
+        let map = map.plus(5000, 5000).plus(0, 99);
+        assert_eq!(map.distinct_value_count(), 101);
+
+        let map = empty.plus(1, 7).plus(2, 7).plus(3, 7);
+        assert_eq!(map.distinct_value_count(), 1);
+
+        let mut colliding = HamtMap::<u64, u64, IS, CollidingHasher>::new();
+        for x in 0u64 .. 200 {
+            colliding = colliding.plus(x, x & 0x7);
+        }
+        assert_eq!(colliding.distinct_value_count(), 8);
+    }
+
+    pub fn test_prune<IS: ItemStore<u64, u64>>(empty: HamtMap<u64, u64, IS>) {
+        let mut map = empty.clone();
+        for x in 0u64 .. 50000 {
+            map = map.plus(x, x);
+        }
+
+        // Keep a tenth of the entries
+        let keep = |key: &u64, _: &u64| *key < 5000;
+        let footprint = map.memory_footprint();
+        let retained = map.clone().retain(keep);
+        let pruned = map.clone().prune(keep);
+
+        assert_eq!(pruned.len(), 5000);
+        assert!(pruned == retained);
+        assert!((0u64 .. 50000).all(|x| (pruned.find(&x) == Some(&x)) == (x < 5000)));
+
+        // The pruned map has no slack left, unlike the one built by `retain()`
+        let stats = pruned.capacity_stats();
+        assert_eq!(stats.wasted_slots, 0);
+        assert!(pruned.memory_footprint() < retained.memory_footprint());
+        assert!(pruned.memory_footprint() < footprint / 5);
+
+        // The pruned map can be modified like any other map
+        let pruned = pruned.plus(5000, 1).minus(&0);
+        assert_eq!(pruned.len(), 5000);
+        assert_find!(pruned, 5000, 1);
+        assert_find!(pruned, 0, None);
+
+        assert_eq!(map.prune(|_, _| false).len(), 0);
+        assert_eq!(empty.prune(|_, _| true).len(), 0);
+    }
+
+    pub fn test_insert_all<IS: ItemStore<u64, u64>>(empty: HamtMap<u64, u64, IS>) {
+        let mut map = empty.clone();
+        for x in 0u64 .. 1000 {
+            map = map.plus(x, x);
+        }
+
+        // 500 of the keys are already there, and 100 keys occur twice in the batch itself
+        let batch = (500u64 .. 1500).map(|x| (x, x + 1)).chain((1400u64 .. 1500).map(|x| (x, 0)));
+        let shared = map.clone();
+        let (map, added) = map.insert_all(batch);
+        assert_eq!(added, 500);
+        assert_eq!(map.len(), 1500);
+
+        for x in 0u64 .. 1500 {
+            let expected = match x {
+                0 ..= 499 => x,
+                1400 ..= 1499 => 0,
+                _ => x + 1,
+            };
+            assert_find!(map, x, expected);
+        }
+        assert_eq!(shared.len(), 1000);
+
+        let (map, added) = map.insert_all(Vec::new());
+        assert_eq!((map.len(), added), (1500, 0));
+
+        let (map, added) = empty.insert_all(vec!((1, 1), (2, 2), (1, 3)));
+        assert_eq!((map.len(), added), (2, 2));
+        assert_find!(map, 1, 3);
+    }
+
+    pub fn test_swap_values<IS: ItemStore<u64, u64>>(empty: HamtMap<u64, u64, IS>) {
+        let mut map = empty;
+        for x in 0u64 .. 1000 {
+            map = map.plus(x, x * 2);
+        }
+
+        let swapped = map.clone().swap_values(&1, &999);
+        assert_find!(swapped, 1, 1998);
+        assert_find!(swapped, 999, 2);
+        assert_find!(map, 1, 2);
+        assert_eq!(swapped.len(), 1000);
+        assert!((2u64 .. 999).all(|x| swapped.find(&x) == Some(&(x * 2))));
+
+        // Swapping twice gives back the original map
+        assert!(swapped.clone().swap_values(&999, &1) == map);
+        assert!(swapped.swap_values(&1, &999) == map);
+
+        // Missing keys and identical keys leave the map unchanged
+        assert!(map.clone().swap_values(&1, &5000) == map);
+        assert!(map.clone().swap_values(&5000, &1) == map);
+        assert!(map.clone().swap_values(&7, &7) == map);
+
+        // A sequence of swaps applies a permutation to the values
+        let mut permuted = map.clone();
+        for x in 0u64 .. 999 {
+            permuted = permuted.swap_values(&x, &(x + 1));
+        }
+        assert_find!(permuted, 999, 0);
+        assert!((0u64 .. 999).all(|x| permuted.find(&x) == Some(&((x + 1) * 2))));
+    }
+
+    pub fn test_group_by_hash<IS: ItemStore<u64, u64>>(empty: HamtMap<u64, u64, IS>) {
+        let mut map = empty;
+        let mut colliding = HamtMap::<u64, u64, IS, CollidingHasher>::new();
+
+        for x in 0u64 .. 1000 {
+            map = map.plus(x, x + 1);
+            colliding = colliding.plus(x, x + 1);
+        }
+
+        // Every hash value is unique
+        let groups = map.group_by_hash::<ShareStore<_, _>>();
+        assert_eq!(groups.len(), 1000);
+        for (&hash, entries) in groups.iter() {
+            assert_eq!(entries.len(), 1);
+            let (key, value) = entries[0];
+            assert_eq!(hash, HamtMap::<u64, u64, IS>::key_hash(&key));
+            assert_eq!(value, key + 1);
+        }
+
+        // Only 16 distinct hash values
+        let groups = colliding.group_by_hash::<CopyStore<_, _>>();
+        assert_eq!(groups.len(), 16);
+        assert_eq!(groups.iter().map(|(_, entries)| entries.len()).sum::<usize>(), 1000);
+        for (&hash, entries) in groups.iter() {
+            assert!(entries.len() > 1);
+            for &(key, value) in entries.iter() {
+                assert_eq!(hash, HamtMap::<u64, u64, IS, CollidingHasher>::key_hash(&key));
+                assert_eq!(value, key + 1);
+            }
+        }
+
+        assert_eq!(HamtMap::<u64, u64, IS>::new().group_by_hash::<ShareStore<_, _>>().len(), 0);
+
+        // The settings of the map carry over to the groups
+        let linear = HamtMap::<u64, u64, IS>::with_growth_policy(GrowthPolicy::Linear).plus(1, 2);
+        let groups = linear.group_by_hash::<CopyStore<_, _>>();
+        assert_eq!(groups.growth_policy(), GrowthPolicy::Linear);
+    }
+
+    pub fn test_collect_into<IS: ItemStore<u64, u64>>(empty: HamtMap<u64, u64, IS>) {
+        let evens = (0u64 .. 1000).filter(|x| x & 1 == 0).map(|x| (x, x));
+        let odds = (0u64 .. 1000).filter(|x| x & 1 == 1).map(|x| (x, x + 1));
+
+        let stage1 = empty.collect_into(evens);
+        assert_eq!(stage1.len(), 500);
+
+        // The second stage overwrites some of the keys of the first one
+        let stage2 = stage1.clone().collect_into(odds.chain((0u64 .. 10).map(|x| (x, 0))));
+        assert_eq!(stage2.len(), 1000);
+        assert_eq!(stage1.len(), 500);
+
+        for x in 0u64 .. 1000 {
+            let expected = if x < 10 { 0 } else if x & 1 == 0 { x } else { x + 1 };
+            assert_find!(stage2, x, expected);
+        }
+
+        let stage3 = stage2.collect_into(None);
+        assert_eq!(stage3.len(), 1000);
+    }
+
+    pub fn test_map_keys<IS: ItemStore<u64, u64>>(empty: HamtMap<u64, u64, IS>) {
+        let mut map = empty;
+        for x in 0u64 .. 1000 {
+            map = map.plus(x, x + 1);
+        }
+
+        let shifted: HamtMap<u64, u64, IS> = map.map_keys(|&k| k + 10000);
+        assert_eq!(shifted.len(), 1000);
+        for x in 0u64 .. 1000 {
+            assert_find!(shifted, x + 10000, x + 1);
+            assert_find!(shifted, x, None);
+        }
+
+        let checked: Result<HamtMap<u64, u64, IS>, u64> = map.map_keys_checked(|&k| k + 10000);
+        assert!(checked.unwrap() == shifted);
+
+        // Every two keys end up on the same new key, the one iterated last wins
+        let mut expected = HashMap::new();
+        for (&k, &v) in map.iter() {
+            expected.insert(k / 2, v);
+        }
+
+        let halved: HamtMap<u64, u64, IS> = map.map_keys(|&k| k / 2);
+        assert_eq!(halved.len(), 500);
+        for (k, v) in expected {
+            assert_find!(halved, k, v);
+        }
+
+        let checked: Result<HamtMap<u64, u64, IS>, u64> = map.map_keys_checked(|&k| k / 2);
+        match checked {
+            Err(key) => assert!(key < 500),
+            Ok(_) => panic!(),
+        }
+
+        // Normalizing string keys
+        let names = HamtMap::<String, u64>::new().plus("Alice".to_string(), 1)
+                                                 .plus("bob".to_string(), 2);
+        let lower: HamtMap<String, u64> = names.map_keys(|name| name.to_lowercase());
+        assert_eq!(lower.find(&"alice".to_string()), Some(&1));
+        assert_eq!(lower.find(&"bob".to_string()), Some(&2));
+
+        let clashing = names.plus("ALICE".to_string(), 3);
+        let lower: Result<HamtMap<String, u64>, String> =
+            clashing.map_keys_checked(|name| name.to_lowercase());
+        match lower {
+            Err(key) => assert_eq!(key, "alice"),
+            Ok(_) => panic!(),
+        }
+    }
+
+    pub fn test_flatten<IS: ItemStore<u64, u64>+'static>(empty: HamtMap<u64, u64, IS>) {
+        type Partitions<IS> = HamtMap<u64, HamtMap<u64, u64, IS>>;
+
+        // Three disjoint partitions
+        let mut partitions = Partitions::<IS>::new();
+        for p in 0u64 .. 3 {
+            let partition = (p * 100 .. p * 100 + 100).fold(empty.clone(), |map, x| map.plus(x, x));
+            partitions = partitions.plus(p, partition);
+        }
+
+        let flattened = HamtMap::flatten(&partitions);
+        assert_eq!(flattened.len(), 300);
+        for x in 0u64 .. 300 {
+            assert_find!(flattened, x, x);
+        }
+
+        // Overlapping partitions: the first one encountered wins, or the values are combined
+        let overlapping = partitions.plus(3, empty.clone().plus(0, 1000).plus(1000, 1000));
+        let first_of = |x: &u64| {
+            overlapping.iter().filter_map(|(_, map)| map.find(x).cloned()).next().unwrap()
+        };
+
+        let flattened = HamtMap::flatten(&overlapping);
+        assert_eq!(flattened.len(), 301);
+        assert_find!(flattened, 0, first_of(&0));
+        assert_find!(flattened, 1000, 1000);
+
+        let summed = HamtMap::flatten_with(&overlapping, |acc, value| acc + value);
+        assert_eq!(summed.len(), 301);
+        assert_find!(summed, 0, 1000);
+        assert_find!(summed, 1, 1);
+
+        assert_eq!(HamtMap::flatten(&Partitions::<IS>::new()).len(), 0);
+
+        // The result has the settings of the first map
+        let linear = HamtMap::<u64, u64, IS>::with_growth_policy(GrowthPolicy::Linear).plus(1, 2);
+        let partitions = Partitions::<IS>::new().plus(0, linear.clone()).plus(1, linear.plus(3, 4));
+        assert_eq!(HamtMap::flatten(&partitions).growth_policy(), GrowthPolicy::Linear);
+        assert_eq!(HamtMap::flatten_with(&partitions, |acc, _| *acc).growth_policy(),
+                   GrowthPolicy::Linear);
+    }
+
+    pub fn test_write_pairs<IS: ItemStore<u64, u64>>(empty: HamtMap<u64, u64, IS>) {
+        let mut map = empty;
+        for x in 0u64 .. 1000 {
+            map = map.plus(x, x + 1);
+        }
+
+        let mut buf = vec![(5000, 5000)];
+        map.write_pairs(&mut buf);
+        assert_eq!(buf.len(), 1000);
+        assert!(buf.iter().cloned().eq(map.iter().map(|(&k, &v)| (k, v))));
+
+        let first = buf.clone();
+        let capacity = buf.capacity();
+        map.write_pairs(&mut buf);
+        assert_eq!(buf, first);
+        assert_eq!(buf.capacity(), capacity);
+
+        HamtMap::<u64, u64, IS>::new().write_pairs(&mut buf);
+        assert!(buf.is_empty());
+        assert_eq!(buf.capacity(), capacity);
+    }
+
+    pub fn test_max_levels<IS: ItemStore<u64, u64>>(empty: HamtMap<u64, u64, IS>) {
+        type Pairs = HamtMap<u64, (u64, u64), CopyStore<u64, (u64, u64)>>;
+
+        assert_eq!(empty.max_levels(), HamtMap::<u64, u64, IS>::MAX_DEPTH);
+        assert_eq!(HamtMap::<u64, u64, IS>::with_max_levels(100).max_levels(),
+                   HamtMap::<u64, u64, IS>::MAX_DEPTH);
+
+        let mut rng = rand::thread_rng();
+
+        for max_levels in 1 .. 4 {
+            let mut reference = HashMap::new();
+            let mut map = HamtMap::<u64, u64, IS>::with_max_levels(max_levels);
+
+            for _ in 0 .. 20000 {
+                let key = rng.gen_range(0, 5000);
+
+                if rng.gen_weighted_bool(3) {
+                    map = map.minus(&key);
+                    reference.remove(&key);
+                } else {
+                    map = map.plus(key, key + 1);
+                    reference.insert(key, key + 1);
+                }
+            }
+
+            assert_eq!(map.len(), reference.len());
+            assert_eq!(map.max_levels(), max_levels);
+            assert!(map.iter_with_depth().all(|(depth, _, _)| depth < max_levels));
+            assert!(map.iter_with_depth().any(|(depth, _, _)| depth == max_levels - 1));
+
+            for key in 0 .. 5000 {
+                assert_eq!(map.find(&key), reference.get(&key));
+                assert_eq!(map.try_find(&key), Ok(reference.get(&key)));
+            }
+
+            // Derived maps keep the limit
+            let retained = map.clone().retain(|&k, _| k & 1 == 0);
+            let pruned = map.clone().prune(|&k, _| k & 1 == 0);
+            for derived in [&retained, &pruned] {
+                assert_eq!(derived.max_levels(), max_levels);
+                assert!(derived.iter_with_depth().all(|(depth, _, _)| depth < max_levels));
+                assert_eq!(derived.len(), reference.keys().filter(|&&k| k & 1 == 0).count());
+                for key in 0 .. 5000 {
+                    assert_eq!(derived.try_find(&key).unwrap().is_some(),
+                               key & 1 == 0 && reference.contains_key(&key));
+                }
+            }
+
+            // Operations based on trie positions see the entries of collision entries individually
+            let half = 1u64 << 59;
+            let (below, rest) = map.clone().split_at_hash(half + 12345);
+            assert_eq!(below.len() + rest.len(), map.len());
+            assert_eq!(below.len(), map.scan_hash_range(0, half + 12345).count());
+            for (key, _) in below.iter() {
+                assert_eq!(rest.find(key), None);
+                assert_eq!(below.try_find(key).unwrap(), reference.get(key));
+            }
+
+            let prefix_of = |key: &u64| HamtMap::<u64, u64, IS>::key_hash(key) & 0x3FF;
+            let mut shard_sizes = 0;
+            for prefix in 0u64 .. 1 << 10 {
+                if let Some(shard) = map.subtree_for_prefix(prefix, 2) {
+                    assert_eq!(shard.max_levels(), max_levels);
+                    assert!(shard.iter().all(|(key, _)| prefix_of(key) == prefix));
+                    assert!(shard.iter().all(|(key, _)| shard.try_find(key).is_ok()));
+                    shard_sizes += shard.len();
+                }
+            }
+            assert_eq!(shard_sizes, map.len());
+
+            let prefixes: HashSet<u64> = map.iter().map(|(key, _)| prefix_of(key)).collect();
+            assert_eq!(map.estimate_unique_prefixes(2), prefixes.len());
+
+            // Combining maps with different limits
+            let unlimited = (2500u64 .. 7500).fold(empty.clone(), |map, x| map.plus(x, 0));
+            let union = map.union(&unlimited);
+            let overlap = map.iter().filter(|&(&k, _)| k >= 2500).count();
+            assert_eq!(union.len(), unlimited.len() + map.len() - overlap);
+
+            let joined: Pairs = unlimited.zip_with(&map);
+            assert_eq!(joined.len(), overlap);
+        }
+    }
+
+    pub fn test_from_sorted_runs<IS: ItemStore<u64, u64>>() {
+        type Map<IS> = HamtMap<u64, u64, IS>;
+
+        let mut rng = rand::thread_rng();
+        let mut runs: Vec<Vec<(u64, u64, u64)>> = Vec::new();
+
+        // Runs in iteration order, as written out by separate shards with overlapping keys
+        for _ in 0 .. 3 {
+            let shard = (0 .. 500).fold(Map::<IS>::new(), |map, _| {
+                map.plus(rng.gen_range(0, 1000), rng.gen_range(0, 1000))
+            });
+            runs.push(shard.iter().map(|(&k, &v)| (Map::<IS>::key_hash(&k), k, v)).collect());
+        }
+
+        // An unsorted run with duplicate keys of its own
+        runs.push((0 .. 500).map(|_| {
+            let k = rng.gen_range(0, 1000);
+            (Map::<IS>::key_hash(&k), k, rng.gen_range(0, 1000))
+        }).collect());
+        runs.push(Vec::new());
+
+        let check = |conflicts: CollisionPolicy<u64>, resolve: fn(u64, u64) -> u64| {
+            let mut reference = Map::<IS>::new();
+            for &(_, k, v) in runs.iter().flat_map(|run| run.iter()) {
+                let value = reference.find(&k).map_or(v, |&existing| resolve(existing, v));
+                reference = reference.plus(k, value);
+            }
+
+            let map = Map::<IS>::from_sorted_runs(runs.clone(), &conflicts);
+            assert_eq!(map.len(), reference.len());
+            assert!(map == reference);
+            assert!(map.iter().eq(reference.iter()));
+        };
+
+        check(CollisionPolicy::Replace, |_, new| new);
+        check(CollisionPolicy::KeepExisting, |existing, _| existing);
+        check(CollisionPolicy::Combine(Arc::new(|existing: &u64, new: u64| existing + new)),
+              |existing, new| existing + new);
+
+        let map = Map::<IS>::from_sorted_runs(Vec::new(), &CollisionPolicy::Replace);
+        assert_eq!(map.len(), 0);
+    }
+
+    pub fn test_find_with_steps<IS: ItemStore<u64, u64>>(empty: HamtMap<u64, u64, IS>) {
+        assert_eq!(empty.find_with_steps(&1), (None, 1));
+
+        let mut map = empty;
+        let mut colliding = HamtMap::<u64, u64, IS, CollidingHasher>::new();
+
+        for x in 0u64 .. 1000 {
+            map = map.plus(x, x * 2);
+            colliding = colliding.plus(x, x * 2);
+        }
+
+        // A lookup visits one node per level down to the entry
+        for (depth, &k, v) in map.iter_with_depth() {
+            assert_eq!(map.find_with_steps(&k), (Some(v), depth + 1));
+        }
+
+        let max_shallow_steps = (0u64 .. 1000).map(|x| map.find_with_steps(&x).1).max().unwrap();
+
+        for x in 0u64 .. 1000 {
+            assert_eq!(colliding.find_with_steps(&x), (Some(&(x * 2)), colliding.max_levels()));
+            assert!(colliding.find_with_steps(&x).1 > max_shallow_steps);
+        }
+
+        assert_eq!(map.find_with_steps(&1000).0, None);
+        assert_eq!(colliding.find_with_steps(&1000), (None, colliding.max_levels()));
+    }
+
+    pub fn test_trim_to_top<IS: ItemStore<u64, u64>>(empty: HamtMap<u64, u64, IS>) {
+        let mut rng = rand::thread_rng();
+        let mut values: Vec<u64> = (0 .. 1000).collect();
+        rng.shuffle(&mut values[..]);
+
+        let mut map = empty.clone();
+        for (k, &v) in values.iter().enumerate() {
+            map = map.plus(k as u64, v);
+        }
+
+        let top = map.clone().trim_to_top(10);
+        assert_eq!(top.len(), 10);
+        let mut kept: Vec<u64> = top.iter().map(|(_, &v)| v).collect();
+        kept.sort();
+        assert_eq!(kept, (990 .. 1000).collect::<Vec<u64>>());
+
+        for (&k, &v) in top.iter() {
+            assert_find!(map, k, v);
+        }
+
+        assert!(map.clone().trim_to_top(1000) == map);
+        assert!(map.clone().trim_to_top(2000) == map);
+        assert_eq!(map.clone().trim_to_top(0).len(), 0);
+
+        // Ten entries per value, the ties at the cut are decided by iteration order
+        let mut tied = empty;
+        for x in 0u64 .. 1000 {
+            tied = tied.plus(x, x / 10);
+        }
+
+        let top = tied.clone().trim_to_top(15);
+        assert_eq!(top.len(), 15);
+        assert_eq!(top.iter().filter(|&(_, &v)| v == 99).count(), 10);
+        let expected: Vec<u64> = tied.iter().filter(|&(_, &v)| v == 98).map(|(&k, _)| k)
+                                     .take(5).collect();
+        let kept: Vec<u64> = top.iter().filter(|&(_, &v)| v == 98).map(|(&k, _)| k).collect();
+        assert_eq!(kept, expected);
+        assert!(tied.clone().trim_to_top(15) == top);
+    }
+
+    pub fn test_equal_with<IS: ItemStore<u64, f64>>(empty: HamtMap<u64, f64, IS>) {
+        let close = |a: &f64, b: &f64| (a - b).abs() < 1e-9;
+
+        let mut map = empty.clone();
+        let mut sums = empty.clone();
+
+        for x in 0u64 .. 1000 {
+            map = map.plus(x, x as f64 * 0.3);
+            sums = sums.plus(x, (0 .. x).fold(0.0, |sum, _| sum + 0.3));
+        }
+
+        assert!(map != sums);
+        assert!(map.equal_with(&sums, close));
+        assert!(sums.equal_with(&map, close));
+        assert!(map.equal_with(&map, |a, b| a == b));
+
+        assert!(!map.equal_with(&sums.clone().plus(1, 0.31), close));
+        assert!(!map.equal_with(&sums.clone().plus(1000, 0.0), close));
+        assert!(!map.equal_with(&sums.minus(&1).plus(1000, 0.3), close));
+        assert!(empty.equal_with(&empty, |_, _| false));
+        assert!(!map.equal_with(&empty, |_, _| true));
+    }
+
+    pub fn test_insert_sorted<IS: ItemStore<u64, u64>>() {
+        fn check<IS: ItemStore<u64, u64>, H: Hasher+Default>(empty: HamtMap<u64, u64, IS, H>) {
+            let mut rng = rand::thread_rng();
+
+            let mut existing = empty.clone();
+            for _ in 0 .. 1000 {
+                existing = existing.plus(rng.gen_range(0, 5000), rng.gen());
+            }
+            let snapshot = existing.clone();
+
+            // Sorted pairs, partly overlapping the existing keys, then an unsorted tail with
+            // duplicates of its own
+            let sorted = (2000u64 .. 7000).fold(empty.clone(), |map, x| map.plus(x, x));
+            let mut pairs: Vec<(u64, u64)> = sorted.iter().map(|(&k, &v)| (k, v)).collect();
+            pairs.extend((0 .. 500).map(|_| (rng.gen_range(0, 8000), rng.gen())));
+
+            let mut reference = existing.clone().transient();
+            for &(k, v) in pairs.iter() {
+                reference.insert(k, v);
+            }
+            let reference = reference.persistent();
+
+            let mut transient = existing.clone().transient();
+            transient.insert_sorted(&pairs);
+            transient.insert_sorted(&[]);
+            assert_eq!(transient.len(), reference.len());
+
+            let map = transient.persistent();
+            assert!(map == reference);
+            for (k, v) in reference.iter() {
+                assert_eq!(map.try_find(k), Ok(Some(v)));
+            }
+
+            // The map the transient was created from is unaffected
+            assert!(existing == snapshot);
+
+            // The result is a regular map
+            let map = map.plus(9000, 1).minus(&2000);
+            assert_eq!(map.len(), reference.len());
+            assert_find!(map, 9000, 1);
+            assert_find!(map, 2000, None);
+
+            let mut transient = empty.transient();
+            transient.insert_sorted(&pairs);
+            assert!(transient.persistent() == HamtMap::from_sorted_pairs(pairs.clone()));
+        }
+
+        check(HamtMap::<u64, u64, IS>::new());
+        check(HamtMap::<u64, u64, IS, CollidingHasher>::new());
+        check(HamtMap::<u64, u64, IS>::with_max_levels(2));
+    }
+
+    pub fn test_collect_node_pointers<IS: ItemStore<u64, u64>>(empty: HamtMap<u64, u64, IS>) {
+        fn shared(a: &[usize], b: &[usize]) -> usize {
+            let a: HashSet<usize> = a.iter().cloned().collect();
+            b.iter().filter(|pointer| a.contains(pointer)).count()
+        }
+
+        assert_eq!(empty.collect_node_pointers().len(), 1);
+
+        let mut map = empty;
+        for x in 0u64 .. 10000 {
+            map = map.plus(x, x);
+        }
+
+        let pointers = map.collect_node_pointers();
+        assert_eq!(pointers.len(), map.node_count());
+        assert_eq!(pointers.iter().collect::<HashSet<_>>().len(), pointers.len());
+
+        // The union of a map with its own clone shares every node
+        let union = map.union(&map.clone());
+        let union_pointers = union.collect_node_pointers();
+        assert_eq!(union_pointers.len(), pointers.len());
+        assert_eq!(shared(&pointers, &union_pointers), pointers.len());
+
+        // Changing one entry copies exactly the nodes on the path to it
+        let (_, depth) = map.find_with_steps(&5);
+        let changed = map.clone().plus(5, 0);
+        let changed_pointers = changed.collect_node_pointers();
+        assert_eq!(changed_pointers.len(), pointers.len());
+        assert_eq!(shared(&pointers, &changed_pointers), pointers.len() - depth);
+    }
+
+    pub fn test_insert_or_combine<IS: ItemStore<u64, u64>>() {
+        fn check<IS: ItemStore<u64, u64>, H: Hasher+Default>(empty: HamtMap<u64, u64, IS, H>) {
+            let mut rng = rand::thread_rng();
+            let keys: Vec<u64> = (0 .. 5000).map(|_| rng.gen_range(0, 500)).collect();
+
+            // A histogram of the keys
+            let mut counts = empty.clone();
+            let mut expected = HashMap::new();
+            for &k in keys.iter() {
+                counts = counts.insert_or_combine(k, 1, |count, one| count + one);
+                *expected.entry(k).or_insert(0) += 1;
+            }
+
+            assert_eq!(counts.len(), expected.len());
+            for (k, &count) in expected.iter() {
+                assert_find!(counts, *k, count);
+            }
+
+            // Maps sharing nodes with the accumulating map are unaffected
+            let snapshot = counts.clone();
+            let sums = counts.clone().insert_or_combine(keys[0], 100, |sum, x| sum + x);
+            assert_find!(sums, keys[0], expected[&keys[0]] + 100);
+            assert_find!(snapshot, keys[0], expected[&keys[0]]);
+            assert!(snapshot == counts);
+            drop(snapshot);
+
+            // A uniquely owned map is modified in-place
+            let pointers = counts.collect_node_pointers();
+            let counts = counts.insert_or_combine(keys[0], 1, |count, one| count + one);
+            assert_eq!(counts.collect_node_pointers(), pointers);
+            assert_find!(counts, keys[0], expected[&keys[0]] + 1);
+        }
+
+        check(HamtMap::<u64, u64, IS>::new());
+        check(HamtMap::<u64, u64, IS, CollidingHasher>::new());
+
+        // The combining function takes precedence over the collision policy
+        let keep = HamtMap::<u64, u64, IS, CollidingHasher>::new()
+            .set_collision_policy(CollisionPolicy::KeepExisting);
+        let keep = (0u64 .. 100).fold(keep, |map, x| map.plus(x, x));
+        let keep = keep.insert_or_combine(5, 10, |existing, new| existing * new);
+        assert_find!(keep, 5, 50);
+        let keep = keep.plus(5, 0);
+        assert_find!(keep, 5, 50);
+    }
+
+    pub fn test_sorted_by_value<IS: ItemStore<u64, u64>>(empty: HamtMap<u64, u64, IS>) {
+        assert!(empty.sorted_by_value().is_empty());
+
+        let mut rng = rand::thread_rng();
+        let mut map = empty;
+        for x in 0u64 .. 1000 {
+            map = map.plus(x, rng.gen_range(0, 100));
+        }
+
+        let ascending = map.sorted_by_value();
+        assert_eq!(ascending.len(), map.len());
+        assert!(ascending.windows(2).all(|pair| pair[0].1 <= pair[1].1));
+
+        let descending = map.sorted_by_value_desc();
+        assert_eq!(descending.len(), map.len());
+        assert!(descending.windows(2).all(|pair| pair[0].1 >= pair[1].1));
+
+        for &(k, v) in ascending.iter().chain(descending.iter()) {
+            assert_find!(map, k, v);
+        }
+
+        let keys: HashSet<u64> = ascending.iter().map(|&(k, _)| k).collect();
+        assert_eq!(keys.len(), map.len());
+    }
+
+    pub fn test_eq_shared_root<IS>(empty: HamtMap<u64, CountingEq, IS>)
+        where IS: ItemStore<u64, CountingEq>
+    {
+        let comparisons = Arc::new(AtomicUsize::new(0));
+
+        let mut map = empty.clone();
+        for x in 0u64 .. 1000 {
+            map = map.plus(x, CountingEq(x, comparisons.clone()));
+        }
+
+        // A map and its clone share the root node, so no values need to be compared
+        let clone = map.clone();
+        assert!(map == clone);
+        assert!(!(map != clone));
+        assert_eq!(comparisons.load(Ordering::SeqCst), 0);
+
+        // Equal maps with different roots still compare their values
+        let rebuilt = map.iter().fold(empty.clone(), |rebuilt, (&k, v)| rebuilt.plus(k, v.clone()));
+        assert!(map == rebuilt);
+        assert_eq!(comparisons.load(Ordering::SeqCst), 1000);
+
+        let changed = clone.plus(5, CountingEq(6, comparisons.clone()));
+        assert!(map != changed);
+        assert!(empty == empty.clone());
+    }
+
+    pub fn test_transient_retain<IS: ItemStore<u64, u64>>() {
+        fn check<IS: ItemStore<u64, u64>, H: Hasher+Default>(empty: HamtMap<u64, u64, IS, H>) {
+            let mut map = empty.clone();
+            for x in 0u64 .. 5000 {
+                map = map.plus(x, x * 2);
+            }
+
+            // The builder shares all nodes with the snapshot at first
+            let snapshot = map.clone();
+            let mut transient = map.transient();
+            transient.retain(|&k, _| k % 3 == 0);
+            assert_eq!(transient.len(), 1667);
+
+            let mut calls = 0;
+            transient.retain(|_, &v| {
+                calls += 1;
+                v % 4 == 0
+            });
+            assert_eq!(calls, 1667);
+            assert_eq!(transient.len(), 834);
+
+            let map = transient.persistent();
+            assert!(map == snapshot.clone().retain(|&k, _| k % 6 == 0));
+            for x in 0u64 .. 5000 {
+                let expected = x * 2;
+                assert_eq!(map.try_find(&x), Ok(if x % 6 == 0 { Some(&expected) } else { None }));
+            }
+
+            assert_eq!(snapshot.len(), 5000);
+            for x in 0u64 .. 5000 {
+                assert_find!(snapshot, x, x * 2);
+            }
+
+            // The result is a regular map
+            let map = map.plus(1, 1).minus(&0);
+            assert_eq!(map.len(), 834);
+            assert_find!(map, 1, 1);
+
+            // A builder that doesn't share its nodes prunes them in-place
+            let root = map.collect_node_pointers()[0];
+            let mut transient = map.transient();
+            transient.retain(|&k, _| k != 6);
+            let map = transient.persistent();
+            assert_eq!(map.collect_node_pointers()[0], root);
+            assert_eq!(map.len(), 833);
+
+            let mut transient = map.transient();
+            transient.retain(|_, _| false);
+            assert!(transient.is_empty());
+            assert_eq!(transient.persistent().iter().count(), 0);
+        }
+
+        check(HamtMap::<u64, u64, IS>::new());
+        check(HamtMap::<u64, u64, IS, CollidingHasher>::new());
+        check(HamtMap::<u64, u64, IS>::with_max_levels(2));
+    }
+
+    pub fn test_iter_root_slot<IS: ItemStore<u64, u64>>() {
+        fn check<IS: ItemStore<u64, u64>, H: Hasher+Default>(empty: HamtMap<u64, u64, IS, H>) {
+            for slot in 0 .. 32 {
+                assert_eq!(empty.iter_root_slot(slot).count(), 0);
+            }
+
+            let mut map = empty;
+            for x in 0u64 .. 2000 {
+                map = map.plus(x, x * 3);
+            }
+
+            let sizes = map.subtree_sizes();
+            let slot_of = |key: &u64| HamtMap::<u64, u64, IS, H>::key_hash(key) & 31;
+            let mut concatenated = Vec::new();
+
+            for (slot, &size) in sizes.iter().enumerate() {
+                let entries: Vec<(&u64, &u64)> = map.iter_root_slot(slot).collect();
+                assert_eq!(entries.len(), size);
+                assert_eq!(map.iter_root_slot(slot).size_hint(), (size, Some(size)));
+                assert!(entries.iter().all(|&(k, _)| slot_of(k) == slot as u64));
+                concatenated.extend(entries);
+            }
+
+            assert!(concatenated.into_iter().eq(map.iter()));
+        }
+
+        check(HamtMap::<u64, u64, IS>::new());
+        check(HamtMap::<u64, u64, IS, CollidingHasher>::new());
+        check(HamtMap::<u64, u64, IS>::with_max_levels(1));
+    }
+
+    pub fn test_find_subtree_fold<IS: ItemStore<u64, u64>>() {
+        fn check<IS: ItemStore<u64, u64>, H: Hasher+Default>(empty: HamtMap<u64, u64, IS, H>) {
+            let hash = |key: u64| HamtMap::<u64, u64, IS, H>::key_hash(&key);
+
+            assert_eq!(empty.find_subtree_fold(&1, 0, |count, _, _| count + 1), None);
+
+            let mut map = empty;
+            for x in 0u64 .. 3000 {
+                map = map.plus(x, x);
+            }
+
+            // Present and absent keys alike
+            for key in (0u64 .. 6000).step_by(7) {
+                let mut folded = map.find_subtree_fold(&key, Vec::new(), |mut keys, &k, &v| {
+                    assert_eq!(k, v);
+                    keys.push(k);
+                    keys
+                }).unwrap();
+                folded.sort();
+
+                // The deepest node is the last one visited by a lookup, and covers all keys
+                // sharing the local keys of the levels above it
+                let (_, steps) = map.find_with_steps(&key);
+                let prefix_mask = (1u64 << (5 * (steps - 1))) - 1;
+                let expected: Vec<u64> = (0u64 .. 3000).filter(|&k| {
+                    (hash(k) ^ hash(key)) & prefix_mask == 0
+                }).collect();
+                assert_eq!(folded, expected);
+            }
+
+            // The whole path is empty if the key's root slot is
+            let single = HamtMap::<u64, u64, IS, H>::new().plus(0, 0);
+            let other = (1u64 ..).find(|&k| (hash(k) ^ hash(0)) & 31 != 0).unwrap();
+            assert_eq!(single.find_subtree_fold(&other, 0, |sum, _, &v| sum + v), None);
+            assert_eq!(single.find_subtree_fold(&0, 10, |sum, _, &v| sum + v + 1), Some(11));
+        }
+
+        check(HamtMap::<u64, u64, IS>::new());
+        check(HamtMap::<u64, u64, IS, CollidingHasher>::new());
+    }
+
+    pub fn test_iter<IS: ItemStore<u64, u64>>() {
+        fn check<IS: ItemStore<u64, u64>, H: Hasher+Default>(empty: HamtMap<u64, u64, IS, H>) {
+            assert_eq!(empty.iter().size_hint(), (0, Some(0)));
+            assert!(empty.iter().next().is_none());
+
+            // Single items, sub-trees and, with the colliding hasher, collision entries
+            let mut map = empty;
+            for x in 0u64 .. 1000 {
+                map = map.plus(x, x + 1);
+            }
+
+            let mut entries: Entries<u64, u64, IS, H> = map.iter();
+            let mut seen = HashSet::new();
+            let mut remaining = map.len();
+
+            while let Some((&k, &v)) = entries.next() {
+                remaining -= 1;
+                assert_eq!(entries.size_hint(), (remaining, Some(remaining)));
+                assert_eq!(v, k + 1);
+                assert!(seen.insert(k));
+            }
+
+            assert_eq!(seen.len(), map.len());
+            assert!(entries.next().is_none());
+        }
+
+        check(HamtMap::<u64, u64, IS>::new());
+        check(HamtMap::<u64, u64, IS, CollidingHasher>::new());
+    }
+
+    pub fn test_keys_values<IS: ItemStore<u64, u64>>() {
+        fn check<IS: ItemStore<u64, u64>, H: Hasher+Default>(empty: HamtMap<u64, u64, IS, H>) {
+            assert!(empty.keys().next().is_none());
+            assert!(empty.values().next().is_none());
+
+            let mut map = empty;
+            for x in 0u64 .. 1000 {
+                map = map.plus(x, x + 1000);
+            }
+
+            let pairs: Vec<(u64, u64)> = map.iter().map(|(&k, &v)| (k, v)).collect();
+            let keys: Vec<u64> = map.keys().cloned().collect();
+            let values: Vec<u64> = map.values().cloned().collect();
+
+            assert_eq!(map.keys().size_hint(), (map.len(), Some(map.len())));
+            assert_eq!(map.values().size_hint(), (map.len(), Some(map.len())));
+            assert_eq!(keys, pairs.iter().map(|p| p.0).collect::<Vec<u64>>());
+            assert_eq!(values, pairs.iter().map(|p| p.1).collect::<Vec<u64>>());
+            assert_eq!(keys.iter().collect::<HashSet<_>>().len(), map.len());
+        }
+
+        check(HamtMap::<u64, u64, IS>::new());
+        check(HamtMap::<u64, u64, IS, CollidingHasher>::new());
+    }
+
+    pub fn test_into_iter<IS: ItemStore<u64, CountingClone>>() {
+        let clones = Arc::new(AtomicUsize::new(0));
+        let build = || {
+            let mut map = HamtMap::<u64, CountingClone, IS>::new();
+            for x in 0u64 .. 1000 {
+                map = map.plus(x, CountingClone(x * 2, clones.clone()));
+            }
+            map
+        };
+        let collect = |map: HamtMap<u64, CountingClone, IS>| {
+            let iter = map.into_iter();
+            assert_eq!(iter.len(), 1000);
+            let pairs: HashMap<u64, u64> = iter.map(|(k, v)| (k, v.0)).collect();
+            assert_eq!(pairs.len(), 1000);
+            assert!(pairs.iter().all(|(&k, &v)| v == k * 2));
+        };
+
+        // A map owning all of its nodes has its entries moved out
+        let map = build();
+        clones.store(0, Ordering::SeqCst);
+        collect(map);
+        assert_eq!(clones.load(Ordering::SeqCst), 0);
+
+        // Entries shared with another map are cloned, which leaves the other map intact
+        let map = build();
+        let snapshot = map.clone();
+        clones.store(0, Ordering::SeqCst);
+        collect(map);
+        assert_eq!(clones.load(Ordering::SeqCst), 1000);
+        assert_eq!(snapshot.len(), 1000);
+        for x in 0u64 .. 1000 {
+            assert_eq!(snapshot.find(&x).map(|v| v.0), Some(x * 2));
+        }
+
+        // Only the entries still shared with the snapshot are cloned after a few modifications
+        let mut map = snapshot.clone();
+        for x in 0u64 .. 10 {
+            map = map.plus(x, CountingClone(x * 2, clones.clone()));
+        }
+        clones.store(0, Ordering::SeqCst);
+        collect(map);
+        let cloned = clones.load(Ordering::SeqCst);
+        assert!(cloned > 0 && cloned < 1000);
+    }
+
+    pub fn test_from_iter<IS: ItemStore<u64, u64>>() {
+        let pairs: Vec<(u64, u64)> = vec!((1, 10), (2, 20), (3, 30), (2, 21), (4, 40), (1, 11),
+                                          (2, 22));
+        let map: HamtMap<u64, u64, IS> = pairs.iter().cloned().collect();
+
+        assert_eq!(map.len(), 4);
+        assert_find!(map, 1, 11);
+        assert_find!(map, 2, 22);
+        assert_find!(map, 3, 30);
+        assert_find!(map, 4, 40);
+
+        let empty: HamtMap<u64, u64, IS> = Vec::new().into_iter().collect();
+        assert_eq!(empty.len(), 0);
+
+        let map: HamtMap<u64, u64, IS> = (0u64 .. 1000).map(|x| (x % 100, x)).collect();
+        assert_eq!(map.len(), 100);
+        for x in 0u64 .. 100 {
+            assert_find!(map, x, 900 + x);
+        }
+    }
+
+    pub fn test_extend<IS: ItemStore<u64, u64>>(empty: HamtMap<u64, u64, IS>) {
+        let mut map = empty;
+        for x in 0u64 .. 100 {
+            map = map.plus(x, x);
+        }
+        let snapshot = map.clone();
+
+        map.extend((100u64 .. 200).map(|x| (x, x * 2)));
+
+        assert_eq!(map.len(), 200);
+        assert_find!(map, 0, 0);
+        assert_find!(map, 99, 99);
+        assert_find!(map, 100, 200);
+        assert_find!(map, 199, 398);
+        assert_find!(map, 200, None);
+
+        // Existing keys get their values replaced, the snapshot is left alone
+        map.extend((50u64 .. 150).map(|x| (x, x * 3)));
+
+        assert_eq!(map.len(), 200);
+        assert_find!(map, 49, 49);
+        assert_find!(map, 50, 150);
+        assert_find!(map, 149, 447);
+        assert_find!(map, 150, 300);
+        assert_eq!(snapshot.len(), 100);
+        assert_find!(snapshot, 50, 50);
+        assert_find!(snapshot, 100, None);
+    }
+
+    pub fn test_update<IS: ItemStore<u64, u64>>() {
+        fn check<IS: ItemStore<u64, u64>, H: Hasher+Default>(empty: HamtMap<u64, u64, IS, H>) {
+            let mut map = empty;
+            for x in 0u64 .. 100 {
+                map = map.plus(x, x);
+            }
+            let snapshot = map.clone();
+
+            // Present keys get their values mapped, absent ones the default
+            let map = map.update(10, 1000, |v| v * 3);
+            assert_eq!(map.len(), 100);
+            assert_find!(map, 10, 30);
+
+            let map = map.update(200, 1000, |v| v * 3);
+            assert_eq!(map.len(), 101);
+            assert_find!(map, 200, 1000);
+
+            let map = map.update(200, 1000, |v| v + 1);
+            assert_eq!(map.len(), 101);
+            assert_find!(map, 200, 1001);
+
+            assert_eq!(snapshot.len(), 100);
+            assert_find!(snapshot, 10, 10);
+            assert_find!(snapshot, 200, None);
+        }
+
+        check(HamtMap::<u64, u64, IS>::new());
+        check(HamtMap::<u64, u64, IS, CollidingHasher>::new());
+    }
+
+    pub fn test_contains_key<IS: ItemStore<u64, u64>>() {
+        fn check<IS: ItemStore<u64, u64>, H: Hasher+Default>(empty: HamtMap<u64, u64, IS, H>) {
+            assert!(!empty.contains_key(&0));
+
+            let mut map = empty;
+            for x in 0u64 .. 1000 {
+                map = map.plus(x * 2, x);
+            }
+
+            for x in 0u64 .. 2000 {
+                assert_eq!(map.contains_key(&x), x % 2 == 0);
+                assert_eq!(map.contains_key(&x), map.find(&x).is_some());
+            }
+
+            let map = map.minus(&10);
+            assert!(!map.contains_key(&10));
+            assert!(map.contains_key(&12));
+
+            let mut transient = map.transient();
+            assert!(transient.contains_key(&12));
+            transient.remove(&12);
+            assert!(!transient.contains_key(&12));
+        }
+
+        check(HamtMap::<u64, u64, IS>::new());
+        check(HamtMap::<u64, u64, IS, CollidingHasher>::new());
+    }
+
+    pub fn test_get_or_insert_with<IS: ItemStore<u64, u64>>() {
+        fn check<IS: ItemStore<u64, u64>, H: Hasher+Default>(empty: HamtMap<u64, u64, IS, H>) {
+            let mut map = empty;
+            for x in 0u64 .. 100 {
+                map = map.plus(x, x * 2);
+            }
+            let snapshot = map.clone();
+
+            // A hit returns the existing value without calling the closure
+            let mut called = false;
+            let (map, value) = map.get_or_insert_with(10, || { called = true; 1000 });
+            assert!(!called);
+            assert_eq!(value, 20);
+            assert_eq!(map.len(), 100);
+            assert_find!(map, 10, 20);
+
+            // A miss inserts the result of the closure
+            let (map, value) = map.get_or_insert_with(200, || { called = true; 1000 });
+            assert!(called);
+            assert_eq!(value, 1000);
+            assert_eq!(map.len(), 101);
+            assert_find!(map, 200, 1000);
+
+            let mut called = false;
+            let (map, value) = map.get_or_insert_with(200, || { called = true; 0 });
+            assert!(!called);
+            assert_eq!(value, 1000);
+            assert_eq!(map.len(), 101);
+
+            assert_eq!(snapshot.len(), 100);
+            assert_find!(snapshot, 200, None);
+        }
+
+        check(HamtMap::<u64, u64, IS>::new());
+        check(HamtMap::<u64, u64, IS, CollidingHasher>::new());
+    }
+
+    pub fn test_eq_same_len<IS: ItemStore<u64, u64>>() {
+        fn check<IS: ItemStore<u64, u64>, H: Hasher+Default>(empty: HamtMap<u64, u64, IS, H>) {
+            let map = (0u64 .. 500).fold(empty.clone(), |map, x| map.plus(x, x));
+            let rebuilt = (0u64 .. 500).rev().fold(empty, |map, x| map.plus(x, x));
+            assert!(map == rebuilt);
+
+            // Same length, one value differs
+            let other_value = rebuilt.clone().plus(250, 0);
+            assert_eq!(other_value.len(), map.len());
+            assert!(map != other_value);
+            assert!(other_value != map);
+
+            // Same length, one key differs
+            let other_key = rebuilt.minus(&250).plus(1000, 250);
+            assert_eq!(other_key.len(), map.len());
+            assert!(map != other_key);
+            assert!(other_key != map);
+        }
+
+        check(HamtMap::<u64, u64, IS>::new());
+        check(HamtMap::<u64, u64, IS, CollidingHasher>::new());
+    }
+
+    pub fn test_observe_after_emptied<IS: ItemStore<u64, u64>>(empty: HamtMap<u64, u64, IS>) {
+        struct Counter(AtomicUsize);
+
+        impl StructuralObserver for Counter {
+            fn on_copy(&self, _: usize) {
+                self.0.fetch_add(1, Ordering::SeqCst);
+            }
+
+            fn on_reuse(&self, _: usize) {
+                self.0.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+
+        let counter = Arc::new(Counter(AtomicUsize::new(0)));
+        let map = empty.observe(counter.clone()).plus(1, 1).plus(2, 2);
+
+        // Removing the last key leaves an empty map which still has the observer installed
+        let (map, _) = map.remove(&1);
+        let (map, _) = map.remove(&2);
+        assert_eq!(map.len(), 0);
+
+        counter.0.store(0, Ordering::SeqCst);
+        let map = map.plus(3, 3);
+        assert!(counter.0.load(Ordering::SeqCst) > 0);
+
+        // The same goes for empty maps derived from an observed one
+        let map = map.retain(|_, _| false);
+        assert_eq!(map.len(), 0);
+
+        counter.0.store(0, Ordering::SeqCst);
+        let map = map.plus(4, 4);
+        assert!(counter.0.load(Ordering::SeqCst) > 0);
+        assert_find!(map, 4, 4);
+    }
+
+    pub fn test_collision_policy_after_emptied<IS: ItemStore<u64, u64>>() {
+        let mut map = HamtMap::<u64, u64, IS, CollidingHasher>::new()
+            .set_collision_policy(CollisionPolicy::KeepExisting);
+        for x in 0u64 .. 100 {
+            map = map.plus(x, x);
+        }
+
+        for x in 0u64 .. 100 {
+            map = map.remove(&x).0;
+        }
+        assert_eq!(map.len(), 0);
+        assert!(matches!(*map.collision_policy(), CollisionPolicy::KeepExisting));
+
+        // Refilled with colliding keys, the configured policy still applies
+        for x in 0u64 .. 100 {
+            map = map.plus(x, x);
+        }
+        for x in 0u64 .. 100 {
+            map = map.plus(x, 1000);
+        }
+        for x in 0u64 .. 100 {
+            assert_find!(map, x, x);
+        }
+    }
+
+    pub fn test_remove_from_collision<IS: ItemStore<u64, u64>>() {
+        // 64 keys sharing 16 hash values, so that each collision entry holds several items
+        let build = || {
+            let mut map = HamtMap::<u64, u64, IS, CollidingHasher>::new();
+            for x in 0u64 .. 64 {
+                map = map.plus(x, x);
+            }
+            map
+        };
+        let check = |map: &HamtMap<u64, u64, IS, CollidingHasher>, removed: u64| {
+            assert_eq!(map.len(), 63);
+            for x in 0u64 .. 64 {
+                if x == removed {
+                    assert_find!(map, x, None);
+                } else {
+                    assert_find!(map, x, x);
+                }
+            }
+        };
+
+        // Every position within the collision entries, including the last ones, both when the
+        // entry is copied and when it is modified in-place
+        let shared = build();
+        for x in 0u64 .. 64 {
+            let (copied, _) = shared.clone().remove(&x);
+            check(&copied, x);
+
+            let (modified, _) = build().remove(&x);
+            check(&modified, x);
+        }
+    }
+
+    pub fn test_iter_collisions<IS: ItemStore<u64, u64>>() {
+        // 200 keys sharing 16 hash values, so that all of them are stored in collision entries
+        let mut map = HamtMap::<u64, u64, IS, CollidingHasher>::new();
+        for x in 0u64 .. 200 {
+            map = map.plus(x, x + 1);
+        }
+
+        // Bounded, so that an iterator not advancing within a collision entry fails instead of
+        // looping forever
+        let entries: Vec<(u64, u64)> = map.iter().take(201).map(|(&k, &v)| (k, v)).collect();
+        assert_eq!(entries.len(), 200);
+
+        let keys: HashSet<u64> = entries.iter().map(|&(k, _)| k).collect();
+        assert_eq!(keys.len(), 200);
+        assert!(entries.iter().all(|&(k, v)| v == k + 1));
+    }
+
+    pub fn test_remove_collapsing_into_root<IS: ItemStore<u64, u64>>() {
+        // Two keys sharing the root slot, so that the root's only entry is a sub-tree
+        let slot = |key: &u64| HamtMap::<u64, u64, IS>::key_hash(key) & 0x1F;
+        let first = 0u64;
+        let second = (1u64 ..).find(|x| slot(x) == slot(&first)).unwrap();
+
+        let map = HamtMap::<u64, u64, IS>::new().plus(first, 1).plus(second, 2);
+        assert_eq!(map.key_path(&first).0.len(), 2);
+
+        // Removing one of them collapses the sub-tree into the remaining item, both when the
+        // nodes are copied and when they are modified in-place
+        let (copied, _) = map.clone().remove(&first);
+        assert_eq!(copied.len(), 1);
+        assert_find!(copied, second, 2);
+        assert_find!(map, first, 1);
+
+        let (modified, _) = map.remove(&second);
+        assert_eq!(modified.len(), 1);
+        assert_find!(modified, first, 1);
+        assert_eq!(modified.key_path(&first).0.len(), 1);
+    }
+
+    pub fn test_insert_in_place_full_node<IS: ItemStore<u64, u64>>(empty: HamtMap<u64, u64, IS>) {
+        // One key for each slot of the root, so that the root holds 32 items and nothing else
+        let slot = |key: &u64| HamtMap::<u64, u64, IS>::key_hash(key) & 0x1F;
+        let mut keys: Vec<u64> = Vec::new();
+        for key in 0u64 .. {
+            if keys.len() == 32 {
+                break;
+            }
+            if keys.iter().all(|other| slot(other) != slot(&key)) {
+                keys.push(key);
+            }
+        }
+
+        // The root of a compacted map is full
+        let map = keys.iter().fold(empty, |map, &key| map.plus(key, key)).compact();
+        assert_eq!(map.node_count(), 1);
+        let stats = map.capacity_stats();
+        assert_eq!(stats.allocated_slots, stats.used_slots);
+        let root = map.collect_node_pointers()[0];
+
+        // Replacing a value takes no additional room, so the full node is modified in-place
+        let map = keys.iter().fold(map, |map, &key| map.plus(key, key + 1));
+        assert_eq!(map.collect_node_pointers(), vec!(root));
+
+        // The same goes for an item replaced by a sub-tree holding it and a new key
+        let new_key = keys.iter().max().unwrap() + 1;
+        let map = map.plus(new_key, 0);
+        assert_eq!(map.node_count(), 2);
+        assert_eq!(map.collect_node_pointers()[0], root);
+
+        assert_eq!(map.len(), 33);
+        assert_find!(map, new_key, 0);
+        for &key in keys.iter() {
+            assert_find!(map, key, key + 1);
+        }
+    }
+
+    pub fn test_merge_by_aligned<IS: ItemStore<u64, u64>>() {
+        fn check<IS: ItemStore<u64, u64>, H: Hasher+Default>(empty: HamtMap<u64, u64, IS, H>) {
+            let mut rng = rand::thread_rng();
+            let mut left = empty.clone();
+            let mut right = empty;
+            let mut reference = HashMap::new();
+
+            for _ in 0 .. 2000 {
+                let key = rng.gen_range(0, 3000);
+                let value = rng.gen_range(0, 100);
+                left = left.plus(key, value);
+                reference.insert(key, value);
+            }
+
+            let mut common_keys = 0;
+            for _ in 0 .. 2000 {
+                let key = rng.gen_range(0, 3000);
+                let value = rng.gen_range(0, 100);
+
+                if right.find(&key).is_none() && left.find(&key).is_some() {
+                    common_keys += 1;
+                }
+
+                right = right.plus(key, value);
+            }
+
+            for (&key, &value) in right.iter() {
+                let stays = reference.get(&key).is_some_and(|&existing| existing >= value);
+                if !stays {
+                    reference.insert(key, value);
+                }
+            }
+
+            let mut ts_calls = 0;
+            let merged = left.merge_by(&right, |_, &value| { ts_calls += 1; value });
+
+            assert_eq!(ts_calls, common_keys * 2);
+            assert_eq!(merged.len(), reference.len());
+            for (key, &value) in reference.iter() {
+                assert_find!(merged, *key, value);
+            }
+        }
+
+        check(HamtMap::<u64, u64, IS>::new());
+        check(HamtMap::<u64, u64, IS, CollidingHasher>::new());
+
+        // Entries only present on one side are shared with the merged map
+        let mut map = HamtMap::<u64, u64, IS>::new();
+        for x in 0u64 .. 1000 {
+            map = map.plus(x, x);
+        }
+        let single = HamtMap::<u64, u64, IS>::new().plus(0, 1);
+        let merged = map.merge_by(&single, |_, &value| value);
+        assert_find!(merged, 0, 1);
+        assert!(merged.count_shared_nodes() >= 31);
+    }
+
+    pub fn random_insert_remove_stress_test<IS: ItemStore<u64, u64>> (empty: HamtMap<u64, u64, IS>) {
+        let mut reference: HashMap<u64, u64> = HashMap::new();
+        let mut rng = rand::thread_rng();
+
+        let mut map = empty;
+
+        for _ in 0 .. 5000000usize {
+            let value: u64 = rng.gen();
+
+            if rng.gen_weighted_bool(2) {
+                let ref_size_change = reference.remove(&value).is_some();
+                let (map1, size_change) = map.remove(&value);
+                assert_eq!(ref_size_change, size_change);
+                assert_find!(map1, value, None);
+                assert_eq!(reference.len(), map1.len());
+                assert_eq!(reference.get(&value), map1.find(&value));
+                map = map1;
+            } else {
+                let ref_size_change = reference.insert(value, value).is_none();
+                let (map1, size_change) = map.insert(value, value);
+                assert_eq!(ref_size_change, size_change);
+                assert_find!(map1, value, value);
+                assert_eq!(reference.len(), map1.len());
+                assert_eq!(reference.get(&value), map1.find(&value));
+                map = map1;
+            }
+        }
+    }
+
+    // Spawns `threads` threads, each repeatedly cloning the shared map, looking up keys in the
+    // clone, deriving a modified map from it and dropping both again. Afterwards, the map must be
+    // unchanged and its root must be referenced exactly as often as before. Broken memory orderings
+    // in the reference counting show up as failed lookups, double frees or leaked references here.
+    pub fn concurrent_clone_find_stress<IS: ItemStore<u64, u64>+'static>(map: HamtMap<u64, u64, IS>,
+                                                                          threads: usize,
+                                                                          iters: usize) {
+        let expected_ref_count = map.ref_count_of_root();
+        let pairs: Arc<Vec<(u64, u64)>> = Arc::new(map.iter().map(|(&k, &v)| (k, v)).collect());
+        let map = Arc::new(map);
+
+        let handles: Vec<_> = (0 .. threads).map(|t| {
+            let map = map.clone();
+            let pairs = pairs.clone();
+            thread::spawn(move || {
+                for i in 0 .. iters {
+                    let clone = (*map).clone();
+                    assert!(clone.ref_count_of_root() > expected_ref_count);
+                    assert_eq!(clone.len(), pairs.len());
+
+                    if !pairs.is_empty() {
+                        let (key, value) = pairs[(i * threads + t) % pairs.len()];
+                        assert_find!(clone, key, value);
+
+                        let modified = clone.plus(key, value + 1);
+                        assert_find!(modified, key, value + 1);
+                        assert_find!(map, key, value);
+                    }
+                }
+            })
+        }).collect();
+
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        let map = match Arc::try_unwrap(map) {
+            Ok(map) => map,
+            Err(_) => panic!("map still shared after all threads finished"),
+        };
+
+        assert_eq!(map.ref_count_of_root(), expected_ref_count);
+        assert_eq!(map.len(), pairs.len());
+        for &(key, value) in pairs.iter() {
+            assert_find!(map, key, value);
+        }
     }
 }